@@ -0,0 +1,682 @@
+use std::fs::File;
+use std::io::{self, BufReader, PipeReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+
+use crate::build::RemoteBuild;
+use crate::error::Error;
+use crate::logging::Run;
+use crate::transport::{kept_names, source_files, spawn_error, Filters, Transport};
+use crate::util::{shell_path, short_hash, slash_path};
+
+#[cfg(unix)]
+mod daemon;
+mod protocol;
+mod server;
+
+pub use protocol::Exec;
+use protocol::{FileInfo, Request, Response};
+
+/// How long a new daemon gets to open its socket.
+const DAEMON_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Transfers and runs through cargo-remote itself running as an agent on the build server. A
+/// daemon on the local machine keeps one ssh session to the agent open across runs, so a run
+/// neither connects nor starts a shell for each step: the agent compares the file list of a
+/// sync with what it has and asks for the changed files only, runs the build and streams its
+/// output back, holds the lock on the build directory and sends the results back. The agent is
+/// this binary, uploaded to `~/.cargo-remote/agent` on first use if the build server has the
+/// same OS and architecture, or [`Agent::path`].
+pub struct Agent {
+    /// A cargo-remote of the same version on the build server to use instead of uploading it.
+    pub path: Option<String>,
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+
+/// A client connection to the daemon. It says bye when dropped, unless it broke, which closes
+/// the session to the agent.
+struct Connection {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+    /// The ssh output of the daemon, for when the connection is lost.
+    log: PathBuf,
+    broken: bool,
+}
+
+impl Connection {
+    fn send(&mut self, request: &Request) -> io::Result<()> {
+        request.write(&mut self.writer)
+    }
+
+    fn receive(&mut self) -> io::Result<Response> {
+        Response::read(&mut self.reader)
+    }
+
+    fn request(&mut self, request: &Request) -> io::Result<Response> {
+        self.send(request)?;
+        self.receive()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !self.broken {
+            let _ = protocol::write_frame(&mut self.writer, protocol::BYE, &[]);
+        }
+    }
+}
+
+/// The error for an answer that doesn't fit the request, the message of an error answer.
+fn unexpected(response: Response) -> String {
+    match response {
+        Response::Error(message) => message,
+        response => format!("Unexpected answer {:?} from the agent", response),
+    }
+}
+
+/// Whether `uname -sm` of the build server names the OS and architecture this binary was built
+/// for.
+fn same_platform(uname: &str) -> bool {
+    let mut words = uname.split_whitespace();
+    let (system, machine) = (words.next(), words.next());
+    let os = match std::env::consts::OS {
+        "linux" => "Linux",
+        "macos" => "Darwin",
+        "freebsd" => "FreeBSD",
+        other => other,
+    };
+    let arch = match machine {
+        Some("arm64") => "aarch64",
+        Some("amd64") => "x86_64",
+        Some(arch) => arch,
+        None => "",
+    };
+    system == Some(os) && arch == std::env::consts::ARCH
+}
+
+/// Uploads [`binary`] to the build server unless it is there already and returns its remote
+/// path.
+fn install(build: &RemoteBuild, binary: &[u8], version: &str) -> Result<String, String> {
+    let what = "install the agent on the build server";
+    let path = format!("~/.cargo-remote/agent/cargo-remote-{}", version);
+    let output = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(format!(
+            "uname -sm; test -x {} && echo installed",
+            shell_path(&path)
+        ))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .logged_output()
+        .map_err(|e| spawn_error(what, e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.lines().any(|line| line == "installed") {
+        return Ok(path);
+    }
+    let uname = stdout.lines().next().unwrap_or_default().trim();
+    if uname.is_empty() {
+        return Err(format!(
+            "Failed to {} (ssh exited with {})",
+            what, output.status
+        ));
+    }
+    if !same_platform(uname) {
+        return Err(format!(
+            "Can't install the agent on {}, it runs {} and this cargo-remote was built for {} \
+             {} (set agent_path to a cargo-remote of the same version on the build server)",
+            build.build_server,
+            uname,
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        ));
+    }
+
+    info!("Installing the agent on {}", build.build_server);
+    let upload = path.clone() + ".tmp";
+    let mut ssh = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(format!(
+            "mkdir -p ~/.cargo-remote/agent && cat > {tmp} && chmod +x {tmp} && mv {tmp} {path}",
+            tmp = shell_path(&upload),
+            path = shell_path(&path)
+        ))
+        .stdin(Stdio::piped())
+        .logged_spawn()
+        .map_err(|e| spawn_error(what, e))?;
+    if let Some(mut stdin) = ssh.stdin.take() {
+        stdin.write_all(binary).map_err(|e| spawn_error(what, e))?;
+    }
+    let status = ssh.wait().map_err(|e| spawn_error(what, e))?;
+    if !status.success() {
+        return Err(format!("Failed to {} (ssh exited with {})", what, status));
+    }
+    Ok(path)
+}
+
+/// The effective user id of this process.
+#[cfg(unix)]
+fn user_id() -> u32 {
+    // a plain system call that can't fail
+    unsafe { libc::geteuid() }
+}
+
+/// The directory of the daemon sockets: the runtime directory of the user if there is one,
+/// otherwise a directory of their own in the temporary directory that only they can access.
+#[cfg(unix)]
+fn socket_dir() -> Result<PathBuf, String> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let uid = user_id();
+    let dir = std::env::temp_dir().join(format!("cargo-remote-{}", uid));
+    let failed = |e: io::Error| format!("Failed to create {} (error: {})", dir.display(), e);
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(failed(e)),
+    }
+    // anyone can create it in the temporary directory before the user does
+    let metadata = std::fs::symlink_metadata(&dir).map_err(failed)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "{} isn't a directory only the current user can access, refusing to put the agent \
+             socket there",
+            dir.display()
+        ));
+    }
+    Ok(dir)
+}
+
+/// The socket of the daemon for [`key`], see [`socket_dir`].
+#[cfg(unix)]
+fn socket_path(key: &str) -> Result<PathBuf, String> {
+    Ok(socket_dir()?.join(format!(
+        "cargo-remote-agent-{}.sock",
+        short_hash(key.as_bytes())
+    )))
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
+
+/// A command started with [`Agent::start`], its output is streamed as it comes.
+pub struct Running {
+    pub stdout: PipeReader,
+    pub stderr: Option<PipeReader>,
+    /// The thread streaming the output, it returns the exit status of the remote command.
+    stream: JoinHandle<Result<ExitStatus, Error>>,
+}
+
+impl Running {
+    /// Waits until the output was streamed completely and returns the exit status of the
+    /// remote command.
+    pub fn wait(self) -> Result<ExitStatus, Error> {
+        self.stream
+            .join()
+            .unwrap_or_else(|_| Err(Error::Run("The agent output stream failed".to_string())))
+    }
+}
+
+/// The lock on a build directory held by the agent, released when dropped.
+pub struct AgentLock {
+    connection: Arc<Mutex<Option<Connection>>>,
+    file: String,
+}
+
+impl Drop for AgentLock {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.lock().unwrap().as_mut() {
+            let file = self.file.clone();
+            let _ = connection.request(&Request::Unlock { file });
+        }
+    }
+}
+
+impl Agent {
+    pub fn new(path: Option<String>) -> Agent {
+        Agent {
+            path,
+            connection: Default::default(),
+        }
+    }
+
+    /// Prints what would be done through the agent if this is a dry run. Returns whether it
+    /// was, nothing must be sent then.
+    fn dry(build: &RemoteBuild, what: &str) -> bool {
+        if crate::logging::dry_run() {
+            println!("# agent on {}: {}", build.ssh.destination(), what);
+        }
+        crate::logging::dry_run()
+    }
+
+    /// Connects to the daemon for the build server of [`build`], starting it and installing
+    /// the agent first if needed.
+    #[cfg(unix)]
+    fn connect(&self, build: &RemoteBuild) -> Result<Connection, String> {
+        use std::os::unix::fs::MetadataExt;
+        use std::os::unix::net::UnixStream;
+        use std::os::unix::process::CommandExt;
+
+        let what = "connect to the agent";
+        let exe = std::env::current_exe().map_err(|e| spawn_error(what, e))?;
+        let binary = std::fs::read(&exe).map_err(|e| spawn_error(what, e))?;
+        let version = short_hash(&binary);
+        // the daemon's session must not go through the connection master of this run
+        let options = build.ssh.options();
+        let socket = socket_path(&format!(
+            "{} {} {} {:?}",
+            build.ssh.destination(),
+            options.join(" "),
+            version,
+            self.path
+        ))?;
+        if let Ok(metadata) = std::fs::symlink_metadata(&socket) {
+            if metadata.uid() != user_id() {
+                return Err(format!(
+                    "Refusing to connect to the agent daemon at {}, it belongs to another user",
+                    socket.display()
+                ));
+            }
+        }
+        let log = socket.with_extension("log");
+        let connection = |stream: UnixStream| -> io::Result<Connection> {
+            Ok(Connection {
+                reader: BufReader::new(Box::new(stream.try_clone()?)),
+                writer: Box::new(stream),
+                log: log.clone(),
+                broken: false,
+            })
+        };
+        if let Ok(stream) = UnixStream::connect(&socket) {
+            debug!("Connected to the agent daemon at {}", socket.display());
+            return connection(stream).map_err(|e| spawn_error(what, e));
+        }
+
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => install(build, &binary, &version)?,
+        };
+        let mut ssh = vec!["ssh".to_string()];
+        ssh.extend(options.iter().cloned());
+        ssh.push("-T".to_string());
+        ssh.push(build.ssh.destination().to_string());
+        ssh.push(format!("{} remote-agent serve", shell_path(&path)));
+        debug!("Starting the agent daemon at {}", socket.display());
+        std::process::Command::new(&exe)
+            .arg("remote-agent")
+            .arg("daemon")
+            .arg(&socket)
+            .arg("--")
+            .args(&ssh)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            // not killed with cargo-remote by a Ctrl-C in the terminal
+            .process_group(0)
+            .logged_spawn()
+            .map_err(|e| spawn_error("start the agent daemon", e))?;
+        let started = Instant::now();
+        loop {
+            match UnixStream::connect(&socket) {
+                Ok(stream) => return connection(stream).map_err(|e| spawn_error(what, e)),
+                Err(_) if started.elapsed() < DAEMON_START_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                Err(e) => return Err(spawn_error("start the agent daemon", e)),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn connect(&self, _build: &RemoteBuild) -> Result<Connection, String> {
+        Err("The agent transport needs cargo-remote to run on a Unix machine".to_string())
+    }
+
+    /// Runs [`f`] on the connection to the agent, connecting first if needed. A connection
+    /// error ends the connection, the next call connects again.
+    fn with<T>(
+        &self,
+        build: &RemoteBuild,
+        what: &str,
+        f: impl FnOnce(&mut Connection) -> io::Result<Result<T, String>>,
+    ) -> Result<T, String> {
+        let mut guard = self.connection.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(self.connect(build)?);
+        }
+        let connection = guard.as_mut().expect("connected");
+        match f(connection) {
+            Ok(result) => result.map_err(|e| format!("Failed to {} ({})", what, e)),
+            Err(e) => {
+                connection.broken = true;
+                let log = connection.log.clone();
+                *guard = None;
+                Err(format!(
+                    "Failed to {} (lost the connection to the agent: {}, the ssh output is in {})",
+                    what,
+                    e,
+                    log.display()
+                ))
+            }
+        }
+    }
+
+    /// Receives the files of a `fetch` request into [`local_dir`].
+    fn fetch(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        paths: &[String],
+        exclude: &[String],
+        local_dir: &Path,
+    ) -> Result<(), String> {
+        let what = "transfer files back from the build server";
+        if Agent::dry(build, &format!("fetch {:?} from {}", paths, remote_dir)) {
+            return Ok(());
+        }
+        let received = self.with(build, what, |connection| {
+            connection.send(&Request::Fetch {
+                dir: remote_dir.to_string(),
+                paths: paths.to_vec(),
+                exclude: exclude.to_vec(),
+            })?;
+            let mut received = 0;
+            let mut failed = None;
+            loop {
+                match connection.receive()? {
+                    Response::File(file) => {
+                        received += file.len;
+                        let written =
+                            server::receive_file(&mut connection.reader, local_dir, &file)?;
+                        failed = failed.or(written.err());
+                    }
+                    Response::Done => return Ok(failed.map_or(Ok(received), Err)),
+                    response => return Ok(Err(unexpected(response))),
+                }
+            }
+        })?;
+        build.timings.add_bytes(0, received);
+        Ok(())
+    }
+
+    /// Starts [`script`] on the build server like ssh would run it and streams its output.
+    pub fn start_script(&self, build: &RemoteBuild, script: &str) -> Result<Running, Error> {
+        let request = Request::Run {
+            script: script.to_string(),
+        };
+        self.start(build, request, script)
+    }
+
+    /// Starts [`exec`] on the build server and streams its output.
+    pub fn start_exec(&self, build: &RemoteBuild, exec: Exec) -> Result<Running, Error> {
+        let line = exec.command.join(" ");
+        self.start(build, Request::Exec(exec), &line)
+    }
+
+    fn start(&self, build: &RemoteBuild, request: Request, line: &str) -> Result<Running, Error> {
+        let what = "run cargo command remotely";
+        if build.tty || !build.forward_ports.is_empty() {
+            warn!("The agent transport runs cargo without a terminal and forwarded ports");
+        }
+        let pipe = || io::pipe().map_err(|e| Error::command(what, e, &[]));
+        let (stdout, mut stdout_writer) = pipe()?;
+        let (stderr, mut stderr_writer) = pipe()?;
+        if Agent::dry(build, &format!("run {}", line)) {
+            let stream = thread::spawn(|| Ok(exit_status(0)));
+            return Ok(Running {
+                stdout,
+                stderr: Some(stderr),
+                stream,
+            });
+        }
+        self.with(build, what, |connection| {
+            connection.send(&request)?;
+            Ok(Ok(()))
+        })
+        .map_err(Error::Run)?;
+
+        let shared = self.connection.clone();
+        let stream = thread::spawn(move || {
+            let mut guard = shared.lock().unwrap();
+            let connection = match guard.as_mut() {
+                Some(connection) => connection,
+                None => return Err(Error::Run("Lost the connection to the agent".to_string())),
+            };
+            loop {
+                let frame = protocol::read_frame(&mut connection.reader);
+                match frame {
+                    Ok((protocol::STDOUT, data)) => {
+                        let _ = stdout_writer.write_all(&data);
+                    }
+                    Ok((protocol::STDERR, data)) => {
+                        let _ = stderr_writer.write_all(&data);
+                    }
+                    Ok((protocol::MESSAGE, payload)) => {
+                        return match Response::parse(&payload) {
+                            Ok(Response::Exit(code)) => Ok(exit_status(code)),
+                            Ok(response) => Err(Error::Run(format!(
+                                "Failed to {} ({})",
+                                what,
+                                unexpected(response)
+                            ))),
+                            Err(e) => Err(Error::Run(format!("Failed to {} ({})", what, e))),
+                        };
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        connection.broken = true;
+                        let log = connection.log.clone();
+                        *guard = None;
+                        return Err(Error::Run(format!(
+                            "Lost the connection to the agent ({}, the ssh output is in {})",
+                            e,
+                            log.display()
+                        )));
+                    }
+                }
+            }
+        });
+        Ok(Running {
+            stdout,
+            stderr: Some(stderr),
+            stream,
+        })
+    }
+
+    /// Writes [`content`] to [`path`] below [`dir`] on the build server.
+    pub fn write_file(
+        &self,
+        build: &RemoteBuild,
+        dir: &str,
+        path: &str,
+        content: &[u8],
+    ) -> Result<(), String> {
+        if Agent::dry(build, &format!("write {}{}", dir, path)) {
+            return Ok(());
+        }
+        let modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let file = FileInfo {
+            dir: dir.to_string(),
+            path: path.to_string(),
+            len: content.len() as u64,
+            mtime: modified.as_secs(),
+            mtime_ns: modified.subsec_nanos(),
+            mode: 0o644,
+        };
+        self.with(build, "write a file on the build server", |connection| {
+            connection.send(&Request::File(file))?;
+            protocol::write_data(&mut connection.writer, content, content.len() as u64)?;
+            Ok(match connection.request(&Request::Synced)? {
+                Response::Done => Ok(()),
+                response => Err(unexpected(response)),
+            })
+        })
+    }
+
+    /// Locks [`file`] on the build server, waiting for other runs if [`wait`] is set. Returns
+    /// `None` in a dry run.
+    pub fn lock(
+        &self,
+        build: &RemoteBuild,
+        file: &str,
+        wait: bool,
+    ) -> Result<Option<AgentLock>, Error> {
+        if Agent::dry(build, &format!("lock {}", file)) {
+            return Ok(None);
+        }
+        let locked = self.with(build, "lock the build directory", |connection| {
+            let file = file.to_string();
+            let mut response = connection.request(&Request::Lock { file, wait })?;
+            if let (Response::Busy, true) = (&response, wait) {
+                info!(
+                    "Another run is using {} on {}, waiting for it to finish",
+                    build.build_path, build.build_server
+                );
+                response = connection.receive()?;
+            }
+            Ok(match response {
+                Response::Busy => Ok(false),
+                Response::Locked => Ok(true),
+                response => Err(unexpected(response)),
+            })
+        });
+        match locked.map_err(Error::Run)? {
+            true => Ok(Some(AgentLock {
+                connection: self.connection.clone(),
+                file: file.to_string(),
+            })),
+            false => Err(Error::Locked(format!(
+                "Another run is using {} on {} (use --lock wait to wait for it)",
+                build.build_path, build.build_server
+            ))),
+        }
+    }
+}
+
+impl Transport for Agent {
+    fn remote_tool(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn agent(&self) -> Option<&Agent> {
+        Some(self)
+    }
+
+    fn upload(
+        &self,
+        build: &RemoteBuild,
+        local_dir: &Path,
+        remote_dir: &str,
+    ) -> Result<(), String> {
+        let what = "transfer project to build server";
+        let files: Vec<_> = source_files(build, local_dir)
+            .iter()
+            .map(|(path, metadata)| server::file_info(remote_dir, &slash_path(path), metadata))
+            .collect();
+        if Agent::dry(
+            build,
+            &format!("sync {} files to {}", files.len(), remote_dir),
+        ) {
+            return Ok(());
+        }
+        // what the tar transport keeps when it replaces the sources
+        let keep = kept_names(build);
+        let sent = self.with(build, what, |connection| {
+            let count = files.len();
+            let response = connection.request(&Request::Sync {
+                dir: remote_dir.to_string(),
+                files,
+                keep,
+                keep_hidden: !build.hidden,
+            })?;
+            let need = match response {
+                Response::Need(need) => need,
+                response => return Ok(Err(unexpected(response))),
+            };
+            debug!("The agent needs {} of {} files", need.len(), count);
+            let mut sent = 0;
+            for path in need {
+                // a file that is gone by now is removed with the next sync
+                let file = match File::open(local_dir.join(&path)) {
+                    Ok(file) => file,
+                    Err(_) => continue,
+                };
+                let metadata = match file.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                let info = server::file_info(remote_dir, &path, &metadata);
+                connection.send(&Request::File(info))?;
+                protocol::write_data(&mut connection.writer, file, metadata.len())?;
+                sent += metadata.len();
+            }
+            Ok(match connection.request(&Request::Synced)? {
+                Response::Done => Ok(sent),
+                response => Err(unexpected(response)),
+            })
+        })?;
+        build.timings.add_bytes(sent, 0);
+        Ok(())
+    }
+
+    fn download(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        path: &str,
+        local_dir: &Path,
+        filters: &Filters,
+    ) -> Result<(), String> {
+        if !filters.include.is_empty() {
+            warn!("Copy-back include patterns are only supported by the rsync transport");
+        }
+        let path = path.trim_end_matches('/').to_string();
+        self.fetch(build, remote_dir, &[path], &filters.exclude, local_dir)
+    }
+
+    fn download_files(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        files: &[String],
+        local_dir: &Path,
+    ) -> Result<(), String> {
+        self.fetch(build, remote_dir, files, &[], local_dir)
+    }
+}
+
+/// `cargo-remote remote-agent <mode> <args>`, started by cargo-remote itself: `serve` on the
+/// build server, `daemon <socket> -- <ssh command>` on the local machine.
+pub fn main(mode: &str, args: &[String]) -> io::Result<()> {
+    match (mode, args) {
+        ("serve", _) => server::serve(),
+        #[cfg(unix)]
+        ("daemon", [socket, ssh @ ..]) if !ssh.is_empty() => daemon::run(Path::new(socket), ssh),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown agent mode '{}'", mode),
+        )),
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs::{OpenOptions, Permissions};
+use std::io::{self, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::protocol::{self, Response};
+
+/// The daemon exits after this long without a client, which closes the connection to the agent.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How long ssh gets to end the session after its stdin was closed before it is killed.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The ssh session that runs the agent on the build server. Everything the agent sends goes to
+/// the client in [`Upstream::client`], if there is one.
+struct Upstream {
+    child: Child,
+    client: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl Upstream {
+    fn spawn(ssh: &[String], log: &Path) -> io::Result<Upstream> {
+        let mut child = Command::new(&ssh[0])
+            .args(&ssh[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(log)
+                    .map_or_else(|_| Stdio::null(), Stdio::from),
+            )
+            .spawn()?;
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let client: Arc<Mutex<Option<UnixStream>>> = Default::default();
+        let forward_to = client.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 64 * 1024];
+            loop {
+                let n = match stdout.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let mut client = forward_to.lock().unwrap();
+                if let Some(stream) = client.as_mut() {
+                    if stream.write_all(&buffer[..n]).is_err() {
+                        *client = None;
+                    }
+                }
+            }
+            // the client waiting for an answer learns that the connection is gone
+            if let Some(stream) = forward_to.lock().unwrap().take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        });
+        Ok(Upstream { child, client })
+    }
+
+    fn alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Passes the frames of [`stream`] on to the agent until the client says bye. Returns
+    /// `false` if it went away without, it may have left a command running then.
+    fn serve(&mut self, stream: UnixStream) -> bool {
+        *self.client.lock().unwrap() = stream.try_clone().ok();
+        let mut reader = BufReader::new(stream);
+        let bye = loop {
+            match protocol::read_frame(&mut reader) {
+                Ok((protocol::BYE, _)) => break true,
+                Ok((kind, payload)) => {
+                    let stdin = self.child.stdin.as_mut().expect("stdin is piped");
+                    if protocol::write_frame(stdin, kind, &payload).is_err() {
+                        break false;
+                    }
+                }
+                Err(_) => break false,
+            }
+        };
+        *self.client.lock().unwrap() = None;
+        bye
+    }
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        // the agent kills a command that still runs when its stdin is closed
+        drop(self.child.stdin.take());
+        let closing = Instant::now();
+        while matches!(self.child.try_wait(), Ok(None)) {
+            if closing.elapsed() > CLOSE_TIMEOUT {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+/// Serves the client on [`stream`] with an idle session from [`idle`] or a new one. The
+/// session goes back to [`idle`] if the client said bye.
+fn serve(mut stream: UnixStream, ssh: &[String], log: &Path, idle: &Mutex<Vec<Upstream>>) {
+    let reused = std::iter::from_fn(|| idle.lock().unwrap().pop()).find_map(|mut upstream| {
+        match upstream.alive() {
+            true => Some(upstream),
+            false => None,
+        }
+    });
+    let mut upstream = match reused.map_or_else(|| Upstream::spawn(ssh, log), Ok) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let error = Response::Error(format!("Can't run ssh: {}", e));
+            let _ = error.write(&mut stream);
+            return;
+        }
+    };
+    if upstream.serve(stream) {
+        idle.lock().unwrap().push(upstream);
+    }
+}
+
+/// `cargo-remote remote-agent daemon <socket> <ssh command>`: keeps ssh sessions to the agent
+/// open and serves the clients connecting to [`socket`] over them, one session per client at a
+/// time so that concurrent runs don't wait for each other. A client that goes away without
+/// saying bye gets its session closed, and with it whatever it still had running. The output of
+/// ssh goes to a log file next to the socket.
+pub fn run(socket: &Path, ssh: &[String]) -> io::Result<()> {
+    let log = socket.with_extension("log");
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    let idle: Arc<Mutex<Vec<Upstream>>> = Default::default();
+    let clients = Arc::new(AtomicUsize::new(0));
+    let mut idle_since = Instant::now();
+    let result = loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                let (ssh, log, idle, clients) =
+                    (ssh.to_vec(), log.clone(), idle.clone(), clients.clone());
+                clients.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    serve(stream, &ssh, &log, &idle);
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if clients.load(Ordering::SeqCst) > 0 {
+                    idle_since = Instant::now();
+                } else if idle_since.elapsed() > IDLE_TIMEOUT {
+                    break Ok(());
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = std::fs::remove_file(socket);
+    // closes the idle sessions
+    drop(idle);
+    result
+}
@@ -0,0 +1,601 @@
+use std::io::{self, Read, Write};
+
+use serde_json::{json, Value};
+
+/// A JSON message, a request to the agent or one of its answers.
+pub const MESSAGE: u8 = 0;
+/// Output of the command the agent runs.
+pub const STDOUT: u8 = 1;
+pub const STDERR: u8 = 2;
+/// Part of the content of the file announced by the message before.
+pub const DATA: u8 = 3;
+/// Tells the daemon that a client is done. It isn't passed on, the connection to the agent
+/// stays open for the next client.
+pub const BYE: u8 = 4;
+
+/// Files are sent in frames of at most this size.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The largest frame that is read, enough for the file list of a sync of a huge project. A
+/// garbled length isn't allocated before anything is read.
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+fn too_large(len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "frame of {} bytes exceeds the limit of {} bytes",
+            len, MAX_FRAME
+        ),
+    )
+}
+
+/// Writes a frame: its kind, the length of the payload as 4 bytes big endian and the payload.
+pub fn write_frame(to: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME {
+        return Err(too_large(payload.len()));
+    }
+    let mut header = [kind, 0, 0, 0, 0];
+    header[1..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    to.write_all(&header)?;
+    to.write_all(payload)?;
+    to.flush()
+}
+
+/// Reads a frame written by [`write_frame`]. A connection that ends in the middle of one is an
+/// `UnexpectedEof` error.
+pub fn read_frame(from: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 5];
+    from.read_exact(&mut header)?;
+    let mut length = [0; 4];
+    length.copy_from_slice(&header[1..]);
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME {
+        return Err(too_large(length));
+    }
+    let mut payload = vec![0; length];
+    from.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+fn write_message(to: &mut impl Write, message: &Value) -> io::Result<()> {
+    write_frame(to, MESSAGE, message.to_string().as_bytes())
+}
+
+/// Reads the next frame, which has to be a message.
+fn read_message(from: &mut impl Read) -> io::Result<Value> {
+    match read_frame(from)? {
+        (MESSAGE, payload) => serde_json::from_slice(&payload).map_err(invalid),
+        (kind, _) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected a message, got a frame of kind {}", kind),
+        )),
+    }
+}
+
+/// Sends [`len`] bytes of [`from`] as data frames. A file that got shorter in the meantime is
+/// padded with zeros, the other side expects exactly [`len`] bytes.
+pub fn write_data(to: &mut impl Write, mut from: impl Read, len: u64) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut left = len;
+    while left > 0 {
+        let n = (left as usize).min(CHUNK_SIZE);
+        let mut filled = 0;
+        while filled < n {
+            match from.read(&mut buffer[filled..n]) {
+                Ok(0) | Err(_) => {
+                    buffer[filled..n].iter_mut().for_each(|byte| *byte = 0);
+                    filled = n;
+                }
+                Ok(read) => filled += read,
+            }
+        }
+        write_frame(to, DATA, &buffer[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+/// Receives [`len`] bytes from data frames and writes them to [`to`].
+pub fn read_data(from: &mut impl Read, to: &mut impl Write, len: u64) -> io::Result<()> {
+    let mut left = len;
+    while left > 0 {
+        match read_frame(from)? {
+            (DATA, payload) if payload.len() as u64 <= left => {
+                to.write_all(&payload)?;
+                left -= payload.len() as u64;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "expected the content of a file",
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A file that is or will be sent, its content follows in data frames.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileInfo {
+    /// Directory [`FileInfo::path`] is relative to, `~/` is the home directory.
+    pub dir: String,
+    pub path: String,
+    pub len: u64,
+    /// Modification time as seconds and nanoseconds since the epoch.
+    pub mtime: u64,
+    pub mtime_ns: u32,
+    /// The Unix permissions, 0 if unknown.
+    pub mode: u32,
+}
+
+/// A program the agent runs directly, without a shell in between that could split or expand
+/// its arguments.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Exec {
+    /// The environment is loaded from this shell profile first, like `source` would.
+    pub profile: Option<String>,
+    /// Directory to run in, `~/` is the home directory.
+    pub cwd: String,
+    /// Variables set for [`Exec::command`].
+    pub env: Vec<(String, String)>,
+    /// Variables that hold paths on the build server, a leading `~/` is the home directory.
+    pub path_env: Vec<(String, String)>,
+    /// Programs run before [`Exec::command`] with only the environment of the profile and
+    /// their output to stderr. The first one that fails ends the request with its exit code.
+    pub setup: Vec<Vec<String>>,
+    /// The program and its arguments.
+    pub command: Vec<String>,
+}
+
+/// What cargo-remote asks the agent for.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// Lists the sources of `dir`, answered with the ones the agent needs. Other files are
+    /// removed except for the top level names in `keep` and hidden ones if `keep_hidden` is
+    /// set.
+    Sync {
+        dir: String,
+        files: Vec<FileInfo>,
+        keep: Vec<String>,
+        keep_hidden: bool,
+    },
+    /// One of the needed files.
+    File(FileInfo),
+    /// All needed files were sent.
+    Synced,
+    /// Asks for the files below `paths` in `dir` except for names matching `exclude`.
+    Fetch {
+        dir: String,
+        paths: Vec<String>,
+        exclude: Vec<String>,
+    },
+    /// Runs a shell script in the home directory, for builds with hooks and wrappers.
+    Run {
+        script: String,
+    },
+    Exec(Exec),
+    Lock {
+        file: String,
+        wait: bool,
+    },
+    Unlock {
+        file: String,
+    },
+}
+
+/// What the agent answers.
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    /// The files of a [`Request::Sync`] that are missing or changed.
+    Need(Vec<String>),
+    /// One of the fetched files.
+    File(FileInfo),
+    Done,
+    /// The lock is held by another run, with [`Request::Lock::wait`] the agent waits for it.
+    Busy,
+    Locked,
+    /// The exit code of the command.
+    Exit(i32),
+    Error(String),
+}
+
+fn string(message: &Value, key: &str) -> String {
+    message
+        .get(key)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn number(message: &Value, key: &str) -> u64 {
+    message.get(key).and_then(Value::as_u64).unwrap_or_default()
+}
+
+fn string_array(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn strings(message: &Value, key: &str) -> Vec<String> {
+    message.get(key).map(string_array).unwrap_or_default()
+}
+
+fn pairs(message: &Value, key: &str) -> Vec<(String, String)> {
+    message
+        .get(key)
+        .and_then(Value::as_array)
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|pair| Some((pair.get(0)?.as_str()?, pair.get(1)?.as_str()?)))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl FileInfo {
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "file",
+            "dir": self.dir,
+            "path": self.path,
+            "len": self.len,
+            "mtime": self.mtime,
+            "mtime_ns": self.mtime_ns,
+            "mode": self.mode,
+        })
+    }
+
+    fn from_json(message: &Value) -> FileInfo {
+        FileInfo {
+            dir: string(message, "dir"),
+            path: string(message, "path"),
+            len: number(message, "len"),
+            mtime: number(message, "mtime"),
+            mtime_ns: number(message, "mtime_ns") as u32,
+            mode: number(message, "mode") as u32,
+        }
+    }
+}
+
+impl Request {
+    fn to_json(&self) -> Value {
+        match self {
+            Request::Sync {
+                dir,
+                files,
+                keep,
+                keep_hidden,
+            } => json!({
+                "type": "sync",
+                "dir": dir,
+                "files": files.iter().map(FileInfo::to_json).collect::<Vec<_>>(),
+                "keep": keep,
+                "keep_hidden": keep_hidden,
+            }),
+            Request::File(file) => file.to_json(),
+            Request::Synced => json!({"type": "synced"}),
+            Request::Fetch {
+                dir,
+                paths,
+                exclude,
+            } => json!({"type": "fetch", "dir": dir, "paths": paths, "exclude": exclude}),
+            Request::Run { script } => json!({"type": "run", "script": script}),
+            Request::Exec(exec) => json!({
+                "type": "exec",
+                "profile": exec.profile,
+                "cwd": exec.cwd,
+                "env": exec.env,
+                "path_env": exec.path_env,
+                "setup": exec.setup,
+                "command": exec.command,
+            }),
+            Request::Lock { file, wait } => json!({"type": "lock", "file": file, "wait": wait}),
+            Request::Unlock { file } => json!({"type": "unlock", "file": file}),
+        }
+    }
+
+    fn from_json(message: &Value) -> Result<Request, String> {
+        Ok(match message.get("type").and_then(Value::as_str) {
+            Some("sync") => Request::Sync {
+                dir: string(message, "dir"),
+                files: message
+                    .get("files")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .map(FileInfo::from_json)
+                    .collect(),
+                keep: strings(message, "keep"),
+                keep_hidden: message.get("keep_hidden").and_then(Value::as_bool) == Some(true),
+            },
+            Some("file") => Request::File(FileInfo::from_json(message)),
+            Some("synced") => Request::Synced,
+            Some("fetch") => Request::Fetch {
+                dir: string(message, "dir"),
+                paths: strings(message, "paths"),
+                exclude: strings(message, "exclude"),
+            },
+            Some("run") => Request::Run {
+                script: string(message, "script"),
+            },
+            Some("exec") => Request::Exec(Exec {
+                profile: message
+                    .get("profile")
+                    .and_then(Value::as_str)
+                    .map(String::from),
+                cwd: string(message, "cwd"),
+                env: pairs(message, "env"),
+                path_env: pairs(message, "path_env"),
+                setup: message
+                    .get("setup")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .map(string_array)
+                    .collect(),
+                command: strings(message, "command"),
+            }),
+            Some("lock") => Request::Lock {
+                file: string(message, "file"),
+                wait: message.get("wait").and_then(Value::as_bool) == Some(true),
+            },
+            Some("unlock") => Request::Unlock {
+                file: string(message, "file"),
+            },
+            other => return Err(format!("Unknown request '{}'", other.unwrap_or_default())),
+        })
+    }
+
+    pub fn write(&self, to: &mut impl Write) -> io::Result<()> {
+        write_message(to, &self.to_json())
+    }
+
+    /// Reads the next request. The outer error means the connection is broken, the inner one
+    /// that the request isn't known, the agent answers it with an error and goes on.
+    pub fn read(from: &mut impl Read) -> io::Result<Result<Request, String>> {
+        Ok(Request::from_json(&read_message(from)?))
+    }
+}
+
+impl Response {
+    fn to_json(&self) -> Value {
+        match self {
+            Response::Need(paths) => json!({"type": "need", "paths": paths}),
+            Response::File(file) => file.to_json(),
+            Response::Done => json!({"type": "done"}),
+            Response::Busy => json!({"type": "busy"}),
+            Response::Locked => json!({"type": "locked"}),
+            Response::Exit(code) => json!({"type": "exit", "code": code}),
+            Response::Error(message) => json!({"type": "error", "message": message}),
+        }
+    }
+
+    fn from_json(message: &Value) -> Result<Response, String> {
+        Ok(match message.get("type").and_then(Value::as_str) {
+            Some("need") => Response::Need(strings(message, "paths")),
+            Some("file") => Response::File(FileInfo::from_json(message)),
+            Some("done") => Response::Done,
+            Some("busy") => Response::Busy,
+            Some("locked") => Response::Locked,
+            Some("exit") => {
+                Response::Exit(message.get("code").and_then(Value::as_i64).unwrap_or(1) as i32)
+            }
+            Some("error") => Response::Error(string(message, "message")),
+            other => return Err(format!("Unknown answer '{}'", other.unwrap_or_default())),
+        })
+    }
+
+    pub fn write(&self, to: &mut impl Write) -> io::Result<()> {
+        write_message(to, &self.to_json())
+    }
+
+    pub fn read(from: &mut impl Read) -> io::Result<Response> {
+        Response::from_json(&read_message(from)?).map_err(invalid)
+    }
+
+    /// Parses the payload of a message frame.
+    pub fn parse(payload: &[u8]) -> io::Result<Response> {
+        let message = serde_json::from_slice(payload).map_err(invalid)?;
+        Response::from_json(&message).map_err(invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, len: u64) -> FileInfo {
+        FileInfo {
+            dir: "~/remote-builds/demo/".to_string(),
+            path: path.to_string(),
+            len,
+            mtime: 1_700_000_000,
+            mtime_ns: 123_456_789,
+            mode: 0o755,
+        }
+    }
+
+    fn requests() -> Vec<Request> {
+        vec![
+            Request::Sync {
+                dir: "~/remote-builds/demo/".to_string(),
+                files: vec![file("src/main.rs", 42), file("dir with spaces/ä.rs", 0)],
+                keep: vec!["target".to_string(), ".cargo-remote-project".to_string()],
+                keep_hidden: true,
+            },
+            Request::File(file("Cargo.toml", 7)),
+            Request::Synced,
+            Request::Fetch {
+                dir: "~/remote-builds/demo/target".to_string(),
+                paths: vec!["debug/demo".to_string()],
+                exclude: vec!["*.d".to_string()],
+            },
+            Request::Run {
+                script: "echo 'a b' \"$HOME\"\n".to_string(),
+            },
+            Request::Exec(Exec {
+                profile: Some("~/.profile".to_string()),
+                cwd: "~/remote-builds/demo/".to_string(),
+                env: vec![("RUSTFLAGS".to_string(), "-C opt-level=3".to_string())],
+                path_env: vec![("CARGO_TARGET_DIR".to_string(), "~/target".to_string())],
+                setup: vec![vec!["rustup".to_string(), "default".to_string()]],
+                command: vec!["cargo".to_string(), "build".to_string()],
+            }),
+            Request::Exec(Exec::default()),
+            Request::Lock {
+                file: "~/remote-builds/demo.lock".to_string(),
+                wait: true,
+            },
+            Request::Unlock {
+                file: "~/remote-builds/demo.lock".to_string(),
+            },
+        ]
+    }
+
+    fn responses() -> Vec<Response> {
+        vec![
+            Response::Need(vec!["src/main.rs".to_string()]),
+            Response::Need(Vec::new()),
+            Response::File(file("debug/demo", 1 << 20)),
+            Response::Done,
+            Response::Busy,
+            Response::Locked,
+            Response::Exit(0),
+            Response::Exit(-5),
+            Response::Error("no such file".to_string()),
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            let mut wire = Vec::new();
+            request.write(&mut wire).unwrap();
+            let read = Request::read(&mut wire.as_slice()).unwrap().unwrap();
+            assert_eq!(read, request);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in responses() {
+            let mut wire = Vec::new();
+            response.write(&mut wire).unwrap();
+            assert_eq!(Response::read(&mut wire.as_slice()).unwrap(), response);
+            assert_eq!(Response::parse(&wire[5..]).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn frames_of_every_kind_round_trip() {
+        let mut wire = Vec::new();
+        for kind in [MESSAGE, STDOUT, STDERR, DATA, BYE].iter() {
+            write_frame(&mut wire, *kind, &[*kind; 3]).unwrap();
+        }
+        write_frame(&mut wire, STDOUT, &[]).unwrap();
+        let mut from = wire.as_slice();
+        for kind in [MESSAGE, STDOUT, STDERR, DATA, BYE].iter() {
+            assert_eq!(read_frame(&mut from).unwrap(), (*kind, vec![*kind; 3]));
+        }
+        assert_eq!(read_frame(&mut from).unwrap(), (STDOUT, Vec::new()));
+        let end = read_frame(&mut from).unwrap_err();
+        assert_eq!(end.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn unknown_requests_keep_the_connection() {
+        let mut wire = Vec::new();
+        write_message(&mut wire, &json!({"type": "reboot"})).unwrap();
+        assert!(Request::read(&mut wire.as_slice()).unwrap().is_err());
+        let mut wire = Vec::new();
+        write_message(&mut wire, &json!({"type": "reboot"})).unwrap();
+        assert!(Response::read(&mut wire.as_slice()).is_err());
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut wire = Vec::new();
+        Request::Synced.write(&mut wire).unwrap();
+        for len in 0..wire.len() {
+            let error = read_frame(&mut &wire[..len]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "{} bytes", len);
+            assert!(Request::read(&mut &wire[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected_before_reading_them() {
+        let mut header = vec![MESSAGE];
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+        let error = read_frame(&mut header.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut header = vec![DATA];
+        header.extend_from_slice(&(MAX_FRAME as u32 + 1).to_be_bytes());
+        let error = read_frame(&mut header.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let error = write_frame(&mut Vec::new(), DATA, &vec![0; MAX_FRAME + 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn garbage_messages_are_errors() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, MESSAGE, b"{not json").unwrap();
+        assert!(Request::read(&mut wire.as_slice()).is_err());
+        let mut wire = Vec::new();
+        write_frame(&mut wire, STDOUT, b"{}").unwrap();
+        assert!(Response::read(&mut wire.as_slice()).is_err());
+    }
+
+    #[test]
+    fn data_round_trips_in_chunks() {
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut wire = Vec::new();
+        write_data(&mut wire, content.as_slice(), content.len() as u64).unwrap();
+        let mut from = wire.as_slice();
+        let mut read = Vec::new();
+        read_data(&mut from, &mut read, content.len() as u64).unwrap();
+        assert_eq!(read, content);
+        assert!(from.is_empty());
+    }
+
+    #[test]
+    fn data_of_a_shrunk_file_is_padded() {
+        let mut wire = Vec::new();
+        write_data(&mut wire, &b"abc"[..], 5).unwrap();
+        let mut read = Vec::new();
+        read_data(&mut wire.as_slice(), &mut read, 5).unwrap();
+        assert_eq!(read, b"abc\0\0");
+    }
+
+    #[test]
+    fn data_longer_or_shorter_than_announced_is_an_error() {
+        let mut wire = Vec::new();
+        write_data(&mut wire, &b"abcdef"[..], 6).unwrap();
+        assert!(read_data(&mut wire.as_slice(), &mut Vec::new(), 4).is_err());
+        let error = read_data(&mut wire.as_slice(), &mut Vec::new(), 8).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut wire = Vec::new();
+        Response::Done.write(&mut wire).unwrap();
+        assert!(read_data(&mut wire.as_slice(), &mut Vec::new(), 1).is_err());
+    }
+}
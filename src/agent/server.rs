@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File, TryLockError};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::protocol::{self, Exec, FileInfo, Request, Response};
+
+/// stdout carries the frames, the threads forwarding the output of a command write to it too.
+type Output = Arc<Mutex<io::Stdout>>;
+
+/// The command that currently runs, killed when the connection is closed.
+type Running = Arc<Mutex<Option<Child>>>;
+
+/// The environment a shell profile sets up, loaded once per modification of the profile.
+type Profiles = HashMap<PathBuf, (Option<SystemTime>, Vec<(OsString, OsString)>)>;
+
+fn send(output: &Output, response: &Response) -> io::Result<()> {
+    response.write(&mut *output.lock().unwrap())
+}
+
+/// [`path`] as sent by cargo-remote, `~/` is the home directory like in the shell.
+fn resolve(path: &str) -> PathBuf {
+    let home = || PathBuf::from(std::env::var_os("HOME").unwrap_or_default());
+    match path.strip_prefix("~/") {
+        Some(rest) => home().join(rest),
+        None if path == "~" => home(),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Modification time as seconds and nanoseconds since the epoch.
+fn mtime(metadata: &fs::Metadata) -> (u64, u32) {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |time| (time.as_secs(), time.subsec_nanos()))
+}
+
+/// The Unix permissions, 0 elsewhere.
+fn mode(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        0
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(file: &File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        0 => Ok(()),
+        mode => file.set_permissions(fs::Permissions::from_mode(mode)),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &File, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// The file [`path`] below [`dir`] with [`metadata`].
+pub fn file_info(dir: &str, path: &str, metadata: &fs::Metadata) -> FileInfo {
+    let (mtime, mtime_ns) = mtime(metadata);
+    FileInfo {
+        dir: dir.to_string(),
+        path: path.to_string(),
+        len: metadata.len(),
+        mtime,
+        mtime_ns,
+        mode: mode(metadata),
+    }
+}
+
+/// Answers a sync request with the files that are missing or changed in [`dir`] and removes
+/// the files that aren't part of the sources anymore, like the tar transport except for the
+/// top level names to keep.
+fn sync(dir: &str, files: &[FileInfo], keep: &[String], keep_hidden: bool) -> Response {
+    let dir = resolve(dir);
+    if let Err(e) = fs::create_dir_all(&dir) {
+        return Response::Error(format!("Can't create {}: {}", dir.display(), e));
+    }
+    let mut listed = HashSet::new();
+    let mut need = Vec::new();
+    for file in files {
+        let fresh = fs::symlink_metadata(dir.join(&file.path))
+            .ok()
+            .is_some_and(|metadata| {
+                metadata.is_file()
+                    && metadata.len() == file.len
+                    && mtime(&metadata) == (file.mtime, file.mtime_ns)
+            });
+        if !fresh {
+            need.push(file.path.clone());
+        }
+        listed.insert(PathBuf::from(&file.path));
+    }
+    let kept =
+        |name: &str| keep.iter().any(|k| k == name) || (keep_hidden && name.starts_with('.'));
+    remove_stale(&dir, Path::new(""), &listed, &kept);
+    Response::Need(need)
+}
+
+fn remove_stale(root: &Path, dir: &Path, listed: &HashSet<PathBuf>, kept: &dyn Fn(&str) -> bool) {
+    for entry in fs::read_dir(root.join(dir)).into_iter().flatten().flatten() {
+        let name = entry.file_name();
+        if dir.as_os_str().is_empty() && kept(&name.to_string_lossy()) {
+            continue;
+        }
+        let path = dir.join(&name);
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => remove_stale(root, &path, listed, kept),
+            Ok(_) if !listed.contains(&path) => {
+                let _ = fs::remove_file(root.join(&path));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Writes [`file`] below [`root`] with the content from the following data frames. The outer
+/// error means the connection is broken, the inner one that the file couldn't be written.
+pub fn receive_file(
+    input: &mut impl Read,
+    root: &Path,
+    file: &FileInfo,
+) -> io::Result<Result<(), String>> {
+    let path = root.join(&file.path);
+    let create = || -> io::Result<File> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // a new file instead of writing into a hard link or a running binary
+        let _ = fs::remove_file(&path);
+        File::create(&path)
+    };
+    let mut written = match create() {
+        Ok(written) => BufWriter::new(written),
+        Err(e) => {
+            protocol::read_data(input, &mut io::sink(), file.len)?;
+            return Ok(Err(format!("Can't write {}: {}", path.display(), e)));
+        }
+    };
+    protocol::read_data(input, &mut written, file.len)?;
+    let finish = || -> io::Result<()> {
+        let written = written.into_inner().map_err(|e| e.into_error())?;
+        written.set_modified(UNIX_EPOCH + Duration::new(file.mtime, file.mtime_ns))?;
+        set_mode(&written, file.mode)
+    };
+    Ok(finish().map_err(|e| format!("Can't write {}: {}", path.display(), e)))
+}
+
+/// Sends the files below [`paths`] in [`dir`], leaving out names that match an [`exclude`]
+/// pattern.
+fn fetch(
+    output: &mut impl Write,
+    dir: &str,
+    paths: &[String],
+    exclude: &[String],
+) -> io::Result<()> {
+    let root = resolve(dir);
+    let exclude: Vec<&str> = exclude
+        .iter()
+        .map(|pattern| pattern.trim_matches('/'))
+        .collect();
+    let excluded = |path: &Path| {
+        path.iter().any(|name| {
+            exclude
+                .iter()
+                .any(|pattern| name.to_string_lossy() == *pattern)
+        })
+    };
+    let mut stack: Vec<PathBuf> = paths
+        .iter()
+        .map(|path| PathBuf::from(path.trim_end_matches('/')))
+        .collect();
+    while let Some(path) = stack.pop() {
+        let metadata = match fs::metadata(root.join(&path)) {
+            Ok(metadata) if !excluded(&path) => metadata,
+            _ => continue,
+        };
+        if metadata.is_dir() {
+            for entry in fs::read_dir(root.join(&path))
+                .into_iter()
+                .flatten()
+                .flatten()
+            {
+                stack.push(path.join(entry.file_name()));
+            }
+            continue;
+        }
+        let file = match File::open(root.join(&path)) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let relative = crate::util::slash_path(&path);
+        Response::File(file_info(dir, &relative, &metadata)).write(output)?;
+        protocol::write_data(output, file, metadata.len())?;
+    }
+    Ok(())
+}
+
+/// The environment after sourcing [`profile`], from [`profiles`] if it didn't change since it
+/// was loaded. The path is passed to the shell as an argument, it isn't part of the script.
+fn profile_env(profiles: &mut Profiles, profile: &str) -> Vec<(OsString, OsString)> {
+    let path = resolve(profile);
+    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+    if let Some((loaded, env)) = profiles.get(&path) {
+        if *loaded == modified {
+            return env.clone();
+        }
+    }
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+    let output = Command::new(shell)
+        .arg("-c")
+        .arg(". \"$1\" >/dev/null 2>&1; exec env -0")
+        .arg("sh")
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    let env: Vec<(OsString, OsString)> = match output {
+        Ok(output) if output.status.success() => output
+            .stdout
+            .split(|byte| *byte == 0)
+            .filter_map(|entry| {
+                let split = entry.iter().position(|byte| *byte == b'=')?;
+                let name = crate::util::path_from_bytes(&entry[..split]);
+                let value = crate::util::path_from_bytes(&entry[split + 1..]);
+                Some((name.into_os_string(), value.into_os_string()))
+            })
+            .collect(),
+        // the environment of the agent itself then
+        _ => std::env::vars_os().collect(),
+    };
+    profiles.insert(path, (modified, env.clone()));
+    env
+}
+
+/// The commands of [`exec`] in the order they run, each with whether it is a setup command.
+fn exec_commands(exec: &Exec, profiles: &mut Profiles) -> Vec<(Command, bool)> {
+    let env = exec
+        .profile
+        .as_ref()
+        .map(|profile| profile_env(profiles, profile));
+    let steps = exec.setup.iter().map(|step| (step, true));
+    steps
+        .chain(std::iter::once((&exec.command, false)))
+        .filter_map(|(argv, setup)| {
+            let (program, args) = argv.split_first()?;
+            let mut command = Command::new(program);
+            command.args(args).current_dir(resolve(&exec.cwd));
+            if let Some(env) = &env {
+                command.env_clear().envs(env.iter().cloned());
+            }
+            if !setup {
+                command.envs(exec.env.iter().cloned());
+                for (name, value) in &exec.path_env {
+                    command.env(name, resolve(value));
+                }
+            }
+            Some((command, setup))
+        })
+        .collect()
+}
+
+/// The shell command of a script request, run in the home directory like ssh runs it.
+fn script_command(script: &str) -> Command {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+    let mut command = Command::new(shell);
+    command.arg("-c").arg(script).current_dir(resolve("~"));
+    command
+}
+
+/// Runs [`command`] and forwards its stdout and stderr as frames, its stdout as stderr if it
+/// is a [`setup`] command.
+fn run_one(
+    output: &Output,
+    running: &Running,
+    mut command: Command,
+    setup: bool,
+) -> io::Result<ExitStatus> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // a process group of its own, so that cargo and everything it started can be killed
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command.spawn()?;
+    let forward = |from: Option<Box<dyn Read + Send>>, kind: u8| {
+        let output = output.clone();
+        thread::spawn(move || {
+            let mut from = match from {
+                Some(from) => from,
+                None => return,
+            };
+            let mut buffer = [0; 8192];
+            loop {
+                match from.read(&mut buffer) {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        let mut output = output.lock().unwrap();
+                        if protocol::write_frame(&mut *output, kind, &buffer[..n]).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => return,
+                }
+            }
+        })
+    };
+    let stdout_kind = match setup {
+        true => protocol::STDERR,
+        false => protocol::STDOUT,
+    };
+    let stdout = forward(child.stdout.take().map(|s| Box::new(s) as _), stdout_kind);
+    let stderr = forward(
+        child.stderr.take().map(|s| Box::new(s) as _),
+        protocol::STDERR,
+    );
+    *running.lock().unwrap() = Some(child);
+    let _ = stdout.join();
+    let _ = stderr.join();
+    loop {
+        let mut guard = running.lock().unwrap();
+        let status = match guard.as_mut().map(Child::try_wait) {
+            Some(Ok(None)) => None,
+            Some(Ok(Some(status))) => Some(Ok(status)),
+            Some(Err(e)) => Some(Err(e)),
+            None => Some(Err(io::Error::other("it was killed"))),
+        };
+        if let Some(status) = status {
+            *guard = None;
+            return status;
+        }
+        drop(guard);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Runs [`commands`] one after the other in the background until one fails and sends the exit
+/// code of the last one that ran.
+fn run(output: &Output, running: &Running, commands: Vec<(Command, bool)>) {
+    let output = output.clone();
+    let running = running.clone();
+    thread::spawn(move || {
+        let mut code = 0;
+        for (command, setup) in commands {
+            let program = command.get_program().to_string_lossy().to_string();
+            match run_one(&output, &running, command, setup) {
+                Ok(status) => code = crate::build::exit_code(status),
+                Err(e) => {
+                    let error = Response::Error(format!("Can't run {}: {}", program, e));
+                    let _ = send(&output, &error);
+                    return;
+                }
+            }
+            if code != 0 {
+                break;
+            }
+        }
+        let _ = send(&output, &Response::Exit(code));
+    });
+}
+
+/// Kills [`child`] and its process group.
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    let _ = Command::new("kill")
+        .arg("-KILL")
+        .arg("--")
+        .arg(format!("-{}", child.id()))
+        .stderr(Stdio::null())
+        .status();
+    let _ = child.kill();
+}
+
+/// Locks [`name`], waiting for it if asked to. The lock is held until it is unlocked or until
+/// the connection is closed.
+fn lock(
+    output: &Output,
+    locks: &mut BTreeMap<String, File>,
+    name: &str,
+    wait: bool,
+) -> io::Result<Response> {
+    let path = resolve(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) if wait => {
+            send(output, &Response::Busy)?;
+            file.lock()?;
+        }
+        Err(TryLockError::WouldBlock) => return Ok(Response::Busy),
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    locks.insert(name.to_string(), file);
+    Ok(Response::Locked)
+}
+
+/// `cargo-remote remote-agent serve`: answers the requests of cargo-remote on stdin until it is
+/// closed. A command that is still running then is killed.
+pub fn serve() -> io::Result<()> {
+    let mut input = BufReader::new(io::stdin());
+    let output: Output = Arc::new(Mutex::new(io::stdout()));
+    let running: Running = Default::default();
+    let mut locks = BTreeMap::new();
+    let mut profiles = Profiles::new();
+    // the first file of a sync that couldn't be written, reported at its end
+    let mut failed = None;
+    let result = loop {
+        let request = match Request::read(&mut input) {
+            Ok(Ok(request)) => request,
+            Ok(Err(unknown)) => match send(&output, &Response::Error(unknown)) {
+                Ok(()) => continue,
+                Err(e) => break Err(e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let response = match request {
+            Request::Sync {
+                dir,
+                files,
+                keep,
+                keep_hidden,
+            } => Some(sync(&dir, &files, &keep, keep_hidden)),
+            Request::File(file) => match receive_file(&mut input, &resolve(&file.dir), &file) {
+                Ok(written) => {
+                    failed = failed.or(written.err());
+                    None
+                }
+                Err(e) => break Err(e),
+            },
+            Request::Synced => Some(match failed.take() {
+                Some(e) => Response::Error(e),
+                None => Response::Done,
+            }),
+            Request::Fetch {
+                dir,
+                paths,
+                exclude,
+            } => {
+                let mut output = output.lock().unwrap();
+                match fetch(&mut *output, &dir, &paths, &exclude) {
+                    Ok(()) => Some(Response::Done),
+                    Err(e) => break Err(e),
+                }
+            }
+            Request::Run { script } => {
+                run(&output, &running, vec![(script_command(&script), false)]);
+                None
+            }
+            Request::Exec(exec) => {
+                run(&output, &running, exec_commands(&exec, &mut profiles));
+                None
+            }
+            Request::Lock { file, wait } => Some(
+                lock(&output, &mut locks, &file, wait)
+                    .unwrap_or_else(|e| Response::Error(e.to_string())),
+            ),
+            Request::Unlock { file } => {
+                locks.remove(&file);
+                Some(Response::Done)
+            }
+        };
+        if let Some(response) = response {
+            if let Err(e) = send(&output, &response) {
+                break Err(e);
+            }
+        }
+    };
+    if let Some(child) = running.lock().unwrap().as_mut() {
+        kill(child);
+    }
+    result
+}
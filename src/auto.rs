@@ -0,0 +1,83 @@
+use std::time::UNIX_EPOCH;
+
+use log::{debug, info};
+
+use crate::build::RemoteBuild;
+use crate::history;
+use crate::transport::source_files;
+
+/// Changes up to this size make a tiny incremental build.
+const TINY_CHANGE_BYTES: u64 = 64 * 1024;
+
+/// Bandwidth assumed until a source sync measured it, in bytes per second.
+const DEFAULT_BANDWIDTH: f64 = 1024.0 * 1024.0;
+
+/// Commands a tiny change is checked locally with before there are local timings to compare.
+const CHECK_COMMANDS: [&str; 2] = ["check", "clippy"];
+
+/// Total size of the source files modified after [`since`], all of them without a [`since`].
+fn changed_bytes(build: &RemoteBuild, since: Option<u64>) -> u64 {
+    source_files(build, &build.project_dir)
+        .into_iter()
+        .filter(|(_, metadata)| {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|time| time.as_secs());
+            match (since, modified) {
+                (Some(since), Some(modified)) => modified >= since,
+                _ => true,
+            }
+        })
+        .map(|(_, metadata)| metadata.len())
+        .sum()
+}
+
+/// Whether `--auto` runs [`build`] on the local machine: estimates how long the command takes
+/// locally and remotely from the build history, the size of the changes and the bandwidth the
+/// previous source syncs measured. Without local timings only tiny incremental checks run
+/// locally, a full rebuild is always offloaded.
+pub fn run_locally(build: &RemoteBuild) -> bool {
+    let runs = history::runs(&build.project_dir, &build.command);
+    let last = match runs.last {
+        Some(last) => last,
+        None => {
+            info!("--auto: no previous build of the project, building remotely");
+            return false;
+        }
+    };
+    let lock_changed = std::fs::metadata(build.project_dir.join("Cargo.lock"))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .is_some_and(|time| time.as_secs() >= last);
+    if lock_changed {
+        info!("--auto: the dependencies changed, building remotely");
+        return false;
+    }
+
+    let changed = changed_bytes(build, Some(last));
+    let transfer = changed_bytes(build, runs.last_remote);
+    let bandwidth = runs.bandwidth.unwrap_or(DEFAULT_BANDWIDTH);
+    let remote_ms = runs.remote_ms.map(|build_ms| {
+        build_ms + runs.sync_ms.unwrap_or(0) + (transfer as f64 * 1000.0 / bandwidth) as u64
+    });
+    debug!(
+        "--auto: {} bytes changed, {} bytes to transfer at {:.0} bytes/s",
+        changed, transfer, bandwidth
+    );
+    let local = match (runs.local_ms, remote_ms) {
+        (Some(local_ms), Some(remote_ms)) => local_ms < remote_ms,
+        (None, _) => changed <= TINY_CHANGE_BYTES && CHECK_COMMANDS.contains(&&*build.command),
+        (Some(_), None) => false,
+    };
+    let estimate = |ms: Option<u64>| ms.map_or("unknown".to_string(), history::format_duration);
+    info!(
+        "--auto: building {} (estimated {} locally, {} remotely)",
+        if local { "locally" } else { "remotely" },
+        estimate(runs.local_ms),
+        estimate(remote_ms)
+    );
+    local
+}
@@ -0,0 +1,1381 @@
+use std::io::{BufRead, BufReader, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use crate::agent::Exec;
+use crate::clippy::LintSummary;
+use crate::container::Container;
+use crate::coverage::{self, Coverage};
+use crate::debug::{self, Debug};
+use crate::debuginfo;
+use crate::error::Error;
+use crate::github::{self, Group};
+use crate::history;
+use crate::lock::{LockMode, RemoteLock};
+use crate::logging::Run;
+use crate::nix::Nix;
+use crate::notify;
+use crate::patches::Patches;
+use crate::platform::{self, RemoteOs};
+use crate::remap::Remap;
+use crate::session::Session;
+use crate::ssh::Ssh;
+use crate::test_binaries::{self, TestBinaries};
+use crate::timings::{self, Phase, Timings};
+use crate::transport::{Filters, Transport};
+use crate::upload::{self, Upload};
+use crate::util::{shell_arg, shell_path, shell_quote};
+use crate::vendor;
+
+/// File in every remote build directory that holds the path of the local project. Its
+/// modification time is the time of the last build.
+pub const PROJECT_MARKER: &str = ".cargo-remote-project";
+
+/// A local file or directory and where it is in the build directory, transferred there with
+/// `--transfer-extra` or from there with `--retrieve`.
+pub struct Extra {
+    pub local: PathBuf,
+    /// Relative to the build directory, with `/` separators. Directories to retrieve end with
+    /// a `/`.
+    pub remote: String,
+}
+
+/// A cargo command to run on the build server together with everything that's needed to get
+/// the project there and the results back, resolved from the command line and config files.
+pub struct RemoteBuild {
+    pub project_dir: PathBuf,
+    pub project_name: String,
+    pub build_server: String,
+    pub remote_base_dir: String,
+    /// Project directory on the build server, always ends with a `/`.
+    pub build_path: String,
+    /// Workspaces outside of the project that it patches in, transferred along with it.
+    pub patches: Patches,
+    pub ssh: Ssh,
+    pub build_env: String,
+    /// Variables exported in the remote shell before cargo runs.
+    pub env_vars: Vec<(String, String)>,
+    /// Print the sccache statistics of the build afterwards, `RUSTC_WRAPPER` is already part of
+    /// [`RemoteBuild::env_vars`].
+    pub sccache: bool,
+    pub rustup_default: String,
+    /// Toolchain passed to the remote cargo as `+<toolchain>`. The rustup default is left alone
+    /// if one is given.
+    pub toolchain: Option<String>,
+    /// Install a missing toolchain or target on the build server instead of failing.
+    pub auto_install_toolchain: bool,
+    pub env: String,
+    pub hidden: bool,
+    /// Hidden files and directories that are transferred anyway, names or paths relative to
+    /// the project directory.
+    pub hidden_allow: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Patterns that are transferred even if they are excluded otherwise.
+    pub include: Vec<String>,
+    /// Files and directories from outside the project that are transferred into the build
+    /// directory after the sources.
+    pub transfer_extra: Vec<Extra>,
+    /// Leave out the files ignored by the project's `.gitignore` files.
+    pub gitignore: bool,
+    /// Bandwidth limit for transfers in KiB/s.
+    pub bwlimit: Option<u32>,
+    pub fallback_local: bool,
+    /// Decide per run whether building locally is faster, see [`crate::auto`].
+    pub auto: bool,
+    /// Whether ssh connections are shared. With [`RemoteBuild::auto`] the connection master is
+    /// only opened once the build goes to the build server.
+    pub multiplex: bool,
+    /// Show a desktop notification when the run finished.
+    pub notify: bool,
+    /// URL the result of the run is posted to as JSON.
+    pub webhook: Option<String>,
+    /// How often failed transfers and builds that lost the connection are retried.
+    pub retries: u32,
+    /// Run cargo in a session on the build server that survives lost connections, see
+    /// [`Session`].
+    pub resilient: bool,
+    /// Whether to wait for or fail on other runs using the same build directory.
+    pub lock: LockMode,
+    /// Durations of the phases of the run and bytes transferred.
+    pub timings: Timings,
+    /// Print [`RemoteBuild::timings`] at the end of the run, set with `--timings`.
+    pub show_timings: Option<timings::Format>,
+    pub forward_ports: Vec<String>,
+    pub tty: bool,
+    pub no_tty: bool,
+    /// Target triples passed to cargo with `--target`, cargo builds all of them in one go.
+    pub target: Vec<String>,
+    pub copy_back: Option<Option<String>>,
+    pub copy_back_include: Vec<String>,
+    /// Copy back only the binaries and libraries cargo reported as built.
+    pub copy_back_artifacts: bool,
+    /// Identity the binaries are signed with on a macOS build server before they are copied
+    /// back, `-` for ad hoc signing.
+    pub codesign: Option<String>,
+    /// Paths relative to the remote target directory of the artifacts reported by the last
+    /// build.
+    pub artifacts: Vec<String>,
+    /// Absolute path of the remote target directory as reported by the remote `cargo metadata`.
+    pub remote_target_dir: Option<String>,
+    /// Target directory on the build server set with `--remote-target-dir`, passed to cargo as
+    /// `CARGO_TARGET_DIR`. Relative paths are relative to the build directory.
+    pub cargo_target_dir: Option<String>,
+    /// [`RemoteBuild::cargo_target_dir`] is shared with other projects, see `--shared-target`.
+    pub shared_target: bool,
+    /// Seed new target directories from the dependency cache on the build server, see
+    /// [`crate::dep_cache`].
+    pub dep_cache: bool,
+    /// Build against the dependencies vendored locally, for build servers without access to
+    /// crates.io, see [`crate::vendor`].
+    pub vendor: bool,
+    /// Passed to the remote cargo to use the vendored dependencies, set once they were
+    /// transferred.
+    pub vendor_args: Vec<String>,
+    /// The local target directory, where copied back files are placed. Not necessarily inside
+    /// the project, e.g. if `CARGO_TARGET_DIR` is set locally.
+    pub local_target_dir: PathBuf,
+    pub copy_back_exclude: Vec<String>,
+    /// Further outputs of the build that are copied back, see `--retrieve`.
+    pub retrieve: Vec<Extra>,
+    pub upload: Option<Upload>,
+    pub no_copy_lock: bool,
+    /// Apply what `cargo fix` changed on the build server to the local files without asking.
+    pub apply_fixes: bool,
+    pub bench_output_dirs: Vec<String>,
+    /// Shell snippets run in the build directory before and after (a successful) cargo.
+    pub pre_build: Option<String>,
+    pub post_build: Option<String>,
+    /// Passed to cargo in front of [`RemoteBuild::options`].
+    pub cargo_flags: Vec<String>,
+    pub command: String,
+    pub options: Vec<String>,
+    /// Set for `cargo doc --open`, the docs are opened locally after they were copied back.
+    pub open_docs: bool,
+    /// Set when several builds run at the same time. Their output is prefixed with it instead
+    /// of being passed through, and they don't read from stdin.
+    pub output_prefix: Option<String>,
+    /// Point the paths on the build server in the output to the local files, see [`Remap`].
+    pub remap_paths: bool,
+    /// `--output-format github`: the phases of the run are groups in the log of GitHub
+    /// Actions and the diagnostics become annotations, see [`crate::github`].
+    pub github: bool,
+    /// Set by [`RemoteBuild::run_cargo`] when it asked cargo for JSON messages to turn their
+    /// diagnostics into annotations.
+    pub annotations: bool,
+    /// Set by `cargo remote clippy`: the diagnostics are rendered from cargo's JSON messages and
+    /// counted for a summary at the end, see [`crate::clippy`].
+    pub lints: Option<LintSummary>,
+    /// Set by `cargo remote test-binaries`, only test executables are copied back.
+    pub test_binaries: Option<TestBinaries>,
+    /// Set by `cargo remote debug`.
+    pub debug: Option<Debug>,
+    /// Set by `cargo remote coverage`, the report is retrieved and its paths rewritten.
+    pub coverage: Option<Coverage>,
+    /// Run the remote cargo in this container instead of directly on the build server.
+    pub container: Option<Container>,
+    /// Run the remote cargo in this nix environment instead of with rustup's toolchain.
+    pub nix: Option<Nix>,
+    /// How sources get to the build server and results back.
+    pub transport: Box<dyn Transport>,
+    /// Whether the build server runs a POSIX shell or PowerShell commands.
+    pub remote_os: RemoteOs,
+}
+
+/// The targets given with `--target` in [`cargo_args`], up to a `--`.
+pub fn targets(cargo_args: &[String]) -> Vec<String> {
+    let mut targets = Vec::new();
+    let mut args = cargo_args.iter().take_while(|a| *a != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--target" {
+            targets.extend(args.next().cloned());
+        } else if let Some(target) = arg.strip_prefix("--target=") {
+            targets.push(target.to_string());
+        }
+    }
+    targets
+}
+
+impl RemoteBuild {
+    /// `cargo`, or `cargo +<toolchain>` if a toolchain was selected.
+    pub fn cargo_binary(&self) -> String {
+        match &self.toolchain {
+            Some(toolchain) => format!("cargo {}", shell_arg(&format!("+{}", toolchain))),
+            None => "cargo".to_string(),
+        }
+    }
+
+    /// The targets given with `--target` in the cargo flags and options.
+    pub fn targets(&self) -> Vec<String> {
+        targets(&self.cargo_args())
+    }
+
+    /// Checks the build server's cached or freshly probed capabilities against the build, e.g.
+    /// that the `--target`s are installed. Windows build servers aren't probed.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.remote_os {
+            RemoteOs::Windows => Ok(()),
+            _ => crate::capabilities::validate(self),
+        }
+    }
+
+    /// Whether the cargo options ask for JSON messages, e.g. `--message-format=json` for an IDE.
+    /// stdout then carries nothing but the remote cargo's output.
+    pub fn json_output(&self) -> bool {
+        let cargo_args = self.cargo_args();
+        let mut args = cargo_args.iter().take_while(|a| *a != "--");
+        while let Some(arg) = args.next() {
+            let format = match arg.strip_prefix("--message-format=") {
+                Some(format) => Some(format),
+                None if arg == "--message-format" => args.next().map(String::as_str),
+                None => None,
+            };
+            if format.is_some_and(|format| format.starts_with("json")) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Applies the special handling of some cargo commands. Has to be called before running the
+    /// command and whenever [`RemoteBuild::command`] changed.
+    pub fn prepare_command(&mut self) {
+        // `cargo doc --open` would try to open a browser on the build server. Only the docs are
+        // copied back and the local copy is opened instead.
+        self.open_docs = self.command == "doc" && self.options.iter().any(|o| o == "--open");
+        if self.command == "doc" {
+            self.options.retain(|o| o != "--open");
+            if self.copy_back.is_none() {
+                self.copy_back = Some(Some("doc/".to_string()));
+            }
+        }
+        if self.bench_output_dirs.is_empty() {
+            self.bench_output_dirs.push("criterion".to_string());
+        }
+        if crate::fix::is_fix(&self.command, &self.options) {
+            crate::fix::allow_unclean(&mut self.options);
+        }
+        // `cargo fetch` only fills the registry cache on the build server, there is nothing to
+        // copy back but an updated Cargo.lock
+        if self.command == "fetch" {
+            self.copy_back = None;
+            self.copy_back_artifacts = false;
+            self.codesign = None;
+            self.dep_cache = false;
+        }
+    }
+
+    /// Everything that follows the cargo command: the configured flags and the remote options.
+    pub fn cargo_args(&self) -> Vec<String> {
+        let target = self
+            .target
+            .iter()
+            .flat_map(|target| vec!["--target".to_string(), target.clone()]);
+        self.cargo_flags
+            .iter()
+            .cloned()
+            .chain(target)
+            .chain(self.options.iter().cloned())
+            .collect()
+    }
+
+    /// [`path`] inside the target directories of the selected targets, e.g. `release/my-bin`
+    /// becomes `aarch64-unknown-linux-gnu/release/my-bin` when building for that target.
+    fn target_paths(&self, path: &str) -> Vec<String> {
+        if self.target.is_empty()
+            || path.is_empty()
+            || self.target.iter().any(|t| path.starts_with(t.as_str()))
+        {
+            return vec![path.to_string()];
+        }
+        self.target
+            .iter()
+            .map(|target| format!("{}/{}", target, path))
+            .collect()
+    }
+
+    /// Syncs the project sources, the workspaces it patches in and the vendored dependencies to
+    /// the build server, leaving out the target directory, excluded files and (unless enabled)
+    /// hidden files.
+    pub fn transfer_sources(&mut self) -> Result<(), Error> {
+        debug!("Transferring sources to build server.");
+        self.vendor_args = self
+            .retry("transfer the project", || {
+                self.timings.record(Phase::Sources, || {
+                    self.transport
+                        .upload(self, &self.project_dir, &self.build_path)
+                })?;
+                if !self.transfer_extra.is_empty() {
+                    self.timings
+                        .record(Phase::Sources, || self.transfer_extra())?;
+                }
+                if !self.patches.is_empty() {
+                    self.timings.record(Phase::Patches, || {
+                        self.patches.upload(self)?;
+                        self.patches.write_manifests(self)
+                    })?;
+                }
+                match self.vendor {
+                    true => self.timings.record(Phase::Vendor, || self.transfer_vendored()),
+                    false => Ok(Vec::new()),
+                }
+            })
+            .map_err(Error::Transfer)?;
+        Ok(())
+    }
+
+    /// Transfers the files and directories of `--transfer-extra` into the build directory.
+    fn transfer_extra(&self) -> Result<(), String> {
+        for extra in &self.transfer_extra {
+            let remote = format!("{}{}", self.build_path, extra.remote);
+            debug!("Transferring {} to {}", extra.local.display(), remote);
+            if extra.local.is_dir() {
+                self.transport
+                    .upload(self, &extra.local, &format!("{}/", remote))?;
+                continue;
+            }
+            let what = format!("transfer {} to build server", extra.local.display());
+            let file = std::fs::File::open(&extra.local)
+                .map_err(|e| format!("Failed to {} (error: {})", what, e))?;
+            let parent = remote.rsplit_once('/').map_or("~", |(parent, _)| parent);
+            let status = self
+                .ssh
+                .command()
+                .arg(self.ssh.destination())
+                .arg(format!(
+                    "mkdir -p {} && cat > {}",
+                    shell_path(parent),
+                    shell_path(&remote)
+                ))
+                .stdin(file)
+                .logged_status()
+                .map_err(|e| format!("Failed to {} (error: {})", what, e))?;
+            if !status.success() {
+                return Err(format!("Failed to {} (ssh exited with {})", what, status));
+            }
+        }
+        Ok(())
+    }
+
+    /// Vendors the dependencies if Cargo.lock changed and syncs them to the build server.
+    /// Returns the arguments that make the remote cargo use them.
+    fn transfer_vendored(&self) -> Result<Vec<String>, String> {
+        let config = vendor::vendor(self)?;
+        self.transport
+            .upload(self, &vendor::local_dir(self), &vendor::remote_dir(self))?;
+        vendor::cargo_args(self, &config)
+    }
+
+    /// Prints the timings report if `--timings` was given.
+    pub fn print_timings(&self) {
+        if let Some(format) = self.show_timings {
+            let prefix = self.output_prefix.as_deref().unwrap_or_default();
+            self.timings.print(format, prefix);
+        }
+    }
+
+    /// Locks the build directory against other runs until the returned lock is dropped.
+    pub fn lock(&self) -> Result<Option<RemoteLock>, Error> {
+        RemoteLock::acquire(self, self.lock)
+    }
+
+    /// Runs [`attempt`] until it succeeds, at most [`RemoteBuild::retries`] more times, waiting
+    /// twice as long after every failure.
+    fn retry<T>(
+        &self,
+        what: &str,
+        mut attempt: impl FnMut() -> Result<T, String>,
+    ) -> Result<T, String> {
+        let mut retry = 0;
+        loop {
+            match attempt() {
+                Err(e) if retry < self.retries => {
+                    retry += 1;
+                    let delay = backoff(retry);
+                    warn!(
+                        "{}, retrying to {} in {}s ({} of {})",
+                        e, what, delay, retry, self.retries
+                    );
+                    std::thread::sleep(Duration::from_secs(delay));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Shell commands that make sure the toolchain (and with `--auto-install-toolchain` the
+    /// targets) the build needs are available on the build server.
+    fn toolchain_setup(&self) -> String {
+        let mut setup = match &self.toolchain {
+            Some(toolchain) if self.auto_install_toolchain => format!(
+                "RUSTUP_AUTO_INSTALL=0 rustup run {tc} rustc --version >/dev/null 2>&1 || \
+                 rustup toolchain install --profile minimal {tc} || exit 1",
+                tc = shell_arg(toolchain)
+            ),
+            Some(toolchain) => format!(
+                "RUSTUP_AUTO_INSTALL=0 rustup run {tc} rustc --version >/dev/null 2>&1 || \
+                 {{ echo \"error: toolchain '{tc}' is not installed on the build server, \
+                 install it with 'rustup toolchain install {tc}'\" >&2; exit 1; }}",
+                tc = shell_arg(toolchain)
+            ),
+            None => format!("rustup default {}", shell_arg(&self.rustup_default)),
+        };
+        if self.auto_install_toolchain {
+            let toolchain = self.toolchain.as_ref().unwrap_or(&self.rustup_default);
+            for target in self.targets() {
+                setup.push_str(&format!(
+                    "; rustup target list --installed --toolchain {tc} | grep -qx {target} || \
+                     rustup target add --toolchain {tc} {target} || exit 1",
+                    tc = shell_arg(toolchain),
+                    target = shell_quote(&target)
+                ));
+            }
+        }
+        setup
+    }
+
+    /// Runs the cargo command on the build server, streaming its output.
+    pub fn run_cargo(&mut self) -> Result<ExitStatus, Error> {
+        debug!("Build ENV: {:?}", self.build_env);
+        debug!("Environment profile: {:?}", self.env);
+        debug!("Build path: {:?}", self.build_path);
+        // not part of `cargo_args`, a local build uses the registry as usual
+        let mut cargo_args = self.vendor_args.clone();
+        cargo_args.extend(self.cargo_args());
+        let json_output = self.json_output();
+        self.annotations = self.github
+            && !json_output
+            && github::MESSAGE_FORMAT_COMMANDS.contains(&self.command.as_str());
+        if json_output {
+            self.lints = None;
+        }
+        // the rendered diagnostics keep their colors if they end up on a terminal, annotations
+        // are plain text
+        let ansi = !self.github
+            && std::io::stderr().is_terminal()
+            && std::env::var_os("NO_COLOR").is_none();
+        // cargo doesn't take two message formats, the one asked for has the artifacts as well
+        let message_format = match self.renders_diagnostics() {
+            // the diagnostics with their location, rendered by forward_output
+            true if ansi => Some("--message-format=json-diagnostic-rendered-ansi"),
+            true => Some("--message-format=json"),
+            false if self.copy_back_artifacts => Some("--message-format=json-render-diagnostics"),
+            false => None,
+        };
+        if let Some(message_format) = message_format.filter(|_| !json_output) {
+            // in front of a `--`, everything after it belongs to the program run by cargo
+            let end = cargo_args
+                .iter()
+                .position(|a| a == "--")
+                .unwrap_or(cargo_args.len());
+            cargo_args.insert(end, message_format.to_string());
+        }
+        // every argument stays one word on the build server, e.g. `--features "a b"`
+        let quoted_args: Vec<String> = cargo_args.iter().map(|arg| shell_arg(arg)).collect();
+        let cargo = format!(
+            "{} {} {} {}",
+            self.build_env,
+            self.cargo_binary(),
+            shell_arg(&self.command),
+            quoted_args.join(" ")
+        );
+        // The remote target directory is needed to map the artifact paths back to the local
+        // target directory. It isn't necessarily `target/` in the build path, e.g. if
+        // `CARGO_TARGET_DIR` is set on the build server.
+        let cargo = if self.copy_back_artifacts {
+            format!(
+                "{} {} metadata --no-deps --format-version 1 2>/dev/null; {}",
+                self.build_env,
+                self.cargo_binary(),
+                cargo
+            )
+        } else {
+            cargo
+        };
+        let mut cargo = cargo;
+        // the output of the hooks must not end up between the JSON messages
+        let hook = |hook: &String| match json_output {
+            true => format!("{{ {}; }} >&2", hook),
+            false => hook.clone(),
+        };
+        if let Some(pre_build) = self.pre_build.as_ref().map(hook) {
+            cargo = format!("{} || exit $?; {}", pre_build, cargo);
+        }
+        if let Some(post_build) = self.post_build.as_ref().map(hook) {
+            cargo = format!(
+                "{}; status=$?; if [ $status -eq 0 ]; then {} || status=$?; fi; (exit $status)",
+                cargo, post_build
+            );
+        }
+        let cargo = if self.sccache {
+            // stats go to stderr, stdout may be parsed for cargo messages
+            format!(
+                "sccache --zero-stats >/dev/null 2>&1; {}; status=$?; sccache --show-stats >&2; \
+                 exit $status",
+                cargo
+            )
+        } else {
+            cargo
+        };
+        // Only ask for a pseudo terminal if we are attached to one. Without a terminal stdout and
+        // stderr of the remote cargo stay separate streams, which CI and other tools rely on.
+        // Programs started with `cargo run` get one whenever the input comes from a terminal, so
+        // interactive programs work even if their output is piped somewhere locally.
+        let interactive = std::io::stdin().is_terminal()
+            && (std::io::stdout().is_terminal() || self.command == "run");
+        let tty = self.tty || (interactive && !self.no_tty && self.output_prefix.is_none());
+        let mut env_vars = self.env_vars.clone();
+        // cargo prints its messages to stderr. If that is a terminal here, colors and the
+        // progress bar are wanted even without a pseudo terminal on the build server.
+        let colored = !tty
+            && !json_output
+            && std::io::stderr().is_terminal()
+            && std::env::var_os("NO_COLOR").is_none();
+        let mut term_var = |name: &str, value: String| {
+            if !env_vars.iter().any(|(n, _)| n == name) {
+                env_vars.push((name.to_string(), value));
+            }
+        };
+        if colored {
+            term_var("CARGO_TERM_COLOR", "always".to_string());
+        }
+        if colored && self.output_prefix.is_none() {
+            term_var("CARGO_TERM_PROGRESS_WHEN", "always".to_string());
+            if let Some(width) = crate::util::terminal_width() {
+                term_var("CARGO_TERM_PROGRESS_WIDTH", width.to_string());
+            }
+        }
+        let mut exports: String = env_vars
+            .iter()
+            .map(|(name, value)| format!("export {}={}; ", name, shell_quote(value)))
+            .collect();
+        if let Some(dir) = &self.cargo_target_dir {
+            // not quoted away, `~/` has to be expanded by the shell
+            exports.push_str(&format!("export CARGO_TARGET_DIR={}; ", shell_path(dir)));
+            env_vars.push(("CARGO_TARGET_DIR".to_string(), dir.clone()));
+        }
+        if let Some(exec) = self.agent_exec(&cargo_args, &env_vars) {
+            return self.run_exec(exec, json_output);
+        }
+        if self.remote_os == RemoteOs::Windows {
+            let build_command =
+                platform::windows_build_script(self, &cargo_args, &env_vars, json_output);
+            return self.run_build_command(build_command, tty, json_output);
+        }
+        // containers and nix environments bring their own toolchain
+        let (setup, workspace, cargo) = match (&self.container, &self.nix) {
+            (Some(container), _) => {
+                let env: Vec<&str> = env_vars.iter().map(|(name, _)| name.as_str()).collect();
+                let cargo = container.wrap(&cargo, &env, tty, !self.forward_ports.is_empty());
+                (String::new(), crate::container::WORKSPACE, cargo)
+            }
+            (None, Some(nix)) => (String::new(), "\"$PWD\"", nix.wrap(&cargo)),
+            (None, None) => (format!("{}; ", self.toolchain_setup()), "\"$PWD\"", cargo),
+        };
+        // after the toolchain setup, the cache entry depends on the rustc version
+        let lock_hash = crate::dep_cache::lock_hash(&self.project_dir).filter(|_| self.dep_cache);
+        let (setup, cargo) = match lock_hash {
+            Some(lock_hash) => (
+                format!("{}{}", setup, crate::dep_cache::seed_script(self, &lock_hash)),
+                format!(
+                    "{}; status=$?; if [ $status -eq 0 ]; then {{ {}; }} >&2; fi; exit $status",
+                    cargo,
+                    crate::dep_cache::store_script(self)
+                ),
+            ),
+            None => (setup, cargo),
+        };
+        // stdout is left to cargo, the setup's output goes to stderr
+        let build_command = format!(
+            "{{ source {}; {}cd {} || exit 1; echo {} > {}; }} >&2; {}{}{}",
+            shell_path(&self.env),
+            setup,
+            shell_path(&self.build_path),
+            shell_quote(&self.project_dir.to_string_lossy()),
+            PROJECT_MARKER,
+            crate::cargo_config::remote_rewrite(&self.project_dir, workspace),
+            exports,
+            cargo
+        );
+        self.run_build_command(build_command, tty, json_output)
+    }
+
+    /// The cargo command as a program with arguments for the agent transport, so nothing in it
+    /// goes through a shell on the build server. `None` if it needs one: for hooks, wrappers
+    /// like containers and sccache, a cargo configuration to rewrite or build variables that
+    /// are more than plain assignments.
+    fn agent_exec(&self, cargo_args: &[String], env_vars: &[(String, String)]) -> Option<Exec> {
+        self.transport.agent()?;
+        let scripted = self.container.is_some()
+            || self.nix.is_some()
+            || self.pre_build.is_some()
+            || self.post_build.is_some()
+            || self.sccache
+            || self.dep_cache
+            || self.copy_back_artifacts
+            || self.auto_install_toolchain
+            || crate::cargo_config::find(&self.project_dir).is_some();
+        if scripted {
+            return None;
+        }
+        let mut env = Vec::new();
+        for assignment in self.build_env.split_whitespace() {
+            let (name, value) = assignment.split_once('=')?;
+            if value.contains(|c| "'\"$`\\".contains(c)) {
+                return None;
+            }
+            env.push((name.to_string(), value.to_string()));
+        }
+        let (path_env, vars): (Vec<_>, Vec<_>) = env_vars
+            .iter()
+            .cloned()
+            .partition(|(name, _)| name == "CARGO_TARGET_DIR");
+        env.extend(vars);
+
+        let mut command = vec!["cargo".to_string()];
+        let setup = match &self.toolchain {
+            Some(toolchain) => {
+                command.push(format!("+{}", toolchain));
+                // fails with rustup's message if the toolchain isn't installed
+                let toolchain = toolchain.as_str();
+                let check = ["env", "RUSTUP_AUTO_INSTALL=0", "rustup", "run", toolchain, "rustc"];
+                vec![check.iter().chain(&["--version"]).map(|arg| arg.to_string()).collect()]
+            }
+            None => vec![vec![
+                "rustup".to_string(),
+                "default".to_string(),
+                self.rustup_default.clone(),
+            ]],
+        };
+        command.push(self.command.clone());
+        command.extend(cargo_args.iter().cloned());
+        Some(Exec {
+            profile: Some(self.env.clone()),
+            cwd: self.build_path.clone(),
+            env,
+            path_env,
+            setup,
+            command,
+        })
+    }
+
+    /// Runs [`exec`] through the agent, see [`RemoteBuild::agent_exec`].
+    fn run_exec(&mut self, exec: Exec, json_output: bool) -> Result<ExitStatus, Error> {
+        debug!("Starting build process.");
+        let agent = self
+            .transport
+            .agent()
+            .expect("only builds with the agent transport get an exec");
+        let marker = format!("{}\n", self.project_dir.to_string_lossy());
+        agent
+            .write_file(self, &self.build_path, PROJECT_MARKER, marker.as_bytes())
+            .map_err(Error::Transfer)?;
+        let mut running = agent.start_exec(self, exec)?;
+        let stderr = running.stderr.take();
+        self.forward_output(&mut running.stdout, stderr, json_output);
+        running.wait()
+    }
+
+    /// Runs [`build_command`] on the build server, with a pseudo terminal if [`tty`] is set,
+    /// and passes its output on.
+    fn run_build_command(
+        &mut self,
+        build_command: String,
+        tty: bool,
+        json_output: bool,
+    ) -> Result<ExitStatus, Error> {
+        debug!("Starting build process.");
+        if self.resilient {
+            let mut session = Session::start(self, &build_command)?;
+            let stderr = session.stderr.take();
+            self.forward_output(&mut session.stdout, stderr, json_output);
+            return session.wait();
+        }
+        let running = match self.transport.agent() {
+            Some(agent) => Some(agent.start_script(self, &build_command)?),
+            None => None,
+        };
+        if let Some(mut running) = running {
+            let stderr = running.stderr.take();
+            self.forward_output(&mut running.stdout, stderr, json_output);
+            return running.wait();
+        }
+        let mut build = self.ssh.command();
+        if self.tty {
+            build.arg("-tt");
+        } else if tty {
+            build.arg("-t");
+        }
+        for forward in &self.forward_ports {
+            debug!("Forwarding local port {}", forward);
+            build.arg("-L").arg(forward);
+        }
+        build.arg(self.ssh.destination()).arg(build_command);
+        let log_file = crate::logging::log_file_set();
+        if self.output_prefix.is_none()
+            && !self.copy_back_artifacts
+            && !self.renders_diagnostics()
+            && !log_file
+            && !self.remap_paths
+        {
+            return build
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .stdin(Stdio::inherit())
+                .logged_status()
+                .map_err(run_error);
+        }
+
+        let stdin = match self.output_prefix {
+            Some(_) => Stdio::null(),
+            None => Stdio::inherit(),
+        };
+        let stderr = match self.output_prefix.is_some() || log_file || self.remap_paths {
+            true => Stdio::piped(),
+            false => Stdio::inherit(),
+        };
+        let mut child = build
+            .stdout(Stdio::piped())
+            .stderr(stderr)
+            .stdin(stdin)
+            .logged_spawn()
+            .map_err(run_error)?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take();
+        self.forward_output(stdout, stderr, json_output);
+        child.wait().map_err(run_error)
+    }
+
+    /// Passes the output of the remote cargo on to stdout and stderr and the log file, with the
+    /// paths on the build server pointing to the local files, see [`Remap`]. With
+    /// [`RemoteBuild::output_prefix`] every line gets the prefix in front, with
+    /// [`RemoteBuild::copy_back_artifacts`] the artifacts are picked out of cargo's JSON
+    /// messages, with [`RemoteBuild::annotations`] and [`RemoteBuild::lints`] the diagnostics
+    /// in them are printed, then annotated or counted. [`stderr`] is `None` if it goes to the
+    /// terminal directly.
+    fn forward_output(
+        &mut self,
+        stdout: impl Read,
+        stderr: Option<impl Read + Send + 'static>,
+        json_output: bool,
+    ) {
+        let remap = Remap::new(self);
+        if self.output_prefix.is_none()
+            && !self.copy_back_artifacts
+            && !self.renders_diagnostics()
+        {
+            // the output is copied as it comes, progress bars and all
+            let stderr = stderr.map(|stderr| {
+                let remap = remap.clone();
+                std::thread::spawn(move || {
+                    crate::logging::tee(stderr, std::io::stderr(), |bytes| remap.bytes(bytes))
+                })
+            });
+            crate::logging::tee(stdout, std::io::stdout(), |bytes| remap.bytes(bytes));
+            if let Some(stderr) = stderr {
+                let _ = stderr.join();
+            }
+            return;
+        }
+
+        let prefix = self.output_prefix.clone().unwrap_or_default();
+        let stderr_prefixer = stderr.map(|stderr| {
+            let (prefix, remap) = (prefix.clone(), remap.clone());
+            std::thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    let line = format!("{}{}", prefix, remap.line(&line));
+                    crate::logging::tee_line(std::io::stderr(), &line);
+                }
+            })
+        });
+        self.artifacts.clear();
+        let mut out = std::io::stdout();
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let message = match self.copy_back_artifacts || self.renders_diagnostics() {
+                true => serde_json::from_str::<serde_json::Value>(&line).ok(),
+                false => None,
+            };
+            match message {
+                // the output of the `cargo metadata` call
+                Some(message) if message.get("packages").is_some() => {
+                    if let Some(dir) = message.get("target_directory").and_then(|d| d.as_str()) {
+                        self.remote_target_dir = Some(dir.trim_end_matches('/').to_string());
+                    }
+                }
+                Some(message) => {
+                    if let Some(artifacts) = self.artifact_paths(&message) {
+                        self.artifacts.extend(artifacts);
+                    }
+                    if self.renders_diagnostics() {
+                        self.annotate(&message, &prefix, &remap);
+                    } else if json_output {
+                        let line = format!("{}{}", prefix, remap.line(&line));
+                        crate::logging::tee_line(&mut out, &line);
+                    }
+                }
+                // everything that isn't a cargo message is output of the program itself
+                None => {
+                    let line = format!("{}{}", prefix, remap.line(&line));
+                    crate::logging::tee_line(&mut out, &line)
+                }
+            }
+        }
+        if let Some(prefixer) = stderr_prefixer {
+            let _ = prefixer.join();
+        }
+        if let Some(lints) = &self.lints {
+            for line in lints.lines() {
+                crate::logging::tee_line(std::io::stderr(), &format!("{}{}", prefix, line));
+            }
+        }
+    }
+
+    /// Whether the diagnostics in cargo's JSON messages are printed by
+    /// [`RemoteBuild::forward_output`] instead of by cargo.
+    fn renders_diagnostics(&self) -> bool {
+        self.annotations || self.lints.is_some()
+    }
+
+    /// Prints the rendered diagnostic of a `compiler-message` to stderr, where cargo prints it
+    /// without JSON messages, and the annotation for it to stdout or counts it.
+    fn annotate(&mut self, message: &serde_json::Value, prefix: &str, remap: &Remap) {
+        let rendered = message
+            .get("message")
+            .and_then(|diagnostic| diagnostic.get("rendered"))
+            .and_then(|rendered| rendered.as_str());
+        if let Some(rendered) = rendered {
+            for line in remap.line(rendered).lines() {
+                crate::logging::tee_line(std::io::stderr(), &format!("{}{}", prefix, line));
+            }
+        }
+        if let Some(lints) = self.lints.as_mut() {
+            lints.add(message);
+        }
+        if !self.annotations {
+            return;
+        }
+        if let Some(annotation) = github::annotation(message, remap, &self.project_dir) {
+            crate::logging::tee_line(std::io::stdout(), &annotation);
+        }
+    }
+
+    /// The binaries and libraries of a `compiler-artifact` message as paths relative to the
+    /// remote target directory. Intermediate outputs like `.rlib` and `.rmeta` files are
+    /// skipped, they are of no use on the local machine.
+    fn artifact_paths(&self, message: &serde_json::Value) -> Option<Vec<String>> {
+        if message.get("reason")?.as_str()? != "compiler-artifact" {
+            return None;
+        }
+        let test = message
+            .get("profile")
+            .and_then(|profile| profile.get("test"))
+            .and_then(|test| test.as_bool())
+            == Some(true);
+        if self.test_binaries.is_some() && !test {
+            return None;
+        }
+        let kinds: Vec<&str> = message
+            .get("target")?
+            .get("kind")?
+            .as_array()?
+            .iter()
+            .filter_map(|k| k.as_str())
+            .collect();
+        let wanted = kinds
+            .iter()
+            .any(|k| ["bin", "cdylib", "dylib", "staticlib"].contains(k));
+        let filenames = message
+            .get("filenames")?
+            .as_array()?
+            .iter()
+            .filter_map(|f| f.as_str());
+        let mut paths: Vec<&str> = Vec::new();
+        if let Some(executable) = message.get("executable").and_then(|e| e.as_str()) {
+            paths.push(executable);
+            // split debug info is a file or bundle of its own, depending on the platform
+            paths.extend(filenames.filter(|f| debuginfo::is_debug_info(f)));
+        } else if wanted {
+            paths.extend(filenames);
+        }
+        let relative = |path: &'_ str| -> Option<String> {
+            match &self.remote_target_dir {
+                Some(dir) => path.strip_prefix(dir.as_str())?.strip_prefix('/').map(String::from),
+                None => {
+                    let target_dir = format!("{}target/", self.build_path.trim_start_matches('~'));
+                    let start = path.rfind(&target_dir)? + target_dir.len();
+                    Some(path[start..].to_string())
+                }
+            }
+        };
+        Some(paths.into_iter().filter_map(relative).collect())
+    }
+
+    /// Copies everything back that was asked for: the target directory (or a part of it),
+    /// benchmark results, docs and the Cargo.lock file.
+    pub fn retrieve_results(&self, build_status: ExitStatus) -> Result<(), Error> {
+        let remote_target = self.remote_target();
+        if let Some(identity) = self.codesign.as_ref().filter(|_| build_status.success()) {
+            self.codesign(identity, &remote_target)?;
+        }
+        if let Some(file_name) = &self.copy_back {
+            debug!("Transferring artifacts back to client.");
+            let filters = self.copy_back_filters();
+            for file_name in self.target_paths(file_name.as_deref().unwrap_or_default()) {
+                self.retry("copy back the target", || {
+                    self.transport.download(
+                        self,
+                        &remote_target,
+                        &file_name,
+                        &self.local_target_dir,
+                        &filters,
+                    )
+                })
+                .map_err(|e| {
+                        Error::CopyBack(format!(
+                            "Failed to transfer target back to local machine: {}",
+                            e
+                        ))
+                    })?;
+            }
+        }
+
+        let artifacts = self.copy_back_artifacts && !self.artifacts.is_empty();
+        if artifacts {
+            debug!("Transferring {} artifacts back to client.", self.artifacts.len());
+            let remote_target_dir = match (&self.remote_target_dir, &self.container) {
+                (Some(dir), Some(container)) => container.host_path(dir, &self.build_path),
+                (Some(dir), None) => dir.clone(),
+                (None, _) => remote_target.clone(),
+            };
+            self.retry("copy back the artifacts", || {
+                self.transport.download_files(
+                    self,
+                    &remote_target_dir,
+                    &self.artifacts,
+                    &self.local_target_dir,
+                )
+            })
+            .map_err(|e| {
+                    Error::CopyBack(format!(
+                        "Failed to transfer artifacts back to local machine: {}",
+                        e
+                    ))
+                })?;
+        }
+        if (artifacts || self.copy_back.is_some()) && build_status.success() {
+            debuginfo::write_source_map(self);
+        }
+
+        if self.command == "bench" {
+            for dir in &self.bench_output_dirs {
+                debug!("Transferring benchmark results in target/{} back to client.", dir);
+                self.retry("copy back benchmark results", || {
+                    self.transport.download(
+                        self,
+                        &remote_target,
+                        &format!("{}/", dir.trim_end_matches('/')),
+                        &self.local_target_dir,
+                        &Filters::default(),
+                    )
+                })
+                .map_err(|e| {
+                        Error::CopyBack(format!(
+                            "Failed to transfer benchmark results back to local machine: {}",
+                            e
+                        ))
+                    })?;
+            }
+        }
+
+        for extra in &self.retrieve {
+            self.retry("retrieve the build outputs", || self.retrieve_path(extra))
+                .map_err(|e| {
+                    Error::CopyBack(format!(
+                        "Failed to retrieve {} from the build server: {}",
+                        extra.remote, e
+                    ))
+                })?;
+        }
+        coverage::localize(self);
+
+        if self.open_docs && build_status.success() {
+            let index = self
+                .local_target_dir
+                .join(&self.target_paths("doc")[0])
+                .join(self.project_name.replace('-', "_"))
+                .join("index.html");
+            open_in_browser(&index);
+        }
+
+        if !self.no_copy_lock {
+            debug!("Transferring Cargo.lock file back to client.");
+            let lock_file = self.project_dir.join("Cargo.lock");
+            let local_lock = std::fs::read(&lock_file).ok();
+            self.retry("copy back Cargo.lock", || {
+                self.transport.download(
+                    self,
+                    &self.build_path,
+                    "Cargo.lock",
+                    &self.project_dir,
+                    &Filters::default(),
+                )
+            })
+            .map_err(|e| {
+                    Error::CopyLock(format!(
+                        "Failed to transfer Cargo.lock back to local machine: {}",
+                        e
+                    ))
+                })?;
+            if std::fs::read(&lock_file).ok() != local_lock {
+                info!("The build server updated Cargo.lock, copied it back");
+            }
+        }
+
+        if crate::fix::is_fix(&self.command, &self.options) {
+            crate::fix::sync_back(self)?;
+        }
+        Ok(())
+    }
+
+    /// Removes [`path`] in the build directory right before cargo starts and creates the
+    /// directory it goes into, for outputs that are retrieved afterwards. A failed run then
+    /// doesn't retrieve the ones of an earlier run.
+    pub fn clear_output(&mut self, path: &str) {
+        let path = path.trim_end_matches('/');
+        let mut prepare = format!("rm -rf {}", shell_quote(path));
+        if let Some((dir, _)) = path.rsplit_once('/') {
+            prepare = format!("mkdir -p {} && {}", shell_quote(dir), prepare);
+        }
+        self.pre_build = Some(match self.pre_build.take() {
+            Some(hook) => format!("{} && {{ {}; }}", prepare, hook),
+            None => prepare,
+        });
+    }
+
+    /// Copies [`extra`] of `--retrieve` from the build directory. It goes through a staging
+    /// directory because it can have another name locally, a directory is merged into the local
+    /// one.
+    fn retrieve_path(&self, extra: &Extra) -> Result<(), String> {
+        debug!("Retrieving {} from the build server", extra.remote);
+        let remote = extra.remote.trim_end_matches('/');
+        let (dir, name) = match remote.rsplit_once('/') {
+            Some((dir, name)) => (format!("{}{}", self.build_path, dir), name),
+            None => (self.build_path.clone(), remote),
+        };
+        let path = match extra.remote.ends_with('/') {
+            true => format!("{}/", name),
+            false => name.to_string(),
+        };
+        let staging = self.local_target_dir.join(".cargo-remote-retrieve");
+        let _ = std::fs::remove_dir_all(&staging);
+        std::fs::create_dir_all(&staging)
+            .map_err(|e| format!("Failed to create {} (error: {})", staging.display(), e))?;
+        let downloaded = self
+            .transport
+            .download(self, &dir, &path, &staging, &Filters::default());
+        let retrieved = staging.join(name);
+        let moved = match downloaded.map(|_| retrieved.exists()) {
+            Err(e) => Err(e),
+            Ok(true) => move_into(&retrieved, &extra.local).map_err(|e| {
+                format!("Failed to move it to {} (error: {})", extra.local.display(), e)
+            }),
+            Ok(false) => {
+                warn!("{} doesn't exist on the build server", extra.remote);
+                Ok(())
+            }
+        };
+        let _ = std::fs::remove_dir_all(&staging);
+        moved
+    }
+
+    /// Signs the binaries that are copied back from [`remote_target`] with [`identity`] on the
+    /// build server: the artifacts cargo reported with `--copy-back-artifacts`, the executables
+    /// in the copied back part of the target directory otherwise. Debug symbol bundles,
+    /// dependencies and build scripts are left alone.
+    fn codesign(&self, identity: &str, remote_target: &str) -> Result<(), Error> {
+        let sign = format!("codesign --force --sign {}", shell_quote(identity));
+        let script = if self.copy_back_artifacts {
+            let binaries: Vec<String> = self
+                .artifacts
+                .iter()
+                .filter(|a| !debuginfo::is_debug_info(a) && !a.ends_with(".a"))
+                .map(|a| shell_quote(a))
+                .collect();
+            if binaries.is_empty() {
+                return Ok(());
+            }
+            let dir = self.remote_target_dir.as_deref().unwrap_or(remote_target);
+            format!("cd {} && {} {}", shell_path(dir), sign, binaries.join(" "))
+        } else {
+            let path = match &self.copy_back {
+                Some(path) => path.as_deref().unwrap_or_default(),
+                None => return Ok(()),
+            };
+            let paths: Vec<String> = self
+                .target_paths(path)
+                .iter()
+                .map(|p| match p.is_empty() {
+                    true => ".".to_string(),
+                    false => shell_quote(p),
+                })
+                .collect();
+            format!(
+                "cd {} && find {} -type f -perm -u+x ! -path '*.dSYM/*' ! -path '*/deps/*' \
+                 ! -path '*/build/*' ! -path '*/incremental/*' -exec {} {{}} +",
+                shell_path(remote_target),
+                paths.join(" "),
+                sign
+            )
+        };
+        debug!("Signing the binaries on the build server");
+        let output = self
+            .ssh
+            .command()
+            .arg(self.ssh.destination())
+            .arg(script)
+            .stdin(Stdio::null())
+            .logged_output()
+            .map_err(|e| Error::command("sign the binaries on the build server", e, &[]))?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(Error::command(
+                "sign the binaries on the build server",
+                output.status,
+                &output.stderr,
+            )),
+        }
+    }
+
+    /// The target directory on the build server, `target/` in the build directory unless
+    /// `--remote-target-dir` moved it.
+    pub fn remote_target(&self) -> String {
+        match self.cargo_target_dir.as_deref().map(|dir| dir.trim_end_matches('/')) {
+            Some(dir) if dir.starts_with('/') || dir.starts_with('~') => dir.to_string(),
+            Some(dir) => format!("{}{}", self.build_path, dir),
+            None => format!("{}target", self.build_path),
+        }
+    }
+
+    /// The `--copy-back-include`/`--copy-back-exclude` patterns.
+    fn copy_back_filters(&self) -> Filters {
+        let mut exclude = self.copy_back_exclude.clone();
+        if self.container.is_some() {
+            // cargo registry cache of the container
+            exclude.push(".cargo-home/".to_string());
+        }
+        Filters {
+            include: self.copy_back_include.clone(),
+            exclude,
+        }
+    }
+
+    /// Transfers the sources, runs the cargo command and copies the results back. Returns the
+    /// exit code of cargo. The run is recorded in the build history.
+    pub fn run(mut self) -> Result<i32, Error> {
+        let started = Instant::now();
+        let result = self.run_build();
+        history::record(&self, &result, started.elapsed());
+        let finished = notify::Finished {
+            project: &self.project_name,
+            command: &self.command,
+            server: self.ran_on(),
+            exit_code: result.as_ref().map_or_else(Error::exit_code, |code| *code),
+            duration: started.elapsed(),
+        };
+        finished.notify(self.notify, self.webhook.as_deref());
+        result
+    }
+
+    /// The build server the cargo command ran on, or `local` if it fell back to a local build.
+    pub fn ran_on(&self) -> &str {
+        match self.timings.duration(Phase::LocalBuild) {
+            Some(_) => history::LOCAL,
+            None => &self.build_server,
+        }
+    }
+
+    fn run_build(&mut self) -> Result<i32, Error> {
+        self.prepare_command();
+        if self.auto {
+            if crate::auto::run_locally(self) {
+                return self.run_locally();
+            }
+            if self.multiplex {
+                self.ssh.start_master();
+            }
+        }
+        match self.validate() {
+            Ok(()) => {}
+            Err(e @ Error::Config(_)) => return Err(e),
+            Err(e) if self.fallback_local => {
+                error!("{}", e);
+                return self.build_locally();
+            }
+            Err(e) => return Err(e),
+        }
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(e @ Error::Locked(_)) => return Err(e),
+            Err(e) if self.fallback_local => {
+                error!("{}", e);
+                return self.build_locally();
+            }
+            Err(e) => return Err(e),
+        };
+        let group = Group::start(self.github, &format!("Sync to {}", self.build_server));
+        let transferred = self.transfer_sources();
+        drop(group);
+        if let Err(e) = transferred {
+            if self.fallback_local {
+                error!("{}", e);
+                return self.build_locally();
+            }
+            return Err(e);
+        }
+
+        // ssh exits with 255 if the connection itself failed. The command is run again, cargo
+        // picks up the work that was already done. Programs started with `cargo run` aren't,
+        // they might not expect to be started twice.
+        let group = Group::start(
+            self.github,
+            &format!("cargo {} on {}", self.command, self.build_server),
+        );
+        let mut retry = 0;
+        let build_status = loop {
+            let started = Instant::now();
+            let status = self.run_cargo()?;
+            self.timings.add(Phase::Build, started.elapsed());
+            if status.code() != Some(255) || retry >= self.retries || self.command == "run" {
+                break status;
+            }
+            retry += 1;
+            let delay = backoff(retry);
+            warn!(
+                "Lost connection to the build server, running cargo again in {}s ({} of {})",
+                delay, retry, self.retries
+            );
+            std::thread::sleep(Duration::from_secs(delay));
+            self.ssh.reconnect();
+        };
+        drop(group);
+        if self.fallback_local && build_status.code() == Some(255) {
+            error!("Lost connection to the build server");
+            return self.build_locally();
+        }
+
+        let group = Group::start(self.github, "Copy back");
+        self.timings
+            .record(Phase::CopyBack, || self.retrieve_results(build_status))?;
+        drop(group);
+        if self.upload.is_some() {
+            let group = Group::start(self.github, "Upload");
+            self.timings
+                .record(Phase::Upload, || upload::upload(self, build_status))?;
+            drop(group);
+        }
+        self.print_timings();
+        self.ssh.stop_master();
+        if self.debug.is_some() && build_status.success() {
+            return debug::start(self);
+        }
+        test_binaries::finish(self, build_status)
+    }
+
+    /// Runs the cargo command on the local machine instead of the build server and returns
+    /// its exit code. Variables from [`RemoteBuild::build_env`] are set for the local cargo
+    /// process as well.
+    fn build_locally(&self) -> Result<i32, Error> {
+        warn!("Falling back to a local build of 'cargo {}'", self.command);
+        self.run_locally()
+    }
+
+    /// Runs the cargo command in the local project with the build's environment variables.
+    fn run_locally(&self) -> Result<i32, Error> {
+        let envs = self
+            .build_env
+            .split_whitespace()
+            .filter_map(|var| {
+                let mut parts = var.splitn(2, '=');
+                Some((parts.next()?, parts.next()?))
+            })
+            .chain(self.env_vars.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        let mut cargo = Command::new("cargo");
+        if let Some(toolchain) = &self.toolchain {
+            cargo.arg(format!("+{}", toolchain));
+        }
+        cargo
+            .arg(&self.command)
+            .args(self.cargo_args())
+            .envs(envs)
+            .current_dir(&self.project_dir)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit());
+        let status = self
+            .timings
+            .record(Phase::LocalBuild, || cargo.logged_status())
+            .map_err(|e| Error::command("run cargo command locally", e, &[]))?;
+        Ok(exit_code(status))
+    }
+}
+
+/// Seconds to wait before the [`retry`]th retry: 1, 2, 4, ... up to a minute.
+pub fn backoff(retry: u32) -> u64 {
+    1u64.checked_shl(retry - 1).unwrap_or(u64::MAX).min(60)
+}
+
+fn run_error(e: std::io::Error) -> Error {
+    Error::command("run cargo command remotely", e, &[])
+}
+
+/// Moves [`from`] to [`to`], the files of a directory into the existing one. Files are copied if
+/// they can't be moved, e.g. to another file system.
+fn move_into(from: &Path, to: &Path) -> std::io::Result<()> {
+    if from.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            move_into(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(from, to).or_else(|_| std::fs::copy(from, to).map(drop))
+}
+
+/// Opens [`path`] with the browser from `$BROWSER` or the default application of the platform.
+pub fn open_in_browser(path: &Path) {
+    let opener = std::env::var("BROWSER").unwrap_or_else(|_| {
+        if cfg!(target_os = "macos") {
+            "open".to_string()
+        } else if cfg!(windows) {
+            "explorer".to_string()
+        } else {
+            "xdg-open".to_string()
+        }
+    });
+    debug!("Opening {:?} with {}", path, opener);
+    if let Err(e) = Command::new(&opener).arg(path).logged_status() {
+        error!("Failed to open '{}' (error: {})", path.to_string_lossy(), e);
+    }
+}
+
+/// The exit code to pass on for a finished process, following the shell convention of 128 plus
+/// the signal number for processes that were killed by a signal.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    1
+}
@@ -0,0 +1,220 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use log::debug;
+use toml::Value;
+
+use crate::build::RemoteBuild;
+use crate::error::Error;
+use crate::logging::Run;
+use crate::manage::now;
+use crate::util::{shell_arg, shell_path};
+
+const CAPABILITIES_FILE: &str = "capabilities.toml";
+
+/// How long the capabilities of a build server are trusted before they are probed again. A
+/// failed validation probes again right away, something might have been installed meanwhile.
+const CAPABILITIES_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// What a build server has installed and what it runs on, probed on first contact and
+/// cached in `~/.cache/cargo-remote/capabilities.toml`.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    /// Toolchain the versions and targets were probed for.
+    pub toolchain: String,
+    pub rustc: String,
+    pub cargo: String,
+    /// Installed targets of [`Capabilities::toolchain`].
+    pub targets: Vec<String>,
+    pub cores: u64,
+    pub mem_kb: u64,
+    /// First line of `rsync --version`, empty if rsync isn't installed.
+    pub rsync: String,
+    pub probed: u64,
+}
+
+impl Capabilities {
+    fn to_value(&self) -> Value {
+        let mut table = toml::value::Table::new();
+        let string = |s: &str| Value::String(s.to_string());
+        table.insert("toolchain".into(), string(&self.toolchain));
+        table.insert("rustc".into(), string(&self.rustc));
+        table.insert("cargo".into(), string(&self.cargo));
+        table.insert(
+            "targets".into(),
+            Value::Array(self.targets.iter().map(|t| string(t)).collect()),
+        );
+        table.insert("cores".into(), Value::Integer(self.cores as i64));
+        table.insert("mem_kb".into(), Value::Integer(self.mem_kb as i64));
+        table.insert("rsync".into(), string(&self.rsync));
+        table.insert("probed".into(), Value::Integer(self.probed as i64));
+        Value::Table(table)
+    }
+
+    fn from_value(value: &Value) -> Option<Capabilities> {
+        let string = |key: &str| Some(value.get(key)?.as_str()?.to_string());
+        Some(Capabilities {
+            toolchain: string("toolchain")?,
+            rustc: string("rustc")?,
+            cargo: string("cargo")?,
+            targets: crate::config::string_list(value, "targets"),
+            cores: value.get("cores")?.as_integer()? as u64,
+            mem_kb: value.get("mem_kb")?.as_integer()? as u64,
+            rsync: string("rsync")?,
+            probed: value.get("probed")?.as_integer()? as u64,
+        })
+    }
+
+    /// Parses the tab separated `name value` lines of [`probe_script`].
+    fn parse(toolchain: &str, output: &str) -> Capabilities {
+        let value = |name: &str| {
+            output
+                .lines()
+                .filter_map(|line| line.split_once('\t'))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.trim().to_string())
+                .unwrap_or_default()
+        };
+        Capabilities {
+            toolchain: toolchain.to_string(),
+            rustc: value("rustc"),
+            cargo: value("cargo"),
+            targets: value("targets")
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            cores: value("cores").parse().unwrap_or(0),
+            mem_kb: value("mem")
+                .split_whitespace()
+                .nth(1)
+                .and_then(|kb| kb.parse().ok())
+                .unwrap_or(0),
+            rsync: value("rsync"),
+            probed: now(),
+        }
+    }
+
+    /// Whether [`target`] is known to be missing. Without a rustup the targets can't be
+    /// listed, nothing is known then.
+    pub fn lacks_target(&self, target: &str) -> bool {
+        !self.targets.is_empty() && !self.targets.iter().any(|t| t == target)
+    }
+}
+
+fn probe_script(build: &RemoteBuild, toolchain: &str) -> String {
+    format!(
+        "source {env} >/dev/null 2>&1; \
+         printf 'rustc\\t%s\\n' \"$(rustc {plus_tc} --version 2>/dev/null)\"; \
+         printf 'cargo\\t%s\\n' \"$(cargo {plus_tc} --version 2>/dev/null)\"; \
+         printf 'targets\\t%s\\n' \"$(rustup target list --installed --toolchain {tc} 2>/dev/null \
+         | tr '\\n' ' ')\"; \
+         printf 'cores\\t%s\\n' \"$(nproc 2>/dev/null || sysctl -n hw.ncpu 2>/dev/null)\"; \
+         printf 'mem\\t%s\\n' \"$(grep MemTotal /proc/meminfo 2>/dev/null)\"; \
+         printf 'rsync\\t%s\\n' \"$(rsync --version 2>/dev/null | head -n 1)\"",
+        env = shell_path(&build.env),
+        tc = shell_arg(toolchain),
+        plus_tc = shell_arg(&format!("+{}", toolchain)),
+    )
+}
+
+fn cache_path() -> Option<std::path::PathBuf> {
+    xdg::BaseDirectories::with_prefix("cargo-remote")
+        .ok()?
+        .place_cache_file(CAPABILITIES_FILE)
+        .ok()
+}
+
+fn read_cache() -> toml::value::Table {
+    cache_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|content| content.parse::<Value>().ok())
+        .and_then(|value| value.as_table().cloned())
+        .unwrap_or_default()
+}
+
+/// The cached capabilities of the build server [`destination`], however old they are.
+pub fn cached(destination: &str) -> Option<Capabilities> {
+    read_cache()
+        .get(destination)
+        .and_then(Capabilities::from_value)
+}
+
+fn probe(build: &RemoteBuild, toolchain: &str) -> Result<Capabilities, Error> {
+    let what = format!("probe build server {}", build.build_server);
+    let output = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(probe_script(build, toolchain))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .logged_output()
+        .map_err(|e| Error::command(&what, e, &[]))?;
+    if !output.status.success() {
+        return Err(Error::command(&what, output.status, &[]));
+    }
+    let capabilities = Capabilities::parse(toolchain, &String::from_utf8_lossy(&output.stdout));
+    debug!("Capabilities of {}: {:?}", build.build_server, capabilities);
+    if !crate::logging::dry_run() {
+        let mut cache = read_cache();
+        cache.insert(build.build_server.clone(), capabilities.to_value());
+        if let Some(path) = cache_path() {
+            let _ = std::fs::write(path, Value::Table(cache).to_string());
+        }
+    }
+    Ok(capabilities)
+}
+
+/// Checks that the build server of [`build`] can run it: rsync for the rsync transport and the
+/// targets given with `--target` for the toolchain. Cached capabilities are used if they are
+/// recent, they are probed again before a check fails.
+pub fn validate(build: &RemoteBuild) -> Result<(), Error> {
+    let toolchain = build.toolchain.as_ref().unwrap_or(&build.rustup_default);
+    let cached = cached(&build.build_server).filter(|c| {
+        c.toolchain == *toolchain && now().saturating_sub(c.probed) < CAPABILITIES_TTL.as_secs()
+    });
+    let mut fresh = cached.is_none();
+    let mut capabilities = match cached {
+        Some(capabilities) => capabilities,
+        None => probe(build, toolchain)?,
+    };
+    loop {
+        match problem(build, &capabilities) {
+            None => return Ok(()),
+            Some(problem) if fresh || crate::logging::dry_run() => {
+                return Err(Error::Config(problem))
+            }
+            Some(_) => {
+                capabilities = probe(build, toolchain)?;
+                fresh = true;
+            }
+        }
+    }
+}
+
+/// What keeps [`build`] from running on a server with [`capabilities`].
+fn problem(build: &RemoteBuild, capabilities: &Capabilities) -> Option<String> {
+    // an empty probe means it didn't run, e.g. in a dry run
+    if capabilities.rustc.is_empty() && capabilities.cores == 0 {
+        return None;
+    }
+    if build.transport.remote_tool() == Some("rsync") && capabilities.rsync.is_empty() {
+        return Some(format!(
+            "rsync isn't installed on {}, install it or use --transport tar",
+            build.build_server
+        ));
+    }
+    // containers and nix environments bring their own toolchain
+    if build.auto_install_toolchain || build.container.is_some() || build.nix.is_some() {
+        return None;
+    }
+    let missing = build
+        .targets()
+        .into_iter()
+        .find(|target| capabilities.lacks_target(target))?;
+    Some(format!(
+        "The target {} isn't installed for toolchain {} on {} (run 'rustup target add {}' \
+         there or use --auto-install-toolchain)",
+        missing, capabilities.toolchain, build.build_server, missing
+    ))
+}
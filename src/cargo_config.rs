@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+use log::warn;
+
+use crate::util::shell_quote;
+
+/// Project level cargo configuration files, in the order cargo looks for them.
+const CARGO_CONFIG_FILES: [&str; 2] = [".cargo/config.toml", ".cargo/config"];
+
+/// The `.cargo/config.toml` (or legacy `.cargo/config`) of the project, relative to
+/// [`project_dir`].
+pub fn find(project_dir: &Path) -> Option<&'static str> {
+    CARGO_CONFIG_FILES
+        .iter()
+        .copied()
+        .find(|file| project_dir.join(file).is_file())
+}
+
+/// rsync filter rules that transfer the project's cargo configuration even though hidden files
+/// are excluded. Have to come before the rule excluding hidden files.
+pub fn rsync_filters(project_dir: &Path) -> Vec<String> {
+    match find(project_dir) {
+        Some(file) => vec![
+            "--include".to_string(),
+            "/.cargo/".to_string(),
+            "--include".to_string(),
+            format!("/{}", file),
+            "--exclude".to_string(),
+            "/.cargo/*".to_string(),
+        ],
+        None => Vec::new(),
+    }
+}
+
+/// Escapes [`text`] for use in a basic regular expression of sed, delimited by `|`.
+fn sed_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\|.[]*^$&".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn collect_strings<'a>(value: &'a Value, strings: &mut Vec<&'a str>) {
+    match value {
+        Value::String(s) => strings.push(s),
+        Value::Array(values) => values.iter().for_each(|v| collect_strings(v, strings)),
+        Value::Table(table) => table.values().for_each(|v| collect_strings(v, strings)),
+        _ => {}
+    }
+}
+
+/// Shell commands, run in the build directory, that rewrite absolute paths into the local
+/// project in the transferred cargo configuration so they point to [`remote_dir`] (a shell word
+/// like `"$PWD"`) instead. Empty if there is nothing to rewrite. Warns about paths into the local home
+/// directory outside of the project, they most likely don't exist on the build server.
+pub fn remote_rewrite(project_dir: &Path, remote_dir: &str) -> String {
+    let file = match find(project_dir) {
+        Some(file) => file,
+        None => return String::new(),
+    };
+    let content = match std::fs::read_to_string(project_dir.join(file)) {
+        Ok(content) => content,
+        Err(_) => return String::new(),
+    };
+    let mut config = content.parse::<Value>().unwrap_or(Value::Boolean(false));
+    // the workspaces that `[patch]` entries point to are copied along with the project
+    if let Some(table) = config.as_table_mut() {
+        table.remove("patch");
+    }
+    let mut strings = Vec::new();
+    collect_strings(&config, &mut strings);
+    // System paths like `/usr/lib` usually exist on the build server as well, files in the
+    // local home directory usually don't.
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from);
+    for path in strings.iter().map(PathBuf::from) {
+        let in_home = home.as_ref().is_some_and(|home| path.starts_with(home));
+        if in_home && !path.starts_with(project_dir) && path.exists() {
+            warn!(
+                "{} refers to '{}' which is outside of the project and won't be available on \
+                 the build server",
+                file,
+                path.to_string_lossy()
+            );
+        }
+    }
+
+    let project = project_dir.to_string_lossy();
+    if !content.contains(project.as_ref()) {
+        return String::new();
+    }
+    format!(
+        "sed -e {}{}'|g' {file} > {file}.tmp && mv {file}.tmp {file}; ",
+        shell_quote(&format!("s|{}|", sed_escape(&project))),
+        remote_dir,
+        file = file
+    )
+}
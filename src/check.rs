@@ -0,0 +1,179 @@
+use std::process::{Command, Stdio};
+
+use crate::build::RemoteBuild;
+use crate::error::Error;
+use crate::logging::Run;
+use crate::manage::format_size;
+use crate::probe::MIN_FREE_DISK_KB;
+use crate::util::{shell_arg, shell_path};
+
+/// Outcome of a single check, `Warn` doesn't make `check-env` fail.
+#[derive(PartialEq)]
+enum Outcome {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+    detail: String,
+}
+
+/// Shell script that prints one tab separated `name value` line per checked tool.
+fn check_script(build: &RemoteBuild, toolchain: &str) -> String {
+    format!(
+        "source {env} >/dev/null 2>&1; \
+         printf 'transport\\t%s\\n' \"$(command -v {tool})\"; \
+         printf 'cargo\\t%s\\n' \"$(cargo {plus_tc} --version 2>/dev/null)\"; \
+         printf 'rustc\\t%s\\n' \"$(rustc {plus_tc} --version 2>/dev/null)\"; \
+         printf 'targets\\t%s\\n' \"$(rustup target list --installed --toolchain {tc} 2>/dev/null \
+         | tr '\\n' ' ')\"; \
+         printf 'disk\\t%s\\n' \"$( (df -Pk {base} 2>/dev/null || df -Pk ~) | tail -n 1 \
+         | awk '{{print $4}}')\"",
+        env = shell_path(&build.env),
+        tc = shell_arg(toolchain),
+        plus_tc = shell_arg(&format!("+{}", toolchain)),
+        tool = build.transport.remote_tool().unwrap_or("true"),
+        base = shell_path(&build.remote_base_dir)
+    )
+}
+
+fn local_rustc_version(toolchain: Option<&str>) -> Option<String> {
+    let mut rustc = Command::new("rustc");
+    if let Some(toolchain) = toolchain {
+        rustc.arg(format!("+{}", toolchain));
+    }
+    let output = rustc.arg("--version").stderr(Stdio::null()).logged_output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn evaluate(build: &RemoteBuild, toolchain: &str, output: &str) -> Vec<Check> {
+    let value = |name: &str| {
+        output
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    let found = |name: &'static str, value: String, missing: &str| Check {
+        name,
+        outcome: if value.is_empty() {
+            Outcome::Fail
+        } else {
+            Outcome::Ok
+        },
+        detail: if value.is_empty() {
+            missing.to_string()
+        } else {
+            value
+        },
+    };
+
+    let mut checks = Vec::new();
+    if let Some(tool) = build.transport.remote_tool() {
+        checks.push(found(tool, value("transport"), &format!("{} not found", tool)));
+    }
+    checks.push(found(
+        "cargo",
+        value("cargo"),
+        &format!("cargo not found for toolchain {}", toolchain),
+    ));
+
+    let remote_rustc = value("rustc");
+    let mut rustc = found(
+        "rustc",
+        remote_rustc.clone(),
+        &format!("toolchain {} not installed", toolchain),
+    );
+    if rustc.outcome == Outcome::Ok {
+        match local_rustc_version(build.toolchain.as_deref()) {
+            Some(local) if local != remote_rustc => {
+                rustc.outcome = Outcome::Warn;
+                rustc.detail = format!("{} (local: {})", remote_rustc, local);
+            }
+            _ => {}
+        }
+    }
+    checks.push(rustc);
+
+    let installed = value("targets");
+    for target in build.targets() {
+        let present = installed.split_whitespace().any(|t| t == target);
+        checks.push(Check {
+            name: "target",
+            outcome: if present { Outcome::Ok } else { Outcome::Fail },
+            detail: if present {
+                target
+            } else {
+                format!("{} not installed for toolchain {}", target, toolchain)
+            },
+        });
+    }
+
+    checks.push(match value("disk").parse::<u64>() {
+        Ok(free_kb) => Check {
+            name: "disk",
+            outcome: if free_kb < MIN_FREE_DISK_KB {
+                Outcome::Fail
+            } else {
+                Outcome::Ok
+            },
+            detail: format!("{} free in {}", format_size(free_kb), build.remote_base_dir),
+        },
+        Err(_) => Check {
+            name: "disk",
+            outcome: Outcome::Warn,
+            detail: "can't determine free disk space".to_string(),
+        },
+    });
+    checks
+}
+
+/// `cargo remote check-env`: checks over ssh that the build server has everything a build
+/// needs (the tool of the transport, cargo and rustc of the selected toolchain, the targets given with `--target`
+/// and enough free disk space) and prints a summary. Returns 1 as exit code if any check failed.
+pub fn check_env(mut build: RemoteBuild) -> Result<i32, Error> {
+    let toolchain = build
+        .toolchain
+        .clone()
+        .unwrap_or_else(|| build.rustup_default.clone());
+    let output = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(check_script(&build, &toolchain))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .logged_output();
+    build.ssh.stop_master();
+    let what = format!("check build server {}", build.build_server);
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => return Err(Error::command(&what, output.status, &[])),
+        Err(e) => return Err(Error::command(&what, e, &[])),
+    };
+
+    let checks = evaluate(&build, &toolchain, &String::from_utf8_lossy(&output.stdout));
+    println!("Checking build server {}:", build.build_server);
+    for check in &checks {
+        let outcome = match check.outcome {
+            Outcome::Ok => "ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        };
+        println!("  {:<4}  {:<7}  {}", outcome, check.name, check.detail);
+    }
+    let failed = checks.iter().filter(|c| c.outcome == Outcome::Fail).count();
+    if failed > 0 {
+        println!("{} of {} checks failed", failed, checks.len());
+        return Ok(1);
+    }
+    println!("All {} checks passed", checks.len());
+    Ok(0)
+}
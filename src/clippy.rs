@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::build::RemoteBuild;
+use crate::error::Error;
+
+/// The diagnostics of a clippy run counted by level and by lint, printed after its output.
+#[derive(Default)]
+pub struct LintSummary {
+    levels: BTreeMap<String, usize>,
+    lints: BTreeMap<(String, String), usize>,
+}
+
+impl LintSummary {
+    /// Counts the diagnostic of a `compiler-message`. Messages without a location, like the
+    /// number of warnings a crate generated, aren't diagnostics of their own.
+    pub fn add(&mut self, message: &Value) {
+        if message.get("reason").and_then(Value::as_str) != Some("compiler-message") {
+            return;
+        }
+        let diagnostic = match message.get("message") {
+            Some(diagnostic) => diagnostic,
+            None => return,
+        };
+        let located = diagnostic
+            .get("spans")
+            .and_then(Value::as_array)
+            .is_some_and(|spans| !spans.is_empty());
+        let level = diagnostic.get("level").and_then(Value::as_str);
+        let level = match level {
+            Some(level) if located && level != "note" && level != "help" => level.to_string(),
+            _ => return,
+        };
+        *self.levels.entry(level.clone()).or_default() += 1;
+        let code = diagnostic
+            .get("code")
+            .and_then(|code| code.get("code"))
+            .and_then(Value::as_str);
+        if let Some(code) = code {
+            *self.lints.entry((level, code.to_string())).or_default() += 1;
+        }
+    }
+
+    /// The summary with one line per lint, the most frequent first.
+    pub fn lines(&self) -> Vec<String> {
+        if self.levels.is_empty() {
+            return vec!["clippy summary: no warnings or errors".to_string()];
+        }
+        let levels: Vec<String> = self
+            .levels
+            .iter()
+            .map(|(level, count)| match count {
+                1 => format!("1 {}", level),
+                _ => format!("{} {}s", count, level),
+            })
+            .collect();
+        let mut lines = vec![format!("clippy summary: {}", levels.join(", "))];
+        let mut lints: Vec<_> = self.lints.iter().collect();
+        lints.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        let width = lints
+            .first()
+            .map_or(1, |(_, count)| count.to_string().len());
+        for ((level, lint), count) in lints {
+            lines.push(format!(
+                "  {:>width$} {:<7} {}",
+                count,
+                level,
+                lint,
+                width = width
+            ));
+        }
+        lines
+    }
+}
+
+/// `cargo remote clippy [-- <clippy options>]`: runs clippy on the build server with JSON
+/// messages and renders its diagnostics locally, in color and with the paths pointing to the
+/// local files, followed by the number of warnings and errors per lint. JSON output asked for
+/// with `--message-format` is passed on as it is.
+pub fn clippy(mut build: RemoteBuild) -> Result<i32, Error> {
+    build.command = "clippy".to_string();
+    build.remap_paths = true;
+    if !build.json_output() {
+        build.lints = Some(LintSummary::default());
+    }
+    build.run()
+}
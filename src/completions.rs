@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+
+use structopt::clap::{App, AppSettings, Shell};
+use structopt::StructOpt;
+
+use crate::config::{self, Configs};
+use crate::error::Error;
+
+/// Options of `cargo remote completions`.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "cargo remote completions")]
+struct CompletionsOpts {
+    #[structopt(
+        help = "Shell to print the completion script for",
+        raw(possible_values = "&[\"bash\", \"zsh\", \"fish\"]")
+    )]
+    shell: Option<String>,
+
+    // used by the completion scripts, prints the configured names one per line
+    #[structopt(
+        long = "list",
+        raw(possible_values = "&[\"servers\", \"profiles\"]"),
+        raw(hidden = "true")
+    )]
+    list: Option<String>,
+}
+
+/// The command line that lists the configured [`what`] for the completion scripts.
+fn list_command(what: &str) -> String {
+    format!("cargo remote completions -- --list {} 2>/dev/null", what)
+}
+
+/// Replaces the file name completion the bash script has for the values of [`option`] with the
+/// configured [`what`].
+fn complete_bash_values(script: &str, option: &str, what: &str) -> String {
+    let files = "COMPREPLY=($(compgen -f \"${cur}\"))";
+    let names = format!(
+        "COMPREPLY=($(compgen -W \"$({})\" -- \"${{cur}}\"))",
+        list_command(what)
+    );
+    let case = format!(" {})\n", option);
+    match script.find(&case) {
+        Some(start) => {
+            let (before, after) = script.split_at(start);
+            format!("{}{}", before, after.replacen(files, &names, 1))
+        }
+        None => script.to_string(),
+    }
+}
+
+fn bash(script: String) -> String {
+    let mut script = script.replace("_cargo()", "_cargo_remote()");
+    for option in &["-r", "--remote"] {
+        script = complete_bash_values(&script, option, "servers");
+    }
+    script = complete_bash_values(&script, "--profile-name", "profiles");
+    // every other cargo command is left to the completion cargo had before, if any
+    let complete = script.rfind("complete -F _cargo ").unwrap_or(script.len());
+    script.truncate(complete);
+    script.push_str(
+        r#"_cargo_remote_previous=$(complete -p cargo 2>/dev/null | sed -n 's/.*-F \([^ ]*\).*/\1/p')
+if [[ "${_cargo_remote_previous}" != _cargo_remote_complete ]]; then
+    _cargo_remote_cargo="${_cargo_remote_previous}"
+fi
+
+_cargo_remote_complete() {
+    if [[ "${COMP_WORDS[1]}" == remote || -z "${_cargo_remote_cargo}" ]]; then
+        _cargo_remote "$@"
+    else
+        "${_cargo_remote_cargo}" "$@"
+    fi
+}
+
+complete -F _cargo_remote_complete -o bashdefault -o default cargo
+"#,
+    );
+    script
+}
+
+fn zsh(script: String) -> String {
+    let script = script
+        .replace("#compdef cargo\n", "")
+        .replace("_cargo", "_cargo_remote");
+    // the generated script is an autoloaded completion function, this one is sourced
+    let mut script = script
+        .trim_end()
+        .trim_end_matches("_cargo_remote \"$@\"")
+        .to_string();
+    for (option, what) in &[
+        ("'-r+[", "servers"),
+        ("'--remote=[", "servers"),
+        ("'--profile-name=[", "profiles"),
+    ] {
+        if let Some(start) = script.find(option) {
+            if let Some(end) = script[start..].find("]'").map(|i| start + i + 1) {
+                script.insert_str(end, &format!(": :_cargo_remote_{}", what));
+            }
+        }
+    }
+    script.push_str(&format!(
+        r#"_cargo_remote_servers() {{
+    local servers; servers=(${{(f)"$({})"}})
+    _describe 'build servers' servers
+}}
+
+_cargo_remote_profiles() {{
+    local profiles; profiles=(${{(f)"$({})"}})
+    _describe 'profiles' profiles
+}}
+
+# every other cargo command is left to cargo's own completion, if there is one
+_cargo_remote_complete() {{
+    if [[ "${{words[2]}}" == remote ]] || ! (( $+functions[_cargo] )); then
+        _cargo_remote "$@"
+    else
+        _cargo "$@"
+    fi
+}}
+
+compdef _cargo_remote_complete cargo
+"#,
+        list_command("servers"),
+        list_command("profiles")
+    ));
+    script
+}
+
+fn fish(mut script: String) -> String {
+    for (option, what) in &[
+        ("-s r -l remote", "servers"),
+        ("-l profile-name", "profiles"),
+    ] {
+        script.push_str(&format!(
+            "complete -c cargo -n \"__fish_seen_subcommand_from remote\" {} -r -f -a \"({})\"\n",
+            option,
+            list_command(what)
+        ));
+    }
+    script
+}
+
+/// The directory of the project the shell is in, found by its project config. Completions
+/// don't wait for `cargo metadata`.
+fn project_dir() -> PathBuf {
+    let current = std::env::current_dir().unwrap_or_default();
+    current
+        .ancestors()
+        .find(|dir| dir.join(config::PROJECT_CONFIG).exists())
+        .map_or(current.clone(), PathBuf::from)
+}
+
+/// `cargo remote completions <shell>`: prints the completion script of `cargo remote` for bash,
+/// zsh or fish, generated from [`app`]. The values of `--remote` and `--profile-name` are
+/// completed with the build servers and profiles configured at the time of the completion.
+pub fn completions(app: App, options: &[String]) -> Result<i32, Error> {
+    let opts = CompletionsOpts::from_iter(
+        std::iter::once("completions".to_string()).chain(options.to_vec()),
+    );
+    match opts.list.as_deref() {
+        Some("servers") => {
+            let configs = Configs::load(&project_dir(), None);
+            for server in configs.servers() {
+                println!("{}", server.name);
+            }
+            return Ok(0);
+        }
+        Some(_) => {
+            for name in config::profile_names() {
+                println!("{}", name);
+            }
+            return Ok(0);
+        }
+        None => {}
+    }
+    let shell = opts
+        .shell
+        .ok_or_else(|| Error::Config("Missing the shell, use bash, zsh or fish".to_string()))?;
+
+    let mut cargo = App::new("cargo")
+        .bin_name("cargo")
+        .setting(AppSettings::DisableHelpSubcommand)
+        .subcommand(app.name("remote"));
+    let mut generated = Vec::new();
+    let generator: Shell = shell.parse().map_err(Error::Config)?;
+    cargo.gen_completions_to("cargo", generator, &mut generated);
+    let generated = String::from_utf8_lossy(&generated).into_owned();
+    let script = match generator {
+        Shell::Bash => bash(generated),
+        Shell::Zsh => zsh(generated),
+        _ => fish(generated),
+    };
+    print!("{}", script);
+    Ok(0)
+}
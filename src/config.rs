@@ -0,0 +1,199 @@
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+use log::{debug, warn};
+
+/// Name of the per project config file, placed next to the workspace `Cargo.toml`.
+pub const PROJECT_CONFIG: &str = ".cargo-remote.toml";
+/// Name of the global config file in `$XDG_CONFIG_HOME/cargo-remote/`.
+pub const GLOBAL_CONFIG: &str = "config.toml";
+/// Name of the global config file used by older versions, still read if present.
+pub const LEGACY_GLOBAL_CONFIG: &str = "cargo-remote.toml";
+
+/// Tries to parse the file [`config_path`]. Logs warnings and returns [`None`] if errors occur
+/// during reading or parsing, [`Some(Value)`] otherwise.
+pub fn config_from_file(config_path: &Path) -> Option<Value> {
+    let config_file = std::fs::read_to_string(config_path)
+        .map_err(|e| {
+            warn!(
+                "Can't parse config file '{}' (error: {})",
+                config_path.to_string_lossy(),
+                e
+            );
+        })
+        .ok()?;
+
+    let value = config_file
+        .parse::<Value>()
+        .map_err(|e| {
+            warn!(
+                "Can't parse config file '{}' (error: {})",
+                config_path.to_string_lossy(),
+                e
+            );
+        })
+        .ok()?;
+
+    Some(value)
+}
+
+/// Returns the path of the global config file [`name`] if it exists.
+fn global_config_path(name: &str) -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("cargo-remote")
+        .ok()
+        .and_then(|base| base.find_config_file(name))
+}
+
+/// All config layers that apply to a project, ordered from highest to lowest precedence.
+///
+/// The order is:
+/// 1. the selected profile from the global config (`[profile.<name>]`)
+/// 2. the project config (`.cargo-remote.toml`)
+/// 3. the top level of the global config (`config.toml`)
+/// 4. the legacy global config (`cargo-remote.toml`)
+///
+/// Command line flags are not part of this list, they always take precedence over every layer.
+pub struct Configs {
+    pub layers: Vec<Value>,
+}
+
+impl Configs {
+    /// Loads all config files for the project in [`project_dir`]. The profile is taken from
+    /// [`profile_name`] if given, otherwise from the `profile` key of the project config or the
+    /// `default_profile` key of the global config.
+    pub fn load(project_dir: &Path, profile_name: Option<&str>) -> Configs {
+        let project_path = project_dir.join(PROJECT_CONFIG);
+        let project = if project_path.exists() {
+            config_from_file(&project_path)
+        } else {
+            None
+        };
+        let global = global_config_path(GLOBAL_CONFIG).and_then(|p| config_from_file(&p));
+        let legacy = global_config_path(LEGACY_GLOBAL_CONFIG).and_then(|p| config_from_file(&p));
+
+        let profile_name = profile_name
+            .map(String::from)
+            .or_else(|| lookup_str(project.as_ref(), "profile"))
+            .or_else(|| lookup_str(global.as_ref(), "default_profile"));
+        let profile = profile_name.and_then(|name| {
+            debug!("Using config profile '{}'", name);
+            let profile = global
+                .as_ref()
+                .and_then(|g| g.get("profile"))
+                .and_then(|p| p.get(&name))
+                .cloned();
+            if profile.is_none() {
+                warn!(
+                    "Config profile '{}' is not defined in the global config",
+                    name
+                );
+            }
+            profile
+        });
+
+        Configs {
+            layers: vec![profile, project, global, legacy]
+                .into_iter()
+                .flatten()
+                .collect(),
+        }
+    }
+
+    /// The `remote` of the config layer with the highest precedence that defines one.
+    pub fn remote(&self) -> Option<String> {
+        self.layers
+            .iter()
+            .find_map(|layer| lookup_str(Some(layer), "remote"))
+    }
+
+    /// Looks up the build server [`name`] in the `[servers]` tables. Only the definition from the
+    /// layer with the highest precedence is used, server definitions aren't merged.
+    pub fn server(&self, name: &str) -> Option<Server> {
+        self.layers
+            .iter()
+            .find_map(|layer| layer.get("servers").and_then(|s| s.get(name)))
+            .map(|settings| Server::from_config(name, settings))
+    }
+
+    /// All configured build servers, each name only once with its highest precedence definition.
+    pub fn servers(&self) -> Vec<Server> {
+        let mut names: Vec<&String> = Vec::new();
+        for layer in &self.layers {
+            if let Some(servers) = layer.get("servers").and_then(Value::as_table) {
+                for name in servers.keys() {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        names
+            .into_iter()
+            .filter_map(|name| self.server(name))
+            .collect()
+    }
+}
+
+/// A named build server from a `[servers.<name>]` table. All keys besides `host` and `user`
+/// are regular config keys that override the other config layers for this server.
+pub struct Server {
+    pub name: String,
+    /// `[user@]host` as passed to ssh and rsync.
+    pub destination: String,
+    pub settings: Value,
+}
+
+impl Server {
+    fn from_config(name: &str, settings: &Value) -> Server {
+        let host = lookup_str(Some(settings), "host").unwrap_or_else(|| name.to_string());
+        let mut server = Server {
+            name: name.to_string(),
+            destination: String::new(),
+            settings: settings.clone(),
+        };
+        server.set_host(&host);
+        server
+    }
+
+    /// Connects to [`host`] with the configured `user`, e.g. the address of a provisioned
+    /// instance.
+    pub fn set_host(&mut self, host: &str) {
+        self.destination = match lookup_str(Some(&self.settings), "user") {
+            Some(user) => format!("{}@{}", user, host),
+            None => host.to_string(),
+        };
+    }
+}
+
+/// The names of the profiles in the global config.
+pub fn profile_names() -> Vec<String> {
+    global_config_path(GLOBAL_CONFIG)
+        .and_then(|path| config_from_file(&path))
+        .and_then(|global| {
+            let profiles = global.get("profile")?.as_table()?;
+            Some(profiles.keys().cloned().collect())
+        })
+        .unwrap_or_default()
+}
+
+fn lookup_str(config: Option<&Value>, key: &str) -> Option<String> {
+    config
+        .and_then(|c| c.get(key))
+        .and_then(Value::as_str)
+        .map(String::from)
+}
+
+/// Reads the string array [`key`] from [`config`], ignoring entries that aren't strings.
+pub fn string_list(config: &Value, key: &str) -> Vec<String> {
+    config
+        .get(key)
+        .and_then(Value::as_array)
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
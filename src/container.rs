@@ -0,0 +1,72 @@
+use crate::util::shell_quote;
+
+/// Where the build directory is mounted inside the container.
+pub const WORKSPACE: &str = "/workspace";
+
+/// The container runtime on the build server.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Engine {
+    Docker,
+    /// Rootless podman, which maps the ssh user into the container with `--userns=keep-id`.
+    Podman,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "docker" => Some(Engine::Docker),
+            "podman" => Some(Engine::Podman),
+            _ => None,
+        }
+    }
+}
+
+/// A container image the remote cargo runs in instead of the toolchain of the build server.
+pub struct Container {
+    pub image: String,
+    pub engine: Engine,
+}
+
+impl Container {
+    /// Wraps the shell [`command`] so it runs in a fresh container with the current directory
+    /// (the build directory) mounted at [`WORKSPACE`]. The variables in [`env`] are passed on
+    /// from the remote shell. The container runs as the ssh user, so the files in the build
+    /// directory stay owned by it, and keeps its cargo registry cache in the target directory.
+    pub fn wrap(&self, command: &str, env: &[&str], tty: bool, host_network: bool) -> String {
+        let mut run = match self.engine {
+            Engine::Docker => vec![
+                "docker run --rm -i".to_string(),
+                "--user \"$(id -u):$(id -g)\"".to_string(),
+                format!("-v \"$PWD\":{}", WORKSPACE),
+            ],
+            // Root in a rootless container is the ssh user already, keep-id maps it to the same
+            // uid inside. `:Z` relabels the mount on SELinux hosts, where podman is common.
+            Engine::Podman => vec![
+                "podman run --rm -i".to_string(),
+                "--userns=keep-id".to_string(),
+                format!("-v \"$PWD\":{}:Z", WORKSPACE),
+            ],
+        };
+        run.push(format!("-w {}", WORKSPACE));
+        run.push(format!("-e CARGO_HOME={}/target/.cargo-home", WORKSPACE));
+        if tty {
+            run.push("-t".to_string());
+        }
+        if host_network {
+            // forwarded ports end at the build server's localhost
+            run.push("--network host".to_string());
+        }
+        run.extend(env.iter().map(|name| format!("-e {}", name)));
+        run.push(shell_quote(&self.image));
+        run.push(format!("sh -c {}", shell_quote(command)));
+        run.join(" ")
+    }
+
+    /// Translates [`path`] inside the container to the path on the build server.
+    pub fn host_path(&self, path: &str, build_path: &str) -> String {
+        match path.strip_prefix(WORKSPACE) {
+            Some(rest) => format!("{}{}", build_path.trim_end_matches('/'), rest),
+            None => path.to_string(),
+        }
+    }
+}
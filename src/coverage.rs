@@ -0,0 +1,179 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+use crate::build::{open_in_browser, Extra, RemoteBuild};
+use crate::debuginfo;
+use crate::error::Error;
+use crate::util::slash_path;
+
+/// The report formats of `cargo llvm-cov` with the file a report is written to by default.
+const FORMATS: [(&str, &str); 5] = [
+    ("--lcov", "lcov.info"),
+    ("--json", "coverage.json"),
+    ("--cobertura", "cobertura.xml"),
+    ("--codecov", "codecov.json"),
+    ("--text", "coverage.txt"),
+];
+
+/// `cargo remote coverage`: the report that is copied back.
+pub struct Coverage {
+    /// The file or, for HTML reports, the directory in the project.
+    pub report: PathBuf,
+    /// Whether it's an HTML report, its links are relative and its paths are left alone.
+    pub html: bool,
+    /// Open the HTML report locally, asked for with `--open`.
+    pub open: bool,
+}
+
+/// The value of the option [`name`] before the `--` in [`options`].
+fn value(options: &[String], name: &str) -> Option<String> {
+    let mut options = options.iter().take_while(|o| *o != "--");
+    let prefix = format!("{}=", name);
+    while let Some(option) = options.next() {
+        match option.strip_prefix(&prefix) {
+            Some(value) => return Some(value.to_string()),
+            None if option == name => return options.next().cloned(),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Inserts [`option`] into [`options`] in front of the `--`.
+fn insert(options: &mut Vec<String>, option: &str) {
+    let end = options
+        .iter()
+        .position(|o| o == "--")
+        .unwrap_or(options.len());
+    options.insert(end, option.to_string());
+}
+
+/// `cargo remote coverage [-- <llvm-cov options> [-- <test arguments>]]`: runs the tests on the
+/// build server with `cargo llvm-cov` and copies the report back into the project. Without
+/// another format it's an lcov report in `lcov.info`, with `--html` the HTML report ends up in
+/// `target/llvm-cov/html` and `--open` opens it locally. The paths of the sources in the report
+/// are rewritten to the local ones, so it can be browsed and uploaded from here.
+pub fn coverage(mut build: RemoteBuild) -> Result<i32, Error> {
+    let before_separator = |options: &[String], option: &str| {
+        options
+            .iter()
+            .take_while(|o| *o != "--")
+            .any(|o| o == option)
+    };
+    // a browser on the build server is of no use
+    let open = before_separator(&build.options, "--open");
+    if let Some(position) = build
+        .options
+        .iter()
+        .position(|o| o == "--open")
+        .filter(|_| open)
+    {
+        build.options.remove(position);
+    }
+    let html = open || before_separator(&build.options, "--html");
+    let (remote, report) = if html {
+        if !before_separator(&build.options, "--html") {
+            insert(&mut build.options, "--html");
+        }
+        match value(&build.options, "--output-dir") {
+            Some(dir) => {
+                let dir = slash_path(Path::new(&dir));
+                let local = build.project_dir.join(&dir).join("html");
+                (format!("{}/html/", dir.trim_end_matches('/')), local)
+            }
+            None => {
+                insert(&mut build.options, "--output-dir");
+                insert(&mut build.options, "target/llvm-cov");
+                let local = build.local_target_dir.join("llvm-cov").join("html");
+                ("target/llvm-cov/html/".to_string(), local)
+            }
+        }
+    } else {
+        let format = FORMATS
+            .iter()
+            .find(|(format, _)| before_separator(&build.options, format));
+        if format.is_none() {
+            insert(&mut build.options, "--lcov");
+        }
+        let output = value(&build.options, "--output-path").unwrap_or_else(|| {
+            let output = format.map_or(FORMATS[0].1, |(_, output)| output);
+            insert(&mut build.options, "--output-path");
+            insert(&mut build.options, output);
+            output.to_string()
+        });
+        let remote = slash_path(Path::new(&output));
+        (remote, build.project_dir.join(output))
+    };
+    if Path::new(&remote).is_absolute() {
+        return Err(Error::Config(
+            "The coverage report is copied back from the build directory, its path has to be \
+             relative"
+                .to_string(),
+        ));
+    }
+    build.clear_output(&remote);
+    build.command = "llvm-cov".to_string();
+    build.retrieve.push(Extra {
+        local: report.clone(),
+        remote,
+    });
+    build.coverage = Some(Coverage { report, html, open });
+    build.run()
+}
+
+/// Rewrites the paths of the sources on the build server in the report that was copied back
+/// to the local ones, and opens an HTML report if it was asked for.
+pub fn localize(build: &RemoteBuild) {
+    let coverage = match &build.coverage {
+        Some(coverage) if coverage.report.exists() => coverage,
+        _ => return,
+    };
+    if coverage.html {
+        let index = coverage.report.join("index.html");
+        info!("The coverage report is in {}", index.display());
+        if coverage.open {
+            open_in_browser(&index);
+        }
+        return;
+    }
+    let mut map = match debuginfo::source_map(build) {
+        Ok(map) => map,
+        Err(e) => {
+            warn!(
+                "Failed to find the source directories on the build server, the coverage \
+                 report has the paths there: {}",
+                e
+            );
+            return;
+        }
+    };
+    // the most specific directory first, patched dependencies can be inside the build directory
+    map.sort_by_key(|(remote, _)| std::cmp::Reverse(remote.len()));
+    let report = match std::fs::read_to_string(&coverage.report) {
+        Ok(report) => report,
+        Err(e) => {
+            warn!("Failed to read {}: {}", coverage.report.display(), e);
+            return;
+        }
+    };
+    let mut localized = report.clone();
+    for (remote, local) in &map {
+        debug!(
+            "Rewriting {} to {} in the coverage report",
+            remote,
+            local.display()
+        );
+        localized = localized.replace(
+            &format!("{}/", remote.trim_end_matches('/')),
+            &format!("{}/", slash_path(local).trim_end_matches('/')),
+        );
+    }
+    if localized != report {
+        if let Err(e) = std::fs::write(&coverage.report, localized) {
+            warn!("Failed to write {}: {}", coverage.report.display(), e);
+            return;
+        }
+    }
+    info!("The coverage report is in {}", coverage.report.display());
+}
@@ -0,0 +1,228 @@
+use std::io::{BufRead, BufReader, IsTerminal};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::build::{exit_code, RemoteBuild};
+use crate::debuginfo::{self, is_debug_info};
+use crate::error::Error;
+use crate::exec::in_build_dir;
+use crate::logging::Run;
+use crate::util::{shell_arg, shell_path, shell_quote};
+
+/// The port the debug server listens on, on the build server and locally.
+pub const DEFAULT_PORT: u16 = 2345;
+
+/// How long the local debugger waits for the debug server to start.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The debug server started on the build server and the local debugger that connects to it.
+#[derive(Clone, Copy)]
+pub enum Debugger {
+    Gdb,
+    Lldb,
+}
+
+impl Debugger {
+    /// The debugger called [`name`], gdb if there is none.
+    pub fn from_name(name: Option<&str>) -> Debugger {
+        match name {
+            Some("lldb") => Debugger::Lldb,
+            _ => Debugger::Gdb,
+        }
+    }
+
+    fn server(self) -> &'static str {
+        match self {
+            Debugger::Gdb => "gdbserver",
+            Debugger::Lldb => "lldb-server",
+        }
+    }
+
+    /// The shell command that starts the debug server for [`program`] with [`args`] on
+    /// [`port`]. It only accepts connections through the forwarded port.
+    fn server_command(self, port: u16, program: &str, args: &[String]) -> String {
+        let args: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+        let server = match self {
+            Debugger::Gdb => format!("gdbserver 127.0.0.1:{} {}", port, program),
+            Debugger::Lldb => format!("lldb-server gdbserver 127.0.0.1:{} -- {}", port, program),
+        };
+        format!(
+            "command -v {name} >/dev/null || {{ echo \"error: {name} isn't installed on the \
+             build server\" >&2; exit 127; }}; exec {} {}",
+            server,
+            args.join(" "),
+            name = self.server()
+        )
+    }
+
+    /// The local debugger for [`binary`] connected to [`port`], with the source map of the
+    /// build if there is one.
+    fn client(self, port: u16, binary: &Path, target_dir: &Path) -> Command {
+        let (program, script, run, connect) = match self {
+            Debugger::Gdb => ("gdb", "-x", "-ex", "target remote"),
+            Debugger::Lldb => ("lldb", "-s", "-o", "gdb-remote"),
+        };
+        let mut command = Command::new(program);
+        let source_map = target_dir
+            .join(debuginfo::SOURCE_MAP)
+            .with_extension(program);
+        if source_map.exists() {
+            command.arg(script).arg(source_map);
+        }
+        command
+            .arg(run)
+            .arg(format!("{} localhost:{}", connect, port))
+            .arg(binary);
+        command
+    }
+}
+
+/// `cargo remote debug`: how the built program is debugged.
+pub struct Debug {
+    pub debugger: Debugger,
+    /// See `--debug-port`.
+    pub port: u16,
+    /// Start the local debugger, see `--launch-debugger`.
+    pub launch: bool,
+    /// The arguments for the program, after the second `--`.
+    pub args: Vec<String>,
+}
+
+/// `cargo remote debug [-- <build options> [-- <program arguments>]]`: builds the project on
+/// the build server, copies the binary back and starts it there under gdbserver or lldb-server,
+/// with its port forwarded to the local machine. The command that connects the local debugger
+/// is printed, with `--launch-debugger` the debugger is started right away.
+pub fn debug(mut build: RemoteBuild, mut debug: Debug) -> Result<i32, Error> {
+    if build.container.is_some() {
+        return Err(Error::Config(
+            "'cargo remote debug' doesn't support builds in containers".to_string(),
+        ));
+    }
+    let separator = build.options.iter().position(|o| o == "--");
+    if let Some(separator) = separator {
+        debug.args = build.options.split_off(separator + 1);
+        build.options.pop();
+    }
+    build.command = "build".to_string();
+    build.copy_back = None;
+    build.copy_back_artifacts = true;
+    build.debug = Some(debug);
+    build.run()
+}
+
+/// Whether the artifact at [`path`] is a library and can't be debugged on its own.
+fn is_library(path: &str) -> bool {
+    [".so", ".dylib", ".dll", ".lib", ".a"]
+        .iter()
+        .any(|extension| path.ends_with(extension))
+}
+
+/// Starts the debug server for the binary that was built and copied back, and the local
+/// debugger if it was asked for. Returns the exit code of the debugger or the debug server.
+pub fn start(build: &RemoteBuild) -> Result<i32, Error> {
+    let debug = match &build.debug {
+        Some(debug) => debug,
+        None => return Ok(0),
+    };
+    let binaries: Vec<&String> = build
+        .artifacts
+        .iter()
+        .filter(|artifact| !is_debug_info(artifact) && !is_library(artifact))
+        .collect();
+    let binary = match binaries.as_slice() {
+        [binary] => binary,
+        [] => {
+            return Err(Error::Config(
+                "cargo didn't build a binary to debug".to_string(),
+            ))
+        }
+        _ => {
+            return Err(Error::Config(format!(
+                "cargo built {} binaries, pick the one to debug with --bin, --example or --test",
+                binaries.len()
+            )))
+        }
+    };
+    let remote_target_dir = match &build.remote_target_dir {
+        Some(dir) => dir.clone(),
+        None => build.remote_target(),
+    };
+    let program = shell_path(&format!("{}/{}", remote_target_dir, binary));
+    let local_binary = build.local_target_dir.join(binary.as_str());
+    let command = debug
+        .debugger
+        .server_command(debug.port, &program, &debug.args);
+
+    let mut client = debug
+        .debugger
+        .client(debug.port, &local_binary, &build.local_target_dir);
+    let mut ssh = build.ssh.command();
+    ssh.arg("-o")
+        .arg("ExitOnForwardFailure=yes")
+        .arg("-L")
+        .arg(format!("{port}:127.0.0.1:{port}", port = debug.port));
+    debug!("Forwarding local port {} to the debug server", debug.port);
+    if !debug.launch {
+        let args: Vec<String> = std::iter::once(client.get_program())
+            .chain(client.get_args())
+            .map(|arg| shell_arg(&arg.to_string_lossy()))
+            .collect();
+        info!(
+            "Starting {} on the build server, connect to it with:\n    {}",
+            debug.debugger.server(),
+            args.join(" ")
+        );
+        if std::io::stdin().is_terminal() {
+            ssh.arg("-t");
+        }
+        let status = ssh
+            .arg(build.ssh.destination())
+            .arg(in_build_dir(build, &command))
+            .logged_status()
+            .map_err(|e| Error::command("run the debug server on the build server", e, &[]))?;
+        return Ok(exit_code(status));
+    }
+
+    let mut server = ssh
+        .arg(build.ssh.destination())
+        .arg(in_build_dir(build, &command))
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .logged_spawn()
+        .map_err(|e| Error::command("run the debug server on the build server", e, &[]))?;
+    // the server says when it accepts connections, the rest of its output is passed on
+    let (listening, started) = mpsc::channel();
+    if let Some(stderr) = server.stderr.take() {
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("{}", line);
+                if line.contains("Listening") {
+                    let _ = listening.send(());
+                }
+            }
+        });
+    }
+    if started.recv_timeout(STARTUP_TIMEOUT).is_err() {
+        let _ = server.kill();
+        let status = server.wait();
+        return Err(Error::Run(match status {
+            Ok(status) if status.code().is_some() => format!(
+                "The debug server on the build server exited with {}",
+                status
+            ),
+            _ => "The debug server on the build server didn't start".to_string(),
+        }));
+    }
+    let status = client
+        .logged_status()
+        .map_err(|e| Error::command("start the local debugger", e, &[]));
+    if let Err(e) = server.kill() {
+        warn!("Failed to stop the debug server: {}", e);
+    }
+    let _ = server.wait();
+    Ok(exit_code(status?))
+}
@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use log::{debug, info, warn};
+
+use crate::build::RemoteBuild;
+use crate::container::WORKSPACE;
+use crate::logging::Run;
+use crate::platform::RemoteOs;
+use crate::util::shell_path;
+
+/// The name of the debugger scripts next to the copied back binaries, with `.gdb` and `.lldb`
+/// extensions.
+pub const SOURCE_MAP: &str = "cargo-remote-source-map";
+
+/// Whether [`path`] is the split debug info of a binary: a `.dSYM` bundle on macOS, a `.pdb`
+/// file on Windows or a `.dwp` package with `split-debuginfo = "packed"` on Linux.
+pub fn is_debug_info(path: &str) -> bool {
+    [".dSYM", ".pdb", ".dwp"]
+        .iter()
+        .any(|extension| path.ends_with(extension))
+}
+
+/// The directories on the build server the debug info of the binaries refers to, with the
+/// local directories they are copies of. Relative ones are resolved on the build server.
+fn source_dirs(build: &RemoteBuild) -> Vec<(String, PathBuf)> {
+    let mut dirs = vec![(build.build_path.clone(), build.project_dir.clone())];
+    for workspace in &build.patches.workspaces {
+        dirs.push((workspace.remote_dir.clone(), workspace.local_dir.clone()));
+    }
+    dirs
+}
+
+/// The absolute paths of [`dirs`] on the build server, empty for the ones that don't exist.
+fn resolve(build: &RemoteBuild, dirs: &[(String, PathBuf)]) -> Result<Vec<String>, String> {
+    let script: Vec<String> = dirs
+        .iter()
+        .map(|(remote, _)| format!("(cd {} 2>/dev/null && pwd) || echo", shell_path(remote)))
+        .collect();
+    let output = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(script.join("; "))
+        .stdin(Stdio::null())
+        .logged_output()
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("ssh exited with {}", output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+/// The absolute paths of the sources on the build server, as the compiler saw them, with the
+/// local directories they are copies of.
+pub fn source_map(build: &RemoteBuild) -> Result<Vec<(String, PathBuf)>, String> {
+    let dirs = source_dirs(build);
+    let mut map: Vec<(String, PathBuf)> = resolve(build, &dirs)?
+        .into_iter()
+        .zip(dirs.into_iter().map(|(_, local)| local))
+        .filter(|(remote, _)| !remote.is_empty())
+        .collect();
+    // builds in a container see the project there
+    if build.container.is_some() {
+        map.push((WORKSPACE.to_string(), build.project_dir.clone()));
+    }
+    Ok(map)
+}
+
+/// Writes the debugger scripts that point the source paths in the debug info of the binaries
+/// that were copied back to the local files, so a debugger shows the sources of remotely built
+/// binaries: `set substitute-path` for gdb and `target.source-map` for lldb. They are only
+/// mentioned when they change.
+pub fn write_source_map(build: &RemoteBuild) {
+    if build.remote_os == RemoteOs::Windows {
+        return;
+    }
+    let map = match source_map(build) {
+        Ok(map) => map,
+        Err(e) => {
+            warn!(
+                "Failed to find the source directories on the build server: {}",
+                e
+            );
+            return;
+        }
+    };
+    let quote = |path: &Path| format!("\"{}\"", path.to_string_lossy().replace('"', "\\\""));
+    let mut gdb = String::new();
+    let mut lldb = String::new();
+    for (remote, local) in &map {
+        debug!("Mapping the sources in {} to {}", remote, local.display());
+        let remote = quote(Path::new(remote));
+        gdb.push_str(&format!(
+            "set substitute-path {} {}\n",
+            remote,
+            quote(local)
+        ));
+        lldb.push_str(&format!(
+            "settings append target.source-map {} {}\n",
+            remote,
+            quote(local)
+        ));
+    }
+    let script = build.local_target_dir.join(SOURCE_MAP);
+    if std::fs::read_to_string(script.with_extension("gdb"))
+        .ok()
+        .as_ref()
+        == Some(&gdb)
+    {
+        return;
+    }
+    let written = std::fs::create_dir_all(&build.local_target_dir)
+        .and_then(|_| std::fs::write(script.with_extension("gdb"), gdb))
+        .and_then(|_| std::fs::write(script.with_extension("lldb"), lldb));
+    match written {
+        Ok(()) => info!(
+            "To debug the binaries with the local sources, load {} in gdb (-x) or {} in lldb \
+             (-s)",
+            script.with_extension("gdb").display(),
+            script.with_extension("lldb").display()
+        ),
+        Err(e) => warn!("Failed to write {}: {}", script.display(), e),
+    }
+}
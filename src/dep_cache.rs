@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use crate::build::RemoteBuild;
+use crate::util::{shell_arg, shell_path, short_hash};
+
+/// Directory below the remote base directory that holds the cached target directories.
+const CACHE_DIR: &str = ".dep-cache";
+
+/// How many cache entries are kept per build directory, the oldest ones are removed.
+const KEEP_ENTRIES: usize = 3;
+
+/// Shell function that recreates the target directory in `$1` at `$2`, leaving out the
+/// incremental compilation data. rustc and the linkers replace their outputs instead of writing
+/// into them, so compiled crates are hard linked. Everything else, like the fingerprints, is
+/// rewritten in place by cargo and copied with its modification time.
+const LINK_TREE: &str = "cr_link_tree() ( \
+    dest=$(mkdir -p \"$2\" && cd \"$2\" && pwd) && cd \"$1\" || exit 1; \
+    find . -name incremental -prune -o -type d \
+    -exec sh -c 'for d; do mkdir -p \"$0/$d\"; done' \"$dest\" {} + && \
+    find . -name incremental -prune -o -type f \\( -name '*.rlib' -o -name '*.rmeta' \
+    -o -name '*.so' -o -name '*.dylib' -o -name '*.a' \\) \
+    -exec sh -c 'for f; do ln -f \"$f\" \"$0/$f\"; done' \"$dest\" {} + && \
+    find . -name incremental -prune -o -type f ! \\( -name '*.rlib' -o -name '*.rmeta' \
+    -o -name '*.so' -o -name '*.dylib' -o -name '*.a' \\) \
+    -exec sh -c 'for f; do cp -p \"$f\" \"$0/$f\"; done' \"$dest\" {} + )";
+
+/// The part of the cache key that is known locally: a hash of the project's Cargo.lock. `None`
+/// if the project has none yet.
+pub fn lock_hash(project_dir: &Path) -> Option<String> {
+    let lock = std::fs::read(project_dir.join("Cargo.lock")).ok()?;
+    Some(short_hash(&lock))
+}
+
+/// Directory of the cache entries of [`build`]'s project. It is named like the build directory,
+/// two projects with the same name don't share their entries then.
+fn cache_dir(build: &RemoteBuild) -> String {
+    let build_dir = build.build_path.trim_end_matches('/');
+    format!(
+        "{}/{}/{}",
+        build.remote_base_dir.trim_end_matches('/'),
+        CACHE_DIR,
+        build_dir.rsplit('/').next().unwrap_or(build_dir)
+    )
+}
+
+/// Shell commands that seed a target directory that doesn't exist yet from the cache entry for
+/// [`lock_hash`] and the rustc version on the build server. They run in the home directory and
+/// set `$cr_cache`, the entry [`store_script`] fills after the build.
+pub fn seed_script(build: &RemoteBuild, lock_hash: &str) -> String {
+    let rustc = match &build.toolchain {
+        Some(toolchain) => format!("rustc {}", shell_arg(&format!("+{}", toolchain))),
+        None => "rustc".to_string(),
+    };
+    format!(
+        "{link_tree}; cr_cache={cache}/{lock}-$({rustc} -vV 2>/dev/null | cksum | cut -d' ' -f1); \
+         if [ ! -d {target} ] && [ -d \"$cr_cache\" ]; then \
+         echo 'Seeding the target directory from the dependency cache'; \
+         cr_link_tree \"$cr_cache\" {target} && touch \"$cr_cache\"; fi; ",
+        link_tree = LINK_TREE,
+        cache = shell_path(&cache_dir(build)),
+        lock = lock_hash,
+        rustc = rustc,
+        target = shell_path(&build.remote_target())
+    )
+}
+
+/// Shell commands that store the target directory in the cache entry `$cr_cache` if there is
+/// none yet, and remove the oldest entries of the project.
+pub fn store_script(build: &RemoteBuild) -> String {
+    format!(
+        "cd && if [ -d {target} ] && [ ! -d \"$cr_cache\" ]; then \
+         rm -rf \"$cr_cache.tmp\" && cr_link_tree {target} \"$cr_cache.tmp\" && \
+         mv \"$cr_cache.tmp\" \"$cr_cache\" && \
+         cd {cache} && ls -t | tail -n +{keep} | while read -r entry; do rm -rf \"$entry\"; done; fi",
+        target = shell_path(&build.remote_target()),
+        cache = shell_path(&cache_dir(build)),
+        keep = KEEP_ENTRIES + 1
+    )
+}
@@ -0,0 +1,250 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::thread;
+use std::time::Instant;
+
+use cargo_metadata::Package;
+use log::{debug, error, info, warn};
+
+use crate::build::{exit_code, RemoteBuild};
+use crate::error::Error;
+use crate::history;
+use crate::notify;
+use crate::timings::Phase;
+use crate::upload;
+
+/// Removes the packages selected with `-p`/`--package` from [`options`] and returns them.
+/// Options after a `--` belong to the program run by cargo and are left alone.
+fn take_packages(options: &mut Vec<String>) -> Vec<String> {
+    let mut packages = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = std::mem::take(options).into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            rest.push(arg);
+            rest.extend(args.by_ref());
+        } else if arg == "-p" || arg == "--package" {
+            packages.extend(args.next());
+        } else if let Some(package) = arg.strip_prefix("--package=") {
+            packages.push(package.to_string());
+        } else if let Some(package) = arg.strip_prefix("-p").filter(|p| !p.is_empty()) {
+            packages.push(package.to_string());
+        } else {
+            rest.push(arg);
+        }
+    }
+    *options = rest;
+    packages
+}
+
+/// Deals [`items`] out to [`servers`] parts like cards, so each part is about the same size.
+fn round_robin(items: &[String], servers: usize) -> Vec<Vec<String>> {
+    let servers = servers.min(items.len()).max(1);
+    (0..servers)
+        .map(|index| items.iter().skip(index).step_by(servers).cloned().collect())
+        .collect()
+}
+
+/// [`root`] and all members it depends on, directly or indirectly.
+fn closure<'a>(deps: &BTreeMap<&'a str, Vec<&'a str>>, root: &'a str) -> BTreeSet<&'a str> {
+    let mut built = BTreeSet::new();
+    let mut stack = vec![root];
+    while let Some(name) = stack.pop() {
+        if built.insert(name) {
+            stack.extend(deps.get(name).into_iter().flatten());
+        }
+    }
+    built
+}
+
+/// Splits the workspace [`members`] into at most [`servers`] parts of about the same number of
+/// crates to compile. The members no other member depends on are independent of each other and
+/// are spread across the parts, each of them builds the members it depends on as well. Every
+/// other member is added to the first part that builds it anyway, so its tests and binaries
+/// are built exactly once.
+fn partition_workspace(members: &[Package], servers: usize) -> Vec<Vec<String>> {
+    let names: BTreeSet<&str> = members.iter().map(|p| p.name.as_str()).collect();
+    let deps: BTreeMap<&str, Vec<&str>> = members
+        .iter()
+        .map(|package| {
+            let member_deps = package
+                .dependencies
+                .iter()
+                .filter(|dep| dep.source.is_none() && dep.name != package.name)
+                .filter_map(|dep| names.get(dep.name.as_str()).copied())
+                .collect();
+            (package.name.as_str(), member_deps)
+        })
+        .collect();
+    let depended: BTreeSet<&str> = deps.values().flatten().copied().collect();
+    let mut roots: Vec<(&str, BTreeSet<&str>)> = Vec::new();
+    for name in names.iter().filter(|name| !depended.contains(*name)) {
+        roots.push((name, closure(&deps, name)));
+    }
+    // members in a dependency cycle, e.g. through dev-dependencies, aren't reached from a root
+    for name in &names {
+        if !roots.iter().any(|(_, built)| built.contains(name)) {
+            roots.push((name, closure(&deps, name)));
+        }
+    }
+    roots.sort_by_key(|(_, built)| std::cmp::Reverse(built.len()));
+
+    let mut parts: Vec<(Vec<String>, BTreeSet<&str>)> =
+        vec![(Vec::new(), BTreeSet::new()); servers.min(roots.len()).max(1)];
+    for (root, built) in roots {
+        let part = parts.iter_mut().min_by_key(|(_, b)| b.len()).unwrap();
+        part.0.push(root.to_string());
+        part.1.extend(built);
+    }
+    for name in &names {
+        if !parts
+            .iter()
+            .any(|(packages, _)| packages.iter().any(|p| p == name))
+        {
+            if let Some(part) = parts.iter_mut().find(|(_, built)| built.contains(name)) {
+                part.0.push(name.to_string());
+            }
+        }
+    }
+    for (packages, built) in &parts {
+        debug!(
+            "Workspace part {:?} builds {} members",
+            packages,
+            built.len()
+        );
+    }
+    parts.into_iter().map(|(packages, _)| packages).collect()
+}
+
+/// Runs one part of a distributed build and returns its exit code.
+fn run_part(mut build: RemoteBuild) -> i32 {
+    let started = Instant::now();
+    build.prepare_command();
+    let result = build
+        .validate()
+        .and_then(|_| build.lock())
+        .and_then(|_lock| {
+            build.transfer_sources()?;
+            let started = Instant::now();
+            let status = build.run_cargo()?;
+            build.timings.add(Phase::Build, started.elapsed());
+            build
+                .timings
+                .record(Phase::CopyBack, || build.retrieve_results(status))?;
+            build
+                .timings
+                .record(Phase::Upload, || upload::upload(&build, status))?;
+            build.print_timings();
+            Ok(exit_code(status))
+        });
+    build.ssh.stop_master();
+    history::record(&build, &result, started.elapsed());
+    result.unwrap_or_else(|e| {
+        error!("{}: {}", build.build_server, e);
+        e.exit_code()
+    })
+}
+
+/// `--distribute`: splits the build by target (or by package if there is only one target, by
+/// the workspace [`members`] if no package was selected) and runs the parts on all [`builds`]
+/// (one per configured build server) at the same time. The output of every server is prefixed
+/// with its name. Exits with the first non-zero exit code of a part, or 0 if all of them
+/// succeeded.
+pub fn distribute(mut builds: Vec<RemoteBuild>, members: &[Package]) -> Result<i32, Error> {
+    if builds.is_empty() {
+        return Err(Error::Config(
+            "--distribute needs build servers configured in [servers]".to_string(),
+        ));
+    }
+    // Split by target if there are several, otherwise by package.
+    let packages = take_packages(&mut builds[0].options);
+    let by_target = builds[0].target.len() > 1;
+    // every server gets one part, so each one only runs cargo once
+    let mut assignments = match by_target {
+        true => round_robin(&builds[0].target, builds.len()),
+        false if packages.is_empty() => partition_workspace(members, builds.len()),
+        false => round_robin(&packages, builds.len()),
+    };
+    if builds.len() == 1 || assignments.len() < 2 {
+        warn!(
+            "Nothing to distribute, building everything on {}",
+            builds[0].build_server
+        );
+        builds.truncate(1);
+        assignments = vec![match by_target {
+            true => builds[0].target.clone(),
+            false => packages.clone(),
+        }];
+    }
+
+    let servers = assignments.len();
+    let mut parts = Vec::new();
+    for (mut build, assigned) in builds.into_iter().zip(assignments) {
+        take_packages(&mut build.options);
+        let packages = match by_target {
+            true => {
+                build.target = assigned;
+                packages.clone()
+            }
+            false if assigned.is_empty() => packages.clone(),
+            false => assigned,
+        };
+        let end = build
+            .options
+            .iter()
+            .position(|o| o == "--")
+            .unwrap_or(build.options.len());
+        for package in packages.iter().rev() {
+            build.options.insert(end, package.clone());
+            build.options.insert(end, "--package".to_string());
+        }
+        // all servers would write the same file
+        build.no_copy_lock |= !parts.is_empty();
+        if servers > 1 {
+            build.output_prefix = Some(format!("[{}] ", build.build_server));
+        }
+        info!(
+            "Building {} on {}",
+            match by_target {
+                true => build.target.join(", "),
+                false => packages.join(", "),
+            },
+            build.build_server
+        );
+        parts.push(build);
+    }
+
+    let started = Instant::now();
+    let notify = parts[0].notify;
+    let webhook = parts[0].webhook.clone();
+    let project_name = parts[0].project_name.clone();
+    let command = parts[0].command.clone();
+    let server_names: Vec<String> = parts.iter().map(|b| b.build_server.clone()).collect();
+    let handles: Vec<_> = parts
+        .into_iter()
+        .map(|build| {
+            let server = build.build_server.clone();
+            (server, thread::spawn(move || run_part(build)))
+        })
+        .collect();
+    let mut result = 0;
+    for (server, handle) in handles {
+        let code = handle.join().unwrap_or(-5);
+        if code == 0 {
+            info!("{} finished successfully", server);
+        } else {
+            error!("{} failed with exit code {}", server, code);
+            if result == 0 {
+                result = code;
+            }
+        }
+    }
+    let finished = notify::Finished {
+        project: &project_name,
+        command: &command,
+        server: &server_names.join(", "),
+        exit_code: result,
+        duration: started.elapsed(),
+    };
+    finished.notify(notify, webhook.as_deref());
+    Ok(result)
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+use std::process::exit;
+
+use log::error;
+
+/// Why a run of cargo-remote failed. Every kind has its own exit code, so scripts can tell a
+/// failed transfer from a failed build. Builds that ran but failed exit with the exit code of
+/// the remote cargo instead.
+#[derive(Debug)]
+pub enum Error {
+    /// The project's metadata couldn't be read with `cargo metadata`. Exit code 1.
+    Metadata(String),
+    /// An invalid option or config value, or a missing build server. Exit code -3.
+    Config(String),
+    /// Transferring the project to the build server failed. Exit code -4.
+    Transfer(String),
+    /// ssh, cargo or another command couldn't be run. Exit code -5.
+    Run(String),
+    /// Copying build results back failed. Exit code -6.
+    CopyBack(String),
+    /// Copying the Cargo.lock file back failed. Exit code -7.
+    CopyLock(String),
+    /// Another run holds the lock on the build directory. Exit code -8.
+    Locked(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Metadata(_) => 1,
+            Error::Config(_) => -3,
+            Error::Transfer(_) => -4,
+            Error::Run(_) => -5,
+            Error::CopyBack(_) => -6,
+            Error::CopyLock(_) => -7,
+            Error::Locked(_) => -8,
+        }
+    }
+
+    /// A command that couldn't be started or exited with [`status`], with its [`stderr`] if it
+    /// was captured.
+    pub fn command(what: &str, status: impl fmt::Display, stderr: &[u8]) -> Error {
+        let stderr = String::from_utf8_lossy(stderr);
+        let stderr = stderr.trim();
+        match stderr.is_empty() {
+            true => Error::Run(format!("Failed to {} ({})", what, status)),
+            false => Error::Run(format!("Failed to {} ({}):\n{}", what, status, stderr)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Metadata(message)
+            | Error::Config(message)
+            | Error::Transfer(message)
+            | Error::Run(message)
+            | Error::CopyBack(message)
+            | Error::CopyLock(message)
+            | Error::Locked(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Exits with the exit code in [`result`], or prints the error and exits with its code.
+pub fn exit_with(result: Result<i32, Error>) -> ! {
+    match result {
+        Ok(code) => exit(code),
+        Err(e) => {
+            error!("{}", e);
+            exit(e.exit_code())
+        }
+    }
+}
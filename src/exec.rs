@@ -0,0 +1,71 @@
+use std::io::IsTerminal;
+
+use crate::build::{exit_code, RemoteBuild};
+use crate::error::Error;
+use crate::logging::Run;
+use crate::util::{shell_path, shell_quote};
+
+/// The shell commands that set up the environment of a build in the build directory before
+/// [`command`] runs: the environment profile, the variables the build gets and the toolchain.
+pub fn in_build_dir(build: &RemoteBuild, command: &str) -> String {
+    let mut exports: String = build
+        .env_vars
+        .iter()
+        .map(|(name, value)| format!("export {}={}; ", name, shell_quote(value)))
+        .collect();
+    if let Some(dir) = &build.cargo_target_dir {
+        exports.push_str(&format!("export CARGO_TARGET_DIR={}; ", shell_path(dir)));
+    }
+    if let Some(toolchain) = &build.toolchain {
+        exports.push_str(&format!(
+            "export RUSTUP_TOOLCHAIN={}; ",
+            shell_quote(toolchain)
+        ));
+    }
+    format!(
+        "{{ source {}; cd {} 2>/dev/null || {{ echo \"error: there is no build directory {} on \
+         the build server yet, run a build first\"; exit 1; }}; }} >&2; {}{}",
+        shell_path(&build.env),
+        shell_path(&build.build_path),
+        build.build_path,
+        exports,
+        command
+    )
+}
+
+/// `cargo remote exec -- <command>`: runs the shell command in the build directory of the
+/// project on the build server, with the environment of a build. The words of the command are
+/// joined like ssh does, so pipes and globs work if they are quoted locally.
+pub fn exec(build: RemoteBuild) -> Result<i32, Error> {
+    if build.options.is_empty() {
+        return Err(Error::Config(
+            "Missing the command to run, e.g. 'cargo remote exec -- ls target/release'".to_string(),
+        ));
+    }
+    let interactive = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    let tty = build.tty || (interactive && !build.no_tty);
+    let command = build.options.join(" ");
+    run(build, tty, &command)
+}
+
+/// `cargo remote shell`: opens an interactive login to the build server in the build directory
+/// of the project, with the environment of a build.
+pub fn shell(build: RemoteBuild) -> Result<i32, Error> {
+    let tty = !build.no_tty;
+    run(build, tty, "exec \"${SHELL:-sh}\" -i")
+}
+
+/// Runs [`command`] in the build directory, with a pseudo terminal if [`tty`] is set.
+fn run(mut build: RemoteBuild, tty: bool, command: &str) -> Result<i32, Error> {
+    let mut ssh = build.ssh.command();
+    if tty {
+        ssh.arg("-t");
+    }
+    let status = ssh
+        .arg(build.ssh.destination())
+        .arg(in_build_dir(&build, command))
+        .logged_status()
+        .map_err(|e| Error::command("run the command on the build server", e, &[]));
+    build.ssh.stop_master();
+    Ok(exit_code(status?))
+}
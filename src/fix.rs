@@ -0,0 +1,154 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use log::{debug, info, warn};
+
+use crate::build::{RemoteBuild, PROJECT_MARKER};
+use crate::error::Error;
+use crate::logging::Run;
+use crate::platform::RemoteOs;
+use crate::util::shell_path;
+
+/// The options that let `cargo fix` change files that aren't committed. The build directory
+/// has no repository, the changes are checked locally before they are applied.
+const ALLOW_UNCLEAN: [&str; 2] = ["--allow-no-vcs", "--allow-dirty"];
+
+/// Whether [`command`] with [`options`] changes the sources: `cargo fix` and `cargo clippy
+/// --fix`.
+pub fn is_fix(command: &str, options: &[String]) -> bool {
+    let before_separator = || options.iter().take_while(|o| *o != "--");
+    command == "fix" || (command == "clippy" && before_separator().any(|o| o == "--fix"))
+}
+
+/// Adds the options of [`ALLOW_UNCLEAN`] to [`options`] that aren't there yet.
+pub fn allow_unclean(options: &mut Vec<String>) {
+    for allow in ALLOW_UNCLEAN.iter() {
+        let end = options
+            .iter()
+            .position(|o| o == "--")
+            .unwrap_or(options.len());
+        if !options[..end].iter().any(|o| o == allow) {
+            options.insert(end, allow.to_string());
+        }
+    }
+}
+
+/// The files in the build directory that changed since the project marker was written right
+/// before cargo started, relative to the build directory.
+fn changed_files(build: &RemoteBuild) -> Result<Vec<String>, Error> {
+    let what = "find the files cargo fixed on the build server";
+    let output = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(format!(
+            "cd {} && find . -path ./target -prune -o -type f -newer {} ! -name Cargo.lock \
+             -print",
+            shell_path(&build.build_path),
+            PROJECT_MARKER
+        ))
+        .stdin(Stdio::null())
+        .logged_output()
+        .map_err(|e| Error::command(what, e, &[]))?;
+    if !output.status.success() {
+        return Err(Error::command(what, output.status, &output.stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|file| file.trim_start_matches("./").to_string())
+        .filter(|file| !file.is_empty())
+        .collect())
+}
+
+/// Prints the difference between the local [`file`] and the [`fixed`] one, with git which
+/// also compares files outside of a repository.
+fn preview(file: &Path, fixed: &Path) {
+    let local = match file.exists() {
+        true => file,
+        false => Path::new("/dev/null"),
+    };
+    let diff = Command::new("git")
+        .args(["--no-pager", "diff", "--no-index", "--"])
+        .arg(local)
+        .arg(fixed)
+        .logged_status();
+    if diff.is_err() {
+        println!("{}", file.display());
+    }
+}
+
+/// Asks whether the fixes should be applied, `--apply-fixes` answers yes. Without a terminal to
+/// ask on they are left alone.
+fn confirm(build: &RemoteBuild, count: usize) -> bool {
+    if build.apply_fixes {
+        return true;
+    }
+    if !std::io::stdin().is_terminal() {
+        warn!(
+            "The fixes weren't applied to the local files, use --apply-fixes to apply them \
+             without asking"
+        );
+        return false;
+    }
+    eprint!("Apply the fixes to {} local files? [y/N] ", count);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut answer);
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Copies the files `cargo fix` or `cargo clippy --fix` changed on the build server back into
+/// the project after showing what changed, so the fixes don't stay on the build server.
+pub fn sync_back(build: &RemoteBuild) -> Result<(), Error> {
+    if build.remote_os == RemoteOs::Windows {
+        warn!("The fixes can't be copied back from Windows build servers");
+        return Ok(());
+    }
+    let files = changed_files(build)?;
+    debug!("Changed on the build server: {:?}", files);
+    let staging = build.local_target_dir.join(".cargo-remote-fixes");
+    let _ = std::fs::remove_dir_all(&staging);
+    if !files.is_empty() {
+        build
+            .transport
+            .download_files(build, &build.build_path, &files, &staging)
+            .map_err(|e| {
+                Error::CopyBack(format!(
+                    "Failed to transfer the fixed files back to local machine: {}",
+                    e
+                ))
+            })?;
+    }
+    let changed: Vec<&String> = files
+        .iter()
+        .filter(|file| {
+            std::fs::read(staging.join(file)).ok()
+                != std::fs::read(build.project_dir.join(file)).ok()
+        })
+        .collect();
+    if changed.is_empty() {
+        info!("There is nothing to fix");
+        let _ = std::fs::remove_dir_all(&staging);
+        return Ok(());
+    }
+    for file in &changed {
+        preview(&build.project_dir.join(file), &staging.join(file));
+    }
+    if confirm(build, changed.len()) {
+        let mut applied = 0;
+        for file in &changed {
+            let local = build.project_dir.join(file);
+            if let Some(parent) = local.parent() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+            match std::fs::copy(staging.join(file), &local) {
+                Ok(_) => applied += 1,
+                Err(e) => warn!("Failed to apply the fixes to {}: {}", local.display(), e),
+            }
+        }
+        info!("Applied the fixes to {} files", applied);
+    }
+    let _ = std::fs::remove_dir_all(&staging);
+    Ok(())
+}
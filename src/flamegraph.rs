@@ -0,0 +1,53 @@
+use std::path::Path;
+
+use log::info;
+
+use crate::build::{Extra, RemoteBuild};
+use crate::error::Error;
+use crate::util::slash_path;
+
+/// Where `cargo flamegraph` writes the flamegraph if `--output` doesn't say otherwise.
+const DEFAULT_OUTPUT: &str = "flamegraph.svg";
+
+/// The file `--output` in [`options`] writes the flamegraph to.
+fn output(options: &[String]) -> Option<String> {
+    let mut options = options.iter().take_while(|o| *o != "--");
+    while let Some(option) = options.next() {
+        match option.strip_prefix("--output=") {
+            Some(output) => return Some(output.to_string()),
+            None if option == "--output" || option == "-o" => return options.next().cloned(),
+            None => {}
+        }
+    }
+    None
+}
+
+/// `cargo remote flamegraph [-- <flamegraph options> [-- <program arguments>]]`: profiles the
+/// program on the build server with `cargo flamegraph`, which builds it with debug info and
+/// runs it under perf, and copies the flamegraph back into the project. Use it for workloads
+/// the local machine can't sustain. A flamegraph left over from an earlier run is removed
+/// before cargo starts, so a failed run doesn't copy back a stale one.
+pub fn flamegraph(mut build: RemoteBuild) -> Result<i32, Error> {
+    let output = output(&build.options).unwrap_or_else(|| DEFAULT_OUTPUT.to_string());
+    if Path::new(&output).is_absolute() {
+        return Err(Error::Config(
+            "The flamegraph is copied back from the build directory, --output has to be a \
+             relative path"
+                .to_string(),
+        ));
+    }
+    let remote = slash_path(Path::new(&output));
+    build.clear_output(&remote);
+
+    let local = build.project_dir.join(&output);
+    build.command = "flamegraph".to_string();
+    build.retrieve.push(Extra {
+        local: local.clone(),
+        remote,
+    });
+    let exit_code = build.run()?;
+    if exit_code == 0 && local.exists() {
+        info!("The flamegraph is in {}", local.display());
+    }
+    Ok(exit_code)
+}
@@ -0,0 +1,33 @@
+use crate::build::RemoteBuild;
+use crate::error::Error;
+
+/// What rust-analyzer checks by default, used if no options are given.
+const DEFAULT_OPTIONS: [&str; 2] = ["--workspace", "--all-targets"];
+
+/// `cargo remote flycheck [-- <check options>]`: the check command for rust-analyzer, set as its
+/// `rust-analyzer.check.overrideCommand`. Syncs the sources and runs `cargo check` with JSON
+/// messages on the build server, which rust-analyzer reads from stdout with the paths pointing
+/// to the local files. Nothing is copied back, not even Cargo.lock, and no notifications are
+/// sent for the checks that run on every save.
+pub fn flycheck(mut build: RemoteBuild) -> Result<i32, Error> {
+    build.command = "check".to_string();
+    if build.options.is_empty() {
+        build.options = DEFAULT_OPTIONS.iter().map(|o| o.to_string()).collect();
+    }
+    if !build.json_output() {
+        build.options.push("--message-format=json".to_string());
+    }
+    build.copy_back = None;
+    build.copy_back_artifacts = false;
+    build.retrieve.clear();
+    build.upload = None;
+    build.codesign = None;
+    build.no_copy_lock = true;
+    build.notify = false;
+    build.webhook = None;
+    build.remap_paths = true;
+    // rust-analyzer reads the output, there is no terminal
+    build.tty = false;
+    build.no_tty = true;
+    build.run()
+}
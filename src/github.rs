@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::remap::Remap;
+
+/// The cargo commands that take `--message-format`, with `--output-format github` their
+/// diagnostics become annotations.
+pub const MESSAGE_FORMAT_COMMANDS: [&str; 8] = [
+    "build", "check", "clippy", "test", "bench", "run", "rustc", "doc",
+];
+
+/// A collapsible group of the GitHub Actions log, ended when it is dropped. Does nothing if it
+/// isn't enabled. Groups can't be nested.
+pub struct Group(bool);
+
+impl Group {
+    pub fn start(enabled: bool, title: &str) -> Group {
+        if enabled {
+            println!("::group::{}", escape_data(title));
+        }
+        Group(enabled)
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        if self.0 {
+            println!("::endgroup::");
+        }
+    }
+}
+
+fn escape_data(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// [`file`] of a diagnostic as a local path relative to the checked out repository, which is
+/// what annotations refer to. Relative paths in diagnostics are relative to the project.
+fn local_file(file: &str, remap: &Remap, project_dir: &Path) -> String {
+    let path = project_dir.join(&*remap.line(file));
+    let root = std::env::var_os("GITHUB_WORKSPACE")
+        .map(PathBuf::from)
+        .or_else(|| std::env::current_dir().ok());
+    let relative = root.and_then(|root| {
+        let root = root.canonicalize().unwrap_or(root);
+        path.strip_prefix(root).ok().map(Path::to_path_buf)
+    });
+    crate::util::slash_path(relative.as_deref().unwrap_or(&path))
+}
+
+/// The workflow command that annotates the code the errors and warnings of a
+/// `compiler-message` point to. `None` for other messages and for diagnostics without a
+/// location, like the summary at the end.
+pub fn annotation(message: &Value, remap: &Remap, project_dir: &Path) -> Option<String> {
+    if message.get("reason")?.as_str()? != "compiler-message" {
+        return None;
+    }
+    let diagnostic = message.get("message")?;
+    let command = match diagnostic.get("level")?.as_str()? {
+        "error" | "error: internal compiler error" => "error",
+        "warning" => "warning",
+        _ => return None,
+    };
+    let span = diagnostic
+        .get("spans")?
+        .as_array()?
+        .iter()
+        .find(|span| span.get("is_primary").and_then(Value::as_bool) == Some(true))?;
+    let file = span.get("file_name")?.as_str()?;
+    let mut properties = vec![format!(
+        "file={}",
+        escape_property(&local_file(file, remap, project_dir))
+    )];
+    let positions = [
+        ("line", "line_start"),
+        ("endLine", "line_end"),
+        ("col", "column_start"),
+        ("endColumn", "column_end"),
+    ];
+    for (name, key) in positions.iter() {
+        if let Some(value) = span.get(key).and_then(Value::as_u64) {
+            properties.push(format!("{}={}", name, value));
+        }
+    }
+    let title = diagnostic.get("message")?.as_str()?;
+    properties.push(format!("title={}", escape_property(title)));
+    let text = diagnostic
+        .get("rendered")
+        .and_then(Value::as_str)
+        .unwrap_or(title);
+    Some(format!(
+        "::{} {}::{}",
+        command,
+        properties.join(","),
+        escape_data(&remap.line(text.trim_end()))
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const PROJECT: &str = "/nonexistent/project";
+
+    fn remap() -> Remap {
+        Remap::with_dirs(vec![("~/remote-builds/project-1234/", Path::new(PROJECT))])
+    }
+
+    fn span(file: &str, line: u64, primary: bool) -> Value {
+        json!({
+            "file_name": file,
+            "line_start": line,
+            "line_end": line + 1,
+            "column_start": 5,
+            "column_end": 9,
+            "is_primary": primary,
+        })
+    }
+
+    fn message(level: &str, title: &str, spans: Vec<Value>) -> Value {
+        json!({
+            "reason": "compiler-message",
+            "message": {
+                "level": level,
+                "message": title,
+                "spans": spans,
+                "rendered": format!("{}: {}\n  --> src/main.rs\n\n", level, title),
+            },
+        })
+    }
+
+    fn annotate(message: &Value) -> Option<String> {
+        annotation(message, &remap(), Path::new(PROJECT))
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            escape_data("100% done\r\nnext: a, b"),
+            "100%25 done%0D%0Anext: a, b"
+        );
+        assert_eq!(
+            escape_property("100% done\r\nnext: a, b"),
+            "100%25 done%0D%0Anext%3A a%2C b"
+        );
+    }
+
+    #[test]
+    fn warnings_annotate_the_primary_span() {
+        let message = message(
+            "warning",
+            "unused variable: `x`",
+            vec![span("src/other.rs", 1, false), span("src/main.rs", 3, true)],
+        );
+        assert_eq!(
+            annotate(&message).unwrap(),
+            "::warning file=/nonexistent/project/src/main.rs,line=3,endLine=4,col=5,\
+             endColumn=9,title=unused variable%3A `x`::warning: unused variable: `x`%0A  --> \
+             src/main.rs"
+        );
+    }
+
+    #[test]
+    fn errors_in_remote_files_point_to_local_ones() {
+        let mismatch = message(
+            "error",
+            "mismatched types, expected `u8`",
+            vec![span(
+                "/home/builder/remote-builds/project-1234/src/lib.rs",
+                10,
+                true,
+            )],
+        );
+        let annotation = annotate(&mismatch).unwrap();
+        assert!(
+            annotation.starts_with(
+                "::error file=/nonexistent/project/src/lib.rs,line=10,endLine=11,col=5,\
+                 endColumn=9,title=mismatched types%2C expected `u8`::"
+            ),
+            "{}",
+            annotation
+        );
+        let ice = message(
+            "error: internal compiler error",
+            "boom",
+            vec![span("src/main.rs", 1, true)],
+        );
+        assert!(annotate(&ice).unwrap().starts_with("::error "));
+    }
+
+    #[test]
+    fn diagnostics_without_a_location_are_skipped() {
+        let summary = message("warning", "2 warnings emitted", Vec::new());
+        assert_eq!(annotate(&summary), None);
+        let secondary_only = message(
+            "warning",
+            "unused import",
+            vec![span("src/main.rs", 1, false)],
+        );
+        assert_eq!(annotate(&secondary_only), None);
+        let note = message("note", "see also", vec![span("src/main.rs", 1, true)]);
+        assert_eq!(annotate(&note), None);
+        let artifact = json!({"reason": "compiler-artifact", "message": {}});
+        assert_eq!(annotate(&artifact), None);
+    }
+
+    #[test]
+    fn the_title_is_the_text_without_a_rendering() {
+        let mut message = message("warning", "a: b", vec![span("src/main.rs", 1, true)]);
+        message["message"]
+            .as_object_mut()
+            .unwrap()
+            .remove("rendered");
+        assert!(annotate(&message).unwrap().ends_with(",title=a%3A b::a: b"));
+    }
+}
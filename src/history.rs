@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::debug;
+use serde_json::{json, Value};
+use structopt::StructOpt;
+
+use crate::build::RemoteBuild;
+use crate::error::Error;
+use crate::manage::{format_age, now, parse_age};
+use crate::timings::Phase;
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// Server name of runs that fell back to a local build.
+pub const LOCAL: &str = "local";
+
+fn history_path() -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("cargo-remote")
+        .ok()?
+        .place_data_file(HISTORY_FILE)
+        .ok()
+}
+
+/// Appends a run of [`build`] that ended with [`result`] after [`duration`] to the build
+/// history, one JSON object per line. Dry runs aren't recorded.
+pub fn record(build: &RemoteBuild, result: &Result<i32, Error>, duration: Duration) {
+    if crate::logging::dry_run() {
+        return;
+    }
+    let exit_code = match result {
+        Ok(code) => *code,
+        Err(e) => e.exit_code(),
+    };
+    let entry = json!({
+        "time": now(),
+        "project": build.project_dir,
+        "name": build.project_name,
+        "server": build.ran_on(),
+        "command": build.command,
+        "target": build.target,
+        "exit_code": exit_code,
+        "duration_ms": duration.as_millis() as u64,
+        "phases_ms": build.timings.to_json(),
+        "bytes_sent": build.timings.bytes_sent(),
+    });
+    let written = history_path().and_then(|path| {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .ok()?;
+        writeln!(file, "{}", entry).ok()
+    });
+    if written.is_none() {
+        debug!("Couldn't write the build history");
+    }
+}
+
+/// A run read back from the build history.
+struct Entry {
+    time: u64,
+    project: String,
+    name: String,
+    server: String,
+    command: String,
+    exit_code: i64,
+    duration_ms: u64,
+    phases_ms: BTreeMap<String, u64>,
+    bytes_sent: u64,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Entry> {
+        let value: Value = serde_json::from_str(line).ok()?;
+        let string = |key: &str| value.get(key)?.as_str().map(str::to_string);
+        let phases_ms = value
+            .get("phases_ms")
+            .and_then(Value::as_object)
+            .map(|phases| {
+                phases
+                    .iter()
+                    .filter_map(|(key, ms)| Some((key.clone(), ms.as_u64()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(Entry {
+            time: value.get("time")?.as_u64()?,
+            project: string("project")?,
+            name: string("name")?,
+            server: string("server")?,
+            command: string("command")?,
+            exit_code: value.get("exit_code")?.as_i64()?,
+            duration_ms: value.get("duration_ms")?.as_u64()?,
+            phases_ms,
+            bytes_sent: value.get("bytes_sent").and_then(Value::as_u64).unwrap_or(0),
+        })
+    }
+
+    fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+
+    fn is_local(&self) -> bool {
+        self.server == LOCAL
+    }
+}
+
+fn read_history() -> Vec<Entry> {
+    history_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map(|content| content.lines().filter_map(Entry::parse).collect())
+        .unwrap_or_default()
+}
+
+/// Transfers of at least this size measure the bandwidth, the time of smaller ones is mostly
+/// the fixed cost of a sync.
+const BANDWIDTH_MIN_BYTES: u64 = 64 * 1024;
+
+/// What the build history knows about the recent runs of a project, for `--auto`.
+#[derive(Default)]
+pub struct Runs {
+    /// Average duration of the last successful local runs of the command.
+    pub local_ms: Option<u64>,
+    /// Average duration of the last successful remote runs of the command without the source
+    /// sync.
+    pub remote_ms: Option<u64>,
+    /// The fastest source sync of the project, roughly the fixed cost of a transfer.
+    pub sync_ms: Option<u64>,
+    /// Bytes per second the source syncs of the project sent.
+    pub bandwidth: Option<f64>,
+    /// When the project was last built, locally or remotely.
+    pub last: Option<u64>,
+    /// When the project was last built remotely, the build server has its sources since then.
+    pub last_remote: Option<u64>,
+}
+
+/// The recent runs of [`command`] in the project in [`project_dir`].
+pub fn runs(project_dir: &Path, command: &str) -> Runs {
+    const RECENT: usize = 10;
+    let project = project_dir.to_string_lossy();
+    let entries: Vec<Entry> = read_history()
+        .into_iter()
+        .filter(|entry| entry.project == project)
+        .collect();
+    let recent = |local: bool| -> Vec<&Entry> {
+        let runs: Vec<&Entry> = entries
+            .iter()
+            .filter(|e| e.success() && e.is_local() == local && e.command == command)
+            .collect();
+        runs[runs.len().saturating_sub(RECENT)..].to_vec()
+    };
+    let sources = |entry: &Entry| entry.phases_ms.get(Phase::Sources.key()).copied();
+    let local: Vec<u64> = recent(true).iter().map(|e| e.duration_ms).collect();
+    let remote: Vec<u64> = recent(false)
+        .iter()
+        .map(|e| e.duration_ms.saturating_sub(sources(e).unwrap_or(0)))
+        .collect();
+    let (bytes, ms) = entries
+        .iter()
+        .filter(|e| e.bytes_sent >= BANDWIDTH_MIN_BYTES)
+        .filter_map(|e| Some((e.bytes_sent, sources(e)?)))
+        .fold((0, 0), |(bytes, ms), (b, m)| (bytes + b, ms + m));
+    Runs {
+        local_ms: Some(average(&local)).filter(|_| !local.is_empty()),
+        remote_ms: Some(average(&remote)).filter(|_| !remote.is_empty()),
+        sync_ms: entries.iter().filter_map(sources).min(),
+        bandwidth: Some(bytes as f64 * 1000.0 / ms.max(1) as f64).filter(|_| bytes > 0),
+        last: entries.iter().map(|e| e.time).max(),
+        last_remote: entries
+            .iter()
+            .filter(|e| !e.is_local())
+            .map(|e| e.time)
+            .max(),
+    }
+}
+
+/// Options of `cargo remote stats`, given after `--`.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "cargo remote stats")]
+struct StatsOpts {
+    #[structopt(
+        long = "all",
+        help = "Show the runs of all projects, not only this one"
+    )]
+    all: bool,
+
+    #[structopt(
+        long = "last",
+        help = "Number of recent runs to list",
+        default_value = "10"
+    )]
+    last: usize,
+
+    #[structopt(
+        long = "local-baseline",
+        help = "How long the command takes on the local machine, e.g. 90s or 5m, to compute the \
+                time saved if no local build was recorded",
+        parse(try_from_str = "parse_age")
+    )]
+    local_baseline: Option<u64>,
+}
+
+/// `cargo remote stats`: prints the recent runs of the project in [`project_dir`], the average
+/// duration per build server and phase and the time saved compared to building locally.
+pub fn stats(project_dir: &Path, options: &[String]) -> Result<i32, Error> {
+    let opts = StatsOpts::from_iter(std::iter::once("stats".to_string()).chain(options.to_vec()));
+    let project = project_dir.to_string_lossy();
+    let entries: Vec<Entry> = read_history()
+        .into_iter()
+        .filter(|entry| opts.all || entry.project == project)
+        .collect();
+    if entries.is_empty() {
+        println!("No runs recorded yet");
+        return Ok(0);
+    }
+
+    let failed = entries.iter().filter(|entry| !entry.success()).count();
+    println!("{} runs, {} failed", entries.len(), failed);
+
+    println!();
+    println!("Recent runs:");
+    let recent = entries.len().saturating_sub(opts.last);
+    for entry in &entries[recent..] {
+        println!(
+            "  {:>14}  {:<16}  {:<12}  {:<6}  {:>9}{}",
+            format_age(now().saturating_sub(entry.time)),
+            entry.server,
+            entry.command,
+            if entry.success() { "ok" } else { "failed" },
+            format_duration(entry.duration_ms),
+            match opts.all {
+                true => format!("  {}", entry.name),
+                false => String::new(),
+            }
+        );
+    }
+    print_trend(&entries, opts.last);
+
+    println!();
+    println!("Average of successful runs per build server:");
+    let mut servers: BTreeMap<&str, Vec<&Entry>> = BTreeMap::new();
+    for entry in entries.iter().filter(|entry| entry.success()) {
+        servers.entry(&entry.server).or_default().push(entry);
+    }
+    for (server, runs) in &servers {
+        let phases: Vec<String> = Phase::ALL
+            .iter()
+            .filter_map(|phase| {
+                let times: Vec<u64> = runs
+                    .iter()
+                    .filter_map(|run| run.phases_ms.get(phase.key()).copied())
+                    .collect();
+                match times.is_empty() {
+                    true => None,
+                    false => Some(format!(
+                        "{} {}",
+                        phase.name(),
+                        format_duration(average(&times))
+                    )),
+                }
+            })
+            .collect();
+        let total: Vec<u64> = runs.iter().map(|run| run.duration_ms).collect();
+        println!(
+            "  {:<16}  {:>4} runs  {:>9}  ({})",
+            server,
+            runs.len(),
+            format_duration(average(&total)),
+            phases.join(", ")
+        );
+    }
+
+    println!();
+    print_time_saved(&entries, opts.local_baseline.map(|secs| secs * 1000));
+    Ok(0)
+}
+
+/// Compares the average duration of the last [`count`] successful runs with the ones before.
+fn print_trend(entries: &[Entry], count: usize) {
+    let durations: Vec<u64> = entries
+        .iter()
+        .filter(|entry| entry.success() && !entry.is_local())
+        .map(|entry| entry.duration_ms)
+        .collect();
+    if count == 0 || durations.len() < count * 2 {
+        return;
+    }
+    let split = durations.len() - count;
+    let last = average(&durations[split..]);
+    let before = average(&durations[split.saturating_sub(count)..split]);
+    let change = (last as f64 / before.max(1) as f64 - 1.0) * 100.0;
+    println!(
+        "Average of the last {} remote runs: {} ({:+.0}% compared to the {} before)",
+        count,
+        format_duration(last),
+        change,
+        count
+    );
+}
+
+/// Sums up how much faster the successful remote runs were than building locally. Local runs
+/// of the same project and command from fallback builds are the baseline, or
+/// [`local_baseline_ms`] if there are none.
+fn print_time_saved(entries: &[Entry], local_baseline_ms: Option<u64>) {
+    let mut local: BTreeMap<(&str, &str), Vec<u64>> = BTreeMap::new();
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.success() && entry.is_local())
+    {
+        local
+            .entry((&entry.project, &entry.command))
+            .or_default()
+            .push(entry.duration_ms);
+    }
+    let mut saved: i64 = 0;
+    let mut compared = 0;
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.success() && !entry.is_local())
+    {
+        let baseline = local
+            .get(&(entry.project.as_str(), entry.command.as_str()))
+            .map(|durations| average(durations))
+            .or(local_baseline_ms);
+        if let Some(baseline) = baseline {
+            saved += baseline as i64 - entry.duration_ms as i64;
+            compared += 1;
+        }
+    }
+    if compared == 0 {
+        println!(
+            "No local baseline to compare with, pass --local-baseline with the duration of a \
+             local build"
+        );
+        return;
+    }
+    let amount = format_duration(saved.unsigned_abs());
+    match saved >= 0 {
+        true => println!(
+            "{} remote runs saved {} compared to local builds",
+            compared, amount
+        ),
+        false => println!(
+            "{} remote runs took {} longer than local builds",
+            compared, amount
+        ),
+    }
+}
+
+fn average(values: &[u64]) -> u64 {
+    values.iter().sum::<u64>() / values.len().max(1) as u64
+}
+
+pub fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    match secs {
+        s if s < 60 => format!("{:.1}s", ms as f64 / 1000.0),
+        s if s < 3600 => format!("{}m {:02}s", s / 60, s % 60),
+        s => format!("{}h {:02}m", s / 3600, s % 3600 / 60),
+    }
+}
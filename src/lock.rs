@@ -0,0 +1,129 @@
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Stdio};
+
+use log::{info, warn};
+
+use crate::build::RemoteBuild;
+use crate::error::Error;
+use crate::logging::Run;
+use crate::util::shell_path;
+
+/// What to do if another run holds the lock on the same build directory.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LockMode {
+    Wait,
+    Fail,
+    Off,
+}
+
+impl LockMode {
+    pub fn from_name(name: &str) -> Option<LockMode> {
+        match name {
+            "wait" => Some(LockMode::Wait),
+            "fail" => Some(LockMode::Fail),
+            "off" => Some(LockMode::Off),
+            _ => None,
+        }
+    }
+}
+
+/// A lock on the build directory, held by an ssh session that keeps a `flock` on a lock file
+/// next to it. The lock is released when the session ends, also if the connection is lost or
+/// cargo-remote is killed, so runs that share a build directory never sync or build at the same
+/// time. With the agent transport the agent holds the lock instead.
+pub enum RemoteLock {
+    Session(Child),
+    /// Only kept for releasing the lock when it is dropped.
+    Agent(#[allow(dead_code)] crate::agent::AgentLock),
+}
+
+/// Shell script that locks [`lock_file`] and keeps it locked until its stdin is closed. It
+/// prints `busy` if the lock is held by someone else, `locked` once it got it and `unsupported`
+/// if the build server has no `flock`.
+fn lock_script(lock_file: &str, mode: LockMode) -> String {
+    let dir = lock_file.rsplit_once('/').map_or(".", |(dir, _)| dir);
+    let when_busy = match mode {
+        LockMode::Wait => "flock 9",
+        _ => "exit 3",
+    };
+    format!(
+        "mkdir -p {dir} && exec 9>>{lock} || exit 1; \
+         if command -v flock >/dev/null 2>&1; then \
+         flock -n 9 || {{ echo busy; {when_busy} || exit 3; }}; echo locked; \
+         else echo unsupported; fi; cat >/dev/null",
+        dir = shell_path(dir),
+        lock = shell_path(lock_file),
+        when_busy = when_busy
+    )
+}
+
+impl RemoteLock {
+    /// Locks the build directory of [`build`], waiting for other runs or failing right away
+    /// depending on [`mode`]. Returns `None` if locking is off.
+    pub fn acquire(build: &RemoteBuild, mode: LockMode) -> Result<Option<RemoteLock>, Error> {
+        if mode == LockMode::Off {
+            return Ok(None);
+        }
+        // Projects sharing a target directory would overwrite each other's binaries before they
+        // are copied back, they take turns instead.
+        let lock_file = match build.shared_target {
+            true => format!("{}.lock", build.remote_target()),
+            false => format!("{}.lock", build.build_path.trim_end_matches('/')),
+        };
+        if let Some(agent) = build.transport.agent() {
+            let lock = agent.lock(build, &lock_file, mode == LockMode::Wait)?;
+            return Ok(lock.map(RemoteLock::Agent));
+        }
+        let mut session = build
+            .ssh
+            .command()
+            .arg(build.ssh.destination())
+            .arg(lock_script(&lock_file, mode))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .logged_spawn()
+            .map_err(|e| Error::command("lock the build directory", e, &[]))?;
+        if crate::logging::dry_run() {
+            return Ok(None);
+        }
+        let stdout = session.stdout.take().expect("stdout is piped");
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            match line.as_str() {
+                "busy" if mode == LockMode::Wait => info!(
+                    "Another run is using {} on {}, waiting for it to finish",
+                    build.build_path, build.build_server
+                ),
+                "locked" => return Ok(Some(RemoteLock::Session(session))),
+                "unsupported" => {
+                    warn!(
+                        "flock is not installed on {}, concurrent runs aren't prevented",
+                        build.build_server
+                    );
+                    return Ok(Some(RemoteLock::Session(session)));
+                }
+                _ => {}
+            }
+        }
+        let status = session
+            .wait()
+            .map_err(|e| Error::command("lock the build directory", e, &[]))?;
+        match status.code() {
+            Some(3) => Err(Error::Locked(format!(
+                "Another run is using {} on {} (use --lock wait to wait for it)",
+                build.build_path, build.build_server
+            ))),
+            _ => Err(Error::command("lock the build directory", status, &[])),
+        }
+    }
+}
+
+impl Drop for RemoteLock {
+    fn drop(&mut self) {
+        // closing stdin ends the session and with it the lock
+        if let RemoteLock::Session(session) = self {
+            drop(session.stdin.take());
+            let _ = session.wait();
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+use crate::util::{format_utc, shell_arg};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static JSON: AtomicBool = AtomicBool::new(false);
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Sets up logging to stderr, stdout is left to the remote cargo. [`verbosity`] is -1 for `-q`,
+/// which leaves only errors, 0 by default and the number of `-v` flags otherwise. `RUST_LOG`
+/// still overrides the level. With [`json`] every message becomes one JSON object per line.
+pub fn init(verbosity: i8, json: bool) {
+    let level = match verbosity {
+        i8::MIN..=-1 => LevelFilter::Error,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    QUIET.store(verbosity < 0, Ordering::Relaxed);
+    JSON.store(json, Ordering::Relaxed);
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(level);
+    let inner: Box<dyn Log> = match json {
+        true => Box::new(JsonLogger),
+        false => Box::new(simple_logger::SimpleLogger::new().with_level(level)),
+    };
+    log::set_boxed_logger(Box::new(FileTee { inner })).unwrap();
+    log::set_max_level(level);
+}
+
+/// Writes cargo-remote's messages and the output of the remote cargo to [`file`] as well, see
+/// [`tee`].
+pub fn set_log_file(file: File) {
+    *LOG_FILE.lock().unwrap() = Some(file);
+}
+
+pub fn log_file_set() -> bool {
+    LOG_FILE.lock().unwrap().is_some()
+}
+
+fn write_log_file(bytes: &[u8]) {
+    if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+        let _ = file.write_all(bytes);
+    }
+}
+
+/// Copies everything from [`from`] to [`to`] and the log file as it arrives, so progress bars
+/// and prompts show up right away. Every chunk goes through [`map`] first.
+pub fn tee(mut from: impl Read, mut to: impl Write, map: impl Fn(&[u8]) -> Cow<[u8]>) {
+    let mut buffer = [0; 8192];
+    loop {
+        match from.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                let bytes = map(&buffer[..n]);
+                let _ = to.write_all(&bytes);
+                let _ = to.flush();
+                write_log_file(&bytes);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+}
+
+/// Writes [`line`] to [`to`] and the log file.
+pub fn tee_line(mut to: impl Write, line: &str) {
+    let _ = writeln!(to, "{}", line);
+    write_log_file(format!("{}\n", line).as_bytes());
+}
+
+/// Whether only cargo's output should be printed, no transfer progress.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// With [`dry_run`] commands are printed to stdout instead of being run, see [`Run`].
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the epoch for the JSON events.
+fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis())
+        .unwrap_or_default()
+}
+
+fn write_event(event: serde_json::Value) {
+    let _ = writeln!(io::stderr(), "{}", event);
+}
+
+struct JsonLogger;
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            write_event(json!({
+                "time": timestamp() as u64,
+                "event": "log",
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            }));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Passes messages on to [`FileTee::inner`] and writes them to the log file if there is one.
+struct FileTee {
+    inner: Box<dyn Log>,
+}
+
+impl Log for FileTee {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+        if log_file_set() {
+            let line = format!(
+                "{} {:<5} [{}] {}\n",
+                format_utc((timestamp() / 1000) as u64),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            write_log_file(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// [`command`] as it would be typed into a shell.
+fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|word| shell_arg(&word.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Prints [`command`] with its directory and environment if this is a dry run. Returns
+/// whether it was, the command must not be run then.
+fn dry(command: &Command) -> bool {
+    if !dry_run() {
+        return false;
+    }
+    let mut line = String::new();
+    if let Some(dir) = command.get_current_dir() {
+        line.push_str(&format!("cd {} && ", shell_arg(&dir.to_string_lossy())));
+    }
+    for (name, value) in command.get_envs() {
+        if let Some(value) = value {
+            let assignment = format!(
+                "{}={} ",
+                name.to_string_lossy(),
+                shell_arg(&value.to_string_lossy())
+            );
+            line.push_str(&assignment);
+        }
+    }
+    println!("{}{}", line, command_line(command));
+    true
+}
+
+/// The exit status of a command that succeeded, for the commands of a dry run.
+fn success() -> ExitStatus {
+    #[cfg(unix)]
+    use std::os::unix::process::ExitStatusExt;
+    #[cfg(windows)]
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(0)
+}
+
+fn log_start(command: &Command) {
+    if JSON.load(Ordering::Relaxed) {
+        if log::max_level() >= LevelFilter::Debug {
+            write_event(json!({
+                "time": timestamp() as u64,
+                "event": "command_started",
+                "command": command_line(command),
+            }));
+        }
+    } else {
+        debug!("Running {}", command_line(command));
+    }
+}
+
+fn log_finish(command: &Command, status: Option<ExitStatus>, started: Instant) {
+    let duration = started.elapsed();
+    let program = command.get_program().to_string_lossy();
+    if JSON.load(Ordering::Relaxed) {
+        if log::max_level() >= LevelFilter::Debug {
+            write_event(json!({
+                "time": timestamp() as u64,
+                "event": "command_finished",
+                "command": command_line(command),
+                "exit_code": status.and_then(|status| status.code()),
+                "duration_ms": duration.as_millis() as u64,
+            }));
+        }
+    } else {
+        match status {
+            Some(status) => debug!("{} finished in {:.2?} ({})", program, duration, status),
+            None => debug!("{} failed to start after {:.2?}", program, duration),
+        }
+    }
+}
+
+/// Running commands with their command line and duration logged for `-v`. In a dry run they
+/// are only printed and succeed without any output.
+pub trait Run {
+    fn logged_output(&mut self) -> io::Result<Output>;
+    fn logged_status(&mut self) -> io::Result<ExitStatus>;
+    /// Only the start is logged, the caller waits for the child. In a dry run the child exits
+    /// right away, it has no stdin and empty pipes for stdout and stderr.
+    fn logged_spawn(&mut self) -> io::Result<Child>;
+    /// Like [`Run::logged_output`], but run in a dry run as well. Only for local commands that
+    /// look something up, like `git ls-files`.
+    fn query_output(&mut self) -> io::Result<Output>;
+}
+
+impl Run for Command {
+    fn logged_output(&mut self) -> io::Result<Output> {
+        if dry(self) {
+            return Ok(Output {
+                status: success(),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        }
+        self.query_output()
+    }
+
+    fn query_output(&mut self) -> io::Result<Output> {
+        log_start(self);
+        let started = Instant::now();
+        let output = self.output();
+        log_finish(self, output.as_ref().ok().map(|o| o.status), started);
+        output
+    }
+
+    fn logged_status(&mut self) -> io::Result<ExitStatus> {
+        if dry(self) {
+            return Ok(success());
+        }
+        log_start(self);
+        let started = Instant::now();
+        let status = self.status();
+        log_finish(self, status.as_ref().ok().copied(), started);
+        status
+    }
+
+    fn logged_spawn(&mut self) -> io::Result<Child> {
+        if dry(self) {
+            let mut noop = match cfg!(windows) {
+                true => {
+                    let mut cmd = Command::new("cmd");
+                    cmd.args(["/C", "exit 0"]);
+                    cmd
+                }
+                false => Command::new("true"),
+            };
+            return noop
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn();
+        }
+        log_start(self);
+        self.spawn()
+    }
+}
@@ -1,286 +1,1771 @@
 use std::path::{Path, PathBuf};
-use std::process::{exit, Command, Stdio};
+use std::process::Command;
 use structopt::StructOpt;
 use toml::Value;
 
-use log::{error, warn, debug};
+use log::{warn, info, debug};
 
-const PROGRESS_FLAG: &str = "--info=progress2";
+mod agent;
+mod auto;
+mod build;
+mod capabilities;
+mod cargo_config;
+mod check;
+mod clippy;
+mod completions;
+mod config;
+mod container;
+mod coverage;
+mod debug;
+mod debuginfo;
+mod dep_cache;
+mod distribute;
+mod error;
+mod exec;
+mod fix;
+mod flamegraph;
+mod flycheck;
+mod github;
+mod history;
+mod lock;
+mod logging;
+mod manage;
+mod nix;
+mod notify;
+mod patches;
+mod platform;
+mod probe;
+mod provision;
+mod publish;
+mod remap;
+mod session;
+mod ssh;
+mod test_binaries;
+mod timings;
+mod transport;
+mod upload;
+mod util;
+mod vendor;
+mod wake;
+mod watch;
+
+use build::RemoteBuild;
+use config::{Configs, Server};
+use error::Error;
+use logging::Run;
+use platform::RemoteOs;
+use ssh::Ssh;
+
+const DEFAULT_REMOTE_BASE_DIR: &str = "~/remote-builds";
+/// Keys of the `[sccache]` config table and the sccache variables they set.
+const SCCACHE_BACKEND_KEYS: [(&str, &str); 5] = [
+    ("redis", "SCCACHE_REDIS"),
+    ("bucket", "SCCACHE_BUCKET"),
+    ("endpoint", "SCCACHE_ENDPOINT"),
+    ("region", "SCCACHE_REGION"),
+    ("dir", "SCCACHE_DIR"),
+];
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "cargo-remote", bin_name = "cargo")]
+#[allow(clippy::large_enum_variant)]
 enum Opts {
     #[structopt(name = "remote")]
-    Remote {
-        #[structopt(short = "r", long = "remote", help = "Remote ssh build server")]
-        remote: Option<String>,
-
-        #[structopt(
-            short = "b",
-            long = "build-env",
-            help = "Set remote environment variables. RUST_BACKTRACE, CC, LIB, etc. ",
-            default_value = "RUST_BACKTRACE=1"
-        )]
-        build_env: String,
-
-        #[structopt(
-            short = "d",
-            long = "rustup-default",
-            help = "Rustup default (stable|beta|nightly)",
-            default_value = "stable"
-        )]
-        rustup_default: String,
-
-        #[structopt(
-            short = "e",
-            long = "env",
-            help = "Environment profile. default_value = /etc/profile",
-            default_value = "/etc/profile"
-        )]
-        env: String,
-
-        #[structopt(
-            short = "c",
-            long = "copy-back",
-            help = "Transfer the target folder or specific file from that folder back to the local machine"
-        )]
-        copy_back: Option<Option<String>>,
-
-        #[structopt(
-            long = "no-copy-lock",
-            help = "don't transfer the Cargo.lock file back to the local machine"
-        )]
-        no_copy_lock: bool,
-
-        #[structopt(
-            long = "manifest-path",
-            help = "Path to the manifest to execute",
-            default_value = "Cargo.toml",
-            parse(from_os_str)
-        )]
-        manifest_path: PathBuf,
-
-        #[structopt(
-            short = "h",
-            long = "transfer-hidden",
-            help = "Transfer hidden files and directories to the build server"
-        )]
-        hidden: bool,
-
-        #[structopt(help = "cargo command that will be executed remotely")]
-        command: String,
-
-        #[structopt(
-            help = "cargo options and flags that will be applied remotely",
-            name = "remote options"
-        )]
-        options: Vec<String>,
-    },
+    Remote(RemoteOpts),
+    // runs the agent of the agent transport, see `agent::main`
+    #[structopt(
+        name = "remote-agent",
+        raw(setting = "structopt::clap::AppSettings::Hidden")
+    )]
+    Agent { mode: String, args: Vec<String> },
 }
 
-/// Tries to parse the file [`config_path`]. Logs warnings and returns [`None`] if errors occur
-/// during reading or parsing, [`Some(Value)`] otherwise.
-fn config_from_file(config_path: &Path) -> Option<Value> {
-    let config_file = std::fs::read_to_string(config_path)
-        .map_err(|e| {
-            warn!(
-                "Can't parse config file '{}' (error: {})",
-                config_path.to_string_lossy(),
-                e
-            );
-        })
-        .ok()?;
-
-    let value = config_file
-        .parse::<Value>()
-        .map_err(|e| {
-            warn!(
-                "Can't parse config file '{}' (error: {})",
-                config_path.to_string_lossy(),
-                e
-            );
-        })
-        .ok()?;
+#[derive(StructOpt, Debug, Clone)]
+struct RemoteOpts {
+    #[structopt(
+        short = "r",
+        long = "remote",
+        help = "Remote ssh build server or the name of a server from the config file"
+    )]
+    remote: Option<String>,
+
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        help = "Print only the output of cargo and errors",
+        conflicts_with = "verbose"
+    )]
+    quiet: bool,
+
+    #[structopt(
+        short = "v",
+        long = "verbose",
+        help = "Print every command that is run and how long it took, -vv for even more",
+        parse(from_occurrences)
+    )]
+    verbose: u8,
+
+    #[structopt(
+        long = "log-json",
+        help = "Print cargo-remote's messages as JSON objects, one per line on stderr"
+    )]
+    log_json: bool,
+
+    #[structopt(
+        long = "log-file",
+        help = "Write the output of the remote cargo and cargo-remote's messages to this file \
+                as well, or to a new timestamped file if it is a directory",
+        parse(from_os_str)
+    )]
+    log_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "profile-name",
+        help = "Use the settings of this profile from the global config file"
+    )]
+    profile_name: Option<String>,
+
+    #[structopt(
+        long = "remote-base-dir",
+        help = "Directory on the build server the project is copied into [default: ~/remote-builds]"
+    )]
+    remote_base_dir: Option<String>,
+
+    #[structopt(
+        long = "remote-target-dir",
+        help = "Target directory on the build server, e.g. on a scratch disk, passed to cargo as \
+                CARGO_TARGET_DIR. Relative to the remote project directory [default: target]"
+    )]
+    remote_target_dir: Option<String>,
+
+    #[structopt(
+        long = "shared-target",
+        help = "Share one target directory on the build server between all projects that use \
+                this name, e.g. the checkouts of several branches [default: the project name]"
+    )]
+    shared_target: Option<Option<String>>,
+
+    #[structopt(
+        long = "dep-cache",
+        help = "Seed new target directories on the build server with the dependencies built for \
+                the same Cargo.lock and rustc version before"
+    )]
+    dep_cache: bool,
+
+    #[structopt(
+        long = "vendor",
+        help = "Vendor the dependencies locally and build against them offline, for build servers \
+                without access to crates.io"
+    )]
+    vendor: bool,
+
+    #[structopt(
+        long = "remote-dir-key",
+        help = "What makes the remote project directory unique: the local project path (path), \
+                the url of the git remote origin (git) or only the project name (name) \
+                [default: path]",
+        raw(possible_values = "&[\"path\", \"git\", \"name\"]")
+    )]
+    remote_dir_key: Option<String>,
+
+    #[structopt(
+        long = "remote-os",
+        help = "Operating system of the build server, windows runs the build in PowerShell and \
+                transfers files with sftp, auto asks the build server [default: unix]",
+        raw(possible_values = "&[\"unix\", \"macos\", \"windows\", \"auto\"]")
+    )]
+    remote_os: Option<String>,
+
+    #[structopt(
+        short = "b",
+        long = "build-env",
+        help = "Set remote environment variables. RUST_BACKTRACE, CC, LIB, etc. [default: RUST_BACKTRACE=1]"
+    )]
+    build_env: Option<String>,
+
+    #[structopt(
+        long = "env-set",
+        help = "Export KEY=VALUE in the remote shell before running cargo (can be repeated)",
+        number_of_values = 1,
+        parse(try_from_str = "parse_env_assignment")
+    )]
+    env_set: Vec<(String, String)>,
+
+    #[structopt(
+        long = "env-pass",
+        help = "Pass the local value of this environment variable on to the remote cargo \
+                (can be repeated)",
+        number_of_values = 1
+    )]
+    env_pass: Vec<String>,
+
+    #[structopt(
+        long = "sccache",
+        help = "Compile through sccache on the build server and print its statistics afterwards"
+    )]
+    sccache: bool,
+
+    #[structopt(
+        short = "d",
+        long = "rustup-default",
+        help = "Rustup default (stable|beta|nightly) [default: stable]"
+    )]
+    rustup_default: Option<String>,
+
+    #[structopt(
+        long = "toolchain",
+        help = "Toolchain to build with on the build server, like `cargo +<toolchain>` \
+                (a leading +<toolchain> argument works as well)",
+        parse(try_from_str = "parse_toolchain")
+    )]
+    toolchain: Option<String>,
+
+    #[structopt(
+        long = "auto-install-toolchain",
+        help = "Install the toolchain and the --target on the build server if they are missing"
+    )]
+    auto_install_toolchain: bool,
+
+    #[structopt(
+        short = "e",
+        long = "env",
+        help = "Environment profile. [default: /etc/profile]"
+    )]
+    env: Option<String>,
+
+    #[structopt(
+        long = "docker",
+        help = "Run the remote build in a docker container of this image, the build directory is \
+                mounted at /workspace"
+    )]
+    docker: Option<String>,
+
+    #[structopt(
+        long = "container-engine",
+        help = "Container engine used for --docker [default: docker]",
+        raw(possible_values = "&[\"docker\", \"podman\"]")
+    )]
+    container_engine: Option<String>,
+
+    #[structopt(
+        long = "nix",
+        help = "Run the remote build in the nix environment of the project (its flake or \
+                shell.nix)",
+        conflicts_with = "docker"
+    )]
+    nix: bool,
+
+    #[structopt(
+        long = "nix-flake",
+        help = "Run the remote build in `nix develop` of this flake, e.g. .#devshell",
+        conflicts_with = "docker"
+    )]
+    nix_flake: Option<String>,
+
+    #[structopt(
+        long = "target",
+        help = "Target triple to build for, paths given to --copy-back are relative to its \
+                target directory (can be repeated to build for several targets at once)",
+        number_of_values = 1
+    )]
+    target: Vec<String>,
+
+    #[structopt(
+        short = "c",
+        long = "copy-back",
+        help = "Transfer the target folder or specific file from that folder back to the local machine"
+    )]
+    copy_back: Option<Option<String>>,
+
+    #[structopt(
+        long = "copy-back-artifacts",
+        help = "Transfer only the binaries and libraries cargo built back to the local machine"
+    )]
+    copy_back_artifacts: bool,
+
+    #[structopt(
+        long = "codesign",
+        help = "Sign the binaries with this identity on a macOS build server before they are \
+                copied back, - signs them ad hoc"
+    )]
+    codesign: Option<String>,
+
+    #[structopt(
+        long = "copy-back-include",
+        help = "Only copy back files in target/ matching this rsync glob pattern (can be repeated)",
+        number_of_values = 1
+    )]
+    copy_back_include: Vec<String>,
+
+    #[structopt(
+        long = "copy-back-exclude",
+        help = "Don't copy back files in target/ matching this rsync glob pattern (can be repeated)",
+        number_of_values = 1
+    )]
+    copy_back_exclude: Vec<String>,
+
+    #[structopt(
+        long = "retrieve",
+        help = "Copy back a file or directory the build wrote outside of target/ as well, as \
+                <remote>[:<local>]. The remote path is relative to the build directory and ends \
+                with a / for a directory, the local one is relative to the project and defaults \
+                to the remote one (can be repeated)",
+        number_of_values = 1,
+        parse(try_from_str = "parse_retrieve")
+    )]
+    retrieve: Vec<(String, String)>,
+
+    #[structopt(
+        long = "upload",
+        help = "Upload a file or directory in the remote target/ to object storage after a \
+                successful build, e.g. release/my-app or glob patterns (can be repeated)",
+        number_of_values = 1
+    )]
+    upload: Vec<String>,
+
+    #[structopt(
+        long = "upload-url",
+        help = "Where --upload puts the artifacts, s3://bucket/prefix or gs://bucket/prefix"
+    )]
+    upload_url: Option<String>,
+
+    #[structopt(
+        long = "upload-endpoint",
+        help = "Endpoint of the S3 compatible object storage --upload uses, e.g. MinIO"
+    )]
+    upload_endpoint: Option<String>,
+
+    #[structopt(long = "upload-region", help = "Region of the bucket --upload uses")]
+    upload_region: Option<String>,
+
+    #[structopt(
+        long = "no-copy-lock",
+        help = "don't transfer the Cargo.lock file back to the local machine, e.g. after the \
+                remote cargo updated it"
+    )]
+    no_copy_lock: bool,
+
+    #[structopt(
+        long = "apply-fixes",
+        help = "Apply the changes of cargo fix and clippy --fix to the local files without asking"
+    )]
+    apply_fixes: bool,
+
+    #[structopt(
+        long = "run-locally",
+        help = "Run the test executables test-binaries copied back on the local machine"
+    )]
+    run_locally: bool,
+
+    #[structopt(
+        long = "debugger",
+        help = "Debugger of cargo remote debug: gdbserver and gdb, or lldb-server and lldb \
+                [default: gdb]",
+        raw(possible_values = "&[\"gdb\", \"lldb\"]")
+    )]
+    debugger: Option<String>,
+
+    #[structopt(
+        long = "debug-port",
+        help = "Port of the debug server on the build server, forwarded to the same local port \
+                [default: 2345]"
+    )]
+    debug_port: Option<u16>,
+
+    #[structopt(
+        long = "launch-debugger",
+        help = "Start the local debugger connected to the debug server of cargo remote debug, \
+                instead of printing the command"
+    )]
+    launch_debugger: bool,
+
+    #[structopt(
+        long = "manifest-path",
+        help = "Path to the manifest to execute",
+        default_value = "Cargo.toml",
+        parse(from_os_str)
+    )]
+    manifest_path: PathBuf,
+
+    #[structopt(
+        short = "h",
+        long = "transfer-hidden",
+        help = "Transfer hidden files and directories to the build server"
+    )]
+    hidden: bool,
+
+    #[structopt(
+        long = "transfer-hidden-file",
+        help = "Transfer this hidden file or directory even though hidden files aren't, a name \
+                or a path relative to the project (can be repeated)",
+        number_of_values = 1
+    )]
+    hidden_allow: Vec<String>,
+
+    #[structopt(
+        long = "transport",
+        help = "How files are transferred to the build server and back, agent keeps a \
+                cargo-remote running there that also runs the build [default: rsync]",
+        raw(possible_values = "&[\"rsync\", \"tar\", \"sftp\", \"agent\"]")
+    )]
+    transport: Option<String>,
+
+    #[structopt(
+        long = "sync",
+        help = "How the sources get to the build server: with the transport or by pushing the \
+                git HEAD and uncommitted changes [default: transport]",
+        raw(possible_values = "&[\"transport\", \"git\"]")
+    )]
+    sync: Option<String>,
+
+    #[structopt(
+        long = "rsync-daemon",
+        help = "Transfer files through this rsync daemon module (rsync://server/module) that \
+                serves the remote base directory, the build still runs over ssh"
+    )]
+    rsync_daemon: Option<String>,
+
+    #[structopt(
+        long = "agent-path",
+        help = "Path of a cargo-remote of the same version on the build server for the agent \
+                transport, instead of uploading this one"
+    )]
+    agent_path: Option<String>,
+
+    #[structopt(
+        long = "bwlimit",
+        help = "Limit the bandwidth of file transfers to this many KiB/s, k, m and g suffixes \
+                are allowed (rsync and sftp)",
+        parse(try_from_str = "parse_bwlimit")
+    )]
+    bwlimit: Option<u32>,
+
+    #[structopt(
+        long = "exclude",
+        help = "Exclude files matching this rsync pattern from the transfer (can be repeated)",
+        number_of_values = 1
+    )]
+    exclude: Vec<String>,
+
+    #[structopt(
+        long = "gitignore",
+        help = "Don't transfer files ignored by the project's .gitignore files"
+    )]
+    gitignore: bool,
+
+    #[structopt(
+        long = "no-patch-handling",
+        help = "Transfer [patch] entries and path dependencies outside of the project as they \
+                are, without copying their workspaces"
+    )]
+    no_patch_handling: bool,
+
+    #[structopt(
+        long = "show-patched-manifest",
+        help = "Print the patched workspaces that would be transferred and a diff of the \
+                rewritten manifests, without transferring anything",
+        conflicts_with = "no_patch_handling"
+    )]
+    show_patched_manifest: bool,
+
+    #[structopt(
+        long = "include",
+        help = "Transfer files matching this rsync pattern even if they are excluded, e.g. \
+                hidden ones (can be repeated)",
+        number_of_values = 1
+    )]
+    include: Vec<String>,
+
+    #[structopt(
+        long = "transfer-extra",
+        help = "Transfer a file or directory from outside the project as well, as \
+                <local>:<remote>. The local path is relative to the project, the remote one to \
+                the build directory (can be repeated)",
+        number_of_values = 1,
+        parse(try_from_str = "parse_transfer_extra")
+    )]
+    transfer_extra: Vec<(String, String)>,
 
-    Some(value)
+    #[structopt(long = "ssh-port", help = "Port of the ssh server on the build server")]
+    ssh_port: Option<u16>,
+
+    #[structopt(
+        long = "ssh-identity",
+        help = "Identity file (private key) used to connect to the build server"
+    )]
+    ssh_identity: Option<String>,
+
+    #[structopt(
+        long = "ssh-jump",
+        help = "Connect to the build server through this jump host ([user@]host[:port])"
+    )]
+    ssh_jump: Option<String>,
+
+    #[structopt(
+        long = "ssh-option",
+        help = "Additional option passed to every ssh invocation, e.g. \"-oBatchMode=yes\" (can be repeated)",
+        number_of_values = 1,
+        allow_hyphen_values = true
+    )]
+    ssh_options: Vec<String>,
+
+    #[structopt(
+        long = "ssh-keepalive",
+        help = "Seconds between keepalive messages to the build server, so quiet connections \
+                aren't dropped by NAT routers, 0 to turn them off [default: 30]"
+    )]
+    ssh_keepalive: Option<u32>,
+
+    #[structopt(
+        long = "ssh-keepalive-count",
+        help = "Unanswered keepalive messages after which the connection counts as lost \
+                [default: 4]"
+    )]
+    ssh_keepalive_count: Option<u32>,
+
+    #[structopt(
+        long = "auto-select",
+        help = "Build on the least loaded of the configured build servers"
+    )]
+    auto_select: bool,
+
+    #[structopt(
+        long = "distribute",
+        help = "Split the build by --target and -p/--package across all configured build servers \
+                and run the parts in parallel"
+    )]
+    distribute: bool,
+
+    #[structopt(
+        long = "dry-run",
+        help = "Print the ssh, rsync and other commands that would be run on the way, without \
+                running them"
+    )]
+    dry_run: bool,
+
+    #[structopt(
+        long = "timings",
+        help = "Print how long syncing, building and copying back took and how much was \
+                transferred, as text or as a JSON object",
+        raw(possible_values = "&[\"text\", \"json\"]")
+    )]
+    timings: Option<Option<String>>,
+
+    #[structopt(
+        long = "fallback-local",
+        help = "Run the cargo command locally if the build server can't be reached"
+    )]
+    fallback_local: bool,
+
+    #[structopt(
+        long = "auto",
+        help = "Run the cargo command locally if that is estimated to be faster, e.g. a check of a \
+                tiny change, from the build history and the measured bandwidth"
+    )]
+    auto: bool,
+
+    #[structopt(
+        long = "notify",
+        help = "Show a desktop notification when the build finished"
+    )]
+    notify: bool,
+
+    #[structopt(
+        long = "webhook",
+        help = "URL the result of the build is posted to as JSON, e.g. a Slack webhook"
+    )]
+    webhook: Option<String>,
+
+    #[structopt(
+        long = "resilient",
+        help = "Run cargo in a session on the build server that keeps going if the connection \
+                is lost, the output is streamed again after reconnecting"
+    )]
+    resilient: bool,
+
+    #[structopt(
+        long = "retries",
+        help = "Retry failed transfers and builds that lost the connection this many times, \
+                waiting longer after every attempt [default: 0]"
+    )]
+    retries: Option<u32>,
+
+    #[structopt(
+        long = "lock",
+        help = "What to do if another run uses the same build directory: wait for it, fail \
+                or don't lock the directory at all [default: wait]",
+        raw(possible_values = "&[\"wait\", \"fail\", \"off\"]")
+    )]
+    lock: Option<String>,
+
+    #[structopt(
+        long = "no-multiplex",
+        help = "Don't share one ssh connection between all transfers and the build"
+    )]
+    no_multiplex: bool,
+
+    #[structopt(
+        long = "forward-port",
+        help = "Forward a local port to the build server while the command runs, as \
+                <local>:<remote> or <port> (can be repeated)",
+        number_of_values = 1,
+        parse(try_from_str = "parse_port_forward")
+    )]
+    forward_ports: Vec<String>,
+
+    #[structopt(
+        long = "tty",
+        help = "Always allocate a pseudo terminal on the remote, even if there is no local one"
+    )]
+    tty: bool,
+
+    #[structopt(
+        long = "no-tty",
+        help = "Never allocate a pseudo terminal on the remote",
+        conflicts_with = "tty"
+    )]
+    no_tty: bool,
+
+    #[structopt(
+        long = "no-remap-paths",
+        help = "Leave the paths on the build server in the output of the remote cargo as they \
+                are instead of pointing them to the local files"
+    )]
+    no_remap_paths: bool,
+
+    #[structopt(
+        long = "output-format",
+        help = "github to put the phases of the run into log groups and turn errors and \
+                warnings into annotations in GitHub Actions [default: human]",
+        raw(possible_values = "&[\"human\", \"github\"]")
+    )]
+    output_format: Option<String>,
+
+    #[structopt(
+        long = "bench-output-dir",
+        help = "Directory in target/ that is copied back after cargo bench [default: criterion] \
+                (can be repeated)",
+        number_of_values = 1
+    )]
+    bench_output_dirs: Vec<String>,
+
+    #[structopt(
+        long = "pre-build",
+        help = "Shell command run in the remote build directory before cargo, the build is \
+                aborted if it fails"
+    )]
+    pre_build: Option<String>,
+
+    #[structopt(
+        long = "post-build",
+        help = "Shell command run in the remote build directory after cargo succeeded"
+    )]
+    post_build: Option<String>,
+
+    #[structopt(
+        long = "cargo-flag",
+        help = "Flag that is always passed to the remote cargo command (can be repeated)",
+        number_of_values = 1,
+        allow_hyphen_values = true
+    )]
+    cargo_flags: Vec<String>,
+
+    #[structopt(
+        long = "offline",
+        help = "Run the remote cargo with --offline, it only uses the dependencies fetched on the \
+                build server before, e.g. with \"cargo remote fetch\""
+    )]
+    offline: bool,
+
+    #[structopt(
+        help = "cargo command that will be executed remotely, \"watch\" to re-run the command \
+                given in the remote options on every change, \"fetch\" to download the \
+                dependencies on the build server ahead of time, \"clippy\" to run clippy with a \
+                summary of its lints, \"test-binaries\" to build the tests and copy back their \
+                executables, \"debug\" to start the binary under a debug server on the build \
+                server, \"flamegraph\" to profile it there and copy back the flamegraph, \
+                \"coverage\" to measure the test coverage with cargo llvm-cov and copy back the \
+                report, \
+                \"flycheck\" to check the project for rust-analyzer, \"exec\" to run a \
+                shell command in the build directory on the build server, \"shell\" to open a \
+                shell there, \"publish\" to publish the crate \
+                from the build server with the local registry token, \"status\" to list the build \
+                directories on the build server, \"gc\" to remove old ones, \"check-env\" to \
+                check that the build server has everything a build needs, \"provision\" to launch \
+                or terminate the instance of a provisioned build server, \"stats\" to show the \
+                recorded runs or \"completions\" to print the completion script for a shell"
+    )]
+    command: String,
+
+    #[structopt(
+        help = "cargo options and flags that will be applied remotely",
+        name = "remote options"
+    )]
+    options: Vec<String>,
+}
+
+impl RemoteOpts {
+    /// Fills every option that wasn't given on the command line from [`config`]. Lists like
+    /// `exclude` or `cargo_flags` are extended. Call it in order of precedence, values that are
+    /// already set are never overwritten.
+    fn complete_from_config(&mut self, config: &Value) {
+        let string = |key: &str| config.get(key).and_then(Value::as_str).map(String::from);
+        let strings = |key: &str| config::string_list(config, key);
+
+        self.remote = self.remote.take().or_else(|| string("remote"));
+        self.remote_base_dir = self.remote_base_dir.take().or_else(|| string("remote_base_dir"));
+        self.remote_target_dir =
+            self.remote_target_dir.take().or_else(|| string("remote_target_dir"));
+        // `shared_target = true` or `shared_target = "name"`
+        if self.shared_target.is_none() {
+            self.shared_target = match config.get("shared_target") {
+                Some(Value::Boolean(true)) => Some(None),
+                Some(Value::String(name)) => Some(Some(name.clone())),
+                _ => None,
+            };
+        }
+        self.remote_dir_key = self.remote_dir_key.take().or_else(|| string("remote_dir_key"));
+        self.remote_os = self.remote_os.take().or_else(|| string("remote_os"));
+        self.rustup_default = self.rustup_default.take().or_else(|| string("rustup_default"));
+        self.toolchain = self.toolchain.take().or_else(|| string("toolchain"));
+        self.env = self.env.take().or_else(|| string("env"));
+        self.docker = self.docker.take().or_else(|| string("docker"));
+        self.codesign = self.codesign.take().or_else(|| string("codesign"));
+        self.transport = self.transport.take().or_else(|| string("transport"));
+        // `sync = "git"` or a `[sync]` table with the mode and additional patterns
+        let sync_table = config.get("sync").filter(|sync| sync.is_table());
+        self.sync = self.sync.take().or_else(|| string("sync")).or_else(|| {
+            sync_table
+                .and_then(|sync| sync.get("mode"))
+                .and_then(Value::as_str)
+                .map(String::from)
+        });
+        if let Some(sync) = sync_table {
+            self.exclude.extend(config::string_list(sync, "exclude"));
+            self.include.extend(config::string_list(sync, "include"));
+        }
+        self.rsync_daemon = self.rsync_daemon.take().or_else(|| string("rsync_daemon"));
+        self.agent_path = self.agent_path.take().or_else(|| string("agent_path"));
+        self.lock = self.lock.take().or_else(|| string("lock"));
+        self.output_format = self.output_format.take().or_else(|| string("output_format"));
+        // `timings = true` or `timings = "json"`
+        if self.timings.is_none() {
+            self.timings = match config.get("timings") {
+                Some(Value::Boolean(true)) => Some(None),
+                Some(Value::String(format)) => Some(Some(format.clone())),
+                _ => None,
+            };
+        }
+        // `bwlimit = 500` or `bwlimit = "2m"`
+        if self.bwlimit.is_none() {
+            let limit = match config.get("bwlimit") {
+                Some(Value::Integer(limit)) => Some(limit.to_string()),
+                Some(Value::String(limit)) => Some(limit.clone()),
+                _ => None,
+            };
+            self.bwlimit = limit.and_then(|limit| match parse_bwlimit(&limit) {
+                Ok(limit) => Some(limit),
+                Err(e) => {
+                    warn!("Ignoring bandwidth limit from config: {}", e);
+                    None
+                }
+            });
+        }
+        self.container_engine = self.container_engine.take().or_else(|| string("container_engine"));
+        self.nix_flake = self.nix_flake.take().or_else(|| string("nix_flake"));
+        self.pre_build = self.pre_build.take().or_else(|| string("pre_build"));
+        self.post_build = self.post_build.take().or_else(|| string("post_build"));
+        self.retries = self.retries.take().or_else(|| {
+            config
+                .get("retries")
+                .and_then(Value::as_integer)
+                .map(|retries| retries.max(0) as u32)
+        });
+        self.ssh_keepalive = self.ssh_keepalive.take().or_else(|| {
+            config
+                .get("ssh_keepalive")
+                .and_then(Value::as_integer)
+                .map(|secs| secs.max(0) as u32)
+        });
+        self.ssh_keepalive_count = self.ssh_keepalive_count.take().or_else(|| {
+            config
+                .get("ssh_keepalive_count")
+                .and_then(Value::as_integer)
+                .map(|count| count.max(1) as u32)
+        });
+        self.ssh_port = self.ssh_port.take().or_else(|| {
+            config
+                .get("ssh_port")
+                .and_then(Value::as_integer)
+                .map(|port| port as u16)
+        });
+        self.debugger = self.debugger.take().or_else(|| string("debugger"));
+        self.debug_port = self.debug_port.take().or_else(|| {
+            config
+                .get("debug_port")
+                .and_then(Value::as_integer)
+                .map(|port| port as u16)
+        });
+        self.launch_debugger |=
+            config.get("launch_debugger").and_then(Value::as_bool) == Some(true);
+        self.ssh_identity = self.ssh_identity.take().or_else(|| string("ssh_identity"));
+        self.ssh_jump = self.ssh_jump.take().or_else(|| string("ssh_jump"));
+        self.webhook = self.webhook.take().or_else(|| string("webhook"));
+        // the credentials of the `[upload]` table are read by `upload::credentials`
+        if let Some(upload) = config.get("upload") {
+            let string = |key: &str| upload.get(key).and_then(Value::as_str).map(String::from);
+            self.upload.extend(config::string_list(upload, "artifacts"));
+            self.upload_url = self.upload_url.take().or_else(|| string("url"));
+            self.upload_endpoint = self.upload_endpoint.take().or_else(|| string("endpoint"));
+            self.upload_region = self.upload_region.take().or_else(|| string("region"));
+        }
+        self.build_env = self.build_env.take().or_else(|| match config.get("build_env")? {
+            Value::String(vars) => Some(vars.clone()),
+            Value::Table(vars) => Some(
+                vars.iter()
+                    .filter_map(|(name, value)| value.as_str().map(|v| format!("{}={}", name, v)))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            _ => None,
+        });
+
+        // `transfer_hidden = true` or a list of the hidden files to transfer
+        self.hidden |= config
+            .get("transfer_hidden")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        self.hidden_allow.extend(strings("transfer_hidden"));
+        self.copy_back_artifacts |= config
+            .get("copy_back_artifacts")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        self.auto_install_toolchain |= config
+            .get("auto_install_toolchain")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        self.nix |= config.get("nix").and_then(Value::as_bool).unwrap_or(false);
+        self.fallback_local |= config
+            .get("fallback_local")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        self.auto |= config.get("auto").and_then(Value::as_bool).unwrap_or(false);
+        self.notify |= config.get("notify").and_then(Value::as_bool).unwrap_or(false);
+        self.offline |= config.get("offline").and_then(Value::as_bool).unwrap_or(false);
+        self.dep_cache |= config.get("dep_cache").and_then(Value::as_bool).unwrap_or(false);
+        self.vendor |= config.get("vendor").and_then(Value::as_bool).unwrap_or(false);
+        self.resilient |= config
+            .get("resilient")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        self.no_copy_lock |= config.get("copy_lock").and_then(Value::as_bool) == Some(false);
+        self.apply_fixes |= config.get("apply_fixes").and_then(Value::as_bool) == Some(true);
+        self.gitignore |= config
+            .get("gitignore")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        self.no_multiplex |= config.get("multiplex").and_then(Value::as_bool) == Some(false);
+        self.no_remap_paths |= config.get("remap_paths").and_then(Value::as_bool) == Some(false);
+        self.no_patch_handling |=
+            config.get("patch_handling").and_then(Value::as_bool) == Some(false);
+        self.exclude.extend(strings("exclude"));
+        // a single target or a list of them
+        self.target.extend(string("target"));
+        self.target.extend(strings("target"));
+        self.env_pass.extend(strings("env_pass"));
+        // `sccache = true` or a `[sccache]` table with the cache backend to use
+        match config.get("sccache") {
+            Some(Value::Boolean(enabled)) => self.sccache |= enabled,
+            Some(Value::Table(backend)) => {
+                self.sccache = true;
+                for (key, var) in SCCACHE_BACKEND_KEYS.iter() {
+                    let value = backend.get(*key).and_then(Value::as_str);
+                    if let Some(value) = value {
+                        if !self.env_set.iter().any(|(name, _)| name == var) {
+                            self.env_set.push((var.to_string(), value.to_string()));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.copy_back_include.extend(strings("copy_back_include"));
+        self.copy_back_exclude.extend(strings("copy_back_exclude"));
+        self.retrieve.extend(
+            strings("retrieve")
+                .iter()
+                .filter_map(|spec| match parse_retrieve(spec) {
+                    Ok(retrieve) => Some(retrieve),
+                    Err(e) => {
+                        warn!("Ignoring retrieve from config: {}", e);
+                        None
+                    }
+                }),
+        );
+        self.bench_output_dirs.extend(strings("bench_output_dirs"));
+        self.forward_ports.extend(
+            strings("forward_ports")
+                .iter()
+                .filter_map(|spec| match parse_port_forward(spec) {
+                    Ok(forward) => Some(forward),
+                    Err(e) => {
+                        warn!("Ignoring port forward from config: {}", e);
+                        None
+                    }
+                }),
+        );
+        self.transfer_extra.extend(
+            strings("transfer_extra")
+                .iter()
+                .filter_map(|spec| match parse_transfer_extra(spec) {
+                    Ok(extra) => Some(extra),
+                    Err(e) => {
+                        warn!("Ignoring extra transfer from config: {}", e);
+                        None
+                    }
+                }),
+        );
+        self.ssh_options.extend(strings("ssh_options"));
+        self.cargo_flags.extend(strings("cargo_flags"));
+    }
+}
+
+/// Turns `<local>:<remote>`, `<local>:<host>:<remote>` or `<port>` into the argument for ssh's
+/// `-L` option. Remote ports without a host are forwarded to `localhost` on the build server.
+fn parse_port_forward(spec: &str) -> Result<String, String> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let (local, host, remote) = match parts.as_slice() {
+        [port] => (*port, "localhost", *port),
+        [local, remote] => (*local, "localhost", *remote),
+        [local, host, remote] if !host.is_empty() => (*local, *host, *remote),
+        _ => return Err(format!("invalid port forward '{}'", spec)),
+    };
+    for port in &[local, remote] {
+        port.parse::<u16>()
+            .map_err(|_| format!("invalid port '{}' in port forward '{}'", port, spec))?;
+    }
+    Ok(format!("{}:{}:{}", local, host, remote))
+}
+
+/// Whether [`path`] is a relative path that stays inside the build directory.
+fn inside_build_dir(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('/')
+        && !path.starts_with('~')
+        && !path.split('/').any(|component| component == "..")
+}
+
+/// Splits `<local>:<remote>` of `--transfer-extra`. The remote path has to stay inside the build
+/// directory, everything else there is replaced by the next sync anyway.
+fn parse_transfer_extra(spec: &str) -> Result<(String, String), String> {
+    let (local, remote) = spec
+        .rsplit_once(':')
+        .ok_or_else(|| format!("invalid extra transfer '{}', use <local>:<remote>", spec))?;
+    let remote = remote.trim_end_matches('/');
+    if local.is_empty() || !inside_build_dir(remote) {
+        return Err(format!(
+            "invalid extra transfer '{}', the remote path has to be relative to the build \
+             directory",
+            spec
+        ));
+    }
+    Ok((local.to_string(), remote.to_string()))
+}
+
+/// Splits `<remote>[:<local>]` of `--retrieve`, the local path defaults to the remote one.
+fn parse_retrieve(spec: &str) -> Result<(String, String), String> {
+    let (remote, local) = spec.split_once(':').unwrap_or((spec, spec));
+    if local.is_empty() || !inside_build_dir(remote.trim_end_matches('/')) {
+        return Err(format!(
+            "invalid retrieve '{}', the remote path has to be relative to the build directory",
+            spec
+        ));
+    }
+    Ok((remote.to_string(), local.to_string()))
+}
+
+/// Checks that [`toolchain`] is a plausible rustup toolchain name, it ends up in the remote shell
+/// command unquoted.
+fn parse_toolchain(toolchain: &str) -> Result<String, String> {
+    let toolchain = toolchain.strip_prefix('+').unwrap_or(toolchain);
+    if toolchain.is_empty()
+        || !toolchain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        return Err(format!("invalid toolchain '{}'", toolchain));
+    }
+    Ok(toolchain.to_string())
+}
+
+/// Parses a bandwidth limit like rsync's `--bwlimit` into KiB/s: a number with an optional `k`,
+/// `m` or `g` suffix.
+fn parse_bwlimit(limit: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid bandwidth limit '{}'", limit);
+    let lower = limit.trim().to_ascii_lowercase();
+    let (number, factor) = match lower.chars().last() {
+        Some('k') => (&lower[..lower.len() - 1], 1),
+        Some('m') => (&lower[..lower.len() - 1], 1024),
+        Some('g') => (&lower[..lower.len() - 1], 1024 * 1024),
+        _ => (&lower[..], 1),
+    };
+    let number: u32 = number.parse().map_err(|_| invalid())?;
+    match number.checked_mul(factor) {
+        Some(0) | None => Err(invalid()),
+        Some(limit) => Ok(limit),
+    }
+}
+
+/// Parses a `KEY=VALUE` environment variable assignment.
+fn parse_env_assignment(assignment: &str) -> Result<(String, String), String> {
+    let mut parts = assignment.splitn(2, '=');
+    let key = parts.next().unwrap_or_default();
+    let value = parts
+        .next()
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", assignment))?;
+    if !is_env_name(key) {
+        return Err(format!("invalid environment variable name '{}'", key));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn is_env_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Name of the project directory on the build server. By default it is made unique with a hash
+/// of the local project path, so two checkouts with the same name don't overwrite each other.
+/// With [`key`] `git` the url of the origin remote is hashed instead, so every checkout of the
+/// same repository shares one directory; `name` uses the plain project name.
+fn remote_dir_name(project_dir: &Path, project_name: &str, key: Option<&str>) -> String {
+    let unique = match key.unwrap_or("path") {
+        "name" => return project_name.to_string(),
+        "git" => Command::new("git")
+            .arg("-C")
+            .arg(project_dir)
+            .args(["remote", "get-url", "origin"])
+            .query_output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .or_else(|| {
+                warn!("Can't determine the git remote of the project, using its path instead");
+                None
+            }),
+        _ => None,
+    };
+    let unique = unique.unwrap_or_else(|| {
+        project_dir
+            .canonicalize()
+            .unwrap_or_else(|_| project_dir.to_path_buf())
+            .to_string_lossy()
+            .into_owned()
+    });
+    format!("{}-{}", project_name, util::short_hash(unique.as_bytes()))
 }
 
 fn main() {
-    simple_logger::SimpleLogger
-        ::from_env()
-        .init()
-        .unwrap();
+    let opts = match Opts::from_args() {
+        Opts::Remote(opts) => opts,
+        Opts::Agent { mode, args } => {
+            if let Err(e) = agent::main(&mode, &args) {
+                eprintln!("cargo-remote agent: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    };
+    let verbosity = match opts.quiet {
+        true => -1,
+        false => opts.verbose.min(2) as i8,
+    };
+    logging::init(verbosity, opts.log_json);
+    logging::set_dry_run(opts.dry_run);
+    if let Some(path) = &opts.log_file {
+        if let Err(e) = open_log_file(path) {
+            error::exit_with(Err(e));
+        }
+    }
+    error::exit_with(run(opts))
+}
 
-    let Opts::Remote {
-        remote,
-        build_env,
-        rustup_default,
-        env,
-        copy_back,
-        no_copy_lock,
-        manifest_path,
-        hidden,
-        command,
-        options,
-    } = Opts::from_args();
+/// Starts writing the output to [`path`], or to `cargo-remote-<time>.log` in it if it is a
+/// directory.
+fn open_log_file(path: &Path) -> Result<(), Error> {
+    let path = match path.is_dir() {
+        true => {
+            let time = util::format_utc(manage::now()).replace(&['-', ':'][..], "");
+            path.join(format!("cargo-remote-{}.log", time.replace(' ', "-")))
+        }
+        false => path.to_path_buf(),
+    };
+    let file = std::fs::File::create(&path)
+        .map_err(|e| Error::command(&format!("create the log file {}", path.display()), e, &[]))?;
+    logging::set_log_file(file);
+    info!("Writing the build output to {}", path.display());
+    Ok(())
+}
 
+/// Runs cargo-remote with [`opts`] and returns the exit code.
+fn run(mut opts: RemoteOpts) -> Result<i32, Error> {
+    if opts.command == "completions" {
+        return completions::completions(RemoteOpts::clap(), &opts.options);
+    }
     let mut metadata_cmd = cargo_metadata::MetadataCommand::new();
-    metadata_cmd.manifest_path(manifest_path).no_deps();
+    metadata_cmd.manifest_path(&opts.manifest_path).no_deps();
 
     let project_metadata = match metadata_cmd.exec() {
         Ok(m) => m,
         Err(cargo_metadata::Error::CargoMetadata { stderr }) => {
-            error!("Cargo Metadata execution failed:\n{}", stderr);
-            exit(1)
-        },
-        Err(e) => {
-            error!("Cargo Metadata failed:\n{:?}", e);
-            exit(1)
+            return Err(Error::Metadata(format!("Cargo Metadata execution failed:\n{}", stderr)))
         },
+        Err(e) => return Err(Error::Metadata(format!("Cargo Metadata failed:\n{:?}", e))),
     };
     let project_dir = project_metadata.workspace_root;
+    let local_target_dir = project_metadata.target_directory;
     debug!("Project dir: {:?}", project_dir);
     let mut manifest_path = project_dir.clone();
     manifest_path.push("Cargo.toml");
-    let project_name = project_metadata
+    let project_name = match project_metadata
         .packages
         .iter()
         .find(|p| p.manifest_path == manifest_path)
-        .map_or_else(
-            || {
-                debug!("No metadata found. Setting the remote dir name like the local. Or use --manifest_path for execute");
-                project_dir.file_name().and_then(|x| x.to_str()).unwrap()
-            },
-            |p| &p.name,
-        );
+    {
+        Some(package) => package.name.as_str(),
+        None => {
+            debug!("No metadata found. Setting the remote dir name like the local. Or use --manifest_path for execute");
+            let name = project_dir.file_name().unwrap_or_default();
+            util::utf8_path(Path::new(name)).map_err(Error::Metadata)?
+        }
+    };
     debug!("Project name: {:?}", project_name);
-    let configs = vec![
-        config_from_file(&project_dir.join(".cargo-remote.toml")),
-        xdg::BaseDirectories::with_prefix("cargo-remote")
-            .ok()
-            .and_then(|base| base.find_config_file("cargo-remote.toml"))
-            .and_then(|p: PathBuf| config_from_file(&p)),
+    if opts.command == "stats" {
+        // only reads the local build history
+        return history::stats(&project_dir, &opts.options);
+    }
+    let configs = Configs::load(&project_dir, opts.profile_name.as_deref());
+    let auto_select = opts.auto_select
+        || configs
+            .layers
+            .iter()
+            .any(|c| c.get("auto_select").and_then(Value::as_bool) == Some(true));
+    if auto_select && opts.remote.is_none() {
+        let base_dir = opts.remote_base_dir.as_deref().unwrap_or(DEFAULT_REMOTE_BASE_DIR);
+        let cli_args: Vec<String> = opts.cargo_flags.iter().chain(&opts.options).cloned().collect();
+        let mut targets = build::targets(&cli_args);
+        targets.extend(opts.target.iter().cloned());
+        match probe::select_server(&configs, &cli_ssh_options(&opts), base_dir, &targets) {
+            Some(name) => {
+                info!("Selected build server '{}'", name);
+                opts.remote = Some(name);
+            }
+            None => warn!("None of the configured build servers could be probed"),
+        }
+    }
+    let mut server = opts
+        .remote
+        .clone()
+        .or_else(|| configs.remote())
+        .and_then(|name| configs.server(&name));
+    if opts.command == "provision" {
+        return provision::provision(server, &opts.options, &cli_ssh_options(&opts));
+    }
+    let distribute = opts.distribute
+        || configs
+            .layers
+            .iter()
+            .any(|c| c.get("distribute").and_then(Value::as_bool) == Some(true));
+    if distribute {
+        let builds = configs
+            .servers()
+            .into_iter()
+            .map(|mut server| {
+                provision::ensure(&mut server, &cli_ssh_options(&opts))?;
+                wake::wake(&server, &cli_ssh_options(&opts))?;
+                let mut opts = opts.clone();
+                opts.remote = Some(server.name.clone());
+                let credentials = upload::credentials(Some(&server), &configs);
+                let mut opts = configured_opts(opts, &configs, Some(server));
+                opts.auto = false;
+                remote_build(opts, &project_dir, project_name, &local_target_dir, credentials)
+            })
+            .collect::<Result<Vec<RemoteBuild>, Error>>()?;
+        return distribute::distribute(builds, &project_metadata.packages);
+    }
+    if let Some(server) = server.as_mut() {
+        provision::ensure(server, &cli_ssh_options(&opts))?;
+        wake::wake(server, &cli_ssh_options(&opts))?;
+    }
+    let credentials = upload::credentials(server.as_ref(), &configs);
+    let mut opts = configured_opts(opts, &configs, server);
+    // only builds decide where they run, the subcommands need the build server
+    let subcommands = [
+        "watch",
+        "status",
+        "gc",
+        "check-env",
+        "fetch",
+        "flycheck",
+        "exec",
+        "shell",
+        "publish",
+        "test-binaries",
+        "debug",
+        "flamegraph",
+        "coverage",
     ];
+    if subcommands.contains(&opts.command.as_str()) {
+        opts.auto = false;
+    }
+    if opts.show_patched_manifest {
+        // nothing is run on the build server
+        opts.no_multiplex = true;
+        let remote_build =
+            remote_build(opts, &project_dir, project_name, &local_target_dir, credentials)?;
+        return remote_build.patches.show();
+    }
+    let run_locally = opts.run_locally;
+    let debug = debug::Debug {
+        debugger: debug::Debugger::from_name(opts.debugger.as_deref()),
+        port: opts.debug_port.unwrap_or(debug::DEFAULT_PORT),
+        launch: opts.launch_debugger,
+        args: Vec::new(),
+    };
+    let remote_build =
+        remote_build(opts, &project_dir, project_name, &local_target_dir, credentials)?;
+    let command = remote_build.command.as_str();
+    if remote_build.remote_os == RemoteOs::Windows
+        && [
+            "status",
+            "gc",
+            "check-env",
+            "exec",
+            "shell",
+            "publish",
+            "debug",
+            "flamegraph",
+            "coverage",
+        ]
+        .contains(&command)
+    {
+        return Err(Error::Config(format!(
+            "'cargo remote {}' isn't supported on Windows build servers",
+            command
+        )));
+    }
+    match command {
+        "watch" => watch::watch(remote_build),
+        "status" => manage::status(remote_build),
+        "gc" => manage::gc(remote_build),
+        "check-env" => check::check_env(remote_build),
+        "flycheck" => flycheck::flycheck(remote_build),
+        "exec" => exec::exec(remote_build),
+        "shell" => exec::shell(remote_build),
+        "publish" => publish::publish(remote_build),
+        "clippy" => clippy::clippy(remote_build),
+        "test-binaries" => test_binaries::test_binaries(remote_build, run_locally),
+        "debug" => debug::debug(remote_build, debug),
+        "flamegraph" => flamegraph::flamegraph(remote_build),
+        "coverage" => coverage::coverage(remote_build),
+        _ => remote_build.run(),
+    }
+}
 
-    // TODO: move Opts::Remote fields into own type and implement complete_from_config(&mut self, config: &Value)
-    let build_server = remote
-        .or_else(|| {
-            configs
-                .into_iter()
-                .flat_map(|config| config.and_then(|c| c["remote"].as_str().map(String::from)))
-                .next()
-        })
-        .unwrap_or_else(|| {
-            error!("No remote build server was defined (use config file or --remote flag)");
-            exit(-3);
-        });
+/// The ssh options given on the command line, for connections made before the build is set up.
+fn cli_ssh_options(opts: &RemoteOpts) -> Vec<String> {
+    let mut ssh_options = ssh::connection_options(
+        opts.ssh_port,
+        opts.ssh_identity.as_deref(),
+        opts.ssh_jump.as_deref(),
+    );
+    ssh_options.extend(opts.ssh_options.iter().cloned());
+    ssh_options
+}
 
-    let build_path = format!("~/remote-builds/{}/", project_name);
-
-    debug!("Transferring sources to build server.");
-    // transfer project to build server
-    let mut rsync_to = Command::new("rsync");
-    rsync_to
-        .arg("-a")
-        .arg("-q")
-        .arg("--delete")
-        .arg("--compress")
-        .arg(PROGRESS_FLAG)
-        .arg("--exclude")
-        .arg("target");
-
-    if !hidden {
-        rsync_to.arg("--exclude").arg(".*");
-    }
-
-    rsync_to
-        .arg("--rsync-path")
-        .arg("mkdir -p remote-builds && rsync")
-        .arg(format!("{}/", project_dir.to_string_lossy()))
-        .arg(format!("{}:{}", build_server, build_path))
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()
-        .unwrap_or_else(|e| {
-            error!("Failed to transfer project to build server (error: {})", e);
-            exit(-4);
-        });
-    debug!("Build ENV: {:?}", build_env);
-    debug!("Environment profile: {:?}", env);
-    debug!("Build path: {:?}", build_path);
-    let build_command = format!(
-        "source {}; rustup default {}; cd {}; {} cargo {} {}",
-        env,
+/// [`opts`] completed from the settings of the build [`server`] and all config files.
+fn configured_opts(mut opts: RemoteOpts, configs: &Configs, server: Option<Server>) -> RemoteOpts {
+    if let Some(server) = server {
+        debug!("Using configured build server '{}' ({})", server.name, server.destination);
+        opts.remote = Some(server.destination.clone());
+        opts.complete_from_config(&server.settings);
+    }
+    for config in &configs.layers {
+        opts.complete_from_config(config);
+    }
+    opts
+}
+
+/// Sets up the build described by [`opts`], including the connection to its build server.
+fn remote_build(
+    opts: RemoteOpts,
+    project_dir: &Path,
+    project_name: &str,
+    local_target_dir: &Path,
+    upload_credentials: Option<upload::Credentials>,
+) -> Result<RemoteBuild, Error> {
+    let RemoteOpts {
+        remote,
+        quiet: _,
+        verbose: _,
+        log_json: _,
+        log_file: _,
+        profile_name: _,
+        remote_base_dir,
+        mut remote_target_dir,
+        shared_target,
+        mut dep_cache,
+        mut vendor,
+        remote_dir_key,
+        remote_os,
+        build_env,
+        env_set,
+        env_pass,
+        sccache,
         rustup_default,
+        mut toolchain,
+        mut auto_install_toolchain,
+        env,
+        mut docker,
+        container_engine,
+        mut nix,
+        mut nix_flake,
+        target,
+        copy_back,
+        mut copy_back_artifacts,
+        mut codesign,
+        copy_back_include,
+        copy_back_exclude,
+        retrieve,
+        upload,
+        upload_url,
+        upload_endpoint,
+        upload_region,
+        no_copy_lock,
+        apply_fixes,
+        run_locally: _,
+        debugger: _,
+        debug_port: _,
+        launch_debugger: _,
+        manifest_path: _,
+        hidden,
+        hidden_allow,
+        transport,
+        sync,
+        rsync_daemon,
+        agent_path,
+        bwlimit,
+        exclude,
+        include,
+        transfer_extra,
+        gitignore,
+        no_patch_handling,
+        show_patched_manifest: _,
+        ssh_port,
+        ssh_identity,
+        ssh_jump,
+        ssh_options,
+        ssh_keepalive,
+        ssh_keepalive_count,
+        auto_select: _,
+        distribute: _,
+        dry_run: _,
+        timings,
+        fallback_local,
+        auto,
+        notify,
+        webhook,
+        mut resilient,
+        retries,
+        lock,
+        no_multiplex,
+        forward_ports,
+        tty,
+        no_tty,
+        no_remap_paths,
+        output_format,
+        bench_output_dirs,
+        pre_build,
+        post_build,
+        mut cargo_flags,
+        offline,
+        mut command,
+        mut options,
+    } = opts;
+    if offline && !cargo_flags.iter().any(|flag| flag == "--offline") {
+        cargo_flags.push("--offline".to_string());
+    }
+    // `cargo remote +nightly build` like `cargo +nightly build`
+    if command.starts_with('+') {
+        toolchain = Some(parse_toolchain(&command).map_err(Error::Config)?);
+        if options.is_empty() {
+            return Err(Error::Config(format!("No cargo command given after {}", command)));
+        }
+        command = options.remove(0);
+    }
+    let build_env = build_env.unwrap_or_else(|| "RUST_BACKTRACE=1".to_string());
+    let rustup_default = rustup_default.unwrap_or_else(|| "stable".to_string());
+
+    let engine = container_engine.as_deref().unwrap_or("docker");
+    let engine = container::Engine::from_name(engine).ok_or_else(|| {
+        Error::Config(format!("Unknown container engine '{}' (use docker or podman)", engine))
+    })?;
+
+    let lock_name = lock.as_deref().unwrap_or("wait");
+    let mut lock = lock::LockMode::from_name(lock_name).ok_or_else(|| {
+        Error::Config(format!("Unknown lock mode '{}' (use wait, fail or off)", lock_name))
+    })?;
+
+    let github = match output_format.as_deref().unwrap_or("human") {
+        "human" => false,
+        "github" => true,
+        format => {
+            return Err(Error::Config(format!(
+                "Unknown output format '{}' (use human or github)",
+                format
+            )))
+        }
+    };
+
+    let timings = match timings {
+        Some(format) => {
+            let format = format.as_deref().unwrap_or("text");
+            Some(timings::Format::from_name(format).ok_or_else(|| {
+                Error::Config(format!("Unknown timings format '{}' (use text or json)", format))
+            })?)
+        }
+        None => None,
+    };
+
+    let mut env_vars = env_set;
+    for name in env_pass {
+        if !is_env_name(&name) {
+            warn!("Invalid environment variable name '{}', not passing it on", name);
+            continue;
+        }
+        match std::env::var(&name) {
+            Ok(value) => env_vars.push((name, value)),
+            Err(_) => warn!("Environment variable {} is not set locally, not passing it on", name),
+        }
+    }
+
+    if sccache && !env_vars.iter().any(|(name, _)| name == "RUSTC_WRAPPER") {
+        env_vars.push(("RUSTC_WRAPPER".to_string(), "sccache".to_string()));
+    }
+
+    let build_server = remote.ok_or_else(|| {
+        Error::Config(
+            "No remote build server was defined (use config file or --remote flag)".to_string(),
+        )
+    })?;
+
+    let remote_base_dir = remote_base_dir.unwrap_or_else(|| DEFAULT_REMOTE_BASE_DIR.to_string());
+    let shared_target = match (shared_target, &remote_target_dir) {
+        (Some(_), Some(_)) => {
+            warn!("--remote-target-dir is given, not using a shared target directory");
+            false
+        }
+        (Some(name), None) => {
+            let name = name.unwrap_or_else(|| project_name.to_string());
+            if name.is_empty() || name.contains('/') {
+                return Err(Error::Config(format!("Invalid shared target name '{}'", name)));
+            }
+            // hidden, so status and gc leave it alone
+            remote_target_dir = Some(format!(
+                "{}/.shared-targets/{}",
+                remote_base_dir.trim_end_matches('/'),
+                name
+            ));
+            true
+        }
+        (None, _) => false,
+    };
+    let build_path = format!(
+        "{}/{}/",
+        remote_base_dir.trim_end_matches('/'),
+        remote_dir_name(project_dir, project_name, remote_dir_key.as_deref())
+    );
+
+    let mut connection_options =
+        ssh::connection_options(ssh_port, ssh_identity.as_deref(), ssh_jump.as_deref());
+    connection_options.extend(ssh_options);
+    // after the --ssh-option ones, ssh uses the first value it gets for an option
+    connection_options.extend(ssh::keepalive_options(
+        ssh_keepalive.unwrap_or(30),
+        ssh_keepalive_count.unwrap_or(4),
+    ));
+    let mut ssh = Ssh::new(&build_server, connection_options);
+    // the OpenSSH of Windows has no connection sharing
+    let multiplex = !no_multiplex && !cfg!(windows);
+    // --auto opens it when the build goes remote
+    if multiplex && !auto {
+        ssh.start_master();
+    }
+
+    let remote_os_name = remote_os.as_deref().unwrap_or("unix");
+    let remote_os = match remote_os_name {
+        "auto" => RemoteOs::detect(&ssh),
+        name => RemoteOs::from_name(name).ok_or_else(|| {
+            Error::Config(format!(
+                "Unknown remote OS '{}' (use unix, macos, windows or auto)",
+                name
+            ))
+        })?,
+    };
+    let windows = remote_os == RemoteOs::Windows;
+    // the Homebrew and rustup paths of a Mac are set up in the zsh profile
+    let env = env.unwrap_or_else(|| match remote_os {
+        RemoteOs::MacOs => "~/.zprofile".to_string(),
+        _ => "/etc/profile".to_string(),
+    });
+    if codesign.is_some() && remote_os != RemoteOs::MacOs {
+        warn!("--codesign only works on macOS build servers, ignoring it");
+        codesign = None;
+    }
+
+    // Windows has neither rsync nor a POSIX shell for tar, but OpenSSH brings an sftp server
+    let transport_name = transport.as_deref().unwrap_or(if windows { "sftp" } else { "rsync" });
+    if windows && (transport_name != "sftp" || sync.as_deref() == Some("git")) {
+        return Err(Error::Config(
+            "Windows build servers only support the sftp transport".to_string(),
+        ));
+    }
+    let transport = transport::from_name(transport_name, rsync_daemon, agent_path).ok_or_else(|| {
+        Error::Config(format!(
+            "Unknown transport '{}' (use rsync, tar, sftp or agent)",
+            transport_name
+        ))
+    })?;
+    if bwlimit.is_some() && (transport_name == "tar" || sync.as_deref() == Some("git")) {
+        warn!("The bandwidth limit only applies to rsync and sftp transfers");
+    }
+    let transport: Box<dyn transport::Transport> = match sync.as_deref() {
+        Some("git") => Box::new(transport::Git {
+            downloads: transport,
+        }),
+        _ => transport,
+    };
+
+    let mut patches = match no_patch_handling {
+        true => patches::Patches::default(),
+        false => patches::collect(project_dir, &build_path),
+    };
+    let transfer_extra = transfer_extra
+        .into_iter()
+        .map(|(local, remote)| {
+            let local = project_dir.join(local);
+            match local.exists() {
+                true => Ok(build::Extra { local, remote }),
+                false => Err(Error::Config(format!(
+                    "{} doesn't exist, it can't be transferred with --transfer-extra",
+                    local.display()
+                ))),
+            }
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let upload = match (upload_url, upload.is_empty()) {
+        (_, true) => None,
+        (Some(url), false) => Some(upload::Upload {
+            artifacts: upload,
+            url: upload::parse_url(&url).map_err(Error::Config)?,
+            endpoint: upload_endpoint,
+            region: upload_region,
+            credentials: upload_credentials,
+        }),
+        (None, false) => {
+            return Err(Error::Config(
+                "--upload needs the bucket to upload to, set --upload-url or `url` in the \
+                 [upload] config table"
+                    .to_string(),
+            ))
+        }
+    };
+    let retrieve = retrieve
+        .into_iter()
+        .map(|(remote, local)| build::Extra {
+            local: project_dir.join(local.trim_end_matches(['/', '\\'])),
+            remote,
+        })
+        .collect();
+
+    if windows {
+        // all of these run POSIX shell scripts on the build server
+        if !patches.is_empty() {
+            warn!("Patched dependencies aren't transferred to Windows build servers");
+            patches = patches::Patches::default();
+        }
+        let unsupported = [
+            ("--docker", docker.take().is_some()),
+            ("--dep-cache", std::mem::take(&mut dep_cache)),
+            ("--nix", std::mem::take(&mut nix) | nix_flake.take().is_some()),
+            ("--resilient", std::mem::take(&mut resilient)),
+            ("--copy-back-artifacts", std::mem::take(&mut copy_back_artifacts)),
+            ("--auto-install-toolchain", std::mem::take(&mut auto_install_toolchain)),
+        ];
+        for (flag, _) in unsupported.iter().filter(|(_, given)| *given) {
+            warn!("{} isn't supported on Windows build servers, ignoring it", flag);
+        }
+        lock = lock::LockMode::Off;
+    }
+    // only the build directory is mounted into the container
+    if docker.is_some() && remote_target_dir.take().is_some() {
+        warn!("--remote-target-dir isn't supported with --docker, ignoring it");
+    }
+    if docker.is_some() && std::mem::take(&mut vendor) {
+        warn!("--vendor isn't supported with --docker, ignoring it");
+    }
+    // the key needs the version of the rustc that builds
+    if dep_cache && (docker.is_some() || nix || nix_flake.is_some()) {
+        warn!("--dep-cache isn't supported with --docker and --nix, ignoring it");
+        dep_cache = false;
+    }
+
+    Ok(RemoteBuild {
+        project_name: project_name.to_string(),
+        project_dir: project_dir.to_path_buf(),
+        build_server,
+        remote_base_dir,
         build_path,
+        patches,
+        timings: Default::default(),
+        show_timings: timings,
+        ssh,
         build_env,
+        env_vars,
+        sccache,
+        rustup_default,
+        toolchain,
+        auto_install_toolchain,
+        env,
+        hidden,
+        hidden_allow,
+        exclude,
+        include,
+        transfer_extra,
+        gitignore,
+        bwlimit,
+        fallback_local,
+        auto,
+        multiplex,
+        notify,
+        webhook,
+        resilient,
+        retries: retries.unwrap_or(0),
+        lock,
+        forward_ports,
+        tty,
+        no_tty,
+        target,
+        copy_back,
+        copy_back_include,
+        copy_back_exclude,
+        retrieve,
+        upload,
+        copy_back_artifacts,
+        codesign,
+        artifacts: Vec::new(),
+        remote_target_dir: None,
+        cargo_target_dir: remote_target_dir,
+        shared_target,
+        dep_cache,
+        vendor,
+        vendor_args: Vec::new(),
+        local_target_dir: local_target_dir.to_path_buf(),
+        no_copy_lock,
+        apply_fixes,
+        bench_output_dirs,
+        pre_build,
+        post_build,
+        cargo_flags,
         command,
-        options.join(" ")
-    );
+        options,
+        open_docs: false,
+        output_prefix: None,
+        remap_paths: !no_remap_paths,
+        github,
+        annotations: false,
+        lints: None,
+        test_binaries: None,
+        debug: None,
+        coverage: None,
+        container: docker.map(|image| container::Container { image, engine }),
+        nix: match nix_flake {
+            Some(flake) => Some(nix::Nix::Flake(flake)),
+            None if nix => Some(nix::Nix::for_project(project_dir)),
+            None => None,
+        },
+        transport,
+        remote_os,
+    })
+}
 
-    debug!("Starting build process.");
-    let output = Command::new("ssh")
-        .arg("-t")
-        .arg(&build_server)
-        .arg(build_command)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()
-        .unwrap_or_else(|e| {
-            error!("Failed to run cargo command remotely (error: {})", e);
-            exit(-5);
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if let Some(file_name) = copy_back {
-        debug!("Transferring artifacts back to client.");
-        let file_name = file_name.unwrap_or_else(String::new);
-        Command::new("rsync")
-            .arg("-a")
-            .arg("-q")
-            .arg("--delete")
-            .arg("--compress")
-            .arg(PROGRESS_FLAG)
-            .arg(format!("{}:{}target/{}", build_server, build_path, file_name))
-            .arg(format!("{}/target/{}", project_dir.to_string_lossy(), file_name))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output()
-            .unwrap_or_else(|e| {
-                error!(
-                    "Failed to transfer target back to local machine (error: {})",
-                    e
-                );
-                exit(-6);
-            });
+    #[test]
+    fn port_forwards() {
+        assert_eq!(parse_port_forward("8080").unwrap(), "8080:localhost:8080");
+        assert_eq!(parse_port_forward("8080:80").unwrap(), "8080:localhost:80");
+        assert_eq!(parse_port_forward("5433:db:5432").unwrap(), "5433:db:5432");
+        for spec in &[
+            "", ":", "8080:", ":80", "8080::80", "http:80", "8080:x", "70000", "1:db:2:3",
+        ] {
+            assert!(parse_port_forward(spec).is_err(), "{}", spec);
+        }
     }
 
-    if !no_copy_lock {
-        debug!("Transferring Cargo.lock file back to client.");
-        Command::new("rsync")
-            .arg("-a")
-            .arg("-q")
-            .arg("--delete")
-            .arg("--compress")
-            .arg(PROGRESS_FLAG)
-            .arg(format!("{}:{}/Cargo.lock", build_server, build_path))
-            .arg(format!("{}/Cargo.lock", project_dir.to_string_lossy()))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output()
-            .unwrap_or_else(|e| {
-                error!(
-                    "Failed to transfer Cargo.lock back to local machine (error: {})",
-                    e
-                );
-                exit(-7);
-            });
+    #[test]
+    fn toolchains() {
+        assert_eq!(parse_toolchain("nightly").unwrap(), "nightly");
+        assert_eq!(parse_toolchain("+nightly").unwrap(), "nightly");
+        assert_eq!(
+            parse_toolchain("+1.70.0-x86_64-unknown-linux-gnu").unwrap(),
+            "1.70.0-x86_64-unknown-linux-gnu"
+        );
+        assert_eq!(
+            parse_toolchain("nightly-2024-01-01").unwrap(),
+            "nightly-2024-01-01"
+        );
+        for toolchain in &["", "+", "++nightly", "nightly;rm", "$(id)", "a b", "stable/x"] {
+            assert!(parse_toolchain(toolchain).is_err(), "{}", toolchain);
+        }
+    }
+
+    #[test]
+    fn bandwidth_limits() {
+        assert_eq!(parse_bwlimit("100"), Ok(100));
+        assert_eq!(parse_bwlimit("100k"), Ok(100));
+        assert_eq!(parse_bwlimit("10M"), Ok(10 * 1024));
+        assert_eq!(parse_bwlimit(" 2m "), Ok(2 * 1024));
+        assert_eq!(parse_bwlimit("1g"), Ok(1024 * 1024));
+        assert_eq!(parse_bwlimit("4095g"), Ok(4095 * 1024 * 1024));
+        for limit in &["", "0", "0m", "k", "-1", "1.5m", "10t", "4096g", "4294967296"] {
+            assert!(parse_bwlimit(limit).is_err(), "{}", limit);
+        }
+    }
+
+    #[test]
+    fn extra_transfers() {
+        let extra = |local: &str, remote: &str| Ok((local.to_string(), remote.to_string()));
+        assert_eq!(parse_transfer_extra("../shared:shared"), extra("../shared", "shared"));
+        assert_eq!(parse_transfer_extra("/etc/x.toml:conf/"), extra("/etc/x.toml", "conf"));
+        assert_eq!(parse_transfer_extra("C:\\data:data"), extra("C:\\data", "data"));
+        for spec in &["shared", ":shared", "shared:", "shared:/", "x:/tmp/x", "x:~/x", "x:../x"] {
+            assert!(parse_transfer_extra(spec).is_err(), "{}", spec);
+        }
+        assert!(parse_transfer_extra("x:a/../../x").is_err());
     }
 
-    if !output.status.success() {
-        exit(output.status.code().unwrap_or(1))
+    #[test]
+    fn retrieves() {
+        let retrieve = |remote: &str, local: &str| Ok((remote.to_string(), local.to_string()));
+        assert_eq!(parse_retrieve("out"), retrieve("out", "out"));
+        assert_eq!(parse_retrieve("gen/out.h"), retrieve("gen/out.h", "gen/out.h"));
+        assert_eq!(parse_retrieve("dist/:../dist"), retrieve("dist/", "../dist"));
+        for spec in &["", "/tmp/out", "~/out", "../out", "a/../../out", "out:", ":out", "/"] {
+            assert!(parse_retrieve(spec).is_err(), "{}", spec);
+        }
     }
 }
@@ -0,0 +1,238 @@
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+
+use log::info;
+
+use crate::build::{RemoteBuild, PROJECT_MARKER};
+use crate::error::Error;
+use crate::logging::Run;
+use crate::util::{shell_path, shell_quote};
+
+/// A project directory cargo-remote created on the build server.
+pub struct RemoteDir {
+    pub name: String,
+    pub size_kb: u64,
+    /// Unix time of the last build in this directory.
+    pub last_build: u64,
+    /// Path of the local project this directory belongs to.
+    pub project: String,
+}
+
+/// Shell script that prints one tab separated line (name, size in KiB, time of the last build,
+/// local project path) for every build directory below [`remote_base_dir`]. Directories without
+/// a project marker weren't created by cargo-remote and are left out.
+fn list_script(remote_base_dir: &str) -> String {
+    format!(
+        "cd {} 2>/dev/null || exit 0; \
+         for d in */; do d=${{d%/}}; m=\"$d/{marker}\"; [ -f \"$m\" ] || continue; \
+         printf '%s\\t%s\\t%s\\t%s\\n' \"$d\" \"$(du -sk \"$d\" | cut -f1)\" \
+         \"$(date -r \"$m\" +%s)\" \"$(head -n 1 \"$m\")\"; done",
+        shell_path(remote_base_dir),
+        marker = PROJECT_MARKER
+    )
+}
+
+/// Lists all build directories on the build server of [`build`] with a single ssh call.
+pub fn list_dirs(build: &RemoteBuild) -> Result<Vec<RemoteDir>, Error> {
+    let what = "list remote build directories";
+    let output = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(list_script(&build.remote_base_dir))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .logged_output()
+        .map_err(|e| Error::command(what, e, &[]))?;
+    if !output.status.success() {
+        return Err(Error::command(what, output.status, &[]));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            Some(RemoteDir {
+                name: fields.next()?.to_string(),
+                size_kb: fields.next()?.trim().parse().ok()?,
+                last_build: fields.next()?.trim().parse().ok()?,
+                project: fields.next()?.to_string(),
+            })
+        })
+        .collect())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn format_size(size_kb: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    let mut size = size_kb as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        s if s < 60 => "just now".to_string(),
+        s if s < 3600 => format!("{} min ago", s / 60),
+        s if s < 86400 => format!("{} h ago", s / 3600),
+        s => format!("{} days ago", s / 86400),
+    }
+}
+
+/// `cargo remote status`: prints all build directories on the build server, their size, the
+/// time of the last build and the local project they belong to. The directory of the current
+/// project is marked with a `*`.
+pub fn status(mut build: RemoteBuild) -> Result<i32, Error> {
+    let dirs = list_dirs(&build)?;
+    let current = build.build_path.trim_end_matches('/').rsplit('/').next();
+    println!(
+        "Build directories in {}:{}",
+        build.build_server, build.remote_base_dir
+    );
+    let width = dirs.iter().map(|d| d.name.len()).max().unwrap_or(0);
+    let mut total = 0;
+    for dir in &dirs {
+        total += dir.size_kb;
+        println!(
+            "{} {:width$}  {:>10}  {:>14}  {}",
+            if Some(dir.name.as_str()) == current { "*" } else { " " },
+            dir.name,
+            format_size(dir.size_kb),
+            format_age(now().saturating_sub(dir.last_build)),
+            dir.project,
+            width = width
+        );
+    }
+    println!("{} directories, {} in total", dirs.len(), format_size(total));
+    build.ssh.stop_master();
+    Ok(0)
+}
+
+/// Options of `cargo remote gc`, given after `--`.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "cargo remote gc")]
+struct GcOpts {
+    #[structopt(
+        long = "older-than",
+        help = "Remove build directories that weren't used for this long, e.g. 12h, 30d or 8w",
+        default_value = "30d",
+        parse(try_from_str = "parse_age")
+    )]
+    older_than: u64,
+
+    #[structopt(long = "dry-run", help = "Only list the directories that would be removed")]
+    dry_run: bool,
+}
+
+/// Parses ages like `90s`, `45m`, `12h`, `30d` or `8w` into seconds. Plain numbers are days.
+pub fn parse_age(age: &str) -> Result<u64, String> {
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (number, unit) = age.split_at(split);
+    let invalid = || format!("invalid age '{}'", age);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "" | "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return Err(format!("invalid unit '{}' in age '{}'", unit, age)),
+    };
+    number.checked_mul(factor).ok_or_else(invalid)
+}
+
+/// `cargo remote gc [-- --older-than <age> --dry-run]`: removes the build directories on the
+/// build server that weren't used for a build in the given time.
+pub fn gc(mut build: RemoteBuild) -> Result<i32, Error> {
+    let opts = GcOpts::from_iter(std::iter::once("gc".to_string()).chain(build.options.clone()));
+    let dirs = list_dirs(&build)?;
+    let stale: Vec<RemoteDir> = dirs
+        .into_iter()
+        .filter(|dir| now().saturating_sub(dir.last_build) > opts.older_than)
+        .collect();
+    if stale.is_empty() {
+        info!("No build directories are older than {}", format_age(opts.older_than));
+        build.ssh.stop_master();
+        return Ok(0);
+    }
+
+    let mut total = 0;
+    for dir in &stale {
+        total += dir.size_kb;
+        println!(
+            "{} {}  {}  last build {}  ({})",
+            if opts.dry_run { "Would remove" } else { "Removing" },
+            dir.name,
+            format_size(dir.size_kb),
+            format_age(now().saturating_sub(dir.last_build)),
+            dir.project
+        );
+    }
+    if opts.dry_run {
+        println!("{} would be freed", format_size(total));
+        build.ssh.stop_master();
+        return Ok(0);
+    }
+
+    let remove = format!(
+        "cd {} && rm -rf {}",
+        shell_path(&build.remote_base_dir),
+        stale
+            .iter()
+            // with the lock file, patched workspaces, vendored crates and build session next to it
+            .flat_map(|dir| {
+                ["", ".lock", ".patches", ".vendor", ".session"]
+                    .iter()
+                    .map(move |suffix| shell_quote(&format!("{}{}", dir.name, suffix)))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let status = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(remove)
+        .stdin(Stdio::null())
+        .logged_status();
+    build.ssh.stop_master();
+    let what = "remove build directories";
+    match status {
+        Ok(status) if status.success() => {
+            println!("Freed {}", format_size(total));
+            Ok(0)
+        }
+        Ok(status) => Err(Error::command(what, status, &[])),
+        Err(e) => Err(Error::command(what, e, &[])),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse_age("90s"), Ok(90));
+        assert_eq!(parse_age("45m"), Ok(45 * 60));
+        assert_eq!(parse_age("12h"), Ok(12 * 3600));
+        assert_eq!(parse_age("30d"), Ok(30 * 86400));
+        assert_eq!(parse_age("30"), Ok(30 * 86400));
+        assert_eq!(parse_age("8w"), Ok(8 * 7 * 86400));
+        assert_eq!(parse_age("0s"), Ok(0));
+        for age in &["", "d", "-1d", "1.5d", "3y", "1 d", "99999999999999999w"] {
+            assert!(parse_age(age).is_err(), "{}", age);
+        }
+        assert_eq!(parse_age("18446744073709551615s"), Ok(u64::MAX));
+    }
+}
@@ -0,0 +1,34 @@
+use std::path::Path;
+
+use crate::util::shell_quote;
+
+/// The nix environment the remote cargo runs in.
+pub enum Nix {
+    /// `nix develop` with a flake reference like `.#devshell`.
+    Flake(String),
+    /// `nix-shell` with the `shell.nix` (or `default.nix`) of the project.
+    Shell,
+}
+
+impl Nix {
+    /// `--nix` uses the project's flake if it has one and `nix-shell` otherwise.
+    pub fn for_project(project_dir: &Path) -> Nix {
+        if project_dir.join("flake.nix").is_file() {
+            Nix::Flake(".".to_string())
+        } else {
+            Nix::Shell
+        }
+    }
+
+    /// Wraps the shell [`command`] so it runs in the nix environment.
+    pub fn wrap(&self, command: &str) -> String {
+        match self {
+            Nix::Flake(flake) => format!(
+                "nix develop {} -c sh -c {}",
+                shell_quote(flake),
+                shell_quote(command)
+            ),
+            Nix::Shell => format!("nix-shell --run {}", shell_quote(command)),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde_json::json;
+
+use crate::history::format_duration;
+use crate::logging::Run;
+
+/// Shows a desktop notification, with `notify-send` on Linux and the BSDs and `osascript` on
+/// macOS. Failing to show it is only logged, the build result matters more.
+fn send(summary: &str, body: &str) {
+    let mut command = if cfg!(target_os = "macos") {
+        let quote = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification \"{}\" with title \"{}\"",
+            quote(body),
+            quote(summary)
+        ));
+        command
+    } else if cfg!(unix) {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name", "cargo-remote", summary, body]);
+        command
+    } else {
+        warn!("Desktop notifications aren't supported on this platform");
+        return;
+    };
+    let result = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .logged_status();
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => debug!("Failed to show a desktop notification ({})", status),
+        Err(e) => debug!("Failed to show a desktop notification ({})", e),
+    }
+}
+
+/// A finished run of `cargo command` in [`Finished::project`], what notifications report on.
+pub struct Finished<'a> {
+    pub project: &'a str,
+    pub command: &'a str,
+    pub server: &'a str,
+    pub exit_code: i32,
+    pub duration: Duration,
+}
+
+impl Finished<'_> {
+    fn summary(&self) -> String {
+        match self.exit_code {
+            0 => format!("cargo {} succeeded", self.command),
+            code => format!("cargo {} failed with exit code {}", self.command, code),
+        }
+    }
+
+    fn body(&self) -> String {
+        format!(
+            "{} on {} in {}",
+            self.project,
+            self.server,
+            format_duration(self.duration.as_millis() as u64)
+        )
+    }
+
+    /// Shows a desktop notification if [`desktop`] is set and posts to [`webhook`] if one is
+    /// configured. Nothing is sent for dry runs.
+    pub fn notify(&self, desktop: bool, webhook: Option<&str>) {
+        if crate::logging::dry_run() {
+            return;
+        }
+        if desktop {
+            send(&self.summary(), &self.body());
+        }
+        if let Some(url) = webhook {
+            if let Err(e) = self.post(url) {
+                warn!("Failed to post the build result to the webhook ({})", e);
+            }
+        }
+    }
+
+    /// Posts the result as JSON to [`url`] with `curl`. The `text` field makes it show up as
+    /// a message in Slack and other chats that take the same payload.
+    fn post(&self, url: &str) -> Result<(), String> {
+        let payload = json!({
+            "text": format!("{}: {}", self.summary(), self.body()),
+            "project": self.project,
+            "command": self.command,
+            "server": self.server,
+            "status": if self.exit_code == 0 { "success" } else { "failure" },
+            "exit_code": self.exit_code,
+            "duration_ms": self.duration.as_millis() as u64,
+        });
+        let mut curl = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--max-time", "10"])
+            .args([
+                "--header",
+                "Content-Type: application/json",
+                "--data-binary",
+                "@-",
+            ])
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .logged_spawn()
+            .map_err(|e| format!("error: {}", e))?;
+        if let Some(mut stdin) = curl.stdin.take() {
+            stdin
+                .write_all(payload.to_string().as_bytes())
+                .map_err(|e| format!("error: {}", e))?;
+        }
+        let output = curl
+            .wait_with_output()
+            .map_err(|e| format!("error: {}", e))?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        }
+    }
+}
@@ -0,0 +1,588 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+use toml::value::Table;
+use toml::Value;
+
+use crate::build::RemoteBuild;
+use crate::error::Error;
+use crate::logging::Run;
+use crate::util::{shell_path, short_hash};
+
+/// How many patched in workspaces are transferred at the same time.
+const PARALLEL_TRANSFERS: usize = 4;
+const WORKSPACE_CACHE_FILE: &str = "workspaces.toml";
+
+/// A workspace outside of the project that is patched in or depended on by path, copied to the
+/// build server next to the build directory.
+pub struct Workspace {
+    pub local_dir: PathBuf,
+    /// Always ends with a `/`.
+    pub remote_dir: String,
+}
+
+/// A manifest or cargo configuration whose patch or dependency paths don't exist on the build
+/// server, with the paths rewritten to the copies there.
+pub struct Manifest {
+    pub local_path: PathBuf,
+    pub remote_path: String,
+    pub content: String,
+}
+
+/// The workspaces the project's `[patch]` entries and path dependencies point to, and the ones
+/// their entries point to in turn, together with the manifests that have to be rewritten for
+/// them.
+#[derive(Default)]
+pub struct Patches {
+    pub workspaces: Vec<Workspace>,
+    pub manifests: Vec<Manifest>,
+}
+
+/// Dependency tables, at the top level and below `[target.<cfg>]`.
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// The `path` keys of the `[patch]` and `[replace]` entries in [`manifest`], with
+/// [`dependencies`] also the ones of the path dependencies, including
+/// `[workspace.dependencies]`.
+fn paths(manifest: &mut Value, dependencies: bool) -> Vec<&mut String> {
+    fn values(table: &mut Table) -> impl Iterator<Item = &mut Value> {
+        table.iter_mut().map(|(_, value)| value)
+    }
+    let mut tables = Vec::new();
+    for (key, value) in manifest.as_table_mut().into_iter().flatten() {
+        match key.as_str() {
+            "patch" => tables.extend(value.as_table_mut().into_iter().flat_map(values)),
+            "replace" => tables.push(value),
+            _ if !dependencies => {}
+            "target" => {
+                let targets = value.as_table_mut().into_iter().flat_map(values);
+                for target in targets.filter_map(Value::as_table_mut) {
+                    tables.extend(
+                        target
+                            .iter_mut()
+                            .filter(|(name, _)| DEPENDENCY_TABLES.contains(&name.as_str()))
+                            .map(|(_, table)| table),
+                    );
+                }
+            }
+            "workspace" => tables.extend(value.get_mut("dependencies")),
+            name if DEPENDENCY_TABLES.contains(&name) => tables.push(value),
+            _ => {}
+        }
+    }
+    tables
+        .into_iter()
+        .filter_map(Value::as_table_mut)
+        .flat_map(values)
+        .filter_map(|dependency| match dependency.get_mut("path") {
+            Some(Value::String(path)) => Some(path),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The root of the workspace [`crate_dir`] belongs to, the crate itself if cargo can't tell.
+fn workspace_root(crate_dir: &Path) -> PathBuf {
+    Command::new("cargo")
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .arg("--manifest-path")
+        .arg(crate_dir.join("Cargo.toml"))
+        .stderr(Stdio::null())
+        .query_output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            let manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+            manifest.parent().map(Path::to_path_buf)
+        })
+        .unwrap_or_else(|| crate_dir.to_path_buf())
+}
+
+/// Modification time of [`manifest`] in milliseconds.
+fn manifest_mtime(manifest: &Path) -> Option<i64> {
+    let modified = std::fs::metadata(manifest).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_millis() as i64)
+}
+
+fn cache_path() -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("cargo-remote")
+        .ok()?
+        .place_cache_file(WORKSPACE_CACHE_FILE)
+        .ok()
+}
+
+/// Workspace roots found by earlier runs, by crate directory.
+fn read_cache() -> Table {
+    cache_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|content| content.parse::<Value>().ok())
+        .and_then(|value| value.as_table().cloned())
+        .unwrap_or_default()
+}
+
+fn write_cache(cache: Table) {
+    if let Some(path) = cache_path() {
+        if let Err(e) = std::fs::write(&path, Value::Table(cache).to_string()) {
+            warn!(
+                "Can't write workspace cache '{}' (error: {})",
+                path.to_string_lossy(),
+                e
+            );
+        }
+    }
+}
+
+/// The manifest of [`workspace_dir`] followed by the ones of its members, as far as
+/// `cargo metadata` can tell.
+fn member_manifests(workspace_dir: &Path) -> Vec<PathBuf> {
+    let root = workspace_dir.join("Cargo.toml");
+    let metadata = Command::new("cargo")
+        .args(["metadata", "--no-deps", "--format-version", "1"])
+        .arg("--manifest-path")
+        .arg(&root)
+        .stderr(Stdio::null())
+        .query_output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| serde_json::from_slice::<serde_json::Value>(&output.stdout).ok());
+    let mut manifests = vec![root];
+    let packages = metadata.as_ref().and_then(|m| m["packages"].as_array());
+    for package in packages.into_iter().flatten() {
+        if let Some(path) = package["manifest_path"].as_str().map(PathBuf::from) {
+            if !manifests.contains(&path) {
+                manifests.push(path);
+            }
+        }
+    }
+    manifests
+}
+
+/// Where [`path`] ends up on the build server, if it is inside one of the transferred [`dirs`].
+fn remote_location(dirs: &[Workspace], path: &Path) -> Option<String> {
+    dirs.iter().find_map(|dir| {
+        let rest = path.strip_prefix(&dir.local_dir).ok()?;
+        Some(format!("{}{}", dir.remote_dir, crate::util::slash_path(rest)))
+    })
+}
+
+/// The remote path [`to`] relative to the remote directory [`from`].
+fn relative_path(from: &str, to: &str) -> String {
+    let from: Vec<&str> = from.split('/').filter(|c| !c.is_empty()).collect();
+    let to: Vec<&str> = to.split('/').filter(|c| !c.is_empty()).collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend(&to[common..]);
+    match parts.is_empty() {
+        true => ".".to_string(),
+        false => parts.join("/"),
+    }
+}
+
+impl Workspace {
+    /// The name the workspace's output is prefixed with.
+    fn name(&self) -> &str {
+        let dir = self.remote_dir.trim_end_matches('/');
+        dir.rsplit('/').next().unwrap_or(dir)
+    }
+
+    fn upload(&self, build: &RemoteBuild) -> Result<(), String> {
+        let started = Instant::now();
+        info!(
+            "[{}] Transferring {}",
+            self.name(),
+            self.local_dir.to_string_lossy()
+        );
+        build
+            .transport
+            .upload(build, &self.local_dir, &self.remote_dir)
+            .map_err(|e| format!("[{}] {}", self.name(), e))?;
+        info!("[{}] Transferred in {:.1?}", self.name(), started.elapsed());
+        Ok(())
+    }
+}
+
+/// Finds the workspaces while the manifests are searched.
+struct Collector {
+    project_dir: PathBuf,
+    /// Remote directory the workspaces are copied into.
+    patch_dir: String,
+    /// The project first, then the workspaces found so far.
+    dirs: Vec<Workspace>,
+    /// Workspace roots of crate directories from earlier runs, see [`read_cache`].
+    roots: Table,
+    roots_changed: bool,
+}
+
+impl Collector {
+    /// [`workspace_root`], reused from earlier runs as long as neither the crate's nor the
+    /// workspace's manifest changed.
+    fn workspace_root(&mut self, crate_dir: &Path) -> PathBuf {
+        let key = crate_dir.to_string_lossy().into_owned();
+        let mtime = manifest_mtime(&crate_dir.join("Cargo.toml"));
+        let cached = self.roots.get(&key).and_then(|entry| {
+            let root = PathBuf::from(entry.get("root")?.as_str()?);
+            let root_mtime = manifest_mtime(&root.join("Cargo.toml"));
+            let fresh = mtime.is_some()
+                && entry.get("mtime")?.as_integer() == mtime
+                && entry.get("root_mtime")?.as_integer() == root_mtime;
+            Some(root).filter(|_| fresh)
+        });
+        if let Some(root) = cached {
+            debug!("Workspace of {:?} from the cache: {:?}", crate_dir, root);
+            return root;
+        }
+        let root = workspace_root(crate_dir);
+        let mut entry = Table::new();
+        entry.insert(
+            "root".to_string(),
+            root.to_string_lossy().into_owned().into(),
+        );
+        if let (Some(mtime), Some(root_mtime)) = (mtime, manifest_mtime(&root.join("Cargo.toml"))) {
+            entry.insert("mtime".to_string(), mtime.into());
+            entry.insert("root_mtime".to_string(), root_mtime.into());
+        }
+        self.roots.insert(key, Value::Table(entry));
+        self.roots_changed = true;
+        root
+    }
+
+    /// Where the local directory [`local`] ends up on the build server, the workspace it
+    /// belongs to is added if it isn't transferred yet. `None` if it can't be transferred.
+    fn remote_location(&mut self, local: &Path, path: &str) -> Option<String> {
+        if let Some(remote) = remote_location(&self.dirs, local) {
+            return Some(remote);
+        }
+        let root = self.workspace_root(local);
+        if self.project_dir.starts_with(&root) {
+            warn!(
+                "The path {} contains the project, it isn't copied to the build server",
+                path
+            );
+            return None;
+        }
+        let name = root
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "workspace".to_string());
+        debug!("Workspace outside of the project: {:?}", root);
+        // like the build directories, two checkouts with the same name don't collide
+        let hash = short_hash(root.to_string_lossy().as_bytes());
+        self.dirs.push(Workspace {
+            local_dir: root,
+            remote_dir: format!("{}{}-{}/", self.patch_dir, name, hash),
+        });
+        remote_location(&self.dirs, local)
+    }
+
+    /// The manifest at [`manifest_path`] in the workspace [`workspace_dir`] with its paths
+    /// rewritten for the build server, `None` if there is nothing to rewrite.
+    fn rewrite_manifest(&mut self, workspace_dir: &Path, manifest_path: &Path) -> Option<Manifest> {
+        let content = std::fs::read_to_string(manifest_path).ok()?;
+        let manifest = content.parse::<Value>().ok()?;
+        let manifest_dir = manifest_path.parent()?;
+        self.rewrite(workspace_dir, manifest_path, manifest_dir, manifest, true)
+    }
+
+    /// The project's cargo configuration with the paths of its `[patch]` entries rewritten.
+    /// They are relative to the project directory, not the `.cargo` directory.
+    fn rewrite_cargo_config(&mut self) -> Option<Manifest> {
+        let project_dir = self.project_dir.clone();
+        let path = project_dir.join(crate::cargo_config::find(&project_dir)?);
+        let content = std::fs::read_to_string(&path).ok()?;
+        let config = content.parse::<Value>().ok()?;
+        self.rewrite(&project_dir, &path, &project_dir, config, false)
+    }
+
+    /// The parsed [`document`] of [`file`] with its [`paths`] rewritten, relative paths are
+    /// resolved against [`base_dir`] first. `None` if nothing changed.
+    fn rewrite(
+        &mut self,
+        workspace_dir: &Path,
+        file: &Path,
+        base_dir: &Path,
+        mut document: Value,
+        dependencies: bool,
+    ) -> Option<Manifest> {
+        let remote_dir = remote_location(&self.dirs, base_dir)?;
+        let mut changed = false;
+        for path in paths(&mut document, dependencies) {
+            let relative = !Path::new(path.as_str()).is_absolute();
+            let local = match base_dir.join(path.as_str()).canonicalize() {
+                // relative paths inside the workspace stay valid, its layout is kept
+                Ok(local) if relative && local.starts_with(workspace_dir) => continue,
+                Ok(local) if local.is_dir() => local,
+                _ => {
+                    warn!(
+                        "The path {} in {} doesn't exist",
+                        path,
+                        file.to_string_lossy()
+                    );
+                    continue;
+                }
+            };
+            let remote = match self.remote_location(&local, path) {
+                Some(remote) => relative_path(&remote_dir, &remote),
+                None => continue,
+            };
+            if *path != remote {
+                *path = remote;
+                changed = true;
+            }
+        }
+        if !changed {
+            return None;
+        }
+        let content = match toml::to_string(&document) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("Can't rewrite {} (error: {})", file.to_string_lossy(), e);
+                return None;
+            }
+        };
+        Some(Manifest {
+            local_path: file.to_path_buf(),
+            remote_path: remote_location(&self.dirs, file)?,
+            content,
+        })
+    }
+}
+
+/// Finds the workspaces outside of the project that `[patch]` entries and path dependencies in
+/// the project's manifests and cargo configuration point to. They are copied below
+/// `<build directory>.patches/` and the paths are rewritten relative to the manifest, in the
+/// root manifest and the ones of the workspace members, also of the copied workspaces
+/// themselves.
+pub fn collect(project_dir: &Path, build_path: &str) -> Patches {
+    let project_dir = project_dir
+        .canonicalize()
+        .unwrap_or_else(|_| project_dir.to_path_buf());
+    let mut collector = Collector {
+        patch_dir: format!("{}.patches/", build_path.trim_end_matches('/')),
+        dirs: vec![Workspace {
+            local_dir: project_dir.clone(),
+            remote_dir: build_path.to_string(),
+        }],
+        project_dir,
+        roots: read_cache(),
+        roots_changed: false,
+    };
+    let mut manifests: Vec<Manifest> = collector.rewrite_cargo_config().into_iter().collect();
+    // every copied workspace gets its manifests checked as well
+    let mut next = 0;
+    while next < collector.dirs.len() {
+        let workspace_dir = collector.dirs[next].local_dir.clone();
+        next += 1;
+        for manifest_path in member_manifests(&workspace_dir) {
+            manifests.extend(collector.rewrite_manifest(&workspace_dir, &manifest_path));
+        }
+    }
+    if collector.roots_changed {
+        write_cache(collector.roots);
+    }
+    Patches {
+        workspaces: collector.dirs.split_off(1),
+        manifests,
+    }
+}
+
+impl Patches {
+    /// Whether there are no workspaces to transfer and no manifests to rewrite.
+    pub fn is_empty(&self) -> bool {
+        self.workspaces.is_empty() && self.manifests.is_empty()
+    }
+
+    /// Prints the workspaces that would be transferred and a diff of every rewritten manifest
+    /// against the local one, for `--show-patched-manifest`. Nothing is transferred.
+    pub fn show(&self) -> Result<i32, Error> {
+        if self.is_empty() {
+            println!("No paths need to be rewritten for the build server");
+            return Ok(0);
+        }
+        println!("Workspaces transferred to the build server:");
+        for workspace in &self.workspaces {
+            println!(
+                "  {} -> {}",
+                workspace.local_dir.to_string_lossy(),
+                workspace.remote_dir
+            );
+        }
+        for manifest in &self.manifests {
+            println!();
+            let local = manifest.local_path.to_string_lossy();
+            let diff = Command::new("diff")
+                .arg("-u")
+                .arg("--label")
+                .arg(local.as_ref())
+                .arg("--label")
+                .arg(&manifest.remote_path)
+                .arg(local.as_ref())
+                .arg("-")
+                .stdin(Stdio::piped())
+                .logged_spawn()
+                .and_then(|mut diff| {
+                    if let Some(mut stdin) = diff.stdin.take() {
+                        stdin.write_all(manifest.content.as_bytes())?;
+                    }
+                    diff.wait()
+                });
+            // diff exits with 1 if the files differ
+            if !matches!(
+                diff.ok().and_then(|status| status.code()),
+                Some(0) | Some(1)
+            ) {
+                warn!("Can't run diff, showing the whole rewritten manifest");
+                println!("{}:\n{}", manifest.remote_path, manifest.content);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Transfers the workspaces with the build's transport, up to [`PARALLEL_TRANSFERS`] at a
+    /// time. Returns the first error, the running transfers are finished first.
+    pub fn upload(&self, build: &RemoteBuild) -> Result<(), String> {
+        let next = AtomicUsize::new(0);
+        let workers = PARALLEL_TRANSFERS.min(self.workspaces.len());
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        while let Some(workspace) =
+                            self.workspaces.get(next.fetch_add(1, Ordering::Relaxed))
+                        {
+                            if let Err(e) = workspace.upload(build) {
+                                // the others don't start any new transfers
+                                next.store(self.workspaces.len(), Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err("Transfer failed".into()))
+                })
+                .fold(Ok(()), Result::and)
+        })
+    }
+
+    /// Replaces the transferred manifests that have patch paths with the rewritten ones. Has
+    /// to run after every transfer, the transfer restores the originals.
+    pub fn write_manifests(&self, build: &RemoteBuild) -> Result<(), String> {
+        let what = "write the patched manifests on the build server";
+        for manifest in &self.manifests {
+            debug!("Rewriting the patch paths in {}", manifest.remote_path);
+            let mut ssh = build
+                .ssh
+                .command()
+                .arg(build.ssh.destination())
+                .arg(format!("cat > {}", shell_path(&manifest.remote_path)))
+                .stdin(Stdio::piped())
+                .logged_spawn()
+                .map_err(|e| format!("Failed to {} (error: {})", what, e))?;
+            if let Some(mut stdin) = ssh.stdin.take() {
+                stdin
+                    .write_all(manifest.content.as_bytes())
+                    .map_err(|e| format!("Failed to {} (error: {})", what, e))?;
+            }
+            let status = ssh
+                .wait()
+                .map_err(|e| format!("Failed to {} (error: {})", what, e))?;
+            if !status.success() {
+                return Err(format!("Failed to {} (ssh exited with {})", what, status));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+        [package]
+        name = "demo"
+        description = "path = \"../dep\""
+
+        [dependencies]
+        dep = { path = "../dep" }
+        registry = "1.0"
+        windows = { path = 'C:\Users\me\win' }
+
+        [target.'cfg(unix)'.dev-dependencies]
+        unix = { path = "../unix" }
+
+        [workspace.dependencies]
+        shared = { path = "../shared" }
+
+        [patch.crates-io]
+        patched = { path = "../patched" }
+
+        [replace]
+        "replaced:0.1.0" = { path = "../replaced" }
+    "#;
+
+    fn found(manifest: &mut Value, dependencies: bool) -> Vec<String> {
+        let mut paths: Vec<String> = paths(manifest, dependencies)
+            .into_iter()
+            .map(|path| path.clone())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn only_path_keys_are_found() {
+        let mut manifest = MANIFEST.parse::<Value>().unwrap();
+        assert_eq!(
+            found(&mut manifest, true),
+            [
+                "../dep",
+                "../patched",
+                "../replaced",
+                "../shared",
+                "../unix",
+                "C:\\Users\\me\\win"
+            ]
+        );
+        assert_eq!(found(&mut manifest, false), ["../patched", "../replaced"]);
+    }
+
+    #[test]
+    fn rewritten_paths_leave_the_rest_alone() {
+        let mut manifest = MANIFEST.parse::<Value>().unwrap();
+        for path in paths(&mut manifest, true) {
+            *path = path.replace("..", "../remote").replace('\\', "/");
+        }
+        let rewritten = toml::to_string(&manifest)
+            .unwrap()
+            .parse::<Value>()
+            .unwrap();
+        assert_eq!(
+            rewritten["package"]["description"].as_str(),
+            Some("path = \"../dep\"")
+        );
+        assert_eq!(
+            rewritten["dependencies"]["dep"]["path"].as_str(),
+            Some("../remote/dep")
+        );
+        assert_eq!(rewritten["dependencies"]["registry"].as_str(), Some("1.0"));
+        assert_eq!(
+            rewritten["dependencies"]["windows"]["path"].as_str(),
+            Some("C:/Users/me/win")
+        );
+        assert_eq!(
+            rewritten["replace"]["replaced:0.1.0"]["path"].as_str(),
+            Some("../remote/replaced")
+        );
+    }
+}
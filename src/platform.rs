@@ -0,0 +1,152 @@
+use std::process::Stdio;
+
+use log::debug;
+
+use crate::build::{RemoteBuild, PROJECT_MARKER};
+use crate::logging::Run;
+use crate::ssh::Ssh;
+
+/// The operating system of the build server. Everything that is run there is a POSIX shell
+/// script, except on Windows where the build runs in PowerShell.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RemoteOs {
+    Unix,
+    /// A Mac, the binaries built there can be signed before they are copied back.
+    MacOs,
+    Windows,
+}
+
+impl RemoteOs {
+    pub fn from_name(name: &str) -> Option<RemoteOs> {
+        match name {
+            "unix" => Some(RemoteOs::Unix),
+            "macos" => Some(RemoteOs::MacOs),
+            "windows" => Some(RemoteOs::Windows),
+            _ => None,
+        }
+    }
+
+    /// Asks the build server behind [`ssh`] which system it runs. `uname` fails in cmd.exe,
+    /// the default shell of OpenSSH on Windows, and `ver` answers there.
+    pub fn detect(ssh: &Ssh) -> RemoteOs {
+        let output = ssh
+            .command()
+            .arg(ssh.destination())
+            .arg("uname -s || ver")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .logged_output();
+        let system = match output {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            Err(e) => {
+                debug!("Couldn't detect the system of the build server ({})", e);
+                return RemoteOs::Unix;
+            }
+        };
+        debug!("Build server system: {:?}", system);
+        match system.as_str() {
+            "Darwin" => RemoteOs::MacOs,
+            system if system.contains("Windows") => RemoteOs::Windows,
+            _ => RemoteOs::Unix,
+        }
+    }
+}
+
+/// Quotes [`value`] for PowerShell, so it is passed on as one word without any expansion.
+pub fn powershell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// The command line that runs [`script`] in PowerShell. The script is passed base64 encoded,
+/// so cmd.exe, the default shell of OpenSSH on Windows, can't get its quoting wrong.
+pub fn powershell(script: &str) -> String {
+    let utf16: Vec<u8> = script.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    format!(
+        "powershell -NoProfile -NonInteractive -EncodedCommand {}",
+        base64(&utf16)
+    )
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+/// The PowerShell script that runs `cargo [`cargo_args`]` for [`build`] on a Windows build
+/// server with the variables in [`env_vars`] set. Like on Unix the build hooks only run if
+/// the step before succeeded, and the script exits with the exit code of cargo.
+pub fn windows_build_script(
+    build: &RemoteBuild,
+    cargo_args: &[String],
+    env_vars: &[(String, String)],
+    json_output: bool,
+) -> String {
+    // the output of the hooks must not end up between the JSON messages
+    let hook = |hook: &String| match json_output {
+        true => format!(
+            "& {{ {} }} | ForEach-Object {{ [Console]::Error.WriteLine($_) }}",
+            hook
+        ),
+        false => hook.clone(),
+    };
+    let mut script = vec![
+        // progress records would end up on stderr as CLIXML
+        "$ProgressPreference = 'SilentlyContinue'".to_string(),
+        format!(
+            "Set-Location -Path {}; if (-not $?) {{ exit 1 }}",
+            powershell_quote(&build.build_path)
+        ),
+        format!(
+            "Set-Content -Path {} -Value {}",
+            PROJECT_MARKER,
+            powershell_quote(&build.project_dir.to_string_lossy())
+        ),
+    ];
+    let build_env = build.build_env.split_whitespace().filter_map(|var| {
+        let mut parts = var.splitn(2, '=');
+        Some((parts.next()?.to_string(), parts.next()?.to_string()))
+    });
+    for (name, value) in build_env.chain(env_vars.iter().cloned()) {
+        script.push(format!("$env:{} = {}", name, powershell_quote(&value)));
+    }
+    let toolchain = match &build.toolchain {
+        Some(toolchain) => vec![powershell_quote(&format!("+{}", toolchain))],
+        None => {
+            script.push(format!(
+                "& rustup default {} > $null",
+                powershell_quote(&build.rustup_default)
+            ));
+            Vec::new()
+        }
+    };
+    if let Some(pre_build) = build.pre_build.as_ref().map(hook) {
+        script.push(pre_build);
+        script.push("if (-not $?) { exit 1 }".to_string());
+    }
+    let args: Vec<String> = toolchain
+        .into_iter()
+        .chain(std::iter::once(powershell_quote(&build.command)))
+        .chain(cargo_args.iter().map(|arg| powershell_quote(arg)))
+        .collect();
+    script.push(format!("& cargo {}", args.join(" ")));
+    script.push("$status = $LASTEXITCODE".to_string());
+    if let Some(post_build) = build.post_build.as_ref().map(hook) {
+        script.push(format!(
+            "if ($status -eq 0) {{ {}; if (-not $?) {{ $status = 1 }} }}",
+            post_build
+        ));
+    }
+    script.push("exit $status".to_string());
+    powershell(&script.join("\n"))
+}
@@ -0,0 +1,209 @@
+use std::process::Stdio;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use toml::Value;
+
+use log::{debug, warn};
+
+use crate::capabilities;
+use crate::config::{Configs, Server};
+use crate::logging::Run;
+use crate::util::shell_path;
+
+/// How long probe results are reused before a server is asked again.
+const PROBE_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Servers with less free disk space than this are only used if no other server is left.
+pub const MIN_FREE_DISK_KB: u64 = 1024 * 1024;
+const PROBE_CACHE_FILE: &str = "probes.toml";
+
+/// Load and free disk space of a build server at the time it was probed.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub cores: u64,
+    pub load: f64,
+    pub free_disk_kb: u64,
+    pub timestamp: u64,
+}
+
+impl Probe {
+    fn load_per_core(&self) -> f64 {
+        self.load / self.cores.max(1) as f64
+    }
+
+    fn to_value(&self) -> Value {
+        let mut table = toml::value::Table::new();
+        table.insert("cores".into(), Value::Integer(self.cores as i64));
+        table.insert("load".into(), Value::Float(self.load));
+        table.insert(
+            "free_disk_kb".into(),
+            Value::Integer(self.free_disk_kb as i64),
+        );
+        table.insert("timestamp".into(), Value::Integer(self.timestamp as i64));
+        Value::Table(table)
+    }
+
+    fn from_value(value: &Value) -> Option<Probe> {
+        Some(Probe {
+            cores: value.get("cores")?.as_integer()? as u64,
+            load: value.get("load")?.as_float()?,
+            free_disk_kb: value.get("free_disk_kb")?.as_integer()? as u64,
+            timestamp: value.get("timestamp")?.as_integer()? as u64,
+        })
+    }
+
+    /// Parses the output of [`probe_script`]: core count, `/proc/loadavg` and the last line of
+    /// `df -Pk`.
+    fn parse(output: &str) -> Option<Probe> {
+        let mut lines = output.lines();
+        let cores = lines.next()?.trim().parse().ok()?;
+        let load = lines.next()?.split_whitespace().next()?.parse().ok()?;
+        let free_disk_kb = lines.next()?.split_whitespace().nth(3)?.parse().ok()?;
+        Some(Probe {
+            cores,
+            load,
+            free_disk_kb,
+            timestamp: now(),
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn probe_script(remote_base_dir: &str) -> String {
+    format!(
+        "nproc; cat /proc/loadavg; (df -Pk {} 2>/dev/null || df -Pk ~) | tail -n 1",
+        shell_path(remote_base_dir)
+    )
+}
+
+/// Asks [`server`] over ssh for its load and free disk space.
+fn probe_server(server: &Server, ssh_options: &[String], remote_base_dir: &str) -> Option<Probe> {
+    let output = crate::ssh::server_command(server, ssh_options)
+        .arg(probe_script(remote_base_dir))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .logged_output()
+        .map_err(|e| {
+            warn!(
+                "Failed to probe build server '{}' (error: {})",
+                server.name, e
+            )
+        })
+        .ok()?;
+    if !output.status.success() {
+        warn!(
+            "Build server '{}' is not reachable, skipping it",
+            server.name
+        );
+        return None;
+    }
+    let probe = Probe::parse(&String::from_utf8_lossy(&output.stdout));
+    if probe.is_none() {
+        warn!(
+            "Can't parse probe results of build server '{}'",
+            server.name
+        );
+    }
+    probe
+}
+
+fn cache_path() -> Option<std::path::PathBuf> {
+    xdg::BaseDirectories::with_prefix("cargo-remote")
+        .ok()?
+        .place_cache_file(PROBE_CACHE_FILE)
+        .ok()
+}
+
+fn read_cache() -> toml::value::Table {
+    cache_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|content| content.parse::<Value>().ok())
+        .and_then(|value| value.as_table().cloned())
+        .unwrap_or_default()
+}
+
+fn write_cache(cache: toml::value::Table) {
+    if let Some(path) = cache_path() {
+        if let Err(e) = std::fs::write(&path, Value::Table(cache).to_string()) {
+            warn!(
+                "Can't write probe cache '{}' (error: {})",
+                path.to_string_lossy(),
+                e
+            );
+        }
+    }
+}
+
+/// Probes all configured build servers (or reuses recent results from the cache) and returns the
+/// name of the least loaded one. Servers that are low on disk space or that are known to lack one
+/// of the [`targets`] are only chosen if every other server is as well. Between equally loaded
+/// servers the one with more memory wins.
+pub fn select_server(
+    configs: &Configs,
+    ssh_options: &[String],
+    default_base_dir: &str,
+    targets: &[String],
+) -> Option<String> {
+    let mut cache = read_cache();
+    let mut candidates = Vec::new();
+    for server in configs.servers() {
+        let cached = cache
+            .get(&server.destination)
+            .and_then(Probe::from_value)
+            .filter(|p| now().saturating_sub(p.timestamp) < PROBE_CACHE_TTL.as_secs());
+        let probe = match cached {
+            Some(probe) => probe,
+            None => {
+                let base_dir = server
+                    .settings
+                    .get("remote_base_dir")
+                    .and_then(Value::as_str)
+                    .unwrap_or(default_base_dir);
+                match probe_server(&server, ssh_options, base_dir) {
+                    Some(probe) => {
+                        cache.insert(server.destination.clone(), probe.to_value());
+                        probe
+                    }
+                    None => continue,
+                }
+            }
+        };
+        debug!(
+            "Build server '{}': {} cores, load {:.2}, {} MiB free",
+            server.name,
+            probe.cores,
+            probe.load,
+            probe.free_disk_kb / 1024
+        );
+        let capabilities = capabilities::cached(&server.destination).unwrap_or_default();
+        let lacks_target = targets.iter().any(|t| capabilities.lacks_target(t));
+        if lacks_target {
+            debug!(
+                "Build server '{}' lacks one of the targets {:?}",
+                server.name, targets
+            );
+        }
+        candidates.push((server.name, probe, lacks_target, capabilities.mem_kb));
+    }
+    write_cache(cache);
+
+    candidates
+        .into_iter()
+        .min_by(|(_, a, a_lacks, a_mem), (_, b, b_lacks, b_mem)| {
+            a_lacks
+                .cmp(b_lacks)
+                .then((a.free_disk_kb < MIN_FREE_DISK_KB).cmp(&(b.free_disk_kb < MIN_FREE_DISK_KB)))
+                .then(
+                    a.load_per_core()
+                        .partial_cmp(&b.load_per_core())
+                        .unwrap_or(std::cmp::Ordering::Equal),
+                )
+                .then(b_mem.cmp(a_mem))
+                .then(b.free_disk_kb.cmp(&a.free_disk_kb))
+        })
+        .map(|(name, ..)| name)
+}
@@ -0,0 +1,420 @@
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use structopt::StructOpt;
+use toml::Value;
+
+use crate::config::Server;
+use crate::error::Error;
+use crate::logging::Run;
+use crate::manage::{format_age, now, parse_age};
+use crate::util::shell_arg;
+
+mod ec2;
+mod gcp;
+mod hetzner;
+mod script;
+
+const INSTANCES_FILE: &str = "instances.toml";
+
+/// How long to wait for ssh on a new instance if the server has no `ssh_timeout`.
+const DEFAULT_SSH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Where the build servers with a `[servers.<name>.provision]` table come from. Every
+/// provider launches instances, tells where a running one can be reached and removes them
+/// again, the rest is shared.
+pub trait Provisioner {
+    /// Starts a new instance for [`server`] and returns its id. Returns once it is running,
+    /// not necessarily once it accepts ssh connections. An instance that was created but
+    /// doesn't come up is terminated again, see [`abandon`].
+    fn launch(&self, server: &Server) -> Result<String, Error>;
+
+    /// The address of the instance [`id`], `host` or `host:port`, or `None` if it doesn't run
+    /// anymore.
+    fn address(&self, id: &str) -> Result<Option<String>, Error>;
+
+    /// Removes the instance [`id`], also if it was stopped already.
+    fn terminate(&self, id: &str) -> Result<(), Error>;
+}
+
+/// The `[servers.<name>.provision]` table of a build server.
+pub struct Settings<'a> {
+    server: &'a str,
+    table: &'a Value,
+}
+
+impl Settings<'_> {
+    fn string(&self, key: &str) -> Option<String> {
+        self.table
+            .get(key)
+            .and_then(Value::as_str)
+            .map(String::from)
+    }
+
+    fn required(&self, key: &str) -> Result<String, Error> {
+        self.string(key).ok_or_else(|| {
+            Error::Config(format!(
+                "The provisioning settings of build server '{}' need '{}'",
+                self.server, key
+            ))
+        })
+    }
+
+    fn strings(&self, key: &str) -> Vec<String> {
+        crate::config::string_list(self.table, key)
+    }
+
+    fn bool(&self, key: &str) -> Option<bool> {
+        self.table.get(key).and_then(Value::as_bool)
+    }
+
+    /// `idle_timeout` in seconds.
+    fn idle_timeout(&self) -> Result<Option<u64>, Error> {
+        match self.string("idle_timeout") {
+            Some(age) => Ok(Some(parse_age(&age).map_err(Error::Config)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The provisioner of [`server`], `None` for a static server.
+pub fn from_server(server: &Server) -> Result<Option<Box<dyn Provisioner>>, Error> {
+    let table = match server.settings.get("provision") {
+        Some(table) => table,
+        None => return Ok(None),
+    };
+    let settings = Settings {
+        server: &server.name,
+        table,
+    };
+    let provisioner: Box<dyn Provisioner> = match settings.string("provider").as_deref() {
+        Some("ec2") | None => Box::new(ec2::Ec2::new(&settings)?),
+        Some("gcp") => Box::new(gcp::Gcp::new(&settings)?),
+        Some("hetzner") => Box::new(hetzner::Hetzner::new(&settings)?),
+        Some("script") => Box::new(script::Script::new(&settings)?),
+        Some(provider) => {
+            return Err(Error::Config(format!(
+                "Unknown provider '{}' for build server '{}' (ec2, gcp, hetzner or script)",
+                provider, server.name
+            )))
+        }
+    };
+    Ok(Some(provisioner))
+}
+
+/// Runs the cli of a provider and returns its trimmed output. Only lookups are run in a dry
+/// run.
+fn run(what: &str, mut command: Command, lookup: bool) -> Result<String, Error> {
+    command.stdin(Stdio::null());
+    let output: std::io::Result<Output> = match lookup {
+        true => command.query_output(),
+        false => command.logged_output(),
+    };
+    let output = output.map_err(|e| Error::command(what, e, &[]))?;
+    if !output.status.success() {
+        return Err(Error::command(what, output.status, &output.stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Script run as root when an instance boots. With an idle timeout of [`idle_timeout`]
+/// seconds it shuts the instance down once no cargo runs and no build directory was used for
+/// that long.
+fn boot_script(idle_timeout: Option<u64>) -> String {
+    let minutes = match idle_timeout {
+        Some(timeout) => timeout.div_ceil(60).max(1),
+        None => return "#!/bin/sh\n".to_string(),
+    };
+    format!(
+        "#!/bin/sh\nnohup sh -c 'while sleep 60; do \
+         pgrep -x cargo >/dev/null && continue; \
+         [ -n \"$(find /home /root -maxdepth 5 -name .cargo-remote-project -mmin -{minutes} \
+         2>/dev/null | head -n 1)\" ] && continue; \
+         [ $(cut -d. -f1 /proc/uptime) -gt {seconds} ] && shutdown -h now; \
+         done' >/dev/null 2>&1 &\n",
+        minutes = minutes,
+        seconds = minutes * 60
+    )
+}
+
+/// Terminates the instance [`id`] that was launched but couldn't be set up. It isn't recorded
+/// yet, nothing else would ever terminate it. [`created`] tells whether it surely exists, a
+/// launch command that failed may or may not have left one behind.
+fn abandon(provisioner: &dyn Provisioner, id: &str, created: bool) {
+    match provisioner.terminate(id) {
+        Ok(()) => warn!("Terminated instance {}, it couldn't be set up", id),
+        Err(e) if created => warn!(
+            "Can't terminate instance {}, it has to be terminated by hand ({})",
+            id, e
+        ),
+        Err(e) => debug!("{}", e),
+    }
+}
+
+/// A name for a new instance of [`server`], unique and valid for every provider: lowercase
+/// letters, digits and dashes.
+fn instance_name(server: &str) -> String {
+    let server: String = server
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect();
+    format!("cargo-remote-{}-{}", server.trim_matches('-'), now())
+}
+
+/// Connects [`server`] to [`address`], a host with an optional `:port`.
+fn connect_to(server: &mut Server, address: &str) {
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse::<u16>() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (address, None),
+        },
+        _ => (address, None),
+    };
+    if let (Some(port), Some(settings)) = (port, server.settings.as_table_mut()) {
+        settings.insert("ssh_port".into(), Value::Integer(port.into()));
+    }
+    server.set_host(host);
+}
+
+/// A launched instance as recorded in the instances file.
+struct Instance {
+    id: String,
+    /// Where it is reached, see [`Provisioner::address`].
+    host: String,
+    launched: u64,
+}
+
+fn instances_path() -> Option<std::path::PathBuf> {
+    xdg::BaseDirectories::with_prefix("cargo-remote")
+        .ok()?
+        .place_data_file(INSTANCES_FILE)
+        .ok()
+}
+
+fn read_instances() -> toml::value::Table {
+    instances_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|content| content.parse::<Value>().ok())
+        .and_then(|value| value.as_table().cloned())
+        .unwrap_or_default()
+}
+
+fn write_instances(instances: toml::value::Table) {
+    if let Some(path) = instances_path() {
+        if let Err(e) = std::fs::write(&path, Value::Table(instances).to_string()) {
+            warn!(
+                "Can't write the instances file '{}' (error: {})",
+                path.to_string_lossy(),
+                e
+            );
+        }
+    }
+}
+
+/// The instance launched for the build server [`name`] before.
+fn recorded(name: &str) -> Option<Instance> {
+    let instances = read_instances();
+    let instance = instances.get(name)?;
+    Some(Instance {
+        id: instance.get("id")?.as_str()?.to_string(),
+        host: instance.get("host")?.as_str()?.to_string(),
+        launched: instance.get("launched")?.as_integer()? as u64,
+    })
+}
+
+fn record(name: &str, instance: Option<&Instance>) {
+    let mut instances = read_instances();
+    match instance {
+        Some(instance) => {
+            let mut table = toml::value::Table::new();
+            table.insert("id".into(), Value::String(instance.id.clone()));
+            table.insert("host".into(), Value::String(instance.host.clone()));
+            table.insert("launched".into(), Value::Integer(instance.launched as i64));
+            instances.insert(name.to_string(), Value::Table(table));
+        }
+        None => {
+            instances.remove(name);
+        }
+    }
+    write_instances(instances);
+}
+
+/// Removes the host key of [`server`] from `known_hosts`. Addresses of terminated instances
+/// are given to new ones, which have a different key.
+fn forget_host_key(server: &Server) {
+    let host = server.destination.rsplit('@').next().unwrap_or_default();
+    let host = match server.settings.get("ssh_port").and_then(Value::as_integer) {
+        Some(port) => format!("[{}]:{}", host, port),
+        None => host.to_string(),
+    };
+    let _ = Command::new("ssh-keygen")
+        .arg("-R")
+        .arg(host)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .logged_status();
+}
+
+/// Waits until [`server`] accepts ssh connections, then installs rustup with the configured
+/// `rustup_default` toolchain if there is no cargo yet.
+fn prepare(server: &Server, ssh_options: &[String]) -> Result<(), Error> {
+    let timeout = server
+        .settings
+        .get("ssh_timeout")
+        .and_then(Value::as_integer)
+        .map_or(DEFAULT_SSH_TIMEOUT, |secs| Duration::from_secs(secs as u64));
+    // the host key of a new instance can't be known yet
+    let mut wait_options = ssh_options.to_vec();
+    wait_options.push("-oStrictHostKeyChecking=accept-new".to_string());
+    crate::ssh::wait_for(server, &wait_options, timeout)?;
+    let toolchain = server
+        .settings
+        .get("rustup_default")
+        .and_then(Value::as_str)
+        .unwrap_or("stable");
+    let install = format!(
+        "[ -x ~/.cargo/bin/cargo ] || command -v cargo >/dev/null 2>&1 || {{ \
+         echo 'Installing the {toolchain} toolchain' >&2; \
+         curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | \
+         sh -s -- -y -q --profile minimal --default-toolchain {toolchain}; }}",
+        toolchain = shell_arg(toolchain)
+    );
+    let status = crate::ssh::server_command(server, ssh_options)
+        .arg(install)
+        .stdin(Stdio::null())
+        .logged_status()
+        .map_err(|e| Error::command("install the toolchain", e, &[]))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::command("install the toolchain", status, &[])),
+    }
+}
+
+/// Points [`server`] to its running instance, launching and preparing one if there is none.
+/// Static servers are left alone.
+pub fn ensure(server: &mut Server, ssh_options: &[String]) -> Result<(), Error> {
+    let provisioner = match from_server(server)? {
+        Some(provisioner) => provisioner,
+        None => return Ok(()),
+    };
+    // rustup's environment isn't part of the system profile
+    if let Some(settings) = server.settings.as_table_mut() {
+        settings
+            .entry("env")
+            .or_insert_with(|| Value::String("~/.cargo/env".to_string()));
+    }
+    if let Some(instance) = recorded(&server.name) {
+        if let Some(address) = provisioner.address(&instance.id)? {
+            debug!("Build server '{}' is instance {}", server.name, instance.id);
+            connect_to(server, &address);
+            return Ok(());
+        }
+        info!(
+            "Instance {} of build server '{}' isn't running anymore",
+            instance.id, server.name
+        );
+        // stopped instances might still be billed, it's fine if it is gone already
+        if !crate::logging::dry_run() {
+            if let Err(e) = provisioner.terminate(&instance.id) {
+                debug!("{}", e);
+            }
+        }
+        record(&server.name, None);
+    }
+    if crate::logging::dry_run() {
+        info!(
+            "Would launch an instance for build server '{}'",
+            server.name
+        );
+        return Ok(());
+    }
+    let id = provisioner.launch(server)?;
+    let address = provisioner.address(&id).and_then(|address| {
+        address.ok_or_else(|| Error::Run(format!("Instance {} has no address to connect to", id)))
+    });
+    let address = match address {
+        Ok(address) => address,
+        Err(e) => {
+            abandon(provisioner.as_ref(), &id, true);
+            return Err(e);
+        }
+    };
+    record(
+        &server.name,
+        Some(&Instance {
+            id: id.clone(),
+            host: address.clone(),
+            launched: now(),
+        }),
+    );
+    connect_to(server, &address);
+    forget_host_key(server);
+    prepare(server, ssh_options)?;
+    info!(
+        "Build server '{}' is ready at {} ({})",
+        server.name, address, id
+    );
+    Ok(())
+}
+
+/// Options of `cargo remote provision`, given after `--`.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "cargo remote provision")]
+struct ProvisionOpts {
+    #[structopt(
+        long = "terminate",
+        help = "Terminate the instance of the build server"
+    )]
+    terminate: bool,
+}
+
+/// `cargo remote provision [-- --terminate]`: launches the instance of a provisioned build
+/// server ahead of the first build, or terminates it.
+pub fn provision(
+    server: Option<Server>,
+    options: &[String],
+    ssh_options: &[String],
+) -> Result<i32, Error> {
+    let opts =
+        ProvisionOpts::from_iter(std::iter::once("provision".to_string()).chain(options.to_vec()));
+    let mut server = server.ok_or_else(|| {
+        Error::Config("No build server with provisioning settings selected".to_string())
+    })?;
+    let provisioner = match from_server(&server)? {
+        Some(provisioner) => provisioner,
+        None => {
+            return Err(Error::Config(format!(
+                "Build server '{}' has no [provision] settings",
+                server.name
+            )))
+        }
+    };
+    if !opts.terminate {
+        ensure(&mut server, ssh_options)?;
+        if let Some(instance) = recorded(&server.name) {
+            println!(
+                "Build server '{}' is running at {} ({}, launched {})",
+                server.name,
+                instance.host,
+                instance.id,
+                format_age(now().saturating_sub(instance.launched))
+            );
+        }
+        return Ok(0);
+    }
+    match recorded(&server.name) {
+        Some(instance) => {
+            provisioner.terminate(&instance.id)?;
+            record(&server.name, None);
+            connect_to(&mut server, &instance.host);
+            forget_host_key(&server);
+            println!("Terminated instance {} of '{}'", instance.id, server.name);
+        }
+        None => println!("Build server '{}' has no instance", server.name),
+    }
+    Ok(0)
+}
@@ -0,0 +1,132 @@
+use std::process::Command;
+
+use log::info;
+
+use super::{abandon, boot_script, run, Provisioner, Settings};
+use crate::config::Server;
+use crate::error::Error;
+
+/// Instances on AWS EC2, managed with the `aws` cli.
+pub struct Ec2 {
+    region: Option<String>,
+    /// Named profile of the aws cli.
+    profile: Option<String>,
+    ami: String,
+    instance_type: String,
+    key_name: Option<String>,
+    security_groups: Vec<String>,
+    subnet: Option<String>,
+    /// Launch a spot instance instead of an on-demand one.
+    spot: bool,
+    idle_timeout: Option<u64>,
+}
+
+impl Ec2 {
+    pub fn new(settings: &Settings) -> Result<Ec2, Error> {
+        Ok(Ec2 {
+            region: settings.string("region"),
+            profile: settings.string("aws_profile"),
+            ami: settings.required("ami")?,
+            instance_type: settings.required("instance_type")?,
+            key_name: settings.string("key_name"),
+            security_groups: settings.strings("security_groups"),
+            subnet: settings.string("subnet"),
+            spot: settings.bool("spot").unwrap_or(true),
+            idle_timeout: settings.idle_timeout()?,
+        })
+    }
+
+    /// The aws cli with the configured profile and region.
+    fn aws(&self) -> Command {
+        let mut aws = Command::new("aws");
+        aws.arg("ec2");
+        if let Some(profile) = &self.profile {
+            aws.arg("--profile").arg(profile);
+        }
+        if let Some(region) = &self.region {
+            aws.arg("--region").arg(region);
+        }
+        aws.arg("--output").arg("text");
+        aws
+    }
+}
+
+impl Provisioner for Ec2 {
+    fn launch(&self, server: &Server) -> Result<String, Error> {
+        info!(
+            "Launching {} {} instance for build server '{}'",
+            match self.spot {
+                true => "a spot",
+                false => "an on-demand",
+            },
+            self.instance_type,
+            server.name
+        );
+        let mut aws = self.aws();
+        aws.arg("run-instances")
+            .arg("--count")
+            .arg("1")
+            .arg("--image-id")
+            .arg(&self.ami)
+            .arg("--instance-type")
+            .arg(&self.instance_type)
+            // the idle shutdown terminates the instance
+            .arg("--instance-initiated-shutdown-behavior")
+            .arg("terminate")
+            .arg("--user-data")
+            .arg(boot_script(self.idle_timeout))
+            .arg("--tag-specifications")
+            .arg(format!(
+                "ResourceType=instance,Tags=[{{Key=Name,Value=cargo-remote-{}}}]",
+                server.name
+            ))
+            .arg("--query")
+            .arg("Instances[0].InstanceId");
+        if let Some(key_name) = &self.key_name {
+            aws.arg("--key-name").arg(key_name);
+        }
+        if !self.security_groups.is_empty() {
+            aws.arg("--security-group-ids").args(&self.security_groups);
+        }
+        if let Some(subnet) = &self.subnet {
+            aws.arg("--subnet-id").arg(subnet);
+        }
+        if self.spot {
+            aws.arg("--instance-market-options").arg(
+                "MarketType=spot,SpotOptions={SpotInstanceType=one-time,\
+                 InstanceInterruptionBehavior=terminate}",
+            );
+        }
+        let id = run("launch the build server instance", aws, false)?;
+        let mut wait = self.aws();
+        wait.args(["wait", "instance-running", "--instance-ids"])
+            .arg(&id);
+        if let Err(e) = run("wait for the build server instance", wait, false) {
+            abandon(self, &id, true);
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    fn address(&self, id: &str) -> Result<Option<String>, Error> {
+        let mut aws = self.aws();
+        aws.arg("describe-instances")
+            .arg("--instance-ids")
+            .arg(id)
+            .arg("--query")
+            .arg("Reservations[0].Instances[0].[State.Name,PublicIpAddress,PrivateIpAddress]");
+        let output = run("look up the build server instance", aws, true)?;
+        let fields: Vec<&str> = output.split_whitespace().collect();
+        match fields.as_slice() {
+            ["running", public, _] if *public != "None" => Ok(Some(public.to_string())),
+            ["running", _, private] => Ok(Some(private.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    fn terminate(&self, id: &str) -> Result<(), Error> {
+        let mut aws = self.aws();
+        aws.arg("terminate-instances").arg("--instance-ids").arg(id);
+        run("terminate the build server instance", aws, false).map(|_| ())
+    }
+}
@@ -0,0 +1,129 @@
+use std::process::Command;
+
+use log::info;
+
+use super::{abandon, boot_script, instance_name, run, Provisioner, Settings};
+use crate::config::Server;
+use crate::error::Error;
+
+/// Instances on Google Compute Engine, managed with the `gcloud` cli. An idle instance stops
+/// itself, it is deleted when the next run finds it stopped.
+pub struct Gcp {
+    project: Option<String>,
+    zone: String,
+    machine_type: String,
+    image: Option<String>,
+    image_family: Option<String>,
+    image_project: Option<String>,
+    /// Launch a spot VM instead of a standard one.
+    spot: bool,
+    idle_timeout: Option<u64>,
+}
+
+impl Gcp {
+    pub fn new(settings: &Settings) -> Result<Gcp, Error> {
+        let gcp = Gcp {
+            project: settings.string("project"),
+            zone: settings.required("zone")?,
+            machine_type: settings.required("machine_type")?,
+            image: settings.string("image"),
+            image_family: settings.string("image_family"),
+            image_project: settings.string("image_project"),
+            spot: settings.bool("spot").unwrap_or(true),
+            idle_timeout: settings.idle_timeout()?,
+        };
+        if gcp.image.is_none() && gcp.image_family.is_none() {
+            return Err(Error::Config(format!(
+                "The provisioning settings of build server '{}' need 'image' or 'image_family'",
+                settings.server
+            )));
+        }
+        Ok(gcp)
+    }
+
+    /// `gcloud compute instances` with the configured project.
+    fn gcloud(&self) -> Command {
+        let mut gcloud = Command::new("gcloud");
+        gcloud.arg("compute").arg("instances");
+        if let Some(project) = &self.project {
+            gcloud.arg("--project").arg(project);
+        }
+        gcloud.arg("--quiet");
+        gcloud
+    }
+}
+
+impl Provisioner for Gcp {
+    fn launch(&self, server: &Server) -> Result<String, Error> {
+        let name = instance_name(&server.name);
+        info!(
+            "Launching {} {} VM for build server '{}'",
+            match self.spot {
+                true => "a spot",
+                false => "a standard",
+            },
+            self.machine_type,
+            server.name
+        );
+        let mut gcloud = self.gcloud();
+        gcloud
+            .arg("create")
+            .arg(&name)
+            .arg("--zone")
+            .arg(&self.zone)
+            .arg("--machine-type")
+            .arg(&self.machine_type)
+            .arg("--metadata")
+            .arg(format!("startup-script={}", boot_script(self.idle_timeout)));
+        if let Some(image) = &self.image {
+            gcloud.arg("--image").arg(image);
+        }
+        if let Some(family) = &self.image_family {
+            gcloud.arg("--image-family").arg(family);
+        }
+        if let Some(project) = &self.image_project {
+            gcloud.arg("--image-project").arg(project);
+        }
+        if self.spot {
+            gcloud
+                .arg("--provisioning-model=SPOT")
+                .arg("--instance-termination-action=DELETE");
+        }
+        if let Err(e) = run("launch the build server VM", gcloud, false) {
+            // gcloud also fails if the VM was created but didn't start
+            abandon(self, &name, false);
+            return Err(e);
+        }
+        Ok(name)
+    }
+
+    fn address(&self, id: &str) -> Result<Option<String>, Error> {
+        let mut gcloud = self.gcloud();
+        gcloud
+            .arg("describe")
+            .arg(id)
+            .arg("--zone")
+            .arg(&self.zone)
+            .arg(
+                "--format=value(status,networkInterfaces[0].accessConfigs[0].natIP,\
+                 networkInterfaces[0].networkIP)",
+            );
+        // a VM that was removed already can't be described
+        let output = match run("look up the build server VM", gcloud, true) {
+            Ok(output) => output,
+            Err(_) => return Ok(None),
+        };
+        let fields: Vec<&str> = output.split_whitespace().collect();
+        match fields.as_slice() {
+            ["RUNNING", public, _] => Ok(Some(public.to_string())),
+            ["RUNNING", private] => Ok(Some(private.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    fn terminate(&self, id: &str) -> Result<(), Error> {
+        let mut gcloud = self.gcloud();
+        gcloud.arg("delete").arg(id).arg("--zone").arg(&self.zone);
+        run("delete the build server VM", gcloud, false).map(|_| ())
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use log::info;
+
+use super::{abandon, boot_script, instance_name, run, Provisioner, Settings};
+use crate::config::Server;
+use crate::error::Error;
+use crate::logging::Run;
+
+/// Servers on Hetzner Cloud, managed with the `hcloud` cli and its active context. An idle
+/// server powers itself off, it is deleted when the next run finds it off.
+pub struct Hetzner {
+    server_type: String,
+    image: String,
+    location: Option<String>,
+    ssh_keys: Vec<String>,
+    idle_timeout: Option<u64>,
+}
+
+impl Hetzner {
+    pub fn new(settings: &Settings) -> Result<Hetzner, Error> {
+        Ok(Hetzner {
+            server_type: settings.required("server_type")?,
+            image: settings.required("image")?,
+            location: settings.string("location"),
+            ssh_keys: settings.strings("ssh_keys"),
+            idle_timeout: settings.idle_timeout()?,
+        })
+    }
+}
+
+impl Provisioner for Hetzner {
+    fn launch(&self, server: &Server) -> Result<String, Error> {
+        let name = instance_name(&server.name);
+        info!(
+            "Creating a {} server for build server '{}'",
+            self.server_type, server.name
+        );
+        let mut hcloud = Command::new("hcloud");
+        hcloud
+            .args(["server", "create", "--name"])
+            .arg(&name)
+            .arg("--type")
+            .arg(&self.server_type)
+            .arg("--image")
+            .arg(&self.image)
+            .arg("--user-data-from-file")
+            .arg("-");
+        if let Some(location) = &self.location {
+            hcloud.arg("--location").arg(location);
+        }
+        for key in &self.ssh_keys {
+            hcloud.arg("--ssh-key").arg(key);
+        }
+        let what = "create the build server";
+        let mut child = hcloud
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .logged_spawn()
+            .map_err(|e| Error::command(what, e, &[]))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(boot_script(self.idle_timeout).as_bytes());
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|e| Error::command(what, e, &[]))?;
+        if !output.status.success() {
+            // hcloud also fails if the server was created but didn't start
+            abandon(self, &name, false);
+            return Err(Error::command(what, output.status, &output.stderr));
+        }
+        Ok(name)
+    }
+
+    fn address(&self, id: &str) -> Result<Option<String>, Error> {
+        let mut hcloud = Command::new("hcloud");
+        hcloud
+            .args(["server", "describe", id, "--output"])
+            .arg("format={{.Status}} {{.PublicNet.IPv4.IP}}");
+        // a server that was deleted already can't be described
+        let output = match run("look up the build server", hcloud, true) {
+            Ok(output) => output,
+            Err(_) => return Ok(None),
+        };
+        match output.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["running", ip] => Ok(Some(ip.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    fn terminate(&self, id: &str) -> Result<(), Error> {
+        let mut hcloud = Command::new("hcloud");
+        hcloud.args(["server", "delete", id]);
+        run("delete the build server", hcloud, false).map(|_| ())
+    }
+}
@@ -0,0 +1,71 @@
+use std::process::Command;
+
+use log::{info, warn};
+
+use super::{run, Provisioner, Settings};
+use crate::config::Server;
+use crate::error::Error;
+
+/// Build servers handed out by a script of your own, e.g. from a pool of machines. The
+/// `launch` script gets the server name in `CARGO_REMOTE_SERVER` and prints the `host` or
+/// `host:port` to connect to as its last line, the optional `terminate` script gets that in
+/// `CARGO_REMOTE_HOST` to give the server back.
+pub struct Script {
+    launch: String,
+    terminate: Option<String>,
+}
+
+impl Script {
+    pub fn new(settings: &Settings) -> Result<Script, Error> {
+        if settings.string("idle_timeout").is_some() {
+            warn!(
+                "The script provider of build server '{}' doesn't support idle_timeout",
+                settings.server
+            );
+        }
+        Ok(Script {
+            launch: settings.required("launch")?,
+            terminate: settings.string("terminate"),
+        })
+    }
+}
+
+fn shell(script: &str) -> Command {
+    let mut sh = Command::new("sh");
+    sh.arg("-c").arg(script);
+    sh
+}
+
+impl Provisioner for Script {
+    fn launch(&self, server: &Server) -> Result<String, Error> {
+        info!(
+            "Running the launch script of build server '{}'",
+            server.name
+        );
+        let mut sh = shell(&self.launch);
+        sh.env("CARGO_REMOTE_SERVER", &server.name);
+        let output = run("run the launch script", sh, false)?;
+        match output.lines().last().map(str::trim) {
+            Some(address) if !address.is_empty() => Ok(address.to_string()),
+            _ => Err(Error::Run(format!(
+                "The launch script of build server '{}' printed no address",
+                server.name
+            ))),
+        }
+    }
+
+    /// The script's servers are used until they are terminated.
+    fn address(&self, id: &str) -> Result<Option<String>, Error> {
+        Ok(Some(id.to_string()))
+    }
+
+    fn terminate(&self, id: &str) -> Result<(), Error> {
+        let terminate = match &self.terminate {
+            Some(terminate) => terminate,
+            None => return Ok(()),
+        };
+        let mut sh = shell(terminate);
+        sh.env("CARGO_REMOTE_HOST", id);
+        run("run the terminate script", sh, false).map(|_| ())
+    }
+}
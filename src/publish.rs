@@ -0,0 +1,113 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Instant;
+
+use log::{debug, info, warn};
+use toml::Value;
+
+use crate::build::{exit_code, RemoteBuild};
+use crate::error::Error;
+use crate::exec::in_build_dir;
+use crate::logging::Run;
+use crate::timings::Phase;
+use crate::util::shell_arg;
+
+/// The registry `--registry` selects in [`options`], `None` for crates.io.
+fn registry(options: &[String]) -> Option<String> {
+    let mut options = options.iter().take_while(|o| *o != "--");
+    while let Some(option) = options.next() {
+        match option.strip_prefix("--registry=") {
+            Some(name) => return Some(name.to_string()),
+            None if option == "--registry" => return options.next().cloned(),
+            None => {}
+        }
+    }
+    None
+}
+
+/// The variable cargo reads the token of [`registry`] from.
+fn token_var(registry: Option<&str>) -> String {
+    match registry {
+        Some(name) => format!(
+            "CARGO_REGISTRIES_{}_TOKEN",
+            name.to_uppercase().replace('-', "_")
+        ),
+        None => "CARGO_REGISTRY_TOKEN".to_string(),
+    }
+}
+
+/// The local token of [`registry`]: from [`var`] or the credentials file `cargo login` wrote.
+fn local_token(var: &str, registry: Option<&str>) -> Option<String> {
+    if let Ok(token) = std::env::var(var) {
+        return Some(token);
+    }
+    let cargo_home = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")))?;
+    let credentials = ["credentials.toml", "credentials"]
+        .iter()
+        .find_map(|name| std::fs::read_to_string(cargo_home.join(name)).ok())?;
+    let credentials = credentials.parse::<Value>().ok()?;
+    let table = match registry {
+        Some(name) => credentials.get("registries")?.get(name)?,
+        None => credentials.get("registry")?,
+    };
+    table.get("token")?.as_str().map(String::from)
+}
+
+/// `cargo remote publish [-- <publish options>]`: syncs the sources and runs `cargo publish`
+/// on the build server, so the verification build of the package runs there as well. The
+/// local token of the registry is passed on stdin and only set for that cargo command, it
+/// never shows up on a command line. Without one the build server's own credentials are used.
+pub fn publish(mut build: RemoteBuild) -> Result<i32, Error> {
+    build.validate()?;
+    let lock = build.lock()?;
+    build.transfer_sources()?;
+
+    let cargo_args = build.cargo_args();
+    let registry = registry(&cargo_args);
+    let var = token_var(registry.as_deref());
+    let token = match cargo_args.iter().any(|o| o.starts_with("--token")) {
+        true => None,
+        false => local_token(&var, registry.as_deref()),
+    };
+    if token.is_none() {
+        info!("No local token for the registry, using the credentials on the build server");
+    }
+    let mut command = match token {
+        Some(_) => format!("IFS= read -r {var}; export {var}; ", var = var),
+        None => String::new(),
+    };
+    let quoted_args: Vec<String> = cargo_args.iter().map(|arg| shell_arg(arg)).collect();
+    command.push_str(&format!(
+        "{} {} publish {}",
+        build.build_env,
+        build.cargo_binary(),
+        quoted_args.join(" ")
+    ));
+
+    debug!("Publishing from the build server");
+    let started = Instant::now();
+    let mut ssh = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(in_build_dir(&build, &command))
+        .stdin(Stdio::piped())
+        .logged_spawn()
+        .map_err(|e| Error::command("run cargo publish on the build server", e, &[]))?;
+    if let (Some(token), Some(mut stdin)) = (token, ssh.stdin.take()) {
+        if let Err(e) = stdin.write_all(format!("{}\n", token).as_bytes()) {
+            warn!("Failed to pass the token to the build server: {}", e);
+        }
+    }
+    let status = ssh
+        .wait()
+        .map_err(|e| Error::command("run cargo publish on the build server", e, &[]));
+    build.timings.add(Phase::Build, started.elapsed());
+    drop(lock);
+    build.print_timings();
+    build.ssh.stop_master();
+    Ok(exit_code(status?))
+}
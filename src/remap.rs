@@ -0,0 +1,243 @@
+use std::borrow::Cow;
+use std::path::Path;
+
+use crate::build::RemoteBuild;
+use crate::container::WORKSPACE;
+
+/// A directory on the build server and the local directory it is a copy of.
+#[derive(Clone)]
+struct Dir {
+    /// The remote path without a leading `~` and the trailing `/`.
+    remote: String,
+    /// Whether [`Dir::remote`] is relative to the home directory on the build server. Cargo
+    /// prints the absolute path then, which starts with a home directory we don't know.
+    in_home: bool,
+    local: String,
+    /// [`Dir::local`] escaped for a JSON string.
+    local_json: String,
+}
+
+/// Rewrites the paths of the remote build directories in the output of the remote cargo to the
+/// local files they are copies of, so that terminal hyperlinks and the jump to an error of an
+/// editor work. Cargo prints the paths inside the workspace relative to it, the absolute ones
+/// show up in its JSON messages, for patched in workspaces and in the output of programs.
+#[derive(Clone)]
+pub struct Remap {
+    dirs: Vec<Dir>,
+}
+
+/// Characters paths in cargo's output are separated from the surrounding text by.
+fn delimiter(c: char) -> bool {
+    c.is_ascii_whitespace() || "\"'`()[]<>,;=".contains(c)
+}
+
+/// Whether [`c`] continues a file name, so a directory that is followed by it doesn't end there.
+fn name_char(c: char) -> bool {
+    c.is_alphanumeric() || "-_.".contains(c)
+}
+
+impl Remap {
+    /// Rewrites the paths of [`build`], none without [`RemoteBuild::remap_paths`].
+    pub fn new(build: &RemoteBuild) -> Remap {
+        if !build.remap_paths {
+            return Remap::with_dirs(Vec::new());
+        }
+        let mut dirs = vec![(build.build_path.as_str(), build.project_dir.as_path())];
+        for workspace in &build.patches.workspaces {
+            dirs.push((&workspace.remote_dir, &workspace.local_dir));
+        }
+        if build.container.is_some() {
+            dirs.push((WORKSPACE, &build.project_dir));
+        }
+        Remap::with_dirs(dirs)
+    }
+
+    /// Rewrites the remote directories of [`dirs`] to the local ones. A leading `~` of a remote
+    /// directory is the home directory on the build server.
+    pub fn with_dirs(dirs: Vec<(&str, &Path)>) -> Remap {
+        let dirs = dirs
+            .into_iter()
+            .map(|(remote, local)| {
+                let local = local.to_string_lossy();
+                let local = local.trim_end_matches(['/', '\\']).to_string();
+                let local_json = serde_json::to_string(&local).unwrap_or_default();
+                Dir {
+                    remote: remote
+                        .trim_start_matches('~')
+                        .trim_end_matches('/')
+                        .to_string(),
+                    in_home: remote.starts_with('~'),
+                    local_json: local_json.trim_matches('"').to_string(),
+                    local,
+                }
+            })
+            .filter(|dir| !dir.remote.is_empty())
+            .collect();
+        Remap { dirs }
+    }
+
+    /// Where the next remote path in [`line`] after [`from`] starts and where the remote
+    /// directory in it ends.
+    fn find(&self, line: &str, from: usize) -> Option<(usize, usize, &Dir)> {
+        self.dirs
+            .iter()
+            .filter_map(|dir| {
+                line[from..]
+                    .match_indices(&dir.remote)
+                    .find_map(|(offset, _)| {
+                        let offset = from + offset;
+                        let end = offset + dir.remote.len();
+                        if line[end..].chars().next().is_some_and(name_char) {
+                            return None;
+                        }
+                        let start = line[from..offset]
+                            .rfind(delimiter)
+                            .map_or(from, |i| from + i + 1);
+                        // `file://` URLs like the ones in package ids
+                        let start = match line[start..offset].rfind("file://") {
+                            Some(i) => start + i + "file://".len(),
+                            None => start,
+                        };
+                        let home = &line[start..offset];
+                        let valid = match dir.in_home {
+                            true => home == "~" || home.starts_with('/'),
+                            false => home.is_empty(),
+                        };
+                        match valid {
+                            true => Some((start, end, dir)),
+                            false => None,
+                        }
+                    })
+            })
+            .min_by_key(|(start, _, _)| *start)
+    }
+
+    /// [`line`] with the remote paths in it replaced by the local ones. Lines of JSON get the
+    /// local paths escaped.
+    pub fn line<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let json = line.starts_with('{');
+        let mut remapped = String::new();
+        let mut copied = 0;
+        while let Some((start, end, dir)) = self.find(line, copied) {
+            remapped.push_str(&line[copied..start]);
+            remapped.push_str(match json {
+                true => &dir.local_json,
+                false => &dir.local,
+            });
+            copied = end;
+        }
+        match copied {
+            0 => Cow::Borrowed(line),
+            _ => {
+                remapped.push_str(&line[copied..]);
+                Cow::Owned(remapped)
+            }
+        }
+    }
+
+    /// [`Remap::line`] for a chunk of output as it arrives, line by line. Output that isn't
+    /// valid UTF-8 is passed on as it is, just like a path that is split across two chunks.
+    pub fn bytes<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        let text = match std::str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(_) => return Cow::Borrowed(bytes),
+        };
+        if !self.dirs.iter().any(|dir| text.contains(&dir.remote)) {
+            return Cow::Borrowed(bytes);
+        }
+        let remapped: String = text
+            .split_inclusive('\n')
+            .map(|line| self.line(line))
+            .collect();
+        Cow::Owned(remapped.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remap() -> Remap {
+        Remap::with_dirs(vec![
+            ("~/remote-builds/demo-1234/", Path::new("/home/me/demo")),
+            (
+                "~/remote-builds/patches/dep-5678/",
+                Path::new("/home/me/dep"),
+            ),
+            (WORKSPACE, Path::new("/home/me/demo")),
+        ])
+    }
+
+    #[test]
+    fn remote_paths_become_local() {
+        let remap = remap();
+        assert_eq!(
+            remap.line("error at /home/builder/remote-builds/demo-1234/src/main.rs:3:5"),
+            "error at /home/me/demo/src/main.rs:3:5"
+        );
+        assert_eq!(
+            remap.line("  --> ~/remote-builds/patches/dep-5678/src/lib.rs:1:1"),
+            "  --> /home/me/dep/src/lib.rs:1:1"
+        );
+        assert_eq!(
+            remap.line("(/workspace/src/main.rs) and '/workspace'"),
+            "(/home/me/demo/src/main.rs) and '/home/me/demo'"
+        );
+        assert_eq!(
+            remap.line("demo 0.1.0 (path+file:///home/builder/remote-builds/demo-1234)"),
+            "demo 0.1.0 (path+file:///home/me/demo)"
+        );
+    }
+
+    #[test]
+    fn similar_paths_are_left_alone() {
+        let remap = remap();
+        for line in [
+            "no paths in here",
+            "/home/builder/remote-builds/demo-12345/src/main.rs",
+            "/home/builder/remote-builds/demo-1234.lock",
+            "relative/remote-builds/demo-1234/src/main.rs",
+            "/srv/workspace/src/main.rs",
+            "/workspace-old/src",
+        ]
+        .iter()
+        {
+            assert!(matches!(remap.line(line), Cow::Borrowed(_)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn json_gets_escaped_local_paths() {
+        let remap = Remap::with_dirs(vec![(
+            "~/remote-builds/demo-1234/",
+            Path::new("C:\\Users\\me \"x\"\\demo\\"),
+        )]);
+        let line = r#"{"file_name":"/home/b/remote-builds/demo-1234/src/main.rs"}"#;
+        let remapped = remap.line(line);
+        assert_eq!(
+            remapped,
+            r#"{"file_name":"C:\\Users\\me \"x\"\\demo/src/main.rs"}"#
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&remapped).unwrap();
+        assert_eq!(parsed["file_name"], "C:\\Users\\me \"x\"\\demo/src/main.rs");
+        assert_eq!(
+            remap.line("see /home/b/remote-builds/demo-1234/src"),
+            "see C:\\Users\\me \"x\"\\demo/src"
+        );
+    }
+
+    #[test]
+    fn chunks_are_remapped_line_by_line() {
+        let remap = remap();
+        let chunk = b"a /home/b/remote-builds/demo-1234/x\nb /workspace/y\nc\n";
+        assert_eq!(
+            &*remap.bytes(chunk),
+            b"a /home/me/demo/x\nb /home/me/demo/y\nc\n"
+        );
+        let binary = b"\xff/workspace/y";
+        assert!(matches!(remap.bytes(binary), Cow::Borrowed(_)));
+        assert!(Remap::with_dirs(Vec::new())
+            .line("/workspace/y")
+            .eq("/workspace/y"));
+    }
+}
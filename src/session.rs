@@ -0,0 +1,200 @@
+use std::io::{self, PipeReader, PipeWriter, Read, Write};
+use std::process::{ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use crate::build::{backoff, RemoteBuild};
+use crate::error::Error;
+use crate::logging::Run;
+use crate::ssh::Ssh;
+use crate::util::shell_path;
+
+/// How often the connection to a build session is re-established before giving up, about half
+/// an hour with the backoff between the attempts.
+const RECONNECT_ATTEMPTS: u32 = 30;
+
+/// A cargo command running detached on the build server with `--resilient`. Its output goes to
+/// files next to the build directory and is streamed from there, so a lost ssh connection
+/// doesn't end the build: cargo-remote reconnects and continues streaming where it stopped.
+pub struct Session {
+    pub stdout: PipeReader,
+    pub stderr: Option<PipeReader>,
+    /// The thread streaming the output, it returns the exit status of the remote command.
+    stream: JoinHandle<Result<ExitStatus, Error>>,
+}
+
+/// Shell script that saves the build script from stdin to [`dir`] and starts it in the
+/// background, detached from the ssh session.
+fn start_script(dir: &str) -> String {
+    format!(
+        "mkdir -p {dir} && cat > {dir}/build.sh && cd {dir} && rm -f status out err && \
+         {{ nohup \"${{SHELL:-sh}}\" -c '\"${{SHELL:-sh}}\" build.sh; echo $? > status.tmp; \
+         mv status.tmp status' > out 2> err < /dev/null & echo $! > pid; }}",
+        dir = shell_path(dir)
+    )
+}
+
+/// Shell script that prints the output of the session in [`dir`] from the given byte offsets
+/// on until the build finished and exits with its exit code. Needs GNU `tail` for `--pid`.
+fn stream_script(dir: &str, stdout_offset: u64, stderr_offset: u64) -> String {
+    format!(
+        "cd {dir} && pid=$(cat pid) || exit 1; \
+         tail -c +{out} --pid=$pid -f out & tail -c +{err} --pid=$pid -f err >&2; wait; \
+         [ -f status ] && exit $(cat status); \
+         echo 'The build session ended without an exit code' >&2; exit 1",
+        dir = shell_path(dir),
+        out = stdout_offset + 1,
+        err = stderr_offset + 1
+    )
+}
+
+/// Copies [`from`] to [`to`] until the end or an error and returns the number of bytes copied.
+fn copy(mut from: impl Read, to: &mut impl Write) -> u64 {
+    let mut buffer = [0; 8192];
+    let mut copied = 0;
+    loop {
+        match from.read(&mut buffer) {
+            Ok(0) => return copied,
+            Ok(n) => {
+                if to.write_all(&buffer[..n]).is_err() {
+                    return copied;
+                }
+                copied += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return copied,
+        }
+    }
+}
+
+/// Everything the streaming thread needs, it outlives the borrow of the build.
+struct Stream {
+    ssh: Ssh,
+    dir: String,
+    forward_ports: Vec<String>,
+    offsets: [u64; 2],
+}
+
+impl Stream {
+    /// Streams the output from the current offsets on and returns the exit status of ssh. ssh
+    /// is quiet, messages of its own would count towards the stderr offset.
+    fn attempt(
+        &mut self,
+        stdout: &mut PipeWriter,
+        stderr: &mut PipeWriter,
+    ) -> io::Result<ExitStatus> {
+        let mut ssh = self.ssh.command();
+        ssh.arg("-q");
+        for forward in &self.forward_ports {
+            ssh.arg("-L").arg(forward);
+        }
+        let mut child = ssh
+            .arg(self.ssh.destination())
+            .arg(stream_script(&self.dir, self.offsets[0], self.offsets[1]))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .logged_spawn()?;
+        let child_stdout = child.stdout.take().expect("stdout is piped");
+        let child_stderr = child.stderr.take().expect("stderr is piped");
+        let [stdout_offset, stderr_offset] = &mut self.offsets;
+        thread::scope(|scope| {
+            scope.spawn(|| *stderr_offset += copy(child_stderr, stderr));
+            *stdout_offset += copy(child_stdout, stdout);
+        });
+        child.wait()
+    }
+
+    /// The exit code of the build if it finished, `None` if it is still running or the build
+    /// server can't be reached.
+    fn exit_code(&self) -> Option<i32> {
+        let output = self
+            .ssh
+            .command()
+            .arg(self.ssh.destination())
+            .arg(format!("cat {}", shell_path(&format!("{}/status", self.dir))))
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .logged_output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+    }
+
+    fn run(mut self, mut stdout: PipeWriter, mut stderr: PipeWriter) -> Result<ExitStatus, Error> {
+        let mut attempt = 0;
+        // ssh exits with 255 if the connection was lost, but so does a cargo that exited with
+        // 255. The session tells which one it was.
+        let mut finished = false;
+        loop {
+            let status = self
+                .attempt(&mut stdout, &mut stderr)
+                .map_err(|e| Error::command("stream the output of the build session", e, &[]))?;
+            if status.code() != Some(255) || finished || attempt >= RECONNECT_ATTEMPTS {
+                return Ok(status);
+            }
+            attempt += 1;
+            let delay = backoff(attempt);
+            warn!(
+                "Lost connection to the build server, the build goes on, reconnecting in {}s \
+                 ({} of {})",
+                delay, attempt, RECONNECT_ATTEMPTS
+            );
+            thread::sleep(Duration::from_secs(delay));
+            self.ssh.reconnect();
+            finished = self.exit_code() == Some(255);
+        }
+    }
+}
+
+impl Session {
+    /// Starts [`command`] in a session next to the build directory of [`build`] and streams
+    /// its output from there.
+    pub fn start(build: &RemoteBuild, command: &str) -> Result<Session, Error> {
+        let dir = format!("{}.session", build.build_path.trim_end_matches('/'));
+        let what = "start the build session";
+        debug!("Starting the build in a session in {}", dir);
+        let mut ssh = build
+            .ssh
+            .command()
+            .arg(build.ssh.destination())
+            .arg(start_script(&dir))
+            .stdin(Stdio::piped())
+            .logged_spawn()
+            .map_err(|e| Error::command(what, e, &[]))?;
+        if let Some(mut stdin) = ssh.stdin.take() {
+            stdin
+                .write_all(command.as_bytes())
+                .map_err(|e| Error::command(what, e, &[]))?;
+        }
+        let status = ssh.wait().map_err(|e| Error::command(what, e, &[]))?;
+        if !status.success() {
+            return Err(Error::command(what, status, &[]));
+        }
+
+        let pipe = || io::pipe().map_err(|e| Error::command(what, e, &[]));
+        let (stdout, stdout_writer) = pipe()?;
+        let (stderr, stderr_writer) = pipe()?;
+        let stream = Stream {
+            ssh: build.ssh.clone(),
+            dir,
+            forward_ports: build.forward_ports.clone(),
+            offsets: [0, 0],
+        };
+        let stream = thread::spawn(move || stream.run(stdout_writer, stderr_writer));
+        Ok(Session {
+            stdout,
+            stderr: Some(stderr),
+            stream,
+        })
+    }
+
+    /// Waits until the output was streamed completely and returns the exit status of the
+    /// remote command.
+    pub fn wait(self) -> Result<ExitStatus, Error> {
+        self.stream
+            .join()
+            .unwrap_or_else(|_| Err(Error::Run("The build session stream failed".to_string())))
+    }
+}
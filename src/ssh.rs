@@ -0,0 +1,257 @@
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use toml::Value;
+
+use crate::config::Server;
+use crate::error::Error;
+use crate::logging::Run;
+use crate::util::shell_arg;
+
+/// Seconds a connection master stays alive after the last session using it has ended. It is
+/// closed when the run is done with it, this only cleans up after runs that were aborted.
+const CONTROL_PERSIST_SECS: u32 = 60;
+
+/// The ssh options to connect to [`port`] using the key [`identity`], optionally through the
+/// jump host [`jump`]. Usable both for ssh itself and inside rsync's `-e` option.
+pub fn connection_options(
+    port: Option<u16>,
+    identity: Option<&str>,
+    jump: Option<&str>,
+) -> Vec<String> {
+    let mut options = Vec::new();
+    if let Some(port) = port {
+        options.push("-p".to_string());
+        options.push(port.to_string());
+    }
+    if let Some(identity) = identity {
+        options.push("-i".to_string());
+        options.push(identity.to_string());
+    }
+    if let Some(jump) = jump {
+        options.push("-J".to_string());
+        options.push(jump.to_string());
+    }
+    options
+}
+
+/// A non-interactive ssh call to the configured [`server`] with its own connection settings
+/// and [`ssh_options`], for quick questions before a run is set up. The remote command is
+/// added by the caller.
+pub fn server_command(server: &Server, ssh_options: &[String]) -> Command {
+    let mut ssh = Command::new("ssh");
+    ssh.arg("-oBatchMode=yes")
+        .arg("-oConnectTimeout=5")
+        .args(connection_options(
+            server
+                .settings
+                .get("ssh_port")
+                .and_then(Value::as_integer)
+                .map(|port| port as u16),
+            server.settings.get("ssh_identity").and_then(Value::as_str),
+            server.settings.get("ssh_jump").and_then(Value::as_str),
+        ))
+        .args(crate::config::string_list(&server.settings, "ssh_options"))
+        .args(ssh_options)
+        .arg(&server.destination);
+    ssh
+}
+
+/// Whether [`server`] accepts ssh connections right now.
+pub fn reachable(server: &Server, ssh_options: &[String]) -> bool {
+    server_command(server, ssh_options)
+        .arg("true")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .logged_status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Polls [`server`] until it accepts ssh connections, e.g. while it boots. Fails once that
+/// took longer than [`timeout`].
+pub fn wait_for(server: &Server, ssh_options: &[String], timeout: Duration) -> Result<(), Error> {
+    info!("Waiting for ssh on {}", server.destination);
+    let started = Instant::now();
+    while !reachable(server, ssh_options) {
+        if started.elapsed() > timeout {
+            return Err(Error::Run(format!(
+                "Build server '{}' didn't accept ssh connections within {}s",
+                server.name,
+                timeout.as_secs()
+            )));
+        }
+        std::thread::sleep(Duration::from_secs(5));
+    }
+    Ok(())
+}
+
+/// The ssh options that send a keepalive message every [`interval`] seconds and give up after
+/// [`count`] unanswered ones. A lost connection is noticed then, and long quiet phases like
+/// linking don't get the connection dropped by NAT routers. An [`interval`] of 0 turns them off.
+pub fn keepalive_options(interval: u32, count: u32) -> Vec<String> {
+    if interval == 0 {
+        return Vec::new();
+    }
+    vec![
+        format!("-oServerAliveInterval={}", interval),
+        format!("-oServerAliveCountMax={}", count.max(1)),
+    ]
+}
+
+/// Builds the ssh invocations for one build server, so that every ssh and rsync call of a run
+/// uses the same options and, if enabled, the same multiplexed connection.
+#[derive(Clone)]
+pub struct Ssh {
+    destination: String,
+    options: Vec<String>,
+    master: Option<Arc<Master>>,
+}
+
+/// A connection master opened by [`Ssh::start_master`]. Clones of an [`Ssh`] share it, it is
+/// closed when the last of them is done with it.
+struct Master {
+    destination: String,
+    control_path: PathBuf,
+}
+
+impl Master {
+    fn exit(&self) {
+        debug!("Closing ssh connection master");
+        let _ = Command::new("ssh")
+            .arg("-o")
+            .arg(format!(
+                "ControlPath={}",
+                self.control_path.to_string_lossy()
+            ))
+            .arg("-O")
+            .arg("exit")
+            .arg(&self.destination)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .logged_status();
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        self.exit();
+    }
+}
+
+impl Ssh {
+    pub fn new(destination: &str, options: Vec<String>) -> Ssh {
+        Ssh {
+            destination: destination.to_string(),
+            options,
+            master: None,
+        }
+    }
+
+    /// Opens a ControlMaster connection in the background that all following ssh and rsync
+    /// calls reuse instead of doing their own handshake. If the master can't be started every
+    /// call falls back to a connection of its own.
+    pub fn start_master(&mut self) {
+        // several masters to different servers can be open at the same time
+        let control_path = std::env::temp_dir().join(format!(
+            "cargo-remote-{}-{}.sock",
+            std::process::id(),
+            crate::util::short_hash(self.destination.as_bytes())
+        ));
+        debug!("Opening ssh connection master at {:?}", control_path);
+        let status = Command::new("ssh")
+            .args(&self.options)
+            .arg("-o")
+            .arg("ControlMaster=yes")
+            .arg("-o")
+            .arg(format!("ControlPath={}", control_path.to_string_lossy()))
+            .arg("-o")
+            .arg(format!("ControlPersist={}", CONTROL_PERSIST_SECS))
+            .arg("-f")
+            .arg("-N")
+            .arg(&self.destination)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .logged_status();
+        match status {
+            Ok(status) if status.success() => {
+                self.master = Some(Arc::new(Master {
+                    destination: self.destination.clone(),
+                    control_path,
+                }))
+            }
+            Ok(status) => warn!("Can't open ssh connection master (ssh exited with {})", status),
+            Err(e) => warn!("Can't open ssh connection master (error: {})", e),
+        }
+    }
+
+    /// Replaces the connection master, if there is one, with a new one after the connection was
+    /// lost.
+    pub fn reconnect(&mut self) {
+        if let Some(master) = self.master.take() {
+            // the clones still hold the master, but it is gone for them as well
+            master.exit();
+            // the socket of a master that died is left behind and would block the new one
+            let _ = std::fs::remove_file(&master.control_path);
+            self.start_master();
+        }
+    }
+
+    /// Closes the connection master opened by [`Ssh::start_master`], if any and no clone of
+    /// this [`Ssh`] still uses it.
+    pub fn stop_master(&mut self) {
+        self.master = None;
+    }
+
+    /// All options that are passed to ssh, including the ones to reuse the connection master.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = self.options.clone();
+        if let Some(master) = &self.master {
+            args.push("-o".to_string());
+            args.push(format!(
+                "ControlPath={}",
+                master.control_path.to_string_lossy()
+            ));
+        }
+        args
+    }
+
+    /// The options without the ones for the connection master, for connections that outlive
+    /// the run.
+    pub fn options(&self) -> &[String] {
+        &self.options
+    }
+
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    /// An ssh command with all options set. The caller adds its own flags, the
+    /// [`Ssh::destination`] and the remote command.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new("ssh");
+        command.args(self.args());
+        command
+    }
+
+    /// [`Ssh::args`] for sftp, which takes the port with `-P`.
+    pub fn sftp_args(&self) -> Vec<String> {
+        self.args()
+            .into_iter()
+            .map(|arg| if arg == "-p" { "-P".to_string() } else { arg })
+            .collect()
+    }
+
+    /// The value for rsync's `-e` option, so rsync connects the same way as [`Ssh::command`].
+    /// rsync and git split it like a shell would.
+    pub fn rsync_shell(&self) -> String {
+        std::iter::once("ssh".to_string())
+            .chain(self.args().iter().map(|arg| shell_arg(arg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
@@ -0,0 +1,77 @@
+use std::process::{Command, ExitStatus};
+
+use log::{info, warn};
+
+use crate::build::{exit_code, RemoteBuild};
+use crate::debuginfo::is_debug_info;
+use crate::error::Error;
+use crate::logging::Run;
+
+/// `cargo remote test-binaries`: what happens with the test executables once they are copied
+/// back.
+pub struct TestBinaries {
+    /// Run them on the local machine, see `--run-locally`.
+    pub run: bool,
+    /// The arguments for the test executables, after the second `--`.
+    pub args: Vec<String>,
+}
+
+/// `cargo remote test-binaries [-- <test options> [-- <test arguments>]]`: builds the tests on
+/// the build server with `cargo test --no-run` and copies back nothing but the test
+/// executables cargo reported, e.g. to run them on a machine with the hardware they need. With
+/// `--run-locally` they are run here, one after another like cargo does.
+pub fn test_binaries(mut build: RemoteBuild, run: bool) -> Result<i32, Error> {
+    let separator = build.options.iter().position(|o| o == "--");
+    let args = match separator {
+        Some(separator) => build.options.split_off(separator + 1),
+        None => Vec::new(),
+    };
+    if separator.is_some() {
+        build.options.pop();
+    }
+    build.command = "test".to_string();
+    build.options.push("--no-run".to_string());
+    build.copy_back = None;
+    build.copy_back_artifacts = true;
+    build.test_binaries = Some(TestBinaries { run, args });
+    build.run()
+}
+
+/// Lists the test executables that were copied back after the build ended with
+/// [`build_status`] and runs them if asked to. Returns the exit code of the build or the first
+/// test executable that failed.
+pub fn finish(build: &RemoteBuild, build_status: ExitStatus) -> Result<i32, Error> {
+    let tests = match &build.test_binaries {
+        Some(tests) if build_status.success() => tests,
+        _ => return Ok(exit_code(build_status)),
+    };
+    if build.artifacts.is_empty() {
+        warn!("cargo didn't build any test executables");
+        return Ok(0);
+    }
+    let binaries: Vec<_> = build
+        .artifacts
+        .iter()
+        .filter(|artifact| !is_debug_info(artifact))
+        .map(|artifact| build.local_target_dir.join(artifact))
+        .collect();
+    if !tests.run {
+        for binary in &binaries {
+            println!("{}", binary.display());
+        }
+        return Ok(0);
+    }
+    for binary in &binaries {
+        info!("Running {}", binary.display());
+        // like cargo, in the directory of the project
+        let status = Command::new(binary)
+            .args(&tests.args)
+            .current_dir(&build.project_dir)
+            .logged_status()
+            .map_err(|e| Error::command(&format!("run {}", binary.display()), e, &[]))?;
+        if !status.success() {
+            return Ok(exit_code(status));
+        }
+    }
+    Ok(0)
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+/// The phases of a run that `--timings` reports on.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Phase {
+    Sources,
+    Patches,
+    Vendor,
+    Build,
+    CopyBack,
+    Upload,
+    LocalBuild,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 7] = [
+        Phase::Sources,
+        Phase::Patches,
+        Phase::Vendor,
+        Phase::Build,
+        Phase::CopyBack,
+        Phase::Upload,
+        Phase::LocalBuild,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Sources => "source sync",
+            Phase::Patches => "patch sync",
+            Phase::Vendor => "vendor sync",
+            Phase::Build => "remote build",
+            Phase::CopyBack => "copy back",
+            Phase::Upload => "upload",
+            Phase::LocalBuild => "local build",
+        }
+    }
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Phase::Sources => "sources",
+            Phase::Patches => "patches",
+            Phase::Vendor => "vendor",
+            Phase::Build => "build",
+            Phase::CopyBack => "copy_back",
+            Phase::Upload => "upload",
+            Phase::LocalBuild => "local_build",
+        }
+    }
+}
+
+/// How `--timings` prints its report.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// How long the phases of a run took and how many bytes the transfers sent and received.
+/// Transfers run in parallel, so everything can be recorded through a shared reference.
+#[derive(Default)]
+pub struct Timings {
+    phases: Mutex<Vec<(Phase, Duration)>>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl Timings {
+    /// Runs [`f`] and adds the time it took to [`phase`].
+    pub fn record<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.add(phase, started.elapsed());
+        result
+    }
+
+    pub fn add(&self, phase: Phase, duration: Duration) {
+        self.phases.lock().unwrap().push((phase, duration));
+    }
+
+    pub fn add_bytes(&self, sent: u64, received: u64) {
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        self.bytes_received.fetch_add(received, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Time spent in [`phase`], `None` if it didn't happen.
+    pub fn duration(&self, phase: Phase) -> Option<Duration> {
+        let phases = self.phases.lock().unwrap();
+        let mut durations = phases.iter().filter(|(p, _)| *p == phase).map(|(_, d)| *d);
+        let first = durations.next()?;
+        Some(durations.fold(first, |total, d| total + d))
+    }
+
+    /// Milliseconds spent in every phase that happened, by [`Phase::key`].
+    pub fn to_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut phases = serde_json::Map::new();
+        for phase in &Phase::ALL {
+            if let Some(duration) = self.duration(*phase) {
+                phases.insert(phase.key().to_string(), json!(duration.as_millis() as u64));
+            }
+        }
+        phases
+    }
+
+    /// Prints the report to stderr, each line of the text report starts with [`prefix`].
+    pub fn print(&self, format: Format, prefix: &str) {
+        let sent = self.bytes_sent();
+        let received = self.bytes_received.load(Ordering::Relaxed);
+        if format == Format::Json {
+            eprintln!(
+                "{}",
+                json!({
+                    "event": "timings",
+                    "duration_ms": self.to_json(),
+                    "bytes_sent": sent,
+                    "bytes_received": received,
+                })
+            );
+            return;
+        }
+        eprintln!("{}Timings:", prefix);
+        let mut total = Duration::default();
+        for phase in &Phase::ALL {
+            if let Some(duration) = self.duration(*phase) {
+                total += duration;
+                eprintln!("{}  {:<14}{:>9.2?}", prefix, phase.name(), duration);
+            }
+        }
+        eprintln!("{}  {:<14}{:>9.2?}", prefix, "total", total);
+        if sent > 0 || received > 0 {
+            eprintln!(
+                "{}  {} sent, {} received",
+                prefix,
+                human_bytes(sent),
+                human_bytes(received)
+            );
+        }
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -0,0 +1,1022 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+
+use log::{debug, warn};
+
+use crate::build::{RemoteBuild, PROJECT_MARKER};
+use crate::logging::Run;
+use crate::util::{shell_arg, shell_path, shell_quote};
+
+const PROGRESS_FLAG: &str = "--info=progress2";
+
+/// rsync style filter patterns for transfers back from the build server. Excludes win over
+/// includes, as soon as there is an include pattern everything else is left out.
+#[derive(Default)]
+pub struct Filters {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// How files get to the build server and back.
+pub trait Transport: Send + Sync {
+    /// The program the transport needs on the build server, if it can be checked for.
+    fn remote_tool(&self) -> Option<&'static str>;
+
+    /// Syncs the sources in [`local_dir`], the project or a workspace it patches in, into
+    /// [`remote_dir`] on the build server, leaving out the target directory, hidden files
+    /// (unless enabled) and excluded files.
+    fn upload(&self, build: &RemoteBuild, local_dir: &Path, remote_dir: &str)
+        -> Result<(), String>;
+
+    /// Copies [`path`] below [`remote_dir`] on the build server to the same relative path in
+    /// [`local_dir`]. An empty path copies the whole directory. Missing files are skipped.
+    fn download(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        path: &str,
+        local_dir: &Path,
+        filters: &Filters,
+    ) -> Result<(), String>;
+
+    /// Copies the [`files`] below [`remote_dir`] to the same relative paths in [`local_dir`].
+    fn download_files(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        files: &[String],
+        local_dir: &Path,
+    ) -> Result<(), String>;
+
+    /// The agent if the transport is one, the build runs through it then.
+    fn agent(&self) -> Option<&crate::agent::Agent> {
+        None
+    }
+}
+
+/// The transport called [`name`] on the command line or in the config.
+pub fn from_name(
+    name: &str,
+    rsync_daemon: Option<String>,
+    agent_path: Option<String>,
+) -> Option<Box<dyn Transport>> {
+    if rsync_daemon.is_some() && name != "rsync" {
+        warn!("The rsync daemon is only used by the rsync transport");
+    }
+    if agent_path.is_some() && name != "agent" {
+        warn!("The agent path is only used by the agent transport");
+    }
+    match name {
+        "rsync" => Some(Box::new(Rsync {
+            daemon: rsync_daemon,
+            local: LocalRsync::detect(),
+        })),
+        "tar" => Some(Box::new(Tar)),
+        "sftp" => Some(Box::new(Sftp)),
+        "agent" => Some(Box::new(crate::agent::Agent::new(agent_path))),
+        _ => None,
+    }
+}
+
+/// Whether [`name`] in the directory [`dir`] of [`root`] (relative, empty for the top level) is
+/// left out of the transfer. Include and exclude patterns are only compared against whole file
+/// names here, which covers the common `dir/` and `file` patterns. The cargo configuration of
+/// [`root`] is transferred even if hidden files aren't.
+fn is_excluded(build: &RemoteBuild, root: &Path, dir: &Path, name: &str) -> bool {
+    let matches = |pattern: &String| pattern.trim_matches('/') == name;
+    if build.include.iter().any(matches) {
+        return false;
+    }
+    let cargo_config = crate::cargo_config::find(root).map(Path::new);
+    let is_cargo_config = |path: &Path| cargo_config.is_some_and(|c| c.starts_with(path));
+    let is_allowed = |path: &Path| {
+        build.hidden_allow.iter().any(|allowed| {
+            let allowed = allowed.trim_matches('/');
+            allowed == name || Path::new(allowed) == path
+        })
+    };
+    name == "target"
+        || (!build.hidden
+            && name.starts_with('.')
+            && !is_cargo_config(&dir.join(name))
+            && !is_allowed(&dir.join(name)))
+        || (!build.hidden && dir == Path::new(".cargo") && !is_cargo_config(&dir.join(name)))
+        || build.exclude.iter().any(matches)
+}
+
+fn collect_files(
+    build: &RemoteBuild,
+    root: &Path,
+    dir: &Path,
+    filtered: bool,
+    files: &mut Vec<(PathBuf, std::fs::Metadata)>,
+) {
+    let entries = match std::fs::read_dir(root.join(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        if filtered && is_excluded(build, root, dir, &name.to_string_lossy()) {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            collect_files(build, root, &dir.join(&name), filtered, files);
+        } else {
+            files.push((dir.join(&name), metadata));
+        }
+    }
+}
+
+/// The files in [`project_dir`] that git doesn't ignore, tracked or not. `None` outside of a git
+/// repository.
+fn unignored_files(project_dir: &Path) -> Option<HashSet<PathBuf>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(project_dir)
+        .args(["ls-files", "-z", "--cached", "--others", "--exclude-standard"])
+        .stderr(Stdio::null())
+        .query_output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(
+        output
+            .stdout
+            .split(|byte| *byte == 0)
+            .filter(|path| !path.is_empty())
+            .map(crate::util::path_from_bytes)
+            .collect(),
+    )
+}
+
+/// Whether the files in [`local_dir`] are left out as configured for the project. The vendored
+/// dependencies are transferred as they are, cargo checks them against their checksums, and so
+/// are the directories of `--transfer-extra`.
+fn filtered(build: &RemoteBuild, local_dir: &Path) -> bool {
+    (!build.vendor || local_dir != crate::vendor::local_dir(build))
+        && !build.transfer_extra.iter().any(|extra| extra.local == local_dir)
+}
+
+/// The names in the build directory that replacing the sources keeps: the target directory,
+/// the project marker, the excluded names and what `--transfer-extra` puts directly into it.
+/// Extras further down are removed and transferred again.
+pub fn kept_names(build: &RemoteBuild) -> Vec<String> {
+    let mut keep = vec!["target".to_string(), PROJECT_MARKER.to_string()];
+    keep.extend(
+        build
+            .exclude
+            .iter()
+            .map(|p| p.trim_matches('/').to_string()),
+    );
+    keep.extend(
+        build
+            .transfer_extra
+            .iter()
+            .filter(|extra| !extra.remote.contains('/'))
+            .map(|extra| extra.remote.clone()),
+    );
+    keep
+}
+
+/// Whether [`path`] in [`root`] is transferred to the build server, going by the names that are
+/// left out. Whether git ignores it only [`source_files`] knows.
+pub fn is_transferred(build: &RemoteBuild, root: &Path, path: &Path) -> bool {
+    if !filtered(build, root) {
+        return true;
+    }
+    let mut dir = PathBuf::new();
+    for component in path.components() {
+        if is_excluded(build, root, &dir, &component.as_os_str().to_string_lossy()) {
+            return false;
+        }
+        dir.push(component);
+    }
+    true
+}
+
+/// All files in [`root`] that are transferred to the build server, relative to [`root`].
+pub fn source_files(build: &RemoteBuild, root: &Path) -> Vec<(PathBuf, std::fs::Metadata)> {
+    let mut files = Vec::new();
+    let filtered = filtered(build, root);
+    collect_files(build, root, Path::new(""), filtered, &mut files);
+    if build.gitignore && filtered {
+        match unignored_files(root) {
+            Some(unignored) => files.retain(|(path, _)| unignored.contains(path)),
+            None => warn!(
+                "{} is not in a git repository, .gitignore files are not used",
+                root.to_string_lossy()
+            ),
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    files
+}
+
+/// Where the output of a transfer goes. The progress of parallel transfers would only garble
+/// each other, and with JSON messages stdout is reserved for cargo.
+fn progress_output(build: &RemoteBuild) -> Stdio {
+    match build.output_prefix {
+        Some(_) => Stdio::null(),
+        None if crate::logging::quiet() => Stdio::null(),
+        None if build.json_output() => Stdio::from(std::io::stderr()),
+        None => Stdio::inherit(),
+    }
+}
+
+/// Where the output of an upload of [`local_dir`] goes. Patched in workspaces are transferred in
+/// parallel, their progress is left out.
+fn upload_output(build: &RemoteBuild, local_dir: &Path) -> Stdio {
+    match local_dir == build.project_dir {
+        true => progress_output(build),
+        false => Stdio::null(),
+    }
+}
+
+/// Adds the totals of rsync's `--stats` in [`stdout`] to the build's timings.
+fn count_rsync_bytes(build: &RemoteBuild, stdout: &[u8]) {
+    let stdout = String::from_utf8_lossy(stdout);
+    let total = |label: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(label))
+            .and_then(|count| count.split_whitespace().next())
+            .and_then(|count| count.replace(',', "").parse().ok())
+            .unwrap_or(0)
+    };
+    build.timings.add_bytes(
+        total("Total bytes sent:"),
+        total("Total bytes received:"),
+    );
+}
+
+/// The result of an rsync run that transferred files back, with rsync's error output if it
+/// failed, which is captured for that. Missing files are left to `--ignore-missing-args`.
+fn rsync_finished(build: &RemoteBuild, output: std::io::Result<Output>) -> Result<(), String> {
+    let output = output.map_err(|e| spawn_error("run rsync", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.trim() {
+            "" => format!("rsync exited with {}", output.status),
+            stderr => format!("rsync exited with {}:\n{}", output.status, stderr),
+        });
+    }
+    count_rsync_bytes(build, &output.stdout);
+    Ok(())
+}
+
+pub fn spawn_error(what: &str, e: std::io::Error) -> String {
+    format!("Failed to {} (error: {})", what, e)
+}
+
+/// Which rsync runs on the local machine. Windows has none of its own, there it is the one of
+/// WSL or of Cygwin (cwRsync, MSYS2), which both want Unix style paths.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LocalRsync {
+    Native,
+    Wsl,
+    Cygwin,
+}
+
+impl LocalRsync {
+    /// A Cygwin rsync if one is installed on Windows, otherwise the one of WSL.
+    fn detect() -> LocalRsync {
+        if !cfg!(windows) {
+            return LocalRsync::Native;
+        }
+        let cygwin = Command::new("rsync")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok();
+        match cygwin {
+            true => LocalRsync::Cygwin,
+            false => LocalRsync::Wsl,
+        }
+    }
+
+    fn command(self) -> Command {
+        match self {
+            LocalRsync::Wsl => {
+                let mut wsl = Command::new("wsl");
+                wsl.arg("rsync");
+                wsl
+            }
+            _ => Command::new("rsync"),
+        }
+    }
+
+    /// [`path`] on the local machine the way this rsync expects it.
+    fn path(self, path: &Path) -> String {
+        match self {
+            LocalRsync::Native => path.to_string_lossy().into_owned(),
+            LocalRsync::Wsl => crate::util::unix_path(path, "/mnt"),
+            LocalRsync::Cygwin => crate::util::unix_path(path, "/cygdrive"),
+        }
+    }
+}
+
+/// Transfers with rsync, only changed files are sent.
+pub struct Rsync {
+    /// Url of an rsync daemon module (`rsync://server/module`) that serves the remote base
+    /// directory. Transfers go to the daemon instead of through ssh if set.
+    pub daemon: Option<String>,
+    pub local: LocalRsync,
+}
+
+impl Rsync {
+    /// rsync with the common options and the location of [`path`] on the build server: below
+    /// the daemon url if there is a daemon and [`path`] is in the remote base directory,
+    /// `server:path` over ssh otherwise. The flag tells whether the daemon is used.
+    fn command(&self, build: &RemoteBuild, path: &str) -> (Command, String, bool) {
+        let daemon_url = self.daemon.as_ref().and_then(|url| {
+            let rest = path.strip_prefix(build.remote_base_dir.trim_end_matches('/'))?;
+            Some(format!("{}{}", url.trim_end_matches('/'), rest))
+        });
+        let mut rsync = self.local.command();
+        rsync
+            .arg("-a")
+            .arg("-q")
+            .arg("--delete")
+            .arg("--compress")
+            .arg(PROGRESS_FLAG)
+            .stdout(progress_output(build))
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit());
+        if let Some(limit) = build.bwlimit {
+            rsync.arg(format!("--bwlimit={}", limit));
+        }
+        // the statistics replace the progress, they are parsed for the timings report
+        if build.show_timings.is_some() {
+            rsync.arg("--stats").stdout(Stdio::piped());
+        }
+        match daemon_url {
+            Some(url) => (rsync, url, true),
+            None => {
+                if self.daemon.is_some() {
+                    warn!("{} is outside of the rsync daemon module, using ssh", path);
+                }
+                rsync.arg("-e").arg(self.shell(build));
+                // The remote shell would split a path with spaces, with --protect-args rsync
+                // passes it on as is. It is relative to the home directory there, like over ssh.
+                let path = match shell_path(path) == path {
+                    true => path,
+                    false => {
+                        rsync.arg("--protect-args");
+                        path.strip_prefix("~/").unwrap_or(path)
+                    }
+                };
+                (rsync, format!("{}:{}", build.build_server, path), false)
+            }
+        }
+    }
+
+    /// Adds the filters that leave out the target directory, excluded files and (unless
+    /// enabled) hidden files of the sources in [`local_dir`].
+    fn source_filters(rsync_to: &mut Command, build: &RemoteBuild, local_dir: &Path) {
+        // rsync uses the first matching rule, includes have to come first
+        for pattern in &build.include {
+            rsync_to.arg("--include").arg(pattern);
+        }
+        rsync_to.arg("--exclude").arg("target");
+        if build.gitignore {
+            rsync_to.arg("--filter").arg(":- .gitignore");
+        }
+        if !build.hidden {
+            rsync_to.args(crate::cargo_config::rsync_filters(local_dir));
+            for allowed in &build.hidden_allow {
+                // a name matches at any depth, a path only relative to the project
+                let allowed = allowed.trim_matches('/');
+                let pattern = match allowed.contains('/') {
+                    true => format!("/{}", allowed),
+                    false => allowed.to_string(),
+                };
+                rsync_to.arg("--include").arg(pattern);
+            }
+            rsync_to.arg("--exclude").arg(".*");
+        }
+        for pattern in &build.exclude {
+            rsync_to.arg("--exclude").arg(pattern);
+        }
+    }
+
+    /// The value for rsync's `-e` option. Under WSL rsync runs the ssh of WSL, paths like the
+    /// one of the identity file are converted for it.
+    fn shell(&self, build: &RemoteBuild) -> String {
+        if self.local != LocalRsync::Wsl {
+            return build.ssh.rsync_shell();
+        }
+        std::iter::once("ssh".to_string())
+            .chain(build.ssh.args().into_iter().map(|arg| {
+                let path = Path::new(&arg);
+                match path.is_absolute() {
+                    true => shell_arg(&self.local.path(path)),
+                    false => shell_arg(&arg),
+                }
+            }))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Transport for Rsync {
+    fn remote_tool(&self) -> Option<&'static str> {
+        Some("rsync")
+    }
+
+    fn upload(
+        &self,
+        build: &RemoteBuild,
+        local_dir: &Path,
+        remote_dir: &str,
+    ) -> Result<(), String> {
+        let (mut rsync_to, destination, daemon) = self.command(build, remote_dir);
+        if filtered(build, local_dir) {
+            Rsync::source_filters(&mut rsync_to, build, local_dir);
+        }
+
+        rsync_to
+            .arg("--filter")
+            .arg(format!("P /{}", PROJECT_MARKER));
+        if local_dir == build.project_dir {
+            for extra in &build.transfer_extra {
+                rsync_to.arg("--filter").arg(format!("P /{}", extra.remote));
+            }
+        }
+        if !daemon {
+            // rsync only creates the last level of the destination
+            let parent = remote_dir
+                .trim_end_matches('/')
+                .rsplit_once('/')
+                .map_or(build.remote_base_dir.as_str(), |(parent, _)| parent);
+            rsync_to
+                .arg("--rsync-path")
+                .arg(format!("mkdir -p {} && rsync", shell_path(parent)));
+        }
+        if build.show_timings.is_none() {
+            rsync_to.stdout(upload_output(build, local_dir));
+        }
+        let transfer = rsync_to
+            .arg(format!("{}/", self.local.path(local_dir)))
+            .arg(destination)
+            .logged_output();
+        match transfer {
+            Ok(output) if output.status.success() => {
+                count_rsync_bytes(build, &output.stdout);
+                Ok(())
+            }
+            Ok(output) => Err(format!(
+                "Failed to transfer project to build server (rsync exited with {})",
+                output.status
+            )),
+            Err(e) => Err(spawn_error("transfer project to build server", e)),
+        }
+    }
+
+    fn download(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        path: &str,
+        local_dir: &Path,
+        filters: &Filters,
+    ) -> Result<(), String> {
+        let local = local_dir.join(path);
+        if let Some(parent) = local.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let (mut rsync, source, _) = self.command(
+            build,
+            &format!("{}/{}", remote_dir.trim_end_matches('/'), path),
+        );
+        rsync.arg("--ignore-missing-args");
+        for pattern in &filters.exclude {
+            rsync.arg("--exclude").arg(pattern);
+        }
+        if !filters.include.is_empty() {
+            for pattern in &filters.include {
+                rsync.arg("--include").arg(pattern);
+            }
+            // all directories are still searched for matching files
+            rsync
+                .arg("--include")
+                .arg("*/")
+                .arg("--exclude")
+                .arg("*")
+                .arg("--prune-empty-dirs");
+        }
+        rsync
+            .arg(source)
+            .arg(self.local.path(&local))
+            .stderr(Stdio::piped());
+        rsync_finished(build, rsync.logged_output())
+    }
+
+    fn download_files(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        files: &[String],
+        local_dir: &Path,
+    ) -> Result<(), String> {
+        let _ = std::fs::create_dir_all(local_dir);
+        let (mut rsync, source, _) =
+            self.command(build, &format!("{}/", remote_dir.trim_end_matches('/')));
+        // --files-from turns off the recursion of -a, the dSYM bundles of macOS are directories
+        let output = rsync
+            .arg("--recursive")
+            .arg("--files-from=-")
+            .arg(source)
+            .arg(format!("{}/", self.local.path(local_dir)))
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .logged_spawn()
+            .and_then(|mut child| {
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(files.join("\n").as_bytes())?;
+                }
+                child.wait_with_output()
+            });
+        rsync_finished(build, output)
+    }
+}
+
+/// Streams a tarball through ssh. Always sends everything, but needs nothing but tar on the
+/// build server and is often faster than rsync for a first sync.
+pub struct Tar;
+
+/// Waits for both ends of a `tar | ssh` or `ssh | tar` pipe.
+fn wait_pipe(what: &str, mut first: Child, mut second: Child) -> Result<(), String> {
+    let second = second.wait().map_err(|e| spawn_error(what, e))?;
+    let first = first.wait().map_err(|e| spawn_error(what, e))?;
+    match (first.success(), second.success()) {
+        (true, true) => Ok(()),
+        _ => Err(format!(
+            "Failed to {} (exited with {} and {})",
+            what, first, second
+        )),
+    }
+}
+
+impl Tar {
+    /// Runs [`remote_command`] over ssh with its stdout extracted into [`local_dir`]. [`input`]
+    /// is written to the stdin of the remote command.
+    fn fetch(
+        build: &RemoteBuild,
+        remote_command: String,
+        input: Option<Vec<u8>>,
+        local_dir: &Path,
+    ) -> Result<(), String> {
+        let what = "transfer files back from the build server";
+        let _ = std::fs::create_dir_all(local_dir);
+        let mut ssh = build
+            .ssh
+            .command()
+            .arg(build.ssh.destination())
+            .arg(remote_command)
+            .stdin(match input {
+                Some(_) => Stdio::piped(),
+                None => Stdio::null(),
+            })
+            .stdout(Stdio::piped())
+            .logged_spawn()
+            .map_err(|e| spawn_error(what, e))?;
+        let stdout = ssh
+            .stdout
+            .take()
+            .map(Stdio::from)
+            .unwrap_or_else(Stdio::null);
+        let tar = Command::new("tar")
+            .arg("xf")
+            .arg("-")
+            .arg("-C")
+            .arg(local_dir)
+            .stdin(stdout)
+            .logged_spawn()
+            .map_err(|e| spawn_error(what, e))?;
+        if let (Some(input), Some(mut stdin)) = (input, ssh.stdin.take()) {
+            stdin.write_all(&input).map_err(|e| spawn_error(what, e))?;
+        }
+        wait_pipe(what, ssh, tar)
+    }
+}
+
+impl Transport for Tar {
+    fn remote_tool(&self) -> Option<&'static str> {
+        Some("tar")
+    }
+
+    fn upload(
+        &self,
+        build: &RemoteBuild,
+        local_dir: &Path,
+        remote_dir: &str,
+    ) -> Result<(), String> {
+        let what = "transfer project to build server";
+        let mut list = Vec::new();
+        let mut size = 0;
+        for (path, metadata) in source_files(build, local_dir) {
+            list.extend_from_slice(&crate::util::path_bytes(&path));
+            list.push(0);
+            size += metadata.len();
+        }
+
+        // Like rsync's `--delete`: everything but the target directory, the project marker and
+        // what isn't transferred is removed before the new sources are unpacked.
+        let mut keep = kept_names(build);
+        if !build.hidden {
+            keep.push(".*".to_string());
+        }
+        let keep: String = keep
+            .iter()
+            .map(|name| format!(" ! -name {}", shell_quote(name)))
+            .collect();
+        let unpack = format!(
+            "mkdir -p {dir} && cd {dir} && find . -mindepth 1 -maxdepth 1{keep} -exec rm -rf {{}} + \
+             && tar xf -",
+            dir = shell_path(remote_dir),
+            keep = keep
+        );
+
+        let mut tar = Command::new("tar")
+            .arg("cf")
+            .arg("-")
+            .arg("--null")
+            .arg("-T")
+            .arg("-")
+            .current_dir(local_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .logged_spawn()
+            .map_err(|e| spawn_error(what, e))?;
+        let stdout = tar
+            .stdout
+            .take()
+            .map(Stdio::from)
+            .unwrap_or_else(Stdio::null);
+        let ssh = build
+            .ssh
+            .command()
+            .arg(build.ssh.destination())
+            .arg(unpack)
+            .stdin(stdout)
+            .stdout(upload_output(build, local_dir))
+            .logged_spawn()
+            .map_err(|e| spawn_error(what, e))?;
+        if let Some(mut stdin) = tar.stdin.take() {
+            stdin.write_all(&list).map_err(|e| spawn_error(what, e))?;
+        }
+        wait_pipe(what, tar, ssh)?;
+        // the size of the files, tar adds a header to each of them
+        build.timings.add_bytes(size, 0);
+        Ok(())
+    }
+
+    fn download(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        path: &str,
+        local_dir: &Path,
+        filters: &Filters,
+    ) -> Result<(), String> {
+        if !filters.include.is_empty() {
+            warn!("Copy-back include patterns are only supported by the rsync transport");
+        }
+        let path = match path.trim_end_matches('/') {
+            "" => ".",
+            path => path,
+        };
+        let excludes: String = filters
+            .exclude
+            .iter()
+            .map(|pattern| format!(" --exclude={}", shell_quote(pattern.trim_end_matches('/'))))
+            .collect();
+        // a missing path sends an empty archive, the local tar fails on no archive at all
+        let command = format!(
+            "cd {} && [ -e {path} ] || exec tar cf - -T /dev/null; tar cf -{} {path}",
+            shell_path(remote_dir),
+            excludes,
+            path = shell_quote(path)
+        );
+        Tar::fetch(build, command, None, local_dir)
+    }
+
+    fn download_files(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        files: &[String],
+        local_dir: &Path,
+    ) -> Result<(), String> {
+        let mut list = Vec::new();
+        for file in files {
+            list.extend_from_slice(file.as_bytes());
+            list.push(0);
+        }
+        let command = format!("cd {} && tar cf - --null -T -", shell_path(remote_dir));
+        Tar::fetch(build, command, Some(list), local_dir)
+    }
+}
+
+/// Transfers with sftp, for build servers that only allow sftp next to the shell. Files that
+/// were deleted locally are not removed on the build server and copy-back filters aren't
+/// supported.
+pub struct Sftp;
+
+/// Quotes [`path`] for an sftp batch file. A leading `~/` is dropped, sftp paths are relative
+/// to the home directory anyway.
+fn sftp_quote(path: &str) -> String {
+    let path = path.strip_prefix("~/").unwrap_or(path);
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Sftp {
+    fn run(build: &RemoteBuild, batch: String, what: &str) -> Result<(), String> {
+        debug!("sftp batch:\n{}", batch);
+        let mut sftp = Command::new("sftp");
+        sftp.arg("-q").arg("-b").arg("-");
+        // sftp takes the limit in Kbit/s
+        if let Some(limit) = build.bwlimit {
+            sftp.arg("-l").arg((u64::from(limit) * 8192 / 1000).to_string());
+        }
+        let mut sftp = sftp
+            .args(build.ssh.sftp_args())
+            .arg(build.ssh.destination())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .logged_spawn()
+            .map_err(|e| spawn_error(what, e))?;
+        if let Some(mut stdin) = sftp.stdin.take() {
+            stdin
+                .write_all(batch.as_bytes())
+                .map_err(|e| spawn_error(what, e))?;
+        }
+        let status = sftp.wait().map_err(|e| spawn_error(what, e))?;
+        if !status.success() {
+            return Err(format!("Failed to {} (sftp exited with {})", what, status));
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Sftp {
+    fn remote_tool(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn upload(
+        &self,
+        build: &RemoteBuild,
+        local_dir: &Path,
+        remote_dir: &str,
+    ) -> Result<(), String> {
+        let build_path = remote_dir.trim_end_matches('/');
+        let files = source_files(build, local_dir);
+        // the batch file is text, names that aren't valid UTF-8 can't be written into it
+        for (path, _) in &files {
+            crate::util::utf8_path(&local_dir.join(path))?;
+        }
+        // sftp has no `mkdir -p`, every level is created and errors (it exists) are ignored
+        let mut dirs: Vec<String> = Path::new(build_path)
+            .ancestors()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .filter(|dir| !["", "/", "~"].contains(&dir.as_str()))
+            .collect();
+        for (path, _) in &files {
+            for dir in path.ancestors().skip(1) {
+                if dir != Path::new("") {
+                    dirs.push(format!("{}/{}", build_path, crate::util::slash_path(dir)));
+                }
+            }
+        }
+        dirs.sort();
+        dirs.dedup();
+
+        let mut batch = String::new();
+        for dir in dirs {
+            batch.push_str(&format!("-mkdir {}\n", sftp_quote(&dir)));
+        }
+        for (path, _) in &files {
+            batch.push_str(&format!(
+                "put {} {}\n",
+                sftp_quote(&local_dir.join(path).to_string_lossy()),
+                sftp_quote(&format!("{}/{}", build_path, crate::util::slash_path(path)))
+            ));
+        }
+        Sftp::run(build, batch, "transfer project to build server")?;
+        let size = files.iter().map(|(_, metadata)| metadata.len()).sum();
+        build.timings.add_bytes(size, 0);
+        Ok(())
+    }
+
+    fn download(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        path: &str,
+        local_dir: &Path,
+        filters: &Filters,
+    ) -> Result<(), String> {
+        if !filters.include.is_empty() || !filters.exclude.is_empty() {
+            warn!("Copy-back filters are only supported by the rsync transport");
+        }
+        let remote_dir = remote_dir.trim_end_matches('/');
+        let path = path.trim_end_matches('/');
+        let (remote, local) = match Path::new(path).parent() {
+            _ if path.is_empty() => (format!("{}/*", remote_dir), local_dir.to_path_buf()),
+            // into the parent, sftp puts directories below an existing local directory
+            Some(parent) => (format!("{}/{}", remote_dir, path), local_dir.join(parent)),
+            None => (format!("{}/{}", remote_dir, path), local_dir.to_path_buf()),
+        };
+        let _ = std::fs::create_dir_all(&local);
+        let batch = format!(
+            "-get -r {} {}\n",
+            sftp_quote(&remote),
+            sftp_quote(&local.to_string_lossy())
+        );
+        Sftp::run(build, batch, "transfer files back from the build server")
+    }
+
+    fn download_files(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        files: &[String],
+        local_dir: &Path,
+    ) -> Result<(), String> {
+        let mut batch = String::new();
+        for file in files {
+            let local = local_dir.join(file);
+            if let Some(parent) = local.parent() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+            batch.push_str(&format!(
+                "-get -R {} {}\n",
+                sftp_quote(&format!("{}/{}", remote_dir.trim_end_matches('/'), file)),
+                sftp_quote(&local.to_string_lossy())
+            ));
+        }
+        Sftp::run(build, batch, "transfer files back from the build server")
+    }
+}
+
+/// Uploads with git instead of copying files: the current `HEAD` plus uncommitted changes to
+/// tracked files is pushed into a bare repository in the build directory and checked out
+/// there. Only the changes since the last build are sent. Untracked files are not transferred.
+/// Results are copied back with [`Git::downloads`].
+pub struct Git {
+    pub downloads: Box<dyn Transport>,
+}
+
+/// Bare repository in the build directory that the sources are pushed to.
+const GIT_MIRROR: &str = ".cargo-remote.git";
+/// Branch of [`GIT_MIRROR`] that holds the last pushed state.
+const GIT_BRANCH: &str = "refs/heads/cargo-remote";
+
+impl Git {
+    fn git(build: &RemoteBuild) -> Command {
+        let mut git = Command::new("git");
+        git.arg("-C").arg(&build.project_dir);
+        git
+    }
+
+    /// The commit to push: a stash commit including the uncommitted changes to tracked files,
+    /// or `HEAD` if there are none.
+    fn snapshot(build: &RemoteBuild) -> Result<String, String> {
+        let run = |args: &[&str]| -> Result<String, String> {
+            let output = Git::git(build)
+                .args(args)
+                .stderr(Stdio::inherit())
+                .query_output()
+                .map_err(|e| spawn_error("run git", e))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "git {} failed in {}, --sync git needs a git repository with at least one \
+                     commit",
+                    args.join(" "),
+                    build.project_dir.to_string_lossy()
+                ));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+        match run(&["stash", "create"])? {
+            stash if stash.is_empty() => run(&["rev-parse", "HEAD"]),
+            stash => Ok(stash),
+        }
+    }
+
+    fn ssh(build: &RemoteBuild, command: String, what: &str) -> Result<(), String> {
+        let status = build
+            .ssh
+            .command()
+            .arg(build.ssh.destination())
+            .arg(command)
+            .stdin(Stdio::null())
+            .logged_status()
+            .map_err(|e| spawn_error(what, e))?;
+        if !status.success() {
+            return Err(format!("Failed to {} (ssh exited with {})", what, status));
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Git {
+    fn remote_tool(&self) -> Option<&'static str> {
+        Some("git")
+    }
+
+    fn upload(
+        &self,
+        build: &RemoteBuild,
+        local_dir: &Path,
+        remote_dir: &str,
+    ) -> Result<(), String> {
+        // patched in workspaces are often not part of the repository, they are copied
+        if local_dir != build.project_dir {
+            return self.downloads.upload(build, local_dir, remote_dir);
+        }
+        let commit = Git::snapshot(build)?;
+        debug!("Pushing {} to the build server", commit);
+        let mirror = format!("{}{}", build.build_path, GIT_MIRROR);
+        Git::ssh(
+            build,
+            format!(
+                "mkdir -p {} && git init -q --bare {}",
+                shell_path(&build.build_path),
+                shell_path(&mirror)
+            ),
+            "create the git repository on the build server",
+        )?;
+
+        // scp like git urls are relative to the home directory
+        let url = format!(
+            "{}:{}",
+            build.build_server,
+            mirror.strip_prefix("~/").unwrap_or(&mirror)
+        );
+        let status = Git::git(build)
+            .env("GIT_SSH_COMMAND", build.ssh.rsync_shell())
+            .arg("push")
+            .arg("-q")
+            .arg("-f")
+            .arg(&url)
+            .arg(format!("{}:{}", commit, GIT_BRANCH))
+            .stdout(progress_output(build))
+            .logged_status()
+            .map_err(|e| spawn_error("push the sources to the build server", e))?;
+        if !status.success() {
+            return Err(format!(
+                "Failed to push the sources to the build server (git exited with {})",
+                status
+            ));
+        }
+
+        // read-tree also removes files that were deleted since the last push
+        Git::ssh(
+            build,
+            format!(
+                "cd {} && git --git-dir={} --work-tree=. read-tree -u --reset {}",
+                shell_path(&build.build_path),
+                GIT_MIRROR,
+                commit
+            ),
+            "check out the sources on the build server",
+        )
+    }
+
+    fn download(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        path: &str,
+        local_dir: &Path,
+        filters: &Filters,
+    ) -> Result<(), String> {
+        self.downloads
+            .download(build, remote_dir, path, local_dir, filters)
+    }
+
+    fn download_files(
+        &self,
+        build: &RemoteBuild,
+        remote_dir: &str,
+        files: &[String],
+        local_dir: &Path,
+    ) -> Result<(), String> {
+        self.downloads
+            .download_files(build, remote_dir, files, local_dir)
+    }
+
+    fn agent(&self) -> Option<&crate::agent::Agent> {
+        self.downloads.agent()
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::Write;
+use std::process::{ExitStatus, Stdio};
+
+use log::{debug, info};
+use toml::Value;
+
+use crate::build::RemoteBuild;
+use crate::config::{Configs, Server};
+use crate::error::Error;
+use crate::logging::Run;
+use crate::platform::RemoteOs;
+use crate::util::{shell_path, shell_quote};
+
+/// The S3 compatible endpoint of Google Cloud Storage, `gs://` URLs are uploaded through it.
+const GCS_ENDPOINT: &str = "https://storage.googleapis.com";
+
+/// The access key of the bucket, passed to the build server on stdin so it doesn't show up in
+/// process lists or the `-v` output.
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+/// Artifacts that are uploaded from the build server to object storage after a successful
+/// build, see `--upload`.
+pub struct Upload {
+    /// Paths or glob patterns relative to the remote target directory.
+    pub artifacts: Vec<String>,
+    /// `s3://bucket/prefix` or `gs://bucket/prefix`.
+    pub url: String,
+    /// The endpoint of an S3 compatible storage like MinIO.
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    /// The server's own AWS configuration is used without them, e.g. an instance role.
+    pub credentials: Option<Credentials>,
+}
+
+/// The credentials of the `[upload]` table of the build [`server`] or the first config file
+/// that has them, otherwise the local `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+pub fn credentials(server: Option<&Server>, configs: &Configs) -> Option<Credentials> {
+    let configured = server
+        .map(|server| &server.settings)
+        .into_iter()
+        .chain(&configs.layers)
+        .filter_map(|config| config.get("upload"))
+        .find_map(|upload| {
+            let string = |key: &str| upload.get(key).and_then(Value::as_str).map(String::from);
+            Some(Credentials {
+                access_key_id: string("access_key_id")?,
+                secret_access_key: string("secret_access_key")?,
+                session_token: string("session_token"),
+            })
+        });
+    configured.or_else(|| {
+        Some(Credentials {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    })
+}
+
+/// Checks that [`url`] is an `s3://` or `gs://` URL with a bucket.
+pub fn parse_url(url: &str) -> Result<String, String> {
+    let bucket = url
+        .strip_prefix("s3://")
+        .or_else(|| url.strip_prefix("gs://"))
+        .ok_or_else(|| {
+            format!(
+                "invalid upload URL '{}', use s3://bucket or gs://bucket",
+                url
+            )
+        })?;
+    match bucket.trim_matches('/').is_empty() {
+        true => Err(format!("the upload URL '{}' has no bucket", url)),
+        false => Ok(url.trim_end_matches('/').to_string()),
+    }
+}
+
+/// [`pattern`] as one word for a POSIX shell that is still expanded as a glob.
+fn shell_glob(pattern: &str) -> String {
+    pattern
+        .chars()
+        .map(|c| match c.is_alphanumeric() || "*?[]/._-+".contains(c) {
+            true => c.to_string(),
+            false => format!("\\{}", c),
+        })
+        .collect()
+}
+
+impl Upload {
+    /// The shell script that uploads the artifacts with the AWS CLI from [`target_dir`], in the
+    /// environment of the build. Each one ends up below the URL by its file name, directories
+    /// with their content.
+    fn script(&self, env: &str, target_dir: &str) -> String {
+        let (url, endpoint) = match self.url.strip_prefix("gs://") {
+            Some(bucket) => (format!("s3://{}", bucket), Some(GCS_ENDPOINT)),
+            None => (self.url.clone(), self.endpoint.as_deref()),
+        };
+        let mut script = format!(
+            "{{ source {}; cd {} || exit 1; }} >&2; ",
+            shell_path(env),
+            shell_path(target_dir)
+        );
+        if self.credentials.is_some() {
+            script.push_str(
+                "IFS= read -r AWS_ACCESS_KEY_ID; IFS= read -r AWS_SECRET_ACCESS_KEY; \
+                 IFS= read -r AWS_SESSION_TOKEN; export AWS_ACCESS_KEY_ID AWS_SECRET_ACCESS_KEY; \
+                 [ -n \"$AWS_SESSION_TOKEN\" ] && export AWS_SESSION_TOKEN || unset AWS_SESSION_TOKEN; ",
+            );
+        }
+        // the AWS CLI needs a region, Cloud Storage and MinIO don't care which one
+        let region = match (&self.region, endpoint) {
+            (Some(region), _) => Some(region.as_str()),
+            (None, Some(_)) => Some("us-east-1"),
+            (None, None) => None,
+        };
+        if let Some(region) = region {
+            script.push_str(&format!(
+                "export AWS_DEFAULT_REGION={}; ",
+                shell_quote(region)
+            ));
+        }
+        let endpoint = endpoint
+            .map(|endpoint| format!(" --endpoint-url {}", shell_quote(endpoint)))
+            .unwrap_or_default();
+        let artifacts: Vec<String> = self.artifacts.iter().map(|a| shell_glob(a)).collect();
+        script.push_str(&format!(
+            "for artifact in {artifacts}; do \
+             [ -e \"$artifact\" ] || {{ echo \"error: $artifact doesn't exist on the build server\" \
+             >&2; exit 1; }}; \
+             if [ -d \"$artifact\" ]; then set -- --recursive; else set --; fi; \
+             aws s3 cp --no-progress{endpoint} \"$@\" \"$artifact\" {url}/\"${{artifact##*/}}\" \
+             || exit 1; done",
+            artifacts = artifacts.join(" "),
+            endpoint = endpoint,
+            url = shell_quote(&url),
+        ));
+        script
+    }
+}
+
+/// Uploads the artifacts of `--upload` from the build server after the build ended with
+/// [`build_status`]. Nothing is uploaded after a failed build.
+pub fn upload(build: &RemoteBuild, build_status: ExitStatus) -> Result<(), Error> {
+    let upload = match &build.upload {
+        Some(upload) if build_status.success() => upload,
+        _ => return Ok(()),
+    };
+    if build.remote_os == RemoteOs::Windows {
+        return Err(Error::Config(
+            "Uploading artifacts isn't supported on Windows build servers".to_string(),
+        ));
+    }
+    let what = "upload the artifacts from the build server";
+    debug!(
+        "Uploading {} to {}",
+        upload.artifacts.join(", "),
+        upload.url
+    );
+    let mut ssh = build
+        .ssh
+        .command()
+        .arg(build.ssh.destination())
+        .arg(upload.script(&build.env, &build.remote_target()))
+        .stdin(Stdio::piped())
+        .logged_spawn()
+        .map_err(|e| Error::command(what, e, &[]))?;
+    if let (Some(credentials), Some(mut stdin)) = (&upload.credentials, ssh.stdin.take()) {
+        let input = format!(
+            "{}\n{}\n{}\n",
+            credentials.access_key_id,
+            credentials.secret_access_key,
+            credentials.session_token.as_deref().unwrap_or_default()
+        );
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|e| Error::command(what, e, &[]))?;
+    }
+    let status = ssh.wait().map_err(|e| Error::command(what, e, &[]))?;
+    match status.success() {
+        true => {
+            info!("Uploaded the artifacts to {}", upload.url);
+            Ok(())
+        }
+        false => Err(Error::command(what, status, &[])),
+    }
+}
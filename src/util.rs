@@ -0,0 +1,238 @@
+/// 64 bit FNV-1a hash of [`bytes`]. Unlike the std hashers its output is stable across Rust
+/// versions and platforms, so it can be used for names that have to be the same on every run.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A short hex digest of [`bytes`] for use in file and directory names.
+pub fn short_hash(bytes: &[u8]) -> String {
+    format!("{:016x}", fnv1a(bytes))[..8].to_string()
+}
+
+/// Quotes [`value`] for a POSIX shell, so it is passed on as one word without any expansion.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// [`value`] as one word for a POSIX shell, quoted with [`shell_quote`] only if it has to be.
+/// Keeps the commands in the `-v` output readable.
+pub fn shell_arg(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._-+,:@%=".contains(c);
+    match !value.is_empty() && value.chars().all(plain) {
+        true => value.to_string(),
+        false => shell_quote(value),
+    }
+}
+
+/// [`path`] on the build server as one word for a POSIX shell. A leading `~/` stays unquoted,
+/// so the shell still expands it to the home directory.
+pub fn shell_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some("") => "~/".to_string(),
+        Some(rest) => format!("~/{}", shell_arg(rest)),
+        None if path == "~" => path.to_string(),
+        None => shell_arg(path),
+    }
+}
+
+/// Width of the controlling terminal in columns, from `COLUMNS` or `stty`.
+pub fn terminal_width() -> Option<u16> {
+    let columns = std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok());
+    if let Some(columns) = columns.filter(|columns| *columns > 0) {
+        return Some(columns);
+    }
+    let tty = std::fs::File::open("/dev/tty").ok()?;
+    let output = std::process::Command::new("stty")
+        .arg("size")
+        .stdin(tty)
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    // `<rows> <columns>`
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+        .filter(|columns| *columns > 0)
+}
+
+/// [`secs`] since the epoch as UTC date and time, `2024-05-01 13:37:00`.
+pub fn format_utc(secs: u64) -> String {
+    // civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = match shifted_month < 10 {
+        true => shifted_month + 3,
+        false => shifted_month - 9,
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// [`path`] with forward slashes, the way it is used in paths on the build server. Only
+/// Windows paths are changed, elsewhere a backslash is a valid character in file names.
+pub fn slash_path(path: &std::path::Path) -> String {
+    let path = path.to_string_lossy();
+    match cfg!(windows) {
+        true => path.replace('\\', "/"),
+        false => path.into_owned(),
+    }
+}
+
+/// The bytes of [`path`] for file lists passed to other programs, like [`slash_path`] but
+/// without losing file names that aren't valid UTF-8 on Unix.
+pub fn path_bytes(path: &std::path::Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        slash_path(path).into_bytes()
+    }
+}
+
+/// The path in the file list [`bytes`] written by another program, the reverse of
+/// [`path_bytes`].
+pub fn path_from_bytes(bytes: &[u8]) -> std::path::PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(bytes).into()
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(bytes).into_owned().into()
+    }
+}
+
+/// [`path`] as a string, or an error naming it if it isn't valid UTF-8 and so can't be put
+/// into a command line or batch file for the build server.
+pub fn utf8_path(path: &std::path::Path) -> Result<&str, String> {
+    path.to_str().ok_or_else(|| {
+        format!(
+            "The path {} isn't valid UTF-8, it can't be used on the build server",
+            path.to_string_lossy()
+        )
+    })
+}
+
+/// A Windows path like `C:\Users\me` the way a Unix program running on Windows sees it, e.g.
+/// `/mnt/c/Users/me` for WSL with [`drive_prefix`] `/mnt` or `/cygdrive/c/Users/me` for
+/// Cygwin. Paths without a drive letter only get forward slashes.
+pub fn unix_path(path: &std::path::Path, drive_prefix: &str) -> String {
+    let path = slash_path(path);
+    // canonicalized paths start with `\\?\`
+    let path = path.strip_prefix("//?/").unwrap_or(&path);
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some(drive), Some(':')) if drive.is_ascii_alphabetic() => format!(
+            "{}/{}{}",
+            drive_prefix,
+            drive.to_ascii_lowercase(),
+            chars.as_str()
+        ),
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a POSIX shell makes of [`word`], run from the home directory `/home/me`.
+    #[cfg(unix)]
+    fn expanded(word: &str) -> String {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", word))
+            .env("HOME", "/home/me")
+            .env("USER", "me")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn shell_quote_quotes_everything() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("plain"), "'plain'");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("~/x"), "'~/x'");
+        assert_eq!(shell_quote("C:\\Users\\me"), "'C:\\Users\\me'");
+    }
+
+    #[test]
+    fn shell_arg_quotes_only_if_needed() {
+        assert_eq!(shell_arg(""), "''");
+        assert_eq!(shell_arg("--features=a,b"), "--features=a,b");
+        assert_eq!(shell_arg("target/debug/my-bin"), "target/debug/my-bin");
+        assert_eq!(shell_arg("a b"), "'a b'");
+        assert_eq!(shell_arg("it's"), "'it'\\''s'");
+        assert_eq!(shell_arg("$(rm -rf /)"), "'$(rm -rf /)'");
+        assert_eq!(shell_arg("a;b"), "'a;b'");
+        assert_eq!(shell_arg("~/x"), "'~/x'");
+        assert_eq!(shell_arg("C:\\Users\\me"), "'C:\\Users\\me'");
+    }
+
+    #[test]
+    fn shell_path_keeps_the_home_directory() {
+        assert_eq!(shell_path(""), "''");
+        assert_eq!(shell_path("~"), "~");
+        assert_eq!(shell_path("~/"), "~/");
+        assert_eq!(shell_path("~/remote-builds/"), "~/remote-builds/");
+        assert_eq!(shell_path("~/my builds/"), "~/'my builds/'");
+        assert_eq!(shell_path("~/it's/$USER"), "~/'it'\\''s/$USER'");
+        assert_eq!(shell_path("~user/x"), "'~user/x'");
+        assert_eq!(shell_path("/tmp/a b"), "'/tmp/a b'");
+        assert_eq!(shell_path("C:\\builds\\x"), "'C:\\builds\\x'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quoted_words_survive_the_shell() {
+        let words = [
+            "",
+            "a b",
+            "it's",
+            "$HOME `id` $(id)",
+            "a;b|c&d>e",
+            "*?[x]",
+            "\"double\"",
+            "C:\\Users\\me",
+            "line\nbreak",
+        ];
+        for word in words.iter() {
+            assert_eq!(expanded(&shell_quote(word)), *word);
+            assert_eq!(expanded(&shell_arg(word)), *word);
+        }
+        assert_eq!(
+            expanded(&shell_path("~/my builds/$x")),
+            "/home/me/my builds/$x"
+        );
+        assert_eq!(expanded(&shell_path("~")), "/home/me");
+        assert_eq!(expanded(&shell_path("/tmp/it's")), "/tmp/it's");
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::info;
+use toml::Value;
+
+use crate::build::RemoteBuild;
+use crate::logging::Run;
+use crate::util::short_hash;
+
+/// Directory below the local target directory the dependencies are vendored into.
+const VENDOR_DIR: &str = "cargo-remote-vendor";
+
+/// The local directory `cargo vendor` fills for [`build`], transferred to [`remote_dir`].
+pub fn local_dir(build: &RemoteBuild) -> PathBuf {
+    build.local_target_dir.join(VENDOR_DIR)
+}
+
+/// Directory on the build server next to the build directory that holds the vendored crates.
+pub fn remote_dir(build: &RemoteBuild) -> String {
+    format!("{}.vendor/", build.build_path.trim_end_matches('/'))
+}
+
+/// The source configuration `cargo vendor` printed, next to the vendor directory. Its first
+/// line records the Cargo.lock it was vendored for.
+fn config_file(build: &RemoteBuild) -> PathBuf {
+    build.local_target_dir.join(format!("{}.toml", VENDOR_DIR))
+}
+
+fn lock_line(project_dir: &Path) -> Option<String> {
+    let lock = std::fs::read(project_dir.join("Cargo.lock")).ok()?;
+    Some(format!("# Cargo.lock {}\n", short_hash(&lock)))
+}
+
+/// Vendors the dependencies of [`build`]'s project into [`local_dir`] unless that was already
+/// done for the current Cargo.lock, and returns the source configuration.
+pub fn vendor(build: &RemoteBuild) -> Result<String, String> {
+    let config_file = config_file(build);
+    if let (Some(lock), Ok(config)) = (
+        lock_line(&build.project_dir),
+        std::fs::read_to_string(&config_file),
+    ) {
+        if config.starts_with(&lock) && local_dir(build).is_dir() {
+            return Ok(config);
+        }
+    }
+    info!("Vendoring the dependencies of {}", build.project_name);
+    // without any dependencies cargo vendor doesn't create it
+    std::fs::create_dir_all(local_dir(build))
+        .map_err(|e| format!("Failed to create the vendor directory: {}", e))?;
+    let output = Command::new("cargo")
+        .arg("vendor")
+        .arg("--manifest-path")
+        .arg(build.project_dir.join("Cargo.toml"))
+        .arg(local_dir(build))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .logged_output()
+        .map_err(|e| format!("Failed to run cargo vendor: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("cargo vendor failed ({})", output.status));
+    }
+    // cargo vendor writes a Cargo.lock if there was none
+    let config = format!(
+        "{}{}",
+        lock_line(&build.project_dir).unwrap_or_default(),
+        String::from_utf8_lossy(&output.stdout)
+    );
+    std::fs::write(&config_file, &config)
+        .map_err(|e| format!("Failed to write {}: {}", config_file.to_string_lossy(), e))?;
+    Ok(config)
+}
+
+/// A key of the source configuration, quoted if it isn't a bare TOML key like `crates-io`.
+fn toml_key(key: &str) -> String {
+    let bare = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    match !key.is_empty() && key.chars().all(bare) {
+        true => key.to_string(),
+        false => Value::String(key.to_string()).to_string(),
+    }
+}
+
+/// The `--config` arguments for the remote cargo that replace crates.io and the git
+/// dependencies with the vendored crates. The project's own `.cargo/config.toml` stays as it
+/// is. Paths given with `--config` are relative to the working directory, the build directory.
+pub fn cargo_args(build: &RemoteBuild, config: &str) -> Result<Vec<String>, String> {
+    let config: Value = config
+        .parse()
+        .map_err(|e| format!("Invalid output of cargo vendor: {}", e))?;
+    let sources = config.get("source").and_then(Value::as_table);
+    let vendored = remote_dir(build);
+    let vendored = vendored
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    let mut args: Vec<String> = match build.cargo_flags.iter().any(|flag| flag == "--offline") {
+        true => Vec::new(),
+        false => vec!["--offline".to_string()],
+    };
+    for (name, source) in sources.into_iter().flatten() {
+        for (key, value) in source.as_table().into_iter().flatten() {
+            let value = match key.as_str() {
+                "directory" => Value::String(format!("../{}", vendored)),
+                _ => value.clone(),
+            };
+            args.push("--config".to_string());
+            args.push(format!(
+                "source.{}.{}={}",
+                toml_key(name),
+                toml_key(key),
+                value
+            ));
+        }
+    }
+    Ok(args)
+}
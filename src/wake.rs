@@ -0,0 +1,103 @@
+use std::convert::TryInto;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use log::info;
+use toml::Value;
+
+use crate::config::Server;
+use crate::error::Error;
+
+/// How long to wait for a woken up build server if its entry has no `timeout`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The `[servers.<name>.wake_on_lan]` table of a build server that sleeps when idle.
+struct WakeOnLan {
+    mac: [u8; 6],
+    /// Address the magic packet is sent to, the broadcast address of the server's network.
+    broadcast: String,
+    port: u16,
+    timeout: Duration,
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let bytes: Vec<u8> = mac
+        .split([':', '-'])
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<_>>()?;
+    bytes.try_into().ok()
+}
+
+impl WakeOnLan {
+    fn from_server(server: &Server) -> Result<Option<WakeOnLan>, Error> {
+        let table = match server.settings.get("wake_on_lan") {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let mac = table.get("mac").and_then(Value::as_str).unwrap_or_default();
+        let mac = parse_mac(mac).ok_or_else(|| {
+            Error::Config(format!(
+                "Invalid MAC address '{}' in the wake_on_lan settings of build server '{}'",
+                mac, server.name
+            ))
+        })?;
+        Ok(Some(WakeOnLan {
+            mac,
+            broadcast: table
+                .get("broadcast")
+                .and_then(Value::as_str)
+                .unwrap_or("255.255.255.255")
+                .to_string(),
+            port: table
+                .get("port")
+                .and_then(Value::as_integer)
+                .map_or(9, |port| port as u16),
+            timeout: table
+                .get("timeout")
+                .and_then(Value::as_integer)
+                .map_or(DEFAULT_TIMEOUT, |secs| Duration::from_secs(secs as u64)),
+        }))
+    }
+
+    /// The magic packet: six `0xff` bytes followed by the MAC address 16 times.
+    fn packet(&self) -> Vec<u8> {
+        let mut packet = vec![0xff; 6];
+        for _ in 0..16 {
+            packet.extend_from_slice(&self.mac);
+        }
+        packet
+    }
+
+    fn send(&self) -> std::io::Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_broadcast(true)?;
+        // UDP may drop a packet, a few more don't hurt
+        for _ in 0..3 {
+            socket.send_to(&self.packet(), (self.broadcast.as_str(), self.port))?;
+        }
+        Ok(())
+    }
+}
+
+/// Wakes [`server`] with a Wake-on-LAN packet if it has `wake_on_lan` settings and doesn't
+/// accept ssh connections, then waits until it does.
+pub fn wake(server: &Server, ssh_options: &[String]) -> Result<(), Error> {
+    let wake_on_lan = match WakeOnLan::from_server(server)? {
+        Some(wake_on_lan) => wake_on_lan,
+        None => return Ok(()),
+    };
+    if crate::ssh::reachable(server, ssh_options) {
+        return Ok(());
+    }
+    info!(
+        "Waking up build server '{}' via {}",
+        server.name, wake_on_lan.broadcast
+    );
+    wake_on_lan.send().map_err(|e| {
+        Error::Run(format!(
+            "Failed to send the Wake-on-LAN packet to {} (error: {})",
+            wake_on_lan.broadcast, e
+        ))
+    })?;
+    crate::ssh::wait_for(server, ssh_options, wake_on_lan.timeout)
+}
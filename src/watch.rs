@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::build::RemoteBuild;
+
+/// How long the sources have to stay unchanged before a build starts, and how often the project
+/// directory is scanned for changes when it can't be watched.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Modification time and size of every file that would be transferred to the build server.
+#[derive(PartialEq, Eq)]
+struct Snapshot(Vec<(PathBuf, SystemTime, u64)>);
+
+impl Snapshot {
+    fn take(build: &RemoteBuild) -> Snapshot {
+        Snapshot(
+            crate::transport::source_files(build, &build.project_dir)
+                .into_iter()
+                .map(|(path, metadata)| {
+                    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    (path, modified, metadata.len())
+                })
+                .collect(),
+        )
+    }
+}
+
+/// How changes to the project are noticed.
+enum Changes {
+    /// File system events, the watcher stops sending them when it's dropped.
+    Events {
+        _watcher: RecommendedWatcher,
+        events: Receiver<notify::Result<notify::Event>>,
+    },
+    /// Scanning the project directory every [`POLL_INTERVAL`].
+    Polling,
+}
+
+impl Changes {
+    /// Watches the project directory, or falls back to scanning it if that's not possible.
+    fn new(build: &RemoteBuild) -> Changes {
+        let (sender, events) = mpsc::channel();
+        let watcher = notify::recommended_watcher(sender).and_then(|mut watcher| {
+            watcher.watch(&build.project_dir, RecursiveMode::Recursive)?;
+            Ok(watcher)
+        });
+        match watcher {
+            Ok(watcher) => Changes::Events {
+                _watcher: watcher,
+                events,
+            },
+            Err(e) => {
+                warn!(
+                    "Failed to watch {}, scanning it for changes instead: {}",
+                    build.project_dir.to_string_lossy(),
+                    e
+                );
+                Changes::Polling
+            }
+        }
+    }
+
+    fn wait(&self, build: &RemoteBuild, last: Snapshot) -> Snapshot {
+        match self {
+            Changes::Events { events, .. } => wait_for_events(build, events, last),
+            Changes::Polling => wait_for_change(build, last),
+        }
+    }
+}
+
+/// Whether [`event`] may change the sources that are transferred. Events in the target
+/// directory and the excluded directories are left out without scanning the project.
+fn is_relevant(build: &RemoteBuild, event: &notify::Result<notify::Event>) -> bool {
+    let event = match event {
+        Ok(event) => event,
+        // events may have been lost, the snapshot tells
+        Err(e) => {
+            debug!("Watching the project failed: {}", e);
+            return true;
+        }
+    };
+    if event.need_rescan() {
+        return true;
+    }
+    if let EventKind::Access(_) = event.kind {
+        return false;
+    }
+    event
+        .paths
+        .iter()
+        .any(|path| match path.strip_prefix(&build.project_dir) {
+            Ok(path) => crate::transport::is_transferred(build, &build.project_dir, path),
+            Err(_) => true,
+        })
+}
+
+/// Blocks until [`events`] report a change to the project sources compared to [`last`], once
+/// no relevant events came in for one poll interval. Only then is the project scanned, which
+/// tells whether the sources really changed.
+fn wait_for_events(
+    build: &RemoteBuild,
+    events: &Receiver<notify::Result<notify::Event>>,
+    last: Snapshot,
+) -> Snapshot {
+    loop {
+        match events.recv() {
+            Ok(event) if is_relevant(build, &event) => {}
+            Ok(_) => continue,
+            Err(_) => {
+                warn!("Watching the project stopped, scanning it for changes instead");
+                return wait_for_change(build, last);
+            }
+        }
+        loop {
+            match events.recv_timeout(POLL_INTERVAL) {
+                Ok(event) if is_relevant(build, &event) => {}
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return wait_for_change(build, last),
+            }
+        }
+        let current = Snapshot::take(build);
+        if current != last {
+            return current;
+        }
+    }
+}
+
+/// Blocks until the project sources differ from [`last`] and then stay unchanged for one poll
+/// interval, so saving several files at once only triggers one build.
+fn wait_for_change(build: &RemoteBuild, last: Snapshot) -> Snapshot {
+    let mut current = Snapshot::take(build);
+    while current == last {
+        sleep(POLL_INTERVAL);
+        current = Snapshot::take(build);
+    }
+    loop {
+        sleep(POLL_INTERVAL);
+        let next = Snapshot::take(build);
+        if next == current {
+            return next;
+        }
+        current = next;
+    }
+}
+
+/// `cargo remote watch [-- <cargo command> <options>]`: syncs and runs the cargo command
+/// (`check` if none is given) on the build server every time a local source file changes.
+pub fn watch(mut build: RemoteBuild) -> ! {
+    let mut args = std::mem::take(&mut build.options).into_iter();
+    build.command = args.next().unwrap_or_else(|| "check".to_string());
+    build.options = args.collect();
+    build.prepare_command();
+    if let Err(e) = build.validate() {
+        crate::error::exit_with(Err(e));
+    }
+
+    let changes = Changes::new(&build);
+    let mut snapshot = Snapshot::take(&build);
+    loop {
+        let result = build.lock().and_then(|_lock| {
+            build.transfer_sources()?;
+            let status = build.run_cargo()?;
+            build.retrieve_results(status)?;
+            Ok(status)
+        });
+        match result {
+            Ok(status) if status.success() => {
+                info!("cargo {} finished successfully", build.command)
+            }
+            Ok(status) => error!("cargo {} failed ({})", build.command, status),
+            // keep watching, the next change may well fix it
+            Err(e) => error!("{}", e),
+        }
+        info!("Waiting for changes in {}", build.project_dir.to_string_lossy());
+        snapshot = changes.wait(&build, snapshot);
+    }
+}